@@ -0,0 +1,300 @@
+use jsonwebtoken::{Algorithm, DecodingKey, jwk::JwkSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::{AuthError, JwksSource};
+
+/// Minimum time between two loads of the key set that are triggered by a
+/// token with an unknown key id, so that garbage tokens cannot hammer the
+/// identity provider.
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+/// After a failed load the next one waits this long, doubling with every
+/// further failure up to the maximum.
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+struct Cached {
+    keys: Option<Arc<JwkSet>>,
+    loaded_at: Option<Instant>,
+    /// Failed loads since the last one that succeeded.
+    failures: u32,
+    failed_at: Option<Instant>,
+}
+
+impl Cached {
+    fn needs_load(&self, kid: Option<&str>, ttl: Duration) -> bool {
+        let wanted = match (&self.keys, self.loaded_at) {
+            (Some(keys), Some(loaded_at)) => {
+                loaded_at.elapsed() > ttl
+                    || find(keys, kid).is_none() && loaded_at.elapsed() > MIN_RELOAD_INTERVAL
+            }
+            _ => true,
+        };
+        wanted
+            && self
+                .failed_at
+                .is_none_or(|failed_at| failed_at.elapsed() > retry_interval(self.failures))
+    }
+}
+
+/// The key set of the identity provider, loaded lazily and kept for a TTL.
+/// Keys are also reloaded when a token names a key id that is not known yet,
+/// which is how providers roll over to a new signing key.
+///
+/// One request at a time loads the key set. Requests that find keys in the
+/// cache use them meanwhile, even stale ones, and only requests that have
+/// no keys at all wait for the load.
+pub struct KeyCache {
+    source: JwksSource,
+    ttl: Duration,
+    client: reqwest::Client,
+    cached: Mutex<Cached>,
+    /// Held by the request that loads the key set.
+    loading: tokio::sync::Mutex<()>,
+}
+
+impl KeyCache {
+    pub fn new(source: JwksSource, ttl: Duration) -> Self {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Can not create the JWKS client");
+        Self {
+            source,
+            ttl,
+            client,
+            cached: Mutex::new(Cached::default()),
+            loading: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub async fn decoding_key(
+        &self,
+        kid: Option<&str>,
+        algorithm: Algorithm,
+    ) -> Result<DecodingKey, AuthError> {
+        if self.cached().needs_load(kid, self.ttl) {
+            self.reload(kid).await;
+        }
+
+        let keys = self.cached().keys.clone().ok_or(AuthError::UnknownKey)?;
+        let jwk = find(&keys, kid).ok_or(AuthError::UnknownKey)?;
+        if let Some(key_algorithm) = jwk.common.key_algorithm
+            && key_algorithm.to_string() != format!("{algorithm:?}")
+        {
+            return Err(AuthError::UnsupportedAlgorithm);
+        }
+        DecodingKey::from_jwk(jwk).map_err(AuthError::InvalidToken)
+    }
+
+    async fn reload(&self, kid: Option<&str>) {
+        let _loading = if self.cached().keys.is_some() {
+            match self.loading.try_lock() {
+                Ok(loading) => loading,
+                // Another request is loading, the keys we have do until then.
+                Err(_) => return,
+            }
+        } else {
+            self.loading.lock().await
+        };
+        // The request that loaded while this one waited may have succeeded,
+        // or failed and started a backoff.
+        if !self.cached().needs_load(kid, self.ttl) {
+            return;
+        }
+
+        let result = self.load().await;
+        let mut cached = self.cached();
+        match result {
+            Ok(keys) => {
+                *cached = Cached {
+                    keys: Some(Arc::new(keys)),
+                    loaded_at: Some(Instant::now()),
+                    failures: 0,
+                    failed_at: None,
+                }
+            }
+            // Keep using the keys we have rather than locking everybody out.
+            Err(e) => {
+                cached.failures += 1;
+                cached.failed_at = Some(Instant::now());
+                eprintln!(
+                    "Error loading the JWKS, retrying in {:?}: {e}",
+                    retry_interval(cached.failures)
+                );
+            }
+        }
+    }
+
+    fn cached(&self) -> MutexGuard<'_, Cached> {
+        self.cached.lock().unwrap()
+    }
+
+    async fn load(&self) -> Result<JwkSet, Box<dyn std::error::Error + Send + Sync>> {
+        match &self.source {
+            JwksSource::File(path) => {
+                let keys = tokio::fs::read(path).await?;
+                Ok(serde_json::from_slice(&keys)?)
+            }
+            JwksSource::Url(url) => Ok(self
+                .client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?),
+        }
+    }
+}
+
+/// The time to wait after the given number of failed loads in a row.
+fn retry_interval(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (MIN_RETRY_INTERVAL * 2u32.pow(doublings)).min(MAX_RETRY_INTERVAL)
+}
+
+/// Tokens without a key id are accepted if the key set has a single key.
+fn find<'a>(keys: &'a JwkSet, kid: Option<&str>) -> Option<&'a jsonwebtoken::jwk::Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{jwk, jwks_file};
+
+    const TTL: Duration = Duration::from_secs(300);
+
+    fn ago(duration: Duration) -> Option<Instant> {
+        Instant::now().checked_sub(duration)
+    }
+
+    fn loaded(kids: &[&str], loaded_at: Option<Instant>) -> Cached {
+        let keys = kids.iter().map(|kid| jwk(kid, "ES256")).collect::<Vec<_>>();
+        let keys = serde_json::from_value(serde_json::json!({ "keys": keys })).unwrap();
+        Cached {
+            keys: Some(Arc::new(keys)),
+            loaded_at,
+            ..Cached::default()
+        }
+    }
+
+    #[test]
+    fn loads_when_keys_are_missing_expired_or_unknown() {
+        assert!(Cached::default().needs_load(Some("key-1"), TTL));
+
+        let fresh = loaded(&["key-1"], Some(Instant::now()));
+        assert!(!fresh.needs_load(Some("key-1"), TTL));
+        assert!(!fresh.needs_load(None, TTL));
+        // Unknown key ids reload at most every MIN_RELOAD_INTERVAL.
+        assert!(!fresh.needs_load(Some("key-2"), TTL));
+        let settled = loaded(&["key-1"], ago(MIN_RELOAD_INTERVAL * 2));
+        assert!(!settled.needs_load(Some("key-1"), TTL));
+        assert!(settled.needs_load(Some("key-2"), TTL));
+
+        let expired = loaded(&["key-1"], ago(TTL * 2));
+        assert!(expired.needs_load(Some("key-1"), TTL));
+    }
+
+    #[test]
+    fn backs_off_after_failed_loads() {
+        let failed = Cached {
+            failures: 3,
+            failed_at: ago(MIN_RETRY_INTERVAL * 3),
+            ..Cached::default()
+        };
+        assert!(!failed.needs_load(Some("key-1"), TTL));
+        let waited = Cached {
+            failed_at: ago(MIN_RETRY_INTERVAL * 5),
+            ..failed
+        };
+        assert!(waited.needs_load(Some("key-1"), TTL));
+
+        assert_eq!(retry_interval(1), MIN_RETRY_INTERVAL);
+        assert_eq!(retry_interval(2), MIN_RETRY_INTERVAL * 2);
+        assert_eq!(retry_interval(3), MIN_RETRY_INTERVAL * 4);
+        assert_eq!(retry_interval(7), MAX_RETRY_INTERVAL);
+        assert_eq!(retry_interval(u32::MAX), MAX_RETRY_INTERVAL);
+    }
+
+    #[tokio::test]
+    async fn reloads_for_a_new_key_id_after_the_minimum_interval() {
+        let path = jwks_file("rollover", &[jwk("key-1", "ES256")]);
+        let cache = KeyCache::new(JwksSource::File(path.clone()), TTL);
+        assert!(
+            cache
+                .decoding_key(Some("key-1"), Algorithm::ES256)
+                .await
+                .is_ok()
+        );
+
+        jwks_file("rollover", &[jwk("key-1", "ES256"), jwk("key-2", "ES256")]);
+        let result = cache.decoding_key(Some("key-2"), Algorithm::ES256).await;
+        assert!(matches!(result, Err(AuthError::UnknownKey)));
+
+        cache.cached().loaded_at = ago(MIN_RELOAD_INTERVAL * 2);
+        assert!(
+            cache
+                .decoding_key(Some("key-2"), Algorithm::ES256)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_its_keys_and_waits_after_a_failed_load() {
+        let path = jwks_file("failing", &[jwk("key-1", "ES256")]);
+        let cache = KeyCache::new(JwksSource::File(path.clone()), TTL);
+        assert!(
+            cache
+                .decoding_key(Some("key-1"), Algorithm::ES256)
+                .await
+                .is_ok()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        cache.cached().loaded_at = ago(TTL * 2);
+        assert!(
+            cache
+                .decoding_key(Some("key-1"), Algorithm::ES256)
+                .await
+                .is_ok()
+        );
+        assert_eq!(cache.cached().failures, 1);
+
+        // The next load waits for the retry interval.
+        assert!(
+            cache
+                .decoding_key(Some("key-1"), Algorithm::ES256)
+                .await
+                .is_ok()
+        );
+        assert_eq!(cache.cached().failures, 1);
+        cache.cached().failed_at = ago(MIN_RETRY_INTERVAL * 2);
+        assert!(
+            cache
+                .decoding_key(Some("key-1"), Algorithm::ES256)
+                .await
+                .is_ok()
+        );
+        assert_eq!(cache.cached().failures, 2);
+    }
+
+    #[tokio::test]
+    async fn has_no_keys_while_the_first_load_fails() {
+        let path = std::env::temp_dir().join(format!("jwks-{}-missing.json", std::process::id()));
+        let cache = KeyCache::new(JwksSource::File(path), TTL);
+        let result = cache.decoding_key(Some("key-1"), Algorithm::ES256).await;
+        assert!(matches!(result, Err(AuthError::UnknownKey)));
+        assert_eq!(cache.cached().failures, 1);
+    }
+}
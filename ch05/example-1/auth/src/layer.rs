@@ -0,0 +1,273 @@
+use axum::{
+    extract::Request,
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{AuthConfig, Authenticator, Principal, unauthorized};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Authenticates requests that carry a bearer token and adds the caller's
+/// [`Principal`] to the request extensions. Requests with an invalid token
+/// are rejected with 401, requests without one are passed on anonymously.
+#[derive(Clone)]
+pub struct AuthLayer {
+    authenticator: Option<Authenticator>,
+}
+
+impl AuthLayer {
+    /// Without a configuration every bearer token is rejected, as there is
+    /// no way to check it.
+    pub fn new(config: Option<AuthConfig>) -> Self {
+        Self {
+            authenticator: config.map(Authenticator::new),
+        }
+    }
+
+    /// Configures the layer from the environment, see [`AuthConfig::from_env`].
+    pub fn from_env() -> Self {
+        let config = AuthConfig::from_env();
+        if config.is_none() {
+            println!("Neither JWKS_URL nor JWKS_PATH is set, authentication is disabled");
+        }
+        Self::new(config)
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            authenticator: self.authenticator.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    authenticator: Option<Authenticator>,
+}
+
+impl<S> Service<Request> for AuthService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        // The clone has not been polled for readiness, so keep the service that
+        // has been and leave the clone in its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let authenticator = self.authenticator.clone();
+        Box::pin(async move {
+            let token = match request.headers().get(header::AUTHORIZATION) {
+                None => None,
+                Some(value) => match value.to_str().ok().and_then(|v| v.strip_prefix("Bearer ")) {
+                    Some(token) => Some(token.to_string()),
+                    None => return Ok(unauthorized("Malformed Authorization header")),
+                },
+            };
+            if let Some(token) = token {
+                let Some(authenticator) = authenticator else {
+                    return Ok(unauthorized("Authentication is not configured"));
+                };
+                match authenticator.authenticate(&token).await {
+                    Ok(principal) => {
+                        request.extensions_mut().insert(principal);
+                    }
+                    Err(e) => {
+                        println!("Rejected bearer token: {e}");
+                        return Ok(unauthorized("Invalid bearer token"));
+                    }
+                }
+            }
+            inner.call(request).await
+        })
+    }
+}
+
+/// Restricts a route to authenticated callers that have at least one of the
+/// given roles. Needs [`AuthLayer`] to run first, e.g.
+/// `.route("/videos", post(create).route_layer(require_roles(["editor"])))`.
+pub fn require_roles<I, R>(roles: I) -> RequireRoles
+where
+    I: IntoIterator<Item = R>,
+    R: Into<String>,
+{
+    RequireRoles {
+        roles: roles.into_iter().map(Into::into).collect(),
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireRoles {
+    roles: Arc<[String]>,
+}
+
+impl<S> Layer<S> for RequireRoles {
+    type Service = RequireRolesService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireRolesService {
+            inner,
+            roles: self.roles.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RequireRolesService<S> {
+    inner: S,
+    roles: Arc<[String]>,
+}
+
+impl<S> Service<Request> for RequireRolesService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let allowed = match request.extensions().get::<Principal>() {
+            None => return Box::pin(async { Ok(unauthorized("Authentication required")) }),
+            Some(principal) => self.roles.iter().any(|role| principal.has_role(role)),
+        };
+        if !allowed {
+            return Box::pin(async { Ok((StatusCode::FORBIDDEN, "Forbidden").into_response()) });
+        }
+        Box::pin(self.inner.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Extension, Router,
+        body::Body,
+        routing::{get, post},
+    };
+    use tower::ServiceExt;
+
+    /// An anonymous `GET /` and an editors only `POST /`, behind an
+    /// unconfigured [`AuthLayer`]. The caller, if any, is added outside of
+    /// it, like a token the layer had accepted.
+    fn app(caller: Option<Principal>) -> Router {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|caller: Option<Principal>| async move {
+                    caller.map_or("anonymous".to_string(), |caller| caller.subject)
+                })
+                .merge(
+                    post(|| async { "created" }).route_layer(require_roles(["editor", "admin"])),
+                ),
+            )
+            .layer(AuthLayer::new(None));
+        match caller {
+            Some(caller) => app.layer(Extension(caller)),
+            None => app,
+        }
+    }
+
+    fn principal(roles: &[&str]) -> Principal {
+        Principal {
+            subject: "alice".to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    async fn status(app: Router, method: &str) -> StatusCode {
+        let request = Request::builder().method(method).uri("/");
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        response.status()
+    }
+
+    #[test]
+    fn principals_have_the_roles_they_were_granted() {
+        let principal = principal(&["viewer", "editor"]);
+        assert!(principal.has_role("editor"));
+        assert!(!principal.has_role("admin"));
+        assert!(!principal.has_role("Editor"));
+    }
+
+    #[tokio::test]
+    async fn passes_requests_without_a_token_on_anonymously() {
+        let response = app(None)
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"anonymous");
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_it_can_not_check() {
+        for authorization in [
+            "Bearer eyJhbGciOiJSUzI1NiJ9.e30.c2ln",
+            "Basic YWxpY2U6c2VjcmV0",
+        ] {
+            let response = app(None)
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .header(header::AUTHORIZATION, authorization)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::UNAUTHORIZED,
+                "{authorization}"
+            );
+            assert_eq!(response.headers()["www-authenticate"], "Bearer");
+        }
+    }
+
+    #[tokio::test]
+    async fn requires_one_of_the_roles() {
+        assert_eq!(status(app(None), "POST").await, StatusCode::UNAUTHORIZED);
+        let viewer = app(Some(principal(&["viewer"])));
+        assert_eq!(status(viewer.clone(), "POST").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(viewer, "GET").await, StatusCode::OK);
+        for role in ["editor", "admin"] {
+            let app = app(Some(principal(&["viewer", role])));
+            assert_eq!(status(app, "POST").await, StatusCode::OK, "{role}");
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! Publish/subscribe messaging shared by the services of the workspace.
+//!
+//! Messages are [`Event`]s, JSON encoded and published to the topic their
+//! type names. Services talk to an [`EventBus`], which is either a RabbitMQ
+//! broker ([`RabbitBus`]), as in compose, or an in-process bus
+//! ([`InMemoryBus`]) for tests and single-process demos. [`from_env`] picks
+//! one by configuration; the typed methods come from [`EventBusExt`].
+
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{env, fmt, future::Future, sync::Arc};
+
+mod memory;
+mod rabbit;
+
+pub use memory::InMemoryBus;
+pub use rabbit::{RabbitBus, RabbitConfig};
+
+/// A message type and the topic it is published to.
+pub trait Event: Serialize + DeserializeOwned + Send + 'static {
+    /// The name of the RabbitMQ exchange.
+    const TOPIC: &'static str;
+}
+
+/// Handles the encoded messages of a subscription. Messages it fails on are
+/// dead-lettered rather than delivered again.
+pub type RawHandler = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    /// Publishes an encoded message to a topic. Returns once the bus has
+    /// taken responsibility for the message, which for RabbitMQ is when the
+    /// broker has confirmed it. A message is dropped rather than delivered
+    /// if the caller stops waiting before that.
+    async fn publish_raw(&self, topic: &str, payload: Vec<u8>) -> Result<(), Error>;
+
+    /// Hands the messages published to a topic to `handler` in the
+    /// background. Every subscription gets each message once, which goes to
+    /// one of the handlers subscribed under its name. Messages published
+    /// before the first subscriber of a subscription came along are lost on
+    /// the in-memory bus, RabbitMQ keeps them in the subscription's queue.
+    fn subscribe_raw(&self, topic: &str, subscription: &str, handler: RawHandler);
+}
+
+/// Publishing and subscribing with typed messages, for every [`EventBus`].
+pub trait EventBusExt: EventBus {
+    fn publish<E: Event>(&self, event: &E) -> impl Future<Output = Result<(), Error>> + Send {
+        let payload = serde_json::to_vec(event);
+        async move { self.publish_raw(E::TOPIC, payload?).await }
+    }
+
+    /// Subscribes to the topic of `E`. Messages that can not be decoded are
+    /// dead-lettered like the ones `handler` fails on.
+    fn subscribe<E, H, F>(&self, subscription: &str, handler: H)
+    where
+        E: Event,
+        H: Fn(E) -> F + Send + Sync + 'static,
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let raw: RawHandler = Arc::new(move |payload: Vec<u8>| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let event = serde_json::from_slice(&payload)
+                    .map_err(|e| format!("invalid message: {e}"))?;
+                handler(event).await
+            })
+        });
+        self.subscribe_raw(E::TOPIC, subscription, raw);
+    }
+}
+
+impl<B: EventBus + ?Sized> EventBusExt for B {}
+
+#[derive(Debug)]
+pub enum Error {
+    Encode(serde_json::Error),
+    Rabbit(amqprs::error::Error),
+    /// More messages are waiting for the broker than the buffer holds.
+    BufferFull,
+    /// The broker rejected the message.
+    Rejected,
+    /// The bus has stopped.
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Encode(e) => write!(f, "can not encode message: {e}"),
+            Error::Rabbit(e) => write!(f, "RabbitMQ error: {e}"),
+            Error::BufferFull => write!(f, "message buffer is full"),
+            Error::Rejected => write!(f, "message rejected by the broker"),
+            Error::Closed => write!(f, "event bus closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Encode(e)
+    }
+}
+
+impl From<amqprs::error::Error> for Error {
+    fn from(e: amqprs::error::Error) -> Self {
+        Error::Rabbit(e)
+    }
+}
+
+/// The bus selected by EVENT_BUS: `rabbit`, the default, connects to RABBIT
+/// (see [`RabbitConfig::from_env`]) with `name` as the connection name, and
+/// `memory` creates an [`InMemoryBus`]. Returns `None` if EVENT_BUS is not
+/// set and neither is RABBIT.
+pub fn from_env(name: &str) -> Option<Arc<dyn EventBus>> {
+    match env::var("EVENT_BUS").as_deref() {
+        Ok("memory") => Some(Arc::new(InMemoryBus::new())),
+        Ok("rabbit") => {
+            let config = RabbitConfig::from_env(name).expect("RABBIT environment variable not set");
+            Some(Arc::new(RabbitBus::new(config)))
+        }
+        Err(_) => {
+            let config = RabbitConfig::from_env(name)?;
+            Some(Arc::new(RabbitBus::new(config)))
+        }
+        Ok(other) => panic!("Unknown EVENT_BUS {other}, expected rabbit or memory"),
+    }
+}
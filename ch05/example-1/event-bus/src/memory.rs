@@ -0,0 +1,170 @@
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Mutex as AsyncMutex, mpsc};
+
+use crate::{Error, EventBus, RawHandler};
+
+/// Delivers messages between the parts of a single process, without
+/// persistence: messages are lost with the process.
+#[derive(Default)]
+pub struct InMemoryBus {
+    /// The queue of every subscription, by topic and subscription name.
+    topics: Mutex<HashMap<String, HashMap<String, Subscription>>>,
+}
+
+struct Subscription {
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+    /// Shared by the handlers subscribed under the same name, each message
+    /// goes to the first one that is free.
+    receiver: Arc<AsyncMutex<mpsc::UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl InMemoryBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl EventBus for InMemoryBus {
+    async fn publish_raw(&self, topic: &str, payload: Vec<u8>) -> Result<(), Error> {
+        let topics = self.topics.lock().unwrap();
+        for subscription in topics.get(topic).into_iter().flat_map(HashMap::values) {
+            subscription
+                .sender
+                .send(payload.clone())
+                .map_err(|_| Error::Closed)?;
+        }
+        Ok(())
+    }
+
+    fn subscribe_raw(&self, topic: &str, subscription: &str, handler: RawHandler) {
+        let receiver = {
+            let mut topics = self.topics.lock().unwrap();
+            let subscription = topics
+                .entry(topic.to_string())
+                .or_default()
+                .entry(subscription.to_string())
+                .or_insert_with(|| {
+                    let (sender, receiver) = mpsc::unbounded_channel();
+                    Subscription {
+                        sender,
+                        receiver: Arc::new(AsyncMutex::new(receiver)),
+                    }
+                });
+            subscription.receiver.clone()
+        };
+        let name = subscription.to_string();
+        tokio::spawn(async move {
+            loop {
+                let Some(payload) = receiver.lock().await.recv().await else {
+                    break;
+                };
+                if let Err(e) = handler(payload).await {
+                    eprintln!("Dropping a message of subscription {name}: {e}");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, EventBusExt};
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct Ping {
+        n: u32,
+    }
+
+    impl Event for Ping {
+        const TOPIC: &'static str = "ping";
+    }
+
+    /// Subscribes a handler that forwards the pings it gets, tagged with `tag`.
+    fn forward(
+        bus: &InMemoryBus,
+        subscription: &str,
+        tag: &'static str,
+        sender: &mpsc::UnboundedSender<(&'static str, u32)>,
+    ) {
+        let sender = sender.clone();
+        bus.subscribe(subscription, move |ping: Ping| {
+            let sent = sender.send((tag, ping.n)).map_err(|e| e.to_string());
+            async move { sent }
+        });
+    }
+
+    async fn receive(
+        receiver: &mut mpsc::UnboundedReceiver<(&'static str, u32)>,
+        count: usize,
+    ) -> Vec<(&'static str, u32)> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .expect("message not delivered");
+            received.push(message.unwrap());
+        }
+        // Nothing more arrives, e.g. a second copy of a message.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(receiver.try_recv().is_err());
+        received
+    }
+
+    #[tokio::test]
+    async fn delivers_every_message_once_to_every_subscription() {
+        let bus = InMemoryBus::new();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        // Two handlers share the history subscription, search has its own.
+        forward(&bus, "history", "history", &sender);
+        forward(&bus, "history", "history", &sender);
+        forward(&bus, "search", "search", &sender);
+
+        for n in 0..10 {
+            bus.publish(&Ping { n }).await.unwrap();
+        }
+        let received = receive(&mut receiver, 20).await;
+        for tag in ["history", "search"] {
+            let mut numbers: Vec<u32> = received
+                .iter()
+                .filter(|(subscription, _)| *subscription == tag)
+                .map(|(_, n)| *n)
+                .collect();
+            numbers.sort();
+            assert_eq!(numbers, (0..10).collect::<Vec<_>>(), "{tag}");
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_messages_it_can_not_decode() {
+        let bus = InMemoryBus::new();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        forward(&bus, "history", "history", &sender);
+
+        bus.publish_raw(Ping::TOPIC, b"not json".to_vec())
+            .await
+            .unwrap();
+        bus.publish_raw("other", br#"{"n":1}"#.to_vec())
+            .await
+            .unwrap();
+        bus.publish(&Ping { n: 2 }).await.unwrap();
+        assert_eq!(receive(&mut receiver, 1).await, [("history", 2)]);
+    }
+
+    #[tokio::test]
+    async fn drops_messages_published_before_the_first_subscriber() {
+        let bus = InMemoryBus::new();
+        bus.publish(&Ping { n: 1 }).await.unwrap();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        forward(&bus, "history", "history", &sender);
+        bus.publish(&Ping { n: 2 }).await.unwrap();
+        assert_eq!(receive(&mut receiver, 1).await, [("history", 2)]);
+    }
+}
@@ -0,0 +1,16 @@
+use std::{env, fs, path::PathBuf};
+
+/// Writes the JSON Schema of every event in every version that has one to
+/// `<type>.v<version>.json` in the directory given as the first argument,
+/// `schemas` by default.
+fn main() {
+    let dir = PathBuf::from(env::args().nth(1).unwrap_or_else(|| "schemas".to_string()));
+    fs::create_dir_all(&dir).expect("Can not create the schema directory");
+    for (file, schema) in events::schemas() {
+        let path = dir.join(file);
+        let mut json = serde_json::to_string_pretty(&schema).expect("Can not encode the schema");
+        json.push('\n');
+        fs::write(&path, json).expect("Can not write the schema");
+        println!("Wrote {}", path.display());
+    }
+}
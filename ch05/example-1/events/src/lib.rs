@@ -0,0 +1,368 @@
+//! The events the services of the workspace exchange, with versioned
+//! payloads in a CloudEvents 1.0 envelope (JSON event format).
+//!
+//! Every payload type implements [`EventData`], which names its CloudEvents
+//! `type`, the event bus topic and the version of its schema. The version
+//! travels with each event in the `schemaversion` extension attribute, and
+//! events of older versions are upcast to the current one while they are
+//! decoded. [`schemas`] returns the JSON Schema of every event in every
+//! version that is still accepted, for consumers outside the workspace; the
+//! `export-schemas` binary writes them to files.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
+use serde_json::{Value, json};
+use std::fmt;
+
+mod video;
+
+pub use video::{VideoDeleted, VideoUploaded, VideoViewed};
+
+pub const SPEC_VERSION: &str = "1.0";
+/// The media type of events in the structured JSON mode of CloudEvents.
+pub const CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// The payload of an event.
+pub trait EventData: Serialize + DeserializeOwned + Send + 'static {
+    /// The CloudEvents `type`, such as `video.viewed`.
+    const TYPE: &'static str;
+    /// The event bus topic events of this type are published to.
+    const TOPIC: &'static str;
+    /// The version of the payload's schema, raised with every change that
+    /// older consumers or producers can not handle.
+    const VERSION: u32;
+
+    /// The JSON Schema of the payload in the current version.
+    fn data_schema() -> Value;
+
+    /// The JSON Schema of the payload in an older `version`, for the versions
+    /// that are upcast and were sent in the envelope. `None` for others.
+    fn older_data_schema(version: u32) -> Option<Value> {
+        let _ = version;
+        None
+    }
+
+    /// Converts a payload of an older `version` to the next version. Called
+    /// repeatedly until the payload has the current version.
+    fn upcast(version: u32, data: Value) -> Result<Value, String> {
+        let _ = data;
+        Err(format!("no upcast from version {version}"))
+    }
+}
+
+/// An event in the CloudEvents envelope.
+#[derive(Clone, Debug, Serialize)]
+pub struct CloudEvent<T> {
+    pub specversion: String,
+    /// Unique per event, and the same when an event is delivered again.
+    pub id: String,
+    /// The service the event happened in, such as `/video-streaming`.
+    pub source: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub time: DateTime<Utc>,
+    pub datacontenttype: String,
+    /// Extension attribute with the version of the schema of `data`.
+    pub schemaversion: u32,
+    pub data: T,
+}
+
+impl<T: EventData> CloudEvent<T> {
+    /// A new event that happened now, with a random id.
+    pub fn new(source: &str, data: T) -> Self {
+        Self {
+            specversion: SPEC_VERSION.to_string(),
+            id: uuid::Uuid::new_v4().to_string(),
+            source: source.to_string(),
+            event_type: T::TYPE.to_string(),
+            time: Utc::now(),
+            datacontenttype: "application/json".to_string(),
+            schemaversion: T::VERSION,
+            data,
+        }
+    }
+}
+
+impl<T: EventData> event_bus::Event for CloudEvent<T> {
+    const TOPIC: &'static str = T::TOPIC;
+}
+
+/// An event as it arrives, before its payload is upcast and decoded.
+#[derive(Deserialize)]
+struct RawCloudEvent {
+    specversion: String,
+    id: String,
+    source: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    time: DateTime<Utc>,
+    #[serde(default)]
+    datacontenttype: Option<String>,
+    /// Every event sent in the envelope has it, see [`Error::MissingVersion`].
+    #[serde(default)]
+    schemaversion: Option<u32>,
+    data: Value,
+}
+
+impl<'de, T: EventData> Deserialize<'de> for CloudEvent<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = RawCloudEvent::deserialize(deserializer)?;
+        decode(raw).map_err(serde::de::Error::custom)
+    }
+}
+
+fn decode<T: EventData>(raw: RawCloudEvent) -> Result<CloudEvent<T>, Error> {
+    if raw.specversion.split('.').next() != Some("1") {
+        return Err(Error::SpecVersion(raw.specversion));
+    }
+    if raw.event_type != T::TYPE {
+        return Err(Error::Type {
+            expected: T::TYPE,
+            found: raw.event_type,
+        });
+    }
+    let mut version = raw.schemaversion.ok_or(Error::MissingVersion)?;
+    if version == 0 || version > T::VERSION {
+        return Err(Error::Version(version));
+    }
+    let mut data = raw.data;
+    while version < T::VERSION {
+        data = T::upcast(version, data).map_err(Error::Upcast)?;
+        version += 1;
+    }
+    Ok(CloudEvent {
+        specversion: raw.specversion,
+        id: raw.id,
+        source: raw.source,
+        event_type: raw.event_type,
+        time: raw.time,
+        datacontenttype: raw
+            .datacontenttype
+            .unwrap_or_else(|| "application/json".to_string()),
+        schemaversion: version,
+        data: serde_json::from_value(data).map_err(Error::Data)?,
+    })
+}
+
+#[derive(Debug)]
+pub enum Error {
+    SpecVersion(String),
+    Type {
+        expected: &'static str,
+        found: String,
+    },
+    /// Without the `schemaversion` extension, whose payload can not be told
+    /// apart from other versions.
+    MissingVersion,
+    /// A schema version this build does not know yet.
+    Version(u32),
+    Upcast(String),
+    Data(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::SpecVersion(version) => write!(f, "unsupported CloudEvents version {version}"),
+            Error::Type { expected, found } => {
+                write!(f, "expected an event of type {expected}, found {found}")
+            }
+            Error::MissingVersion => write!(f, "missing schemaversion"),
+            Error::Version(version) => write!(f, "unknown schema version {version}"),
+            Error::Upcast(message) => write!(f, "can not upcast the event: {message}"),
+            Error::Data(e) => write!(f, "invalid event data: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The JSON Schema of events of type `T` in the current version, envelope
+/// included.
+pub fn schema<T: EventData>() -> Value {
+    envelope_schema::<T>(T::VERSION, T::data_schema())
+}
+
+/// The JSON Schema of events of type `T` in `version`, envelope included, or
+/// `None` for versions without one.
+pub fn versioned_schema<T: EventData>(version: u32) -> Option<Value> {
+    let data = match version {
+        version if version == T::VERSION => T::data_schema(),
+        version => T::older_data_schema(version)?,
+    };
+    Some(envelope_schema::<T>(version, data))
+}
+
+fn envelope_schema<T: EventData>(version: u32, data: Value) -> Value {
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": versioned_schema_file::<T>(version),
+        "title": format!("{} version {version}", T::TYPE),
+        "type": "object",
+        "required": ["specversion", "id", "source", "type", "time", "schemaversion", "data"],
+        "properties": {
+            "specversion": { "type": "string", "const": SPEC_VERSION },
+            "id": { "type": "string", "minLength": 1 },
+            "source": { "type": "string", "format": "uri-reference", "minLength": 1 },
+            "type": { "type": "string", "const": T::TYPE },
+            "time": { "type": "string", "format": "date-time" },
+            "datacontenttype": { "type": "string", "const": "application/json" },
+            "schemaversion": { "type": "integer", "const": version },
+            "data": data,
+        },
+    })
+}
+
+/// The file name of the schema of `T`, such as `video.viewed.v3.json`.
+pub fn schema_file<T: EventData>() -> String {
+    versioned_schema_file::<T>(T::VERSION)
+}
+
+pub fn versioned_schema_file<T: EventData>(version: u32) -> String {
+    format!("{}.v{version}.json", T::TYPE)
+}
+
+/// The file names and schemas of all events in all versions that have one.
+pub fn schemas() -> Vec<(String, Value)> {
+    let mut schemas = Vec::new();
+    all_versions::<VideoViewed>(&mut schemas);
+    all_versions::<VideoUploaded>(&mut schemas);
+    all_versions::<VideoDeleted>(&mut schemas);
+    schemas
+}
+
+fn all_versions<T: EventData>(schemas: &mut Vec<(String, Value)>) {
+    for version in 1..=T::VERSION {
+        if let Some(schema) = versioned_schema::<T>(version) {
+            schemas.push((versioned_schema_file::<T>(version), schema));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn viewed(schemaversion: Value, data: Value) -> Value {
+        json!({
+            "specversion": "1.0",
+            "id": "8d4a3c1e-1f0b-4a9e-9b7a-2f3e4d5c6b7a",
+            "source": "/video-streaming",
+            "type": "video.viewed",
+            "time": "2024-01-01T12:00:00Z",
+            "schemaversion": schemaversion,
+            "data": data,
+        })
+    }
+
+    fn decode_viewed(event: Value) -> Result<CloudEvent<VideoViewed>, Error> {
+        decode(serde_json::from_value(event).unwrap())
+    }
+
+    #[test]
+    fn decodes_the_current_version() {
+        let data = json!({
+            "videoId": "65a1b2c3d4e5f6a7b8c9d0e1",
+            "videoPath": "videos/sample.mp4",
+            "viewerId": "alice",
+            "anonymousId": null,
+            "userAgent": "Mozilla/5.0",
+            "referrer": "https://example.com/",
+        });
+        let event = decode_viewed(viewed(json!(3), data)).unwrap();
+        assert_eq!(event.schemaversion, 3);
+        assert_eq!(event.datacontenttype, "application/json");
+        assert_eq!(event.data.viewer_id.as_deref(), Some("alice"));
+        assert_eq!(event.data.referrer.as_deref(), Some("https://example.com/"));
+    }
+
+    #[test]
+    fn upcasts_version_1_to_the_current_version() {
+        let event = decode_viewed(viewed(
+            json!(1),
+            json!({ "video_path": "videos/sample.mp4" }),
+        ))
+        .unwrap();
+        assert_eq!(event.schemaversion, VideoViewed::VERSION);
+        assert_eq!(
+            event.data,
+            VideoViewed {
+                video_id: None,
+                video_path: "videos/sample.mp4".to_string(),
+                viewer_id: None,
+                anonymous_id: None,
+                user_agent: None,
+                referrer: None,
+            }
+        );
+    }
+
+    #[test]
+    fn upcasts_version_2_to_the_current_version() {
+        let event = decode_viewed(viewed(
+            json!(2),
+            json!({ "videoId": "65a1b2c3d4e5f6a7b8c9d0e1", "videoPath": "videos/sample.mp4" }),
+        ))
+        .unwrap();
+        assert_eq!(event.schemaversion, VideoViewed::VERSION);
+        assert_eq!(
+            event.data.video_id.as_deref(),
+            Some("65a1b2c3d4e5f6a7b8c9d0e1")
+        );
+        assert_eq!(event.data.video_path, "videos/sample.mp4");
+        assert_eq!(event.data.viewer_id, None);
+    }
+
+    #[test]
+    fn rejects_unknown_and_missing_versions() {
+        let data = json!({ "videoPath": "videos/sample.mp4" });
+        assert!(matches!(
+            decode_viewed(viewed(json!(0), data.clone())),
+            Err(Error::Version(0))
+        ));
+        assert!(matches!(
+            decode_viewed(viewed(json!(4), data.clone())),
+            Err(Error::Version(4))
+        ));
+        let mut event = viewed(json!(3), data);
+        event.as_object_mut().unwrap().remove("schemaversion");
+        assert!(matches!(decode_viewed(event), Err(Error::MissingVersion)));
+    }
+
+    #[test]
+    fn rejects_other_types_and_spec_versions() {
+        let data = json!({ "videoPath": "videos/sample.mp4" });
+        let mut event = viewed(json!(3), data.clone());
+        event["type"] = json!("video.uploaded");
+        assert!(matches!(
+            decode_viewed(event),
+            Err(Error::Type { expected: "video.viewed", found }) if found == "video.uploaded"
+        ));
+
+        let mut event = viewed(json!(3), data);
+        event["specversion"] = json!("0.3");
+        assert!(matches!(
+            decode_viewed(event),
+            Err(Error::SpecVersion(version)) if version == "0.3"
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_serde() {
+        let sent = CloudEvent::new(
+            "/video-streaming",
+            VideoViewed {
+                video_id: None,
+                video_path: "videos/sample.mp4".to_string(),
+                viewer_id: Some("alice".to_string()),
+                anonymous_id: None,
+                user_agent: None,
+                referrer: None,
+            },
+        );
+        let received: CloudEvent<VideoViewed> =
+            serde_json::from_str(&serde_json::to_string(&sent).unwrap()).unwrap();
+        assert_eq!(received.id, sent.id);
+        assert_eq!(received.data, sent.data);
+    }
+}
@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::EventData;
+
+/// A video was played, counted once per view rather than per request.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoViewed {
+    /// Missing in events upcast from version 1.
+    pub video_id: Option<String>,
+    pub video_path: String,
+    /// Subject of the signed in viewer.
+    pub viewer_id: Option<String>,
+    /// Stands for a viewer who is not signed in. Missing in events upcast
+    /// from version 2, like the other client context.
+    pub anonymous_id: Option<String>,
+    /// The `User-Agent` of the player.
+    pub user_agent: Option<String>,
+    /// The `Referer` of the request that started the view.
+    pub referrer: Option<String>,
+}
+
+impl EventData for VideoViewed {
+    const TYPE: &'static str = "video.viewed";
+    const TOPIC: &'static str = "viewed";
+    /// Version 1 was the bare `{"video_path": ...}` message, version 2 had no
+    /// viewer and client context.
+    const VERSION: u32 = 3;
+
+    fn data_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["videoPath"],
+            "properties": {
+                "videoId": {
+                    "type": ["string", "null"],
+                    "description": "ObjectId of the video in the catalog",
+                },
+                "videoPath": { "type": "string", "minLength": 1 },
+                "viewerId": {
+                    "type": ["string", "null"],
+                    "description": "Subject of the signed in viewer",
+                },
+                "anonymousId": {
+                    "type": ["string", "null"],
+                    "description": "Pseudonymous id of a viewer who is not signed in",
+                },
+                "userAgent": { "type": ["string", "null"] },
+                "referrer": { "type": ["string", "null"] },
+            },
+        })
+    }
+
+    /// Version 1 was never sent in the envelope and has no schema.
+    fn older_data_schema(version: u32) -> Option<Value> {
+        match version {
+            2 => Some(json!({
+                "type": "object",
+                "required": ["videoPath"],
+                "properties": {
+                    "videoId": {
+                        "type": ["string", "null"],
+                        "description": "ObjectId of the video in the catalog",
+                    },
+                    "videoPath": { "type": "string", "minLength": 1 },
+                },
+            })),
+            _ => None,
+        }
+    }
+
+    fn upcast(version: u32, data: Value) -> Result<Value, String> {
+        match version {
+            1 => {
+                let video_path = data
+                    .get("video_path")
+                    .and_then(Value::as_str)
+                    .ok_or("missing video_path")?;
+                Ok(json!({ "videoId": null, "videoPath": video_path }))
+            }
+            2 => {
+                let mut data = data;
+                let fields = data.as_object_mut().ok_or("data is not an object")?;
+                for field in ["viewerId", "anonymousId", "userAgent", "referrer"] {
+                    fields.insert(field.to_string(), Value::Null);
+                }
+                Ok(data)
+            }
+            _ => Err(format!("no upcast from version {version}")),
+        }
+    }
+}
+
+/// A video was added to the catalog.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoUploaded {
+    pub video_id: String,
+    pub video_path: String,
+    pub title: String,
+    pub owner: Option<String>,
+}
+
+impl EventData for VideoUploaded {
+    const TYPE: &'static str = "video.uploaded";
+    const TOPIC: &'static str = "uploaded";
+    const VERSION: u32 = 1;
+
+    fn data_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["videoId", "videoPath", "title"],
+            "properties": {
+                "videoId": { "type": "string" },
+                "videoPath": { "type": "string", "minLength": 1 },
+                "title": { "type": "string" },
+                "owner": {
+                    "type": ["string", "null"],
+                    "description": "Subject of the user the video belongs to",
+                },
+            },
+        })
+    }
+}
+
+/// A video was removed from the catalog.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoDeleted {
+    pub video_id: String,
+}
+
+impl EventData for VideoDeleted {
+    const TYPE: &'static str = "video.deleted";
+    const TOPIC: &'static str = "deleted";
+    const VERSION: u32 = 1;
+
+    fn data_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["videoId"],
+            "properties": {
+                "videoId": { "type": "string" },
+            },
+        })
+    }
+}
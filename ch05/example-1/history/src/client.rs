@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+/// The kind of client a view came from, derived from its user agent. Only
+/// the class is stored, not the user agent itself.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UserAgentClass {
+    Bot,
+    Tv,
+    Tablet,
+    Mobile,
+    Desktop,
+    /// No user agent, or records from before user agents were sent.
+    Unknown,
+}
+
+impl UserAgentClass {
+    /// Classifies a user agent by the tokens browsers, players and crawlers
+    /// commonly send. Checked from the most to the least specific.
+    pub fn of(user_agent: Option<&str>) -> Self {
+        let Some(user_agent) = user_agent.map(str::to_lowercase) else {
+            return UserAgentClass::Unknown;
+        };
+        let contains_any = |tokens: &[&str]| tokens.iter().any(|token| user_agent.contains(token));
+        if contains_any(&[
+            "bot",
+            "crawler",
+            "spider",
+            "curl",
+            "wget",
+            "python-requests",
+        ]) {
+            UserAgentClass::Bot
+        } else if contains_any(&["smart-tv", "smarttv", "appletv", "roku", "tizen", "webos"]) {
+            UserAgentClass::Tv
+        } else if contains_any(&["ipad", "tablet"])
+            || (user_agent.contains("android") && !user_agent.contains("mobile"))
+        {
+            UserAgentClass::Tablet
+        } else if contains_any(&["mobile", "iphone", "ipod", "android"]) {
+            UserAgentClass::Mobile
+        } else if contains_any(&["windows", "macintosh", "x11", "linux", "cros"]) {
+            UserAgentClass::Desktop
+        } else {
+            UserAgentClass::Unknown
+        }
+    }
+}
+
+/// The referrer without its query and fragment, which may carry tokens or
+/// other data of the referring page that is not ours to keep.
+pub fn referrer_page(referrer: Option<&str>) -> Option<String> {
+    let page = referrer?.split(['?', '#']).next()?.trim();
+    (!page.is_empty()).then(|| page.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_user_agents() {
+        let cases = [
+            (
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+                UserAgentClass::Bot,
+            ),
+            ("curl/8.5.0", UserAgentClass::Bot),
+            (
+                "Mozilla/5.0 (SMART-TV; Linux; Tizen 6.0) AppleWebKit/537.36",
+                UserAgentClass::Tv,
+            ),
+            ("Roku/DVP-12.0 (12.0.0.4182)", UserAgentClass::Tv),
+            (
+                "Mozilla/5.0 (iPad; CPU OS 17_0 like Mac OS X) AppleWebKit/605.1.15",
+                UserAgentClass::Tablet,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; SM-X710) AppleWebKit/537.36 Safari/537.36",
+                UserAgentClass::Tablet,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 Mobile Safari/537.36",
+                UserAgentClass::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) Mobile/15E148",
+                UserAgentClass::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 Chrome/126.0",
+                UserAgentClass::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+                UserAgentClass::Desktop,
+            ),
+            ("SomePlayer/1.0", UserAgentClass::Unknown),
+        ];
+        for (user_agent, class) in cases {
+            assert_eq!(UserAgentClass::of(Some(user_agent)), class, "{user_agent}");
+        }
+        assert_eq!(UserAgentClass::of(None), UserAgentClass::Unknown);
+    }
+
+    #[test]
+    fn keeps_only_the_page_of_a_referrer() {
+        assert_eq!(
+            referrer_page(Some("https://example.com/watch?v=1&token=secret#t=30")).as_deref(),
+            Some("https://example.com/watch")
+        );
+        assert_eq!(
+            referrer_page(Some("https://example.com/#top")).as_deref(),
+            Some("https://example.com/")
+        );
+        assert_eq!(referrer_page(Some(" ?only=query")), None);
+        assert_eq!(referrer_page(Some("")), None);
+        assert_eq!(referrer_page(None), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! HyperLogLog sketches, which estimate the number of distinct viewers in a
+//! fixed amount of memory and can be merged, so that the sketches of hours
+//! and days add up to the sketch of any window made of them.
+//!
+//! With a precision of 14 there are 16384 registers and the standard error of
+//! an estimate is 1.04 / sqrt(16384), about 0.81%: two thirds of the
+//! estimates are within 0.81% of the true count, 95% within 1.6% and 99.7%
+//! within 2.4%. Small counts are close to exact.
+//!
+//! Sketches are stored sparse, as a document of the registers that are not
+//! zero keyed by their index, so that a view updates a single register with
+//! `$max` and a video with few viewers takes little space.
+
+use mongodb::bson::{Bson, Document};
+use sha2::{Digest, Sha256};
+
+pub const PRECISION: u32 = 14;
+pub const REGISTERS: usize = 1 << PRECISION;
+pub const STANDARD_ERROR: f64 = 0.0081;
+
+/// The register a viewer falls into and the value it raises it to: the
+/// first bits of the hash of the viewer id pick the register, the position
+/// of the first set bit of the rest is the value.
+pub fn register_of(viewer: &str) -> (usize, u8) {
+    let digest = Sha256::digest(viewer.as_bytes());
+    let hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    let index = (hash >> (64 - PRECISION)) as usize;
+    // The guard bit bounds the value for hashes that are zero after the
+    // index bits.
+    let rest = (hash << PRECISION) | (1 << (PRECISION - 1));
+    (index, rest.leading_zeros() as u8 + 1)
+}
+
+pub struct Sketch {
+    registers: Vec<u8>,
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl Sketch {
+    /// Merges the sparse registers of a stored sketch into this one.
+    /// Entries that are not registers are ignored.
+    pub fn merge_document(&mut self, registers: &Document) {
+        for (index, value) in registers {
+            let Some(index) = index.parse::<usize>().ok().filter(|i| *i < REGISTERS) else {
+                continue;
+            };
+            let value = match value {
+                Bson::Int32(value) => *value as i64,
+                Bson::Int64(value) => *value,
+                _ => continue,
+            };
+            let value = value.clamp(0, u8::MAX as i64) as u8;
+            self.registers[index] = self.registers[index].max(value);
+        }
+    }
+
+    /// The estimated number of distinct viewers, by the improved estimator of
+    /// Otmar Ertl ("New cardinality estimation algorithms for HyperLogLog
+    /// sketches", 2017). Unlike the original estimator with its switch to
+    /// linear counting for small counts, it has no bias in the range between.
+    pub fn estimate(&self) -> u64 {
+        let m = REGISTERS as f64;
+        // The largest register value, hash bits after the index plus one.
+        let q = 64 - PRECISION as usize;
+        let mut histogram = vec![0u32; q + 2];
+        for &value in &self.registers {
+            histogram[(value as usize).min(q + 1)] += 1;
+        }
+        let mut z = m * tau(1.0 - histogram[q + 1] as f64 / m);
+        for count in histogram[1..=q].iter().rev() {
+            z = 0.5 * (z + *count as f64);
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        let alpha = 0.5 / std::f64::consts::LN_2;
+        (alpha * m * m / z).round() as u64
+    }
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let (mut y, mut z) = (1.0, x);
+    loop {
+        x *= x;
+        let previous = z;
+        z += x * y;
+        y += y;
+        if z == previous {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let (mut y, mut z) = (1.0, 1.0 - x);
+    loop {
+        x = x.sqrt();
+        let previous = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z == previous {
+            return z / 3.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    fn sketch_of(viewers: impl IntoIterator<Item = String>) -> Sketch {
+        let mut sketch = Sketch::default();
+        for viewer in viewers {
+            let (index, value) = register_of(&viewer);
+            sketch.registers[index] = sketch.registers[index].max(value);
+        }
+        sketch
+    }
+
+    fn viewers(range: std::ops::Range<u32>) -> impl Iterator<Item = String> {
+        range.map(|i| format!("viewer-{i}"))
+    }
+
+    #[test]
+    fn places_viewers_in_registers() {
+        for viewer in viewers(0..1000) {
+            let (index, value) = register_of(&viewer);
+            assert!(index < REGISTERS);
+            assert!((1..=(64 - PRECISION + 1) as u8).contains(&value));
+            assert_eq!(register_of(&viewer), (index, value));
+        }
+    }
+
+    #[test]
+    fn counts_few_viewers_exactly() {
+        assert_eq!(Sketch::default().estimate(), 0);
+        assert_eq!(sketch_of(viewers(0..1)).estimate(), 1);
+        assert_eq!(sketch_of(viewers(0..100)).estimate(), 100);
+        // Repeated views do not count.
+        assert_eq!(
+            sketch_of(viewers(0..100).chain(viewers(0..100))).estimate(),
+            100
+        );
+    }
+
+    #[test]
+    fn estimates_many_viewers_within_the_standard_error() {
+        for count in [10_000, 200_000] {
+            let estimate = sketch_of(viewers(0..count)).estimate() as f64;
+            let error = (estimate - count as f64).abs() / count as f64;
+            assert!(error < 4.0 * STANDARD_ERROR, "{estimate} for {count}");
+        }
+    }
+
+    #[test]
+    fn merges_stored_sketches_into_their_union() {
+        let mut stored = Document::new();
+        for viewer in viewers(0..3000) {
+            let (index, value) = register_of(&viewer);
+            let current = stored.get_i32(index.to_string()).unwrap_or(0);
+            stored.insert(index.to_string(), current.max(value as i32));
+        }
+        let mut merged = sketch_of(viewers(2000..5000));
+        merged.merge_document(&stored);
+        assert_eq!(merged.estimate(), sketch_of(viewers(0..5000)).estimate());
+
+        let mut sketch = Sketch::default();
+        sketch.merge_document(&doc! {
+            "3": 5_i64,
+            "4": 300,
+            "video": 7,
+            "99999": 7,
+            "5": "7",
+        });
+        assert_eq!(&sketch.registers[3..6], [5, u8::MAX, 0]);
+        assert_eq!(
+            sketch.registers.iter().filter(|value| **value != 0).count(),
+            2
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use auth::AuthLayer;
 use axum::{
     Router,
     body::Body,
@@ -5,17 +6,27 @@ use axum::{
     response::IntoResponse,
     routing::{get, post},
 };
-use serde::{Deserialize, Serialize};
-use std::env;
+use event_bus::EventBusExt;
+use mongodb::bson::DateTime;
+use stats::Stats;
+use std::{env, sync::Arc, time::Duration};
+use views::{ViewRecord, ViewedMessage, Views};
 
-#[derive(Deserialize, Serialize)]
-struct VideoPath {
-    video_path: String,
-}
+mod client;
+mod hll;
+mod migrate;
+mod query;
+mod stats;
+mod views;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const SWEEP_GRACE: Duration = Duration::from_secs(60);
+const SWEEP_BATCH: i64 = 500;
 
 #[derive(Clone)]
 struct AppState {
-    history_collection: mongodb::Collection<VideoPath>,
+    views: Arc<Views>,
+    stats: Arc<Stats>,
 }
 
 #[tokio::main]
@@ -33,11 +44,49 @@ async fn main() {
     client_options.server_api = Some(server_api);
     let client = mongodb::Client::with_options(client_options).expect("Can not create clients");
     let db = client.database(&db_name);
-    let collection = db.collection::<VideoPath>("history");
+    let legacy_window = env::var("LEGACY_DEDUP_WINDOW_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("LEGACY_DEDUP_WINDOW_SECS is not a number")
+        })
+        .unwrap_or(views::DEFAULT_LEGACY_DEDUP_WINDOW_SECS);
+    let views = Arc::new(Views::new(&db, Duration::from_secs(legacy_window)));
+    if let Err(e) = views.ensure_indexes().await {
+        eprintln!("Error creating the history indexes: {e}");
+    }
+    let stats = Arc::new(Stats::new(&db));
+    if let Err(e) = stats.ensure_indexes().await {
+        eprintln!("Error creating the view count indexes: {e}");
+    }
+    {
+        let (db, views) = (db.clone(), views.clone());
+        tokio::spawn(async move {
+            match migrate::backfill(&db, &views).await {
+                Ok(backfilled) => println!(
+                    "Backfilled the timestamps of {} and the video ids of {} history records",
+                    backfilled.timestamps, backfilled.video_ids
+                ),
+                Err(e) => eprintln!("Error backfilling history records: {e}"),
+            }
+        });
+    }
 
-    let state = AppState {
-        history_collection: collection,
-    };
+    let state = AppState { views, stats };
+    spawn_sweeper(state.clone());
+
+    // The bus lives as long as the server, so that its subscriptions do.
+    let bus = event_bus::from_env("history");
+    match &bus {
+        Some(bus) => {
+            let queue = env::var("VIEWED_QUEUE").unwrap_or_else(|_| "viewed".to_string());
+            let state = state.clone();
+            bus.subscribe(&queue, move |message: ViewedMessage| {
+                let state = state.clone();
+                async move { record_viewed(&state, message).await }
+            });
+        }
+        None => println!("RABBIT not set, viewed messages are only accepted by HTTP"),
+    }
 
     // Extremely important comment
     let app = app(state);
@@ -56,23 +105,99 @@ fn app(state: AppState) -> Router {
     Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/viewed", post(handle_viewed_request))
+        .route("/history", get(query::get_history))
+        .route("/history/users/{id}/recent", get(query::get_recent))
+        .route("/stats/videos/{id}", get(stats::get_video_stats))
+        .route("/stats/videos/{id}/viewers", get(stats::get_unique_viewers))
+        .route("/trending", get(stats::get_trending))
+        .layer(AuthLayer::from_env())
         .with_state(state)
 }
 
+/// `POST /viewed`. Answers 200 for messages that were recorded already, so
+/// that senders stop retrying them.
 async fn handle_viewed_request(
     State(app_state): State<AppState>,
-    Json(payload): Json<VideoPath>,
+    Json(message): Json<ViewedMessage>,
 ) -> impl IntoResponse {
-    println!(
-        "Received viewed message with video path: {}",
-        payload.video_path
-    );
-    match app_state.history_collection.insert_one(payload).await {
-        Ok(_) => (axum::http::StatusCode::OK, Body::from("")).into_response(),
-        Err(e) => (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            Body::from(format!("{e}")),
-        )
-            .into_response(),
+    match record_viewed(&app_state, message).await {
+        Ok(()) => (axum::http::StatusCode::OK, Body::from("")).into_response(),
+        Err(e) => (axum::http::StatusCode::INTERNAL_SERVER_ERROR, Body::from(e)).into_response(),
+    }
+}
+
+/// Records a viewed message from the event bus or the body of `POST /viewed`
+/// and counts the view. Fails only when the view could not be recorded, so
+/// that the message is delivered again: a view that is recorded but could
+/// not be counted is counted by [`spawn_sweeper`] instead, as the bus
+/// dead-letters messages rather than delivering them again.
+async fn record_viewed(state: &AppState, message: ViewedMessage) -> Result<(), String> {
+    let video_path = match &message {
+        ViewedMessage::Event(event) => event.data.video_path.clone(),
+        ViewedMessage::Bare(bare) => bare.video_path.clone(),
+    };
+    let record = match state.views.record(message).await {
+        Ok(Some(record)) => record,
+        Ok(None) => {
+            println!("Ignoring a duplicate viewed message for {video_path}");
+            return Ok(());
+        }
+        Err(e) => return Err(format!("database error: {e}")),
+    };
+    println!("Received viewed message with video path: {video_path}");
+    if let Err(e) = count_view(state, &record).await {
+        eprintln!("Error counting the view of {video_path}, counting it later: {e}");
     }
+    Ok(())
+}
+
+/// Counts a recorded view and notes that it is counted. Counting a view
+/// again leaves the counts as they are, so this may be retried.
+async fn count_view(state: &AppState, record: &ViewRecord) -> Result<(), mongodb::error::Error> {
+    let (Some(id), Some(video_id), false) = (record.id, record.video_id, record.counted) else {
+        return Ok(());
+    };
+    state
+        .stats
+        .count(id, video_id, record.viewed_at, viewer_of(record))
+        .await?;
+    state.views.mark_counted(id).await
+}
+
+/// Counts the views that were recorded but not counted, every
+/// [`SWEEP_INTERVAL`]. Views are left to the message they were recorded
+/// from for [`SWEEP_GRACE`] first.
+fn spawn_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+            let before = DateTime::from_millis(
+                DateTime::now().timestamp_millis() - SWEEP_GRACE.as_millis() as i64,
+            );
+            let records = match state.views.uncounted_before(before, SWEEP_BATCH).await {
+                Ok(records) => records,
+                Err(e) => {
+                    eprintln!("Error finding uncounted views: {e}");
+                    continue;
+                }
+            };
+            for record in records {
+                match count_view(&state, &record).await {
+                    Ok(()) => println!("Counted the view of {} later", record.video_path),
+                    Err(e) => {
+                        eprintln!("Error counting the view of {}: {e}", record.video_path);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// The signed in viewer of a view, or else the anonymous one.
+fn viewer_of(record: &ViewRecord) -> Option<&str> {
+    record
+        .viewer_id
+        .as_deref()
+        .or(record.anonymous_id.as_deref())
 }
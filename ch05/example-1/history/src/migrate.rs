@@ -0,0 +1,60 @@
+//! Brings records of the `history` collection from before views carried
+//! their context, which only have a `video_path`, to the current format.
+//! Only records that miss fields are touched, so the backfill can run at
+//! every start and on several instances at a time.
+
+use mongodb::bson::{Bson, Document, doc};
+
+use crate::views::Views;
+
+/// Counts of the records the backfill changed.
+pub struct Backfilled {
+    pub timestamps: u64,
+    pub video_ids: u64,
+}
+
+/// Gives legacy records the time their ObjectId was generated, which is when
+/// they were inserted, as view and receive time, and the unknown user agent
+/// class. Then looks up the ids of their videos by path; records of videos
+/// that are no longer in the catalog stay without one.
+pub async fn backfill(
+    db: &mongodb::Database,
+    views: &Views,
+) -> Result<Backfilled, mongodb::error::Error> {
+    let history = db.collection::<Document>("history");
+    let timestamps = history
+        .update_many(
+            doc! { "viewedAt": { "$exists": false } },
+            vec![doc! { "$set": {
+                "viewedAt": { "$toDate": "$_id" },
+                "receivedAt": { "$toDate": "$_id" },
+                "userAgentClass": "unknown",
+            } }],
+        )
+        .await?
+        .modified_count;
+
+    let mut video_ids = 0;
+    let paths = history
+        .distinct("video_path", doc! { "videoId": { "$exists": false } })
+        .await?;
+    for path in paths {
+        let Bson::String(path) = path else {
+            continue;
+        };
+        let Some(video_id) = views.video_id_of(&path).await? else {
+            continue;
+        };
+        video_ids += history
+            .update_many(
+                doc! { "video_path": &path, "videoId": { "$exists": false } },
+                doc! { "$set": { "videoId": video_id } },
+            )
+            .await?
+            .modified_count;
+    }
+    Ok(Backfilled {
+        timestamps,
+        video_ids,
+    })
+}
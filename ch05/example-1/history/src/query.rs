@@ -0,0 +1,314 @@
+use auth::Principal;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::{DateTime, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::AppState;
+use crate::client::UserAgentClass;
+use crate::views::ViewRecord;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+const DEFAULT_RECENT: i64 = 20;
+const MAX_RECENT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct HistoryParams {
+    /// Subject of a signed in viewer.
+    viewer: Option<String>,
+    /// ObjectId of a video.
+    video: Option<String>,
+    /// RFC 3339 timestamps, `from` inclusive and `to` exclusive.
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<i64>,
+    /// The `nextCursor` of the previous page.
+    cursor: Option<String>,
+}
+
+pub struct HistoryFilter {
+    pub viewer: Option<String>,
+    pub video: Option<ObjectId>,
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+}
+
+/// The position of the last view of a page, in the order of
+/// [`crate::views::Views::find`]: by view time, ties broken by id.
+pub struct Cursor {
+    pub viewed_at: DateTime,
+    pub id: ObjectId,
+}
+
+impl Cursor {
+    fn parse(cursor: &str) -> Option<Self> {
+        let (viewed_at, id) = cursor.split_once('_')?;
+        Some(Self {
+            viewed_at: DateTime::from_millis(viewed_at.parse().ok()?),
+            id: ObjectId::from_str(id).ok()?,
+        })
+    }
+
+    fn of(view: &ViewRecord) -> Option<String> {
+        let id = view.id?;
+        Some(format!(
+            "{}_{}",
+            view.viewed_at.timestamp_millis(),
+            id.to_hex()
+        ))
+    }
+}
+
+/// JSON representation of a history record.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryView {
+    id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    video_id: Option<String>,
+    video_path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    viewer_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    anonymous_id: Option<String>,
+    viewed_at: String,
+    received_at: String,
+    user_agent_class: UserAgentClass,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    referrer: Option<String>,
+}
+
+impl From<ViewRecord> for HistoryView {
+    fn from(view: ViewRecord) -> Self {
+        let timestamp = |time: DateTime| time.try_to_rfc3339_string().unwrap_or_default();
+        Self {
+            id: view.id.map(|id| id.to_hex()),
+            video_id: view.video_id.map(|id| id.to_hex()),
+            video_path: view.video_path,
+            viewer_id: view.viewer_id,
+            anonymous_id: view.anonymous_id,
+            viewed_at: timestamp(view.viewed_at),
+            received_at: timestamp(view.received_at),
+            user_agent_class: view.user_agent_class,
+            referrer: view.referrer,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryPage {
+    views: Vec<HistoryView>,
+    /// Set when there may be more views, to be passed as `cursor`.
+    next_cursor: Option<String>,
+}
+
+/// `GET /history`: views, latest first. Admins see all views, other callers
+/// only their own.
+pub async fn get_history(
+    State(app_state): State<AppState>,
+    caller: Principal,
+    Query(params): Query<HistoryParams>,
+) -> Response {
+    let Some(viewer) = viewer_filter(caller, params.viewer) else {
+        return forbidden();
+    };
+    let video = match params.video.as_deref().map(ObjectId::from_str) {
+        None => None,
+        Some(Ok(video)) => Some(video),
+        Some(Err(_)) => return bad_request("video must be an ObjectId"),
+    };
+    let (from, to) = match (parse_time(params.from), parse_time(params.to)) {
+        (Ok(from), Ok(to)) => (from, to),
+        _ => return bad_request("from and to must be RFC 3339 timestamps"),
+    };
+    let cursor = match params.cursor.as_deref().map(Cursor::parse) {
+        None => None,
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return bad_request("Invalid cursor"),
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let filter = HistoryFilter {
+        viewer,
+        video,
+        from,
+        to,
+    };
+
+    // One more than the page holds, to tell whether there is a next page.
+    let mut views = match app_state
+        .views
+        .find(&filter, cursor.as_ref(), limit + 1)
+        .await
+    {
+        Ok(views) => views,
+        Err(e) => return internal_error(e),
+    };
+    let next_cursor = if views.len() as i64 > limit {
+        views.truncate(limit as usize);
+        views.last().and_then(Cursor::of)
+    } else {
+        None
+    };
+    Json(HistoryPage {
+        views: views.into_iter().map(HistoryView::from).collect(),
+        next_cursor,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RecentParams {
+    limit: Option<i64>,
+}
+
+/// `GET /history/users/{id}/recent`: the videos a user watched last, each
+/// with its latest view. For the user themselves and admins.
+pub async fn get_recent(
+    State(app_state): State<AppState>,
+    caller: Principal,
+    Path(id): Path<String>,
+    Query(params): Query<RecentParams>,
+) -> Response {
+    if !may_see_views_of(&caller, &id) {
+        return forbidden();
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_RECENT).clamp(1, MAX_RECENT);
+    match app_state.views.recent_videos(&id, limit).await {
+        Ok(views) => {
+            Json(views.into_iter().map(HistoryView::from).collect::<Vec<_>>()).into_response()
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+/// Whether `caller` may see the views of the viewer `id`: their own, or
+/// anybody's for admins.
+fn may_see_views_of(caller: &Principal, id: &str) -> bool {
+    id == caller.subject || caller.has_role("admin")
+}
+
+/// The viewer to filter the history of `caller` by, given the `viewer` they
+/// asked for. Admins see the views of everybody unless they ask for one
+/// viewer, other callers only their own. `None` if the caller may not see
+/// the views they asked for.
+fn viewer_filter(caller: Principal, viewer: Option<String>) -> Option<Option<String>> {
+    match viewer {
+        _ if caller.has_role("admin") => Some(viewer),
+        Some(viewer) if !may_see_views_of(&caller, &viewer) => None,
+        _ => Some(Some(caller.subject)),
+    }
+}
+
+pub fn parse_time(time: Option<String>) -> Result<Option<DateTime>, ()> {
+    time.map(|time| DateTime::parse_rfc3339_str(time).map_err(|_| ()))
+        .transpose()
+}
+
+fn forbidden() -> Response {
+    (StatusCode::FORBIDDEN, "Only admins see the views of others").into_response()
+}
+
+fn bad_request(message: &'static str) -> Response {
+    (StatusCode::BAD_REQUEST, message).into_response()
+}
+
+fn internal_error(e: mongodb::error::Error) -> Response {
+    eprintln!("Error querying the history: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(subject: &str, roles: &[&str]) -> Principal {
+        Principal {
+            subject: subject.to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    fn view(id: Option<ObjectId>, viewed_at: i64) -> ViewRecord {
+        ViewRecord {
+            id,
+            video_path: "intro.mp4".to_string(),
+            video_id: None,
+            viewer_id: None,
+            anonymous_id: None,
+            viewed_at: DateTime::from_millis(viewed_at),
+            received_at: DateTime::from_millis(viewed_at),
+            user_agent_class: UserAgentClass::Unknown,
+            referrer: None,
+            event_id: None,
+            counted: true,
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let id = ObjectId::new();
+        let cursor = Cursor::of(&view(Some(id), 1_704_067_200_123)).unwrap();
+        assert_eq!(cursor, format!("1704067200123_{}", id.to_hex()));
+
+        let parsed = Cursor::parse(&cursor).unwrap();
+        assert_eq!(parsed.viewed_at.timestamp_millis(), 1_704_067_200_123);
+        assert_eq!(parsed.id, id);
+    }
+
+    #[test]
+    fn views_without_id_have_no_cursor() {
+        assert_eq!(Cursor::of(&view(None, 0)), None);
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        let id = ObjectId::new().to_hex();
+        for cursor in [
+            String::new(),
+            "1704067200123".to_string(),
+            format!("yesterday_{id}"),
+            "1704067200123_not-an-id".to_string(),
+            format!("1704067200123-{id}"),
+        ] {
+            assert!(Cursor::parse(&cursor).is_none(), "{cursor}");
+        }
+    }
+
+    #[test]
+    fn non_admins_only_see_their_own_views() {
+        let alice = || principal("alice", &["viewer"]);
+        assert_eq!(
+            viewer_filter(alice(), None),
+            Some(Some("alice".to_string()))
+        );
+        assert_eq!(
+            viewer_filter(alice(), Some("alice".to_string())),
+            Some(Some("alice".to_string()))
+        );
+        assert_eq!(viewer_filter(alice(), Some("bob".to_string())), None);
+
+        assert!(may_see_views_of(&alice(), "alice"));
+        assert!(!may_see_views_of(&alice(), "bob"));
+    }
+
+    #[test]
+    fn admins_see_the_views_of_everybody() {
+        let admin = || principal("root", &["admin"]);
+        assert_eq!(viewer_filter(admin(), None), Some(None));
+        assert_eq!(
+            viewer_filter(admin(), Some("bob".to_string())),
+            Some(Some("bob".to_string()))
+        );
+        assert!(may_see_views_of(&admin(), "bob"));
+    }
+}
@@ -0,0 +1,644 @@
+//! View counts, kept up to date as views are recorded so that reading them
+//! does not scan the `history` collection: a total per video in `viewCounts`,
+//! and the views per video and hour or day in `viewRollups`. The viewers of
+//! every video, in total and per hour and day, are counted approximately by
+//! the HyperLogLog sketches of `viewerSketches`, see [`crate::hll`].
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use futures::TryStreamExt;
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, time::Duration};
+
+use crate::AppState;
+use crate::hll::{self, Sketch};
+use crate::query::parse_time;
+use crate::views::is_duplicate_key;
+
+const HOUR_MILLIS: i64 = 3_600_000;
+const DAY_MILLIS: i64 = 24 * HOUR_MILLIS;
+/// Windows up to this many hours are summed from hourly rollups.
+const MAX_HOURLY_WINDOW: i64 = 48;
+const MAX_DAILY_WINDOW: i64 = 365;
+const DEFAULT_TRENDING: i64 = 10;
+const MAX_TRENDING: i64 = 100;
+/// Unique viewer windows are made of at most this many days.
+const MAX_VIEWER_WINDOW_DAYS: i64 = 400;
+/// The ids of the last views added to a count, see [`add_view`].
+const COUNTED_VIEWS: &str = "countedViews";
+/// How many of them are kept. A view is counted again only if that many
+/// other views of the same video and bucket are counted before its retry.
+const RECENTLY_COUNTED: i32 = 1000;
+
+/// The length of the buckets of a rollup.
+#[derive(Clone, Copy)]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    fn name(self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+
+    fn millis(self) -> i64 {
+        match self {
+            Granularity::Hour => HOUR_MILLIS,
+            Granularity::Day => DAY_MILLIS,
+        }
+    }
+
+    /// How long buckets are kept, longer than the longest window they are
+    /// read for.
+    fn retention(self) -> Duration {
+        match self {
+            Granularity::Hour => Duration::from_secs(8 * 24 * 3600),
+            Granularity::Day => Duration::from_secs(400 * 24 * 3600),
+        }
+    }
+
+    /// The start of the bucket `time` falls into. Days are UTC days.
+    fn bucket(self, time: DateTime) -> DateTime {
+        let millis = time.timestamp_millis();
+        DateTime::from_millis(millis - millis.rem_euclid(self.millis()))
+    }
+
+    /// The start of the oldest of the last `buckets` buckets, the current one
+    /// included.
+    fn window_start(self, buckets: i64) -> DateTime {
+        let current = self.bucket(DateTime::now()).timestamp_millis();
+        DateTime::from_millis(current - (buckets - 1) * self.millis())
+    }
+}
+
+pub struct Stats {
+    counts: Collection<Document>,
+    rollups: Collection<Document>,
+    /// Like the rollups, plus one sketch per video with the granularity
+    /// `all` and no start.
+    sketches: Collection<Document>,
+}
+
+impl Stats {
+    pub fn new(db: &mongodb::Database) -> Self {
+        Self {
+            counts: db.collection("viewCounts"),
+            rollups: db.collection("viewRollups"),
+            sketches: db.collection("viewerSketches"),
+        }
+    }
+
+    /// The unique indexes the rollups and sketches are upserted by, the index
+    /// trending windows are summed by and TTL indexes on `expiresAt`.
+    pub async fn ensure_indexes(&self) -> Result<(), mongodb::error::Error> {
+        let bucket_index = IndexModel::builder()
+            .keys(doc! { "videoId": 1, "granularity": 1, "start": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let window_index = IndexModel::builder()
+            .keys(doc! { "granularity": 1, "start": 1 })
+            .build();
+        let expiry_index = IndexModel::builder()
+            .keys(doc! { "expiresAt": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        self.rollups
+            .create_indexes([bucket_index, window_index, expiry_index.clone()])
+            .await?;
+        let sketch_index = IndexModel::builder()
+            .keys(doc! { "videoId": 1, "granularity": 1, "start": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.sketches
+            .create_indexes([sketch_index, expiry_index])
+            .await?;
+        Ok(())
+    }
+
+    /// Counts a view of a video in its total and the hour and day buckets of
+    /// the time it was viewed, and the viewer, if known, in the sketches of
+    /// the same. Counting a view again, after an attempt that failed part
+    /// way, leaves the counts as they are, see [`add_view`].
+    pub async fn count(
+        &self,
+        view: ObjectId,
+        video_id: ObjectId,
+        viewed_at: DateTime,
+        viewer: Option<&str>,
+    ) -> Result<(), mongodb::error::Error> {
+        add_view(
+            &self.counts,
+            doc! { "_id": video_id },
+            view,
+            doc! { "$max": { "lastViewedAt": viewed_at } },
+        )
+        .await?;
+        let register = viewer.map(hll::register_of);
+        if let Some((index, value)) = register {
+            self.sketches
+                .update_one(
+                    doc! { "videoId": video_id, "granularity": "all", "start": null },
+                    doc! { "$max": { format!("registers.{index}"): value as i32 } },
+                )
+                .upsert(true)
+                .await?;
+        }
+        for granularity in [Granularity::Hour, Granularity::Day] {
+            let start = granularity.bucket(viewed_at);
+            let expires_at = DateTime::from_millis(
+                start.timestamp_millis() + granularity.retention().as_millis() as i64,
+            );
+            let bucket = doc! {
+                "videoId": video_id,
+                "granularity": granularity.name(),
+                "start": start,
+            };
+            add_view(
+                &self.rollups,
+                bucket.clone(),
+                view,
+                doc! { "$setOnInsert": { "expiresAt": expires_at } },
+            )
+            .await?;
+            if let Some((index, value)) = register {
+                self.sketches
+                    .update_one(
+                        bucket,
+                        doc! {
+                            "$max": { format!("registers.{index}"): value as i32 },
+                            "$setOnInsert": { "expiresAt": expires_at },
+                        },
+                    )
+                    .upsert(true)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The merged sketch of the buckets of a video, or of all its viewers
+    /// without buckets.
+    async fn sketch(
+        &self,
+        video_id: ObjectId,
+        buckets: Option<&[(Granularity, DateTime)]>,
+    ) -> Result<Sketch, mongodb::error::Error> {
+        let filter = match buckets {
+            None => doc! { "videoId": video_id, "granularity": "all" },
+            Some(buckets) => {
+                let buckets: Vec<Document> = buckets
+                    .iter()
+                    .map(|(granularity, start)| {
+                        doc! { "granularity": granularity.name(), "start": start }
+                    })
+                    .collect();
+                doc! { "videoId": video_id, "$or": buckets }
+            }
+        };
+        let mut sketch = Sketch::default();
+        let mut cursor = self
+            .sketches
+            .find(filter)
+            .projection(doc! { "registers": 1 })
+            .await?;
+        while let Some(stored) = cursor.try_next().await? {
+            if let Ok(registers) = stored.get_document("registers") {
+                sketch.merge_document(registers);
+            }
+        }
+        Ok(sketch)
+    }
+
+    async fn total(&self, video_id: ObjectId) -> Result<Option<Document>, mongodb::error::Error> {
+        self.counts
+            .find_one(doc! { "_id": video_id })
+            .projection(doc! { "views": 1, "lastViewedAt": 1 })
+            .await
+    }
+
+    /// The non-empty buckets of a video within the last `buckets`, oldest
+    /// first.
+    async fn buckets(
+        &self,
+        video_id: ObjectId,
+        granularity: Granularity,
+        buckets: i64,
+    ) -> Result<Vec<Bucket>, mongodb::error::Error> {
+        let rollups: Vec<Document> = self
+            .rollups
+            .find(doc! {
+                "videoId": video_id,
+                "granularity": granularity.name(),
+                "start": { "$gte": granularity.window_start(buckets) },
+            })
+            .sort(doc! { "start": 1 })
+            .projection(doc! { "start": 1, "views": 1 })
+            .await?
+            .try_collect()
+            .await?;
+        Ok(rollups
+            .iter()
+            .filter_map(|rollup| {
+                Some(Bucket {
+                    start: timestamp(*rollup.get_datetime("start").ok()?),
+                    views: views_of(rollup),
+                })
+            })
+            .collect())
+    }
+
+    /// The videos with the most views within the last `buckets`.
+    async fn trending(
+        &self,
+        granularity: Granularity,
+        buckets: i64,
+        limit: i64,
+    ) -> Result<Vec<TrendingVideo>, mongodb::error::Error> {
+        let pipeline = vec![
+            doc! { "$match": {
+                "granularity": granularity.name(),
+                "start": { "$gte": granularity.window_start(buckets) },
+            } },
+            doc! { "$group": { "_id": "$videoId", "views": { "$sum": "$views" } } },
+            doc! { "$sort": { "views": -1, "_id": 1 } },
+            doc! { "$limit": limit },
+        ];
+        let totals: Vec<Document> = self
+            .rollups
+            .aggregate(pipeline)
+            .await?
+            .try_collect()
+            .await?;
+        Ok(totals
+            .iter()
+            .filter_map(|total| {
+                Some(TrendingVideo {
+                    video_id: total.get_object_id("_id").ok()?.to_hex(),
+                    views: views_of(total),
+                })
+            })
+            .collect())
+    }
+}
+
+/// Adds a view to the `views` of the document `filter` selects, creating it
+/// if need be, along with `update`. The ids of the last views added are kept
+/// in the document, and a view among them is not added again, so that a
+/// retry does not count a view twice although the other documents of the
+/// view are written separately. There is no transaction to write them all at
+/// once in, as MongoDB runs without a replica set.
+async fn add_view(
+    collection: &Collection<Document>,
+    filter: Document,
+    view: ObjectId,
+    mut update: Document,
+) -> Result<(), mongodb::error::Error> {
+    let mut not_added = filter;
+    not_added.insert(COUNTED_VIEWS, doc! { "$ne": view });
+    update.insert("$inc", doc! { "views": 1_i64 });
+    update.insert(
+        "$push",
+        doc! { COUNTED_VIEWS: { "$each": [view], "$slice": -RECENTLY_COUNTED } },
+    );
+    match collection
+        .update_one(not_added.clone(), update.clone())
+        .upsert(true)
+        .await
+    {
+        Ok(_) => Ok(()),
+        // The document exists, so the filter did not match because the view
+        // is in it already, or because the document was inserted since by
+        // the count of another view. An update without upsert tells which.
+        Err(e) if is_duplicate_key(&e) => {
+            collection.update_one(not_added, update).await?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Serialize)]
+struct Bucket {
+    start: String,
+    views: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VideoStats {
+    video_id: String,
+    views: i64,
+    /// Estimated, see [`crate::hll`].
+    unique_viewers: u64,
+    last_viewed_at: Option<String>,
+    /// The last 24 hours, the current one included.
+    hourly: Vec<Bucket>,
+    /// The last 30 days, today included.
+    daily: Vec<Bucket>,
+}
+
+/// `GET /stats/videos/{id}`: the total views and unique viewers of a video
+/// and its views per hour and day. Buckets without views are left out.
+pub async fn get_video_stats(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+) -> Response {
+    let Ok(video_id) = ObjectId::from_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "id must be an ObjectId").into_response();
+    };
+    let stats = &app_state.stats;
+    let (total, viewers, hourly, daily) = match tokio::try_join!(
+        stats.total(video_id),
+        stats.sketch(video_id, None),
+        stats.buckets(video_id, Granularity::Hour, 24),
+        stats.buckets(video_id, Granularity::Day, 30),
+    ) {
+        Ok(stats) => stats,
+        Err(e) => return internal_error(e),
+    };
+    Json(VideoStats {
+        video_id: video_id.to_hex(),
+        views: total.as_ref().map(views_of).unwrap_or(0),
+        unique_viewers: viewers.estimate(),
+        last_viewed_at: total
+            .as_ref()
+            .and_then(|total| total.get_datetime("lastViewedAt").ok())
+            .map(|time| timestamp(*time)),
+        hourly,
+        daily,
+    })
+    .into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ViewersParams {
+    /// RFC 3339 timestamps, `from` inclusive and `to` exclusive. The last 24
+    /// hours by default.
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UniqueViewers {
+    video_id: String,
+    /// The window the estimate is for, see [`cover`].
+    from: String,
+    to: String,
+    unique_viewers: u64,
+    standard_error: f64,
+}
+
+/// `GET /stats/videos/{id}/viewers`: the estimated number of distinct
+/// viewers of a video within a window, merged from the sketches of the hours
+/// and days it is made of.
+pub async fn get_unique_viewers(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<ViewersParams>,
+) -> Response {
+    let Ok(video_id) = ObjectId::from_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "id must be an ObjectId").into_response();
+    };
+    let now = DateTime::now().timestamp_millis();
+    let (from, to) = match (parse_time(params.from), parse_time(params.to)) {
+        (Ok(from), Ok(to)) => (
+            from.map_or(now - DAY_MILLIS, |from| from.timestamp_millis()),
+            to.map_or(now, |to| to.timestamp_millis()),
+        ),
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "from and to must be RFC 3339 timestamps",
+            )
+                .into_response();
+        }
+    };
+    if from >= to || to - from > MAX_VIEWER_WINDOW_DAYS * DAY_MILLIS {
+        return (
+            StatusCode::BAD_REQUEST,
+            "from must be before to, and at most 400 days before it",
+        )
+            .into_response();
+    }
+    let (buckets, covered_from, covered_to) = cover(from, to, now);
+    match app_state.stats.sketch(video_id, Some(&buckets)).await {
+        Ok(sketch) => Json(UniqueViewers {
+            video_id: video_id.to_hex(),
+            from: timestamp(DateTime::from_millis(covered_from)),
+            to: timestamp(DateTime::from_millis(covered_to)),
+            unique_viewers: sketch.estimate(),
+            standard_error: hll::STANDARD_ERROR,
+        })
+        .into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// The buckets that make up the window from `from` to `to`, in
+/// milliseconds: whole days where they fit and hours at the edges. The
+/// window is widened to whole hours, and to whole days where hourly sketches
+/// are no longer kept. Returns the buckets and the window they cover.
+fn cover(from: i64, to: i64, now: i64) -> (Vec<(Granularity, DateTime)>, i64, i64) {
+    let hours_kept_since = Granularity::Hour.bucket(DateTime::from_millis(
+        now - Granularity::Hour.retention().as_millis() as i64,
+    ));
+    let from = Granularity::Hour
+        .bucket(DateTime::from_millis(from))
+        .timestamp_millis();
+    let (mut start, mut end) = (from, from);
+    let mut buckets = Vec::new();
+    while end < to {
+        let day = Granularity::Day
+            .bucket(DateTime::from_millis(end))
+            .timestamp_millis();
+        let granularity = if (day == end && day + DAY_MILLIS <= to)
+            || end < hours_kept_since.timestamp_millis() + HOUR_MILLIS
+        {
+            Granularity::Day
+        } else {
+            Granularity::Hour
+        };
+        let bucket = granularity.bucket(DateTime::from_millis(end));
+        buckets.push((granularity, bucket));
+        start = start.min(bucket.timestamp_millis());
+        end = bucket.timestamp_millis() + granularity.millis();
+    }
+    (buckets, start, end)
+}
+
+#[derive(Deserialize)]
+pub struct TrendingParams {
+    /// A number of hours (`24h`, at most 48) or days (`7d`, at most 365).
+    window: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TrendingVideo {
+    video_id: String,
+    views: i64,
+}
+
+#[derive(Serialize)]
+struct Trending {
+    window: String,
+    videos: Vec<TrendingVideo>,
+}
+
+/// `GET /trending?window=24h`: the most viewed videos within the last hours
+/// or days, the current one included.
+pub async fn get_trending(
+    State(app_state): State<AppState>,
+    Query(params): Query<TrendingParams>,
+) -> Response {
+    let window = params.window.unwrap_or_else(|| "24h".to_string());
+    let Some((granularity, buckets)) = parse_window(&window) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "window must be a number of hours up to 48h or of days up to 365d",
+        )
+            .into_response();
+    };
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_TRENDING)
+        .clamp(1, MAX_TRENDING);
+    match app_state.stats.trending(granularity, buckets, limit).await {
+        Ok(videos) => Json(Trending { window, videos }).into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn parse_window(window: &str) -> Option<(Granularity, i64)> {
+    let (count, granularity, max) = if let Some(hours) = window.strip_suffix('h') {
+        (hours, Granularity::Hour, MAX_HOURLY_WINDOW)
+    } else if let Some(days) = window.strip_suffix('d') {
+        (days, Granularity::Day, MAX_DAILY_WINDOW)
+    } else {
+        return None;
+    };
+    let count = count
+        .parse()
+        .ok()
+        .filter(|count| (1..=max).contains(count))?;
+    Some((granularity, count))
+}
+
+/// Counts are written as 64 bit integers, 32 bit ones are accepted for
+/// documents written by other tools.
+fn views_of(document: &Document) -> i64 {
+    document
+        .get_i64("views")
+        .or_else(|_| document.get_i32("views").map(i64::from))
+        .unwrap_or(0)
+}
+
+fn timestamp(time: DateTime) -> String {
+    time.try_to_rfc3339_string().unwrap_or_default()
+}
+
+fn internal_error(e: mongodb::error::Error) -> Response {
+    eprintln!("Error reading view counts: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01T00:00:00Z.
+    const DAY_ONE: i64 = 1_704_067_200_000;
+
+    fn at(day: i64, hour: i64, minute: i64) -> i64 {
+        DAY_ONE + (day - 1) * DAY_MILLIS + hour * HOUR_MILLIS + minute * 60_000
+    }
+
+    /// The buckets as `(granularity, day, hour)`.
+    fn buckets(covered: &[(Granularity, DateTime)]) -> Vec<(&'static str, i64, i64)> {
+        covered
+            .iter()
+            .map(|(granularity, start)| {
+                let since = start.timestamp_millis() - DAY_ONE;
+                (
+                    granularity.name(),
+                    since.div_euclid(DAY_MILLIS) + 1,
+                    since.rem_euclid(DAY_MILLIS) / HOUR_MILLIS,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn widens_windows_to_whole_hours() {
+        let (covered, from, to) = cover(at(3, 10, 30), at(3, 13, 10), at(3, 20, 0));
+        assert_eq!(
+            buckets(&covered),
+            [
+                ("hour", 3, 10),
+                ("hour", 3, 11),
+                ("hour", 3, 12),
+                ("hour", 3, 13)
+            ]
+        );
+        assert_eq!((from, to), (at(3, 10, 0), at(3, 14, 0)));
+
+        // The end is exclusive.
+        let (covered, from, to) = cover(at(3, 10, 0), at(3, 11, 0), at(3, 20, 0));
+        assert_eq!(buckets(&covered), [("hour", 3, 10)]);
+        assert_eq!((from, to), (at(3, 10, 0), at(3, 11, 0)));
+    }
+
+    #[test]
+    fn takes_whole_days_where_they_fit() {
+        let (covered, from, to) = cover(at(3, 22, 0), at(5, 2, 0), at(5, 3, 0));
+        assert_eq!(
+            buckets(&covered),
+            [
+                ("hour", 3, 22),
+                ("hour", 3, 23),
+                ("day", 4, 0),
+                ("hour", 5, 0),
+                ("hour", 5, 1)
+            ]
+        );
+        assert_eq!((from, to), (at(3, 22, 0), at(5, 2, 0)));
+
+        let (covered, ..) = cover(at(4, 0, 0), at(5, 0, 0), at(5, 3, 0));
+        assert_eq!(buckets(&covered), [("day", 4, 0)]);
+        // A day that ends after the window is made of hours.
+        let (covered, ..) = cover(at(4, 0, 0), at(4, 2, 0), at(5, 3, 0));
+        assert_eq!(buckets(&covered), [("hour", 4, 0), ("hour", 4, 1)]);
+    }
+
+    #[test]
+    fn takes_days_where_hours_are_no_longer_kept() {
+        // Hours are kept for 8 days, so on day 20 day 5 only has days.
+        let (covered, from, to) = cover(at(5, 5, 0), at(6, 5, 0), at(20, 0, 0));
+        assert_eq!(buckets(&covered), [("day", 5, 0), ("day", 6, 0)]);
+        assert_eq!((from, to), (at(5, 0, 0), at(7, 0, 0)));
+
+        // Hours from the first one kept whole on.
+        let now = at(20, 12, 30);
+        let oldest_hour = at(12, 13, 0);
+        let (covered, from, to) = cover(at(12, 6, 0), at(12, 15, 0), now);
+        assert_eq!(buckets(&covered), [("day", 12, 0)]);
+        assert_eq!((from, to), (at(12, 0, 0), at(13, 0, 0)));
+        let (covered, ..) = cover(oldest_hour, at(12, 15, 0), now);
+        assert_eq!(buckets(&covered), [("hour", 12, 13), ("hour", 12, 14)]);
+    }
+}
@@ -0,0 +1,410 @@
+//! Records views exactly once from messages that are delivered at least once.
+//!
+//! Events carry an id, and the unique index on `eventId` turns a second
+//! delivery of an event into a duplicate key error. The bare messages of
+//! older senders have no id; a view of a video is taken for a redelivery of
+//! the previous one when it arrives within the dedup window, tracked in the
+//! `viewedDedup` collection.
+//!
+//! A record notes whether its view is in the counts of [`crate::stats`] yet,
+//! so that a redelivery of a message that was recorded but not counted still
+//! counts it, as does a periodic sweep of the records left uncounted when
+//! there is no redelivery.
+
+use events::{CloudEvent, EventData, VideoViewed};
+use futures::TryStreamExt;
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, Document, doc, oid::ObjectId},
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+};
+use serde::{Deserialize, Serialize};
+use std::{str::FromStr, time::Duration};
+
+use crate::client::{UserAgentClass, referrer_page};
+use crate::query::{Cursor, HistoryFilter};
+
+pub const DEFAULT_LEGACY_DEDUP_WINDOW_SECS: u64 = 30;
+
+#[derive(Deserialize, Serialize)]
+pub struct VideoPath {
+    pub video_path: String,
+}
+
+/// A viewed message as video-streaming sends it. Services and outbox entries
+/// from before the events crate send the bare video path.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ViewedMessage {
+    Event(Box<CloudEvent<VideoViewed>>),
+    Bare(VideoPath),
+}
+
+impl event_bus::Event for ViewedMessage {
+    const TOPIC: &'static str = VideoViewed::TOPIC;
+}
+
+/// A record of the `history` collection.
+#[derive(Deserialize, Serialize)]
+pub struct ViewRecord {
+    /// Set by the database on insert.
+    #[serde(rename = "_id", default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub video_path: String,
+    /// Missing for videos that were not in the catalog when the view was
+    /// recorded or backfilled.
+    #[serde(rename = "videoId", default, skip_serializing_if = "Option::is_none")]
+    pub video_id: Option<ObjectId>,
+    /// Subject of the signed in viewer.
+    #[serde(rename = "viewerId", default, skip_serializing_if = "Option::is_none")]
+    pub viewer_id: Option<String>,
+    /// Stands for a viewer who was not signed in.
+    #[serde(
+        rename = "anonymousId",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub anonymous_id: Option<String>,
+    /// When the video was viewed, as sent by video-streaming.
+    #[serde(rename = "viewedAt")]
+    pub viewed_at: DateTime,
+    /// When history recorded the view.
+    #[serde(rename = "receivedAt")]
+    pub received_at: DateTime,
+    #[serde(rename = "userAgentClass")]
+    pub user_agent_class: UserAgentClass,
+    /// The referring page, without query and fragment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+    /// The id of the event the view was recorded from. Records of bare
+    /// messages and from before deduplication have none.
+    #[serde(rename = "eventId", default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    /// Whether the view is in the view counts. Views without a video id have
+    /// nothing to count and are recorded as counted.
+    #[serde(default)]
+    pub counted: bool,
+}
+
+pub struct Views {
+    db: mongodb::Database,
+    records: Collection<ViewRecord>,
+    /// The catalog of video-streaming, for the ids of videos that messages
+    /// only name by path.
+    videos: Collection<Document>,
+    /// The last view of every video from a bare message within the window.
+    legacy_dedup: Collection<Document>,
+    legacy_window: Duration,
+}
+
+impl Views {
+    pub fn new(db: &mongodb::Database, legacy_window: Duration) -> Self {
+        Self {
+            db: db.clone(),
+            records: db.collection("history"),
+            videos: db.collection("videos"),
+            legacy_dedup: db.collection("viewedDedup"),
+            legacy_window,
+        }
+    }
+
+    /// The unique index on event ids, sparse for the records without one,
+    /// the indexes of [`Views::find`], [`Views::recent_videos`] and
+    /// [`Views::uncounted_before`], and a TTL
+    /// index that removes dedup entries once the window passed. The TTL of
+    /// an existing index is changed when the window has been changed.
+    pub async fn ensure_indexes(&self) -> Result<(), mongodb::error::Error> {
+        let event_id_index = IndexModel::builder()
+            .keys(doc! { "eventId": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build();
+        let query_indexes = [
+            doc! { "viewedAt": -1, "_id": -1 },
+            doc! { "viewerId": 1, "viewedAt": -1, "_id": -1 },
+            doc! { "videoId": 1, "viewedAt": -1, "_id": -1 },
+        ]
+        .into_iter()
+        .map(|keys| IndexModel::builder().keys(keys).build());
+        let uncounted_index = IndexModel::builder()
+            .keys(doc! { "receivedAt": 1 })
+            .options(
+                IndexOptions::builder()
+                    .partial_filter_expression(doc! { "counted": false })
+                    .name("uncounted".to_string())
+                    .build(),
+            )
+            .build();
+        self.records
+            .create_indexes(
+                [event_id_index, uncounted_index]
+                    .into_iter()
+                    .chain(query_indexes),
+            )
+            .await?;
+        let seen_index = IndexModel::builder()
+            .keys(doc! { "seenAt": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(self.legacy_window)
+                    .build(),
+            )
+            .build();
+        match self.legacy_dedup.create_index(seen_index).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_index_options_conflict(&e) => {
+                self.db
+                    .run_command(doc! {
+                        "collMod": self.legacy_dedup.name(),
+                        "index": {
+                            "keyPattern": { "seenAt": 1 },
+                            "expireAfterSeconds": self.legacy_window.as_secs() as i64,
+                        },
+                    })
+                    .await?;
+                println!(
+                    "Changed the TTL of the viewed dedup entries to {:?}",
+                    self.legacy_window
+                );
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Records the view of a message. Returns the record to count, which for
+    /// a message that has been recorded already is its earlier record if that
+    /// is not counted yet, and `None` otherwise.
+    pub async fn record(
+        &self,
+        message: ViewedMessage,
+    ) -> Result<Option<ViewRecord>, mongodb::error::Error> {
+        let received_at = DateTime::now();
+        match message {
+            ViewedMessage::Event(event) => {
+                let CloudEvent {
+                    id,
+                    time,
+                    data: viewed,
+                    ..
+                } = *event;
+                let video_id = match viewed.video_id.as_deref().map(ObjectId::from_str) {
+                    Some(Ok(id)) => Some(id),
+                    _ => self.video_id_of(&viewed.video_path).await?,
+                };
+                let record = ViewRecord {
+                    id: Some(ObjectId::new()),
+                    video_path: viewed.video_path,
+                    video_id,
+                    viewer_id: viewed.viewer_id,
+                    anonymous_id: viewed.anonymous_id,
+                    viewed_at: DateTime::from_millis(time.timestamp_millis()),
+                    received_at,
+                    user_agent_class: UserAgentClass::of(viewed.user_agent.as_deref()),
+                    referrer: referrer_page(viewed.referrer.as_deref()),
+                    counted: video_id.is_none(),
+                    event_id: Some(id),
+                };
+                match self.records.insert_one(&record).await {
+                    Ok(_) => Ok(Some(record)),
+                    Err(e) if is_duplicate_key(&e) => {
+                        self.uncounted(doc! { "eventId": record.event_id }).await
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            ViewedMessage::Bare(VideoPath { video_path }) => {
+                if !self.mark_legacy_view(&video_path, received_at).await? {
+                    // The view the message was taken for a redelivery of.
+                    let earlier = doc! {
+                        "video_path": &video_path,
+                        "eventId": { "$exists": false },
+                        "receivedAt": { "$gte": self.window_start(received_at) },
+                    };
+                    return self.uncounted(earlier).await;
+                }
+                let video_id = self.video_id_of(&video_path).await?;
+                let record = ViewRecord {
+                    id: Some(ObjectId::new()),
+                    video_id,
+                    video_path: video_path.clone(),
+                    viewer_id: None,
+                    anonymous_id: None,
+                    viewed_at: received_at,
+                    received_at,
+                    user_agent_class: UserAgentClass::Unknown,
+                    referrer: None,
+                    event_id: None,
+                    counted: video_id.is_none(),
+                };
+                if let Err(e) = self.records.insert_one(&record).await {
+                    // Lets the redelivery of the message record the view.
+                    let unmark = doc! { "_id": &video_path, "seenAt": received_at };
+                    if let Err(e) = self.legacy_dedup.delete_one(unmark).await {
+                        eprintln!("Error removing the dedup entry of {video_path}: {e}");
+                    }
+                    return Err(e);
+                }
+                Ok(Some(record))
+            }
+        }
+    }
+
+    /// Notes that a view is in the view counts.
+    pub async fn mark_counted(&self, id: ObjectId) -> Result<(), mongodb::error::Error> {
+        self.records
+            .update_one(doc! { "_id": id }, doc! { "$set": { "counted": true } })
+            .await?;
+        Ok(())
+    }
+
+    /// The oldest records that are not counted yet and were received before
+    /// `before`.
+    pub async fn uncounted_before(
+        &self,
+        before: DateTime,
+        limit: i64,
+    ) -> Result<Vec<ViewRecord>, mongodb::error::Error> {
+        self.records
+            .find(doc! { "counted": false, "receivedAt": { "$lt": before } })
+            .sort(doc! { "receivedAt": 1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// The latest record that matches `filter` and is not counted yet.
+    async fn uncounted(
+        &self,
+        filter: Document,
+    ) -> Result<Option<ViewRecord>, mongodb::error::Error> {
+        self.records
+            .find_one(doc! { "$and": [filter, { "counted": { "$ne": true } }] })
+            .sort(doc! { "receivedAt": -1 })
+            .await
+    }
+
+    /// The views that match `filter`, latest first, after the position of
+    /// `after` in that order.
+    pub async fn find(
+        &self,
+        filter: &HistoryFilter,
+        after: Option<&Cursor>,
+        limit: i64,
+    ) -> Result<Vec<ViewRecord>, mongodb::error::Error> {
+        let mut conditions = Vec::new();
+        if let Some(viewer) = &filter.viewer {
+            conditions.push(doc! { "viewerId": viewer });
+        }
+        if let Some(video) = filter.video {
+            conditions.push(doc! { "videoId": video });
+        }
+        if let Some(from) = filter.from {
+            conditions.push(doc! { "viewedAt": { "$gte": from } });
+        }
+        if let Some(to) = filter.to {
+            conditions.push(doc! { "viewedAt": { "$lt": to } });
+        }
+        if let Some(after) = after {
+            conditions.push(doc! { "$or": [
+                { "viewedAt": { "$lt": after.viewed_at } },
+                { "viewedAt": after.viewed_at, "_id": { "$lt": after.id } },
+            ] });
+        }
+        let query = if conditions.is_empty() {
+            doc! {}
+        } else {
+            doc! { "$and": conditions }
+        };
+        self.records
+            .find(query)
+            .sort(doc! { "viewedAt": -1, "_id": -1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await
+    }
+
+    /// The latest view of each of the last `limit` videos a viewer watched,
+    /// latest first. Views of videos without an id count by path.
+    pub async fn recent_videos(
+        &self,
+        viewer: &str,
+        limit: i64,
+    ) -> Result<Vec<ViewRecord>, mongodb::error::Error> {
+        let pipeline = vec![
+            doc! { "$match": { "viewerId": viewer } },
+            doc! { "$sort": { "viewedAt": -1, "_id": -1 } },
+            doc! { "$group": {
+                "_id": { "$ifNull": ["$videoId", "$video_path"] },
+                "latest": { "$first": "$$ROOT" },
+            } },
+            doc! { "$replaceWith": "$latest" },
+            doc! { "$sort": { "viewedAt": -1, "_id": -1 } },
+            doc! { "$limit": limit },
+        ];
+        let mut cursor = self.records.aggregate(pipeline).await?;
+        let mut views = Vec::new();
+        while let Some(document) = cursor.try_next().await? {
+            views.push(mongodb::bson::from_document(document)?);
+        }
+        Ok(views)
+    }
+
+    /// The id of the video with the given path in the catalog.
+    pub async fn video_id_of(
+        &self,
+        video_path: &str,
+    ) -> Result<Option<ObjectId>, mongodb::error::Error> {
+        let video = self
+            .videos
+            .find_one(doc! { "videoPath": video_path })
+            .projection(doc! { "_id": 1 })
+            .await?;
+        Ok(video.and_then(|video| video.get_object_id("_id").ok()))
+    }
+
+    /// Notes a view of `video_path` from a bare message. Returns false when
+    /// there was one within the window already: the upsert then finds no
+    /// entry old enough to update and fails to insert a second one.
+    async fn mark_legacy_view(
+        &self,
+        video_path: &str,
+        now: DateTime,
+    ) -> Result<bool, mongodb::error::Error> {
+        let result = self
+            .legacy_dedup
+            .update_one(
+                doc! { "_id": video_path, "seenAt": { "$lt": self.window_start(now) } },
+                doc! { "$set": { "seenAt": now } },
+            )
+            .upsert(true)
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn window_start(&self, now: DateTime) -> DateTime {
+        DateTime::from_millis(now.timestamp_millis() - self.legacy_window.as_millis() as i64)
+    }
+}
+
+/// Whether an index exists with the same keys and other options.
+fn is_index_options_conflict(e: &mongodb::error::Error) -> bool {
+    const INDEX_OPTIONS_CONFLICT: i32 = 85;
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Command(error) if error.code == INDEX_OPTIONS_CONFLICT
+    )
+}
+
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == 11000
+    )
+}
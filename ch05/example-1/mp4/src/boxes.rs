@@ -0,0 +1,215 @@
+use crate::{Error, Result};
+
+/// Size and type of a box. `size` includes the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoxHeader {
+    pub kind: [u8; 4],
+    pub size: u64,
+    pub header_size: u64,
+}
+
+impl BoxHeader {
+    /// Parses the header at the start of `data`. A size of 0 means the box
+    /// extends to the end of the file, which is reported as `u64::MAX` since
+    /// the caller may only hold part of it.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let size = read_u32(data, 0)? as u64;
+        let kind: [u8; 4] = data.get(4..8).ok_or(Error::Truncated)?.try_into().unwrap();
+        let (size, header_size) = match size {
+            0 => (u64::MAX, 8),
+            1 => (read_u64(data, 8)?, 16),
+            size => (size, 8),
+        };
+        if size < header_size {
+            return Err(Error::Invalid(format!(
+                "{} box smaller than its header",
+                String::from_utf8_lossy(&kind)
+            )));
+        }
+        Ok(Self {
+            kind,
+            size,
+            header_size,
+        })
+    }
+
+    pub fn is(&self, kind: &[u8; 4]) -> bool {
+        &self.kind == kind
+    }
+}
+
+/// Iterates over the boxes in `data`, yielding each header with the complete
+/// box (header included).
+pub struct Boxes<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Boxes<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = Result<(BoxHeader, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let header = match BoxHeader::parse(self.data) {
+            Ok(header) => header,
+            Err(e) => {
+                self.data = &[];
+                return Some(Err(e));
+            }
+        };
+        let size = header.size.min(self.data.len() as u64) as usize;
+        if header.size != u64::MAX && header.size > self.data.len() as u64 {
+            self.data = &[];
+            return Some(Err(Error::Truncated));
+        }
+        let (current, rest) = self.data.split_at(size);
+        self.data = rest;
+        Some(Ok((header, current)))
+    }
+}
+
+/// The payload of a box, i.e. everything after its header.
+pub fn payload<'a>(header: &BoxHeader, data: &'a [u8]) -> &'a [u8] {
+    &data[header.header_size as usize..]
+}
+
+/// The first child of `kind` in the payload of a container box.
+pub fn child<'a>(container: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    for entry in Boxes::new(container) {
+        let (header, data) = entry?;
+        if header.is(kind) {
+            return Ok(Some(data));
+        }
+    }
+    Ok(None)
+}
+
+/// Like [`child`], but returns the payload and fails if the box is missing.
+pub fn required<'a>(container: &'a [u8], kind: &[u8; 4], name: &'static str) -> Result<&'a [u8]> {
+    let data = child(container, kind)?.ok_or(Error::MissingBox(name))?;
+    let header = BoxHeader::parse(data)?;
+    Ok(payload(&header, data))
+}
+
+/// Finds a top-level box in a complete file, returning its offset and header.
+pub fn find_top_level(file: &[u8], kind: &[u8; 4]) -> Result<Option<(u64, BoxHeader)>> {
+    let mut offset = 0u64;
+    for entry in Boxes::new(file) {
+        let (header, data) = entry?;
+        if header.is(kind) {
+            return Ok(Some((offset, header)));
+        }
+        offset += data.len() as u64;
+    }
+    Ok(None)
+}
+
+pub fn read_u8(data: &[u8], at: usize) -> Result<u8> {
+    data.get(at).copied().ok_or(Error::Truncated)
+}
+
+pub fn read_u16(data: &[u8], at: usize) -> Result<u16> {
+    Ok(u16::from_be_bytes(bytes(data, at)?))
+}
+
+pub fn read_u32(data: &[u8], at: usize) -> Result<u32> {
+    Ok(u32::from_be_bytes(bytes(data, at)?))
+}
+
+pub fn read_u64(data: &[u8], at: usize) -> Result<u64> {
+    Ok(u64::from_be_bytes(bytes(data, at)?))
+}
+
+fn bytes<const N: usize>(data: &[u8], at: usize) -> Result<[u8; N]> {
+    data.get(at..at + N)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or(Error::Truncated)
+}
+
+/// Appends a box to `out`, with the size filled in once `body` has written
+/// the payload.
+pub fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Appends a full box, i.e. one that starts with a version and flags.
+pub fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&((version as u32) << 24 | flags & 0x00ff_ffff).to_be_bytes());
+        body(out);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_compact_large_and_open_ended_sizes() {
+        let header = BoxHeader::parse(b"\0\0\0\x10free").unwrap();
+        assert_eq!(
+            (header.kind, header.size, header.header_size),
+            (*b"free", 16, 8)
+        );
+        let header = BoxHeader::parse(b"\0\0\0\x01mdat\0\0\0\x01\0\0\0\0").unwrap();
+        assert_eq!((header.size, header.header_size), (1 << 32, 16));
+        let header = BoxHeader::parse(b"\0\0\0\0mdat").unwrap();
+        assert_eq!(header.size, u64::MAX);
+
+        assert!(matches!(
+            BoxHeader::parse(b"\0\0\0\x04free"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            BoxHeader::parse(b"\0\0\0\x01mdat\0\0"),
+            Err(Error::Truncated)
+        ));
+    }
+
+    #[test]
+    fn iterates_over_sibling_boxes_and_finds_children() {
+        let mut data = Vec::new();
+        write_box(&mut data, b"free", |out| out.extend_from_slice(b"abc"));
+        write_full_box(&mut data, b"mvhd", 1, 2, |out| out.push(7));
+        let kinds: Vec<_> = Boxes::new(&data)
+            .map(|entry| entry.unwrap().0.kind)
+            .collect();
+        assert_eq!(kinds, [*b"free", *b"mvhd"]);
+
+        assert_eq!(required(&data, b"mvhd", "mvhd").unwrap(), [1, 0, 0, 2, 7]);
+        assert_eq!(find_top_level(&data, b"mvhd").unwrap().unwrap().0, 11);
+        assert!(matches!(
+            required(&data, b"moov", "moov"),
+            Err(Error::MissingBox("moov"))
+        ));
+    }
+
+    #[test]
+    fn stops_at_a_box_larger_than_the_data() {
+        let mut data = Vec::new();
+        write_box(&mut data, b"free", |_| {});
+        data.extend_from_slice(b"\0\0\0\x20moov\0\0");
+        let mut boxes = Boxes::new(&data);
+        assert!(boxes.next().unwrap().is_ok());
+        assert!(matches!(boxes.next(), Some(Err(Error::Truncated))));
+        assert!(boxes.next().is_none());
+    }
+}
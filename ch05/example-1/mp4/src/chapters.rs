@@ -0,0 +1,205 @@
+use crate::boxes::{BoxHeader, child, payload, read_u8, read_u16, read_u32, read_u64};
+use crate::movie::{Movie, Track};
+use crate::{Error, Result};
+
+/// Nero chapter start times are in units of 100 ns.
+const CHPL_TIMESCALE: f64 = 10_000_000.0;
+
+/// A chapter embedded in a file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chapter {
+    /// Start in seconds.
+    pub start: f64,
+    pub title: String,
+}
+
+impl Movie {
+    /// The chapters of the Nero `chpl` box in `udta`, as written by ffmpeg
+    /// and most MP4 taggers.
+    pub fn nero_chapters(&self) -> Result<Vec<Chapter>> {
+        let Some(udta) = &self.udta else {
+            return Ok(Vec::new());
+        };
+        let Some(chpl) = child(payload(&BoxHeader::parse(udta)?, udta), b"chpl")? else {
+            return Ok(Vec::new());
+        };
+        let chpl = payload(&BoxHeader::parse(chpl)?, chpl);
+        // Version 1 has four more bytes of unknown purpose before the count.
+        let mut at = if read_u8(chpl, 0)? == 1 { 8 } else { 4 };
+        let count = read_u8(chpl, at)?;
+        at += 1;
+        let mut chapters = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let start = read_u64(chpl, at)?;
+            let length = read_u8(chpl, at + 8)? as usize;
+            let title = chpl.get(at + 9..at + 9 + length).ok_or(Error::Truncated)?;
+            chapters.push(Chapter {
+                start: start as f64 / CHPL_TIMESCALE,
+                title: String::from_utf8_lossy(title).into_owned(),
+            });
+            at += 9 + length;
+        }
+        Ok(chapters)
+    }
+
+    /// The text track QuickTime chapters are read from, referenced by another
+    /// track's `tref/chap` box. Its samples are chapter titles, see
+    /// [`chapter_title`].
+    pub fn chapter_track(&self) -> Option<&Track> {
+        self.tracks
+            .iter()
+            .flat_map(|track| &track.chapter_tracks)
+            .find_map(|id| self.tracks.iter().find(|track| track.id == *id))
+    }
+}
+
+/// The title in a sample of a QuickTime chapter track: a 16 bit length and
+/// the text, which is UTF-16 if it starts with a byte order mark.
+pub fn chapter_title(sample: &[u8]) -> Result<String> {
+    let length = read_u16(sample, 0)? as usize;
+    let text = sample.get(2..2 + length).ok_or(Error::Truncated)?;
+    let utf16 = |read: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = text[2..]
+            .chunks_exact(2)
+            .map(|unit| read([unit[0], unit[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+    Ok(match text {
+        [0xFE, 0xFF, ..] => utf16(u16::from_be_bytes),
+        [0xFF, 0xFE, ..] => utf16(u16::from_le_bytes),
+        _ => String::from_utf8_lossy(text).into_owned(),
+    })
+}
+
+/// The track ids of a `tref/chap` box in the payload of a `trak` box.
+pub(crate) fn chapter_references(trak: &[u8]) -> Result<Vec<u32>> {
+    let Some(tref) = child(trak, b"tref")? else {
+        return Ok(Vec::new());
+    };
+    let Some(chap) = child(payload(&BoxHeader::parse(tref)?, tref), b"chap")? else {
+        return Ok(Vec::new());
+    };
+    let chap = payload(&BoxHeader::parse(chap)?, chap);
+    (0..chap.len() / 4)
+        .map(|index| read_u32(chap, index * 4))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boxes::{write_box, write_full_box};
+    use crate::movie::tests::progressive;
+
+    /// A `udta` box with a `chpl` of `version` holding `chapters`, their
+    /// starts in 100 ns units.
+    fn udta(version: u8, chapters: &[(u64, &str)]) -> Vec<u8> {
+        let mut udta = Vec::new();
+        write_box(&mut udta, b"udta", |out| {
+            write_full_box(out, b"chpl", version, 0, |out| {
+                if version == 1 {
+                    out.extend_from_slice(&[0; 4]);
+                }
+                out.push(chapters.len() as u8);
+                for (start, title) in chapters {
+                    out.extend_from_slice(&start.to_be_bytes());
+                    out.push(title.len() as u8);
+                    out.extend_from_slice(title.as_bytes());
+                }
+            });
+        });
+        udta
+    }
+
+    fn movie() -> Movie {
+        Movie::parse(&progressive(4, 2)).unwrap()
+    }
+
+    #[test]
+    fn reads_nero_chapters_of_both_versions() {
+        let chapters = [(0, "Intro"), (15_000_000, "Über uns")];
+        let expected = vec![
+            Chapter {
+                start: 0.0,
+                title: "Intro".to_string(),
+            },
+            Chapter {
+                start: 1.5,
+                title: "Über uns".to_string(),
+            },
+        ];
+        for version in [0, 1] {
+            let mut movie = movie();
+            movie.udta = Some(udta(version, &chapters));
+            assert_eq!(
+                movie.nero_chapters().unwrap(),
+                expected,
+                "version {version}"
+            );
+        }
+    }
+
+    #[test]
+    fn movies_without_chpl_have_no_nero_chapters() {
+        let mut movie = movie();
+        assert!(movie.nero_chapters().unwrap().is_empty());
+        let mut other = Vec::new();
+        write_box(&mut other, b"udta", |out| write_box(out, b"meta", |_| {}));
+        movie.udta = Some(other);
+        assert!(movie.nero_chapters().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_titles_running_past_the_box() {
+        let mut movie = movie();
+        let mut udta = udta(0, &[(0, "Intro")]);
+        // The title claims a byte more than there is.
+        let length_at = udta.len() - "Intro".len() - 1;
+        udta[length_at] += 1;
+        movie.udta = Some(udta);
+        assert!(matches!(movie.nero_chapters(), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn finds_the_chapter_track_referenced_by_another_track() {
+        let mut movie = movie();
+        assert!(movie.chapter_track().is_none());
+        let mut chapters = movie.tracks[0].clone();
+        chapters.id = 2;
+        movie.tracks.push(chapters);
+        movie.tracks[0].chapter_tracks = vec![2];
+        assert_eq!(movie.chapter_track().unwrap().id, 2);
+        // A reference to a track that does not exist.
+        movie.tracks[0].chapter_tracks = vec![7];
+        assert!(movie.chapter_track().is_none());
+    }
+
+    #[test]
+    fn reads_the_chap_references_of_a_track() {
+        let mut trak = Vec::new();
+        write_box(&mut trak, b"tref", |out| {
+            write_box(out, b"chap", |out| {
+                out.extend_from_slice(&3u32.to_be_bytes());
+                out.extend_from_slice(&5u32.to_be_bytes());
+            });
+        });
+        assert_eq!(chapter_references(&trak).unwrap(), [3, 5]);
+        assert!(chapter_references(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn decodes_chapter_titles_in_utf8_and_utf16() {
+        assert_eq!(chapter_title(b"\x00\x05Intro\x00\x0c").unwrap(), "Intro");
+        let be = [0, 8, 0xFE, 0xFF, 0, b'H', 0, 0xE9, 0, b'!'];
+        assert_eq!(chapter_title(&be).unwrap(), "Hé!");
+        let le = [0, 6, 0xFF, 0xFE, b'O', 0, b'k', 0];
+        assert_eq!(chapter_title(&le).unwrap(), "Ok");
+        assert_eq!(chapter_title(&[0, 0]).unwrap(), "");
+        assert!(matches!(
+            chapter_title(b"\x00\x09Intro"),
+            Err(Error::Truncated)
+        ));
+        assert!(matches!(chapter_title(&[0]), Err(Error::Truncated)));
+    }
+}
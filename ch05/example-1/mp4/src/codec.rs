@@ -0,0 +1,388 @@
+use crate::boxes::{BoxHeader, Boxes, child, payload, read_u8, read_u16, read_u32};
+use crate::movie::{SampleEntry, TrackKind};
+use crate::{Error, Result};
+
+/// Size of the fields of a visual sample entry before its child boxes.
+const VISUAL_ENTRY_SIZE: usize = 78;
+/// Size of the fields of an audio sample entry before its children, by
+/// QuickTime sound description version.
+const AUDIO_ENTRY_SIZES: [usize; 3] = [28, 44, 64];
+
+/// Reads the first entry of an `stsd` payload.
+pub fn sample_entry(stsd: &[u8], kind: TrackKind) -> Result<SampleEntry> {
+    let entries = stsd.get(8..).ok_or(Error::Truncated)?;
+    let (header, data) = Boxes::new(entries)
+        .next()
+        .ok_or(Error::MissingBox("sample entry"))??;
+    let format = header.kind;
+    if matches!(&format, b"encv" | b"enca") {
+        return Err(Error::Unsupported("encrypted tracks".to_string()));
+    }
+    let fields = payload(&header, data);
+    let mut entry = SampleEntry {
+        format,
+        codec: String::from_utf8_lossy(&format).trim().to_string(),
+        width: 0,
+        height: 0,
+        channels: 0,
+        sample_rate: 0,
+    };
+    match kind {
+        TrackKind::Video => {
+            entry.width = read_u16(fields, 24)?;
+            entry.height = read_u16(fields, 26)?;
+            let children = fields.get(VISUAL_ENTRY_SIZE..).unwrap_or_default();
+            if let Some(codec) = video_codec(&format, children)? {
+                entry.codec = codec;
+            }
+        }
+        TrackKind::Audio => {
+            entry.channels = read_u16(fields, 16)?;
+            entry.sample_rate = read_u32(fields, 24)? >> 16;
+            let entry_size = AUDIO_ENTRY_SIZES
+                .get(read_u16(fields, 8)? as usize)
+                .ok_or_else(|| Error::Unsupported("sound description version".to_string()))?;
+            let children = fields.get(*entry_size..).unwrap_or_default();
+            if let Some(codec) = audio_codec(&format, children)? {
+                entry.codec = codec;
+            }
+        }
+        TrackKind::Other(_) => {}
+    }
+    Ok(entry)
+}
+
+fn config<'a>(children: &'a [u8], kind: &[u8; 4]) -> Result<Option<&'a [u8]>> {
+    match child(children, kind)? {
+        Some(data) => Ok(Some(payload(&BoxHeader::parse(data)?, data))),
+        None => Ok(None),
+    }
+}
+
+/// Codec strings as defined by RFC 6381 and the codec specific ISO mappings.
+fn video_codec(format: &[u8; 4], children: &[u8]) -> Result<Option<String>> {
+    let fourcc = String::from_utf8_lossy(format);
+    match format {
+        b"avc1" | b"avc3" => {
+            let Some(avcc) = config(children, b"avcC")? else {
+                return Ok(None);
+            };
+            Ok(Some(format!(
+                "{fourcc}.{:02x}{:02x}{:02x}",
+                read_u8(avcc, 1)?,
+                read_u8(avcc, 2)?,
+                read_u8(avcc, 3)?
+            )))
+        }
+        b"hvc1" | b"hev1" => {
+            let Some(hvcc) = config(children, b"hvcC")? else {
+                return Ok(None);
+            };
+            let profile = read_u8(hvcc, 1)?;
+            let space = ["", "A", "B", "C"][(profile >> 6) as usize];
+            let tier = if profile & 0x20 != 0 { 'H' } else { 'L' };
+            // The compatibility flags are written in reverse bit order.
+            let compatibility = read_u32(hvcc, 2)?.reverse_bits();
+            let mut codec = format!(
+                "{fourcc}.{space}{}.{compatibility:x}.{tier}{}",
+                profile & 0x1f,
+                read_u8(hvcc, 12)?
+            );
+            let constraints = hvcc.get(6..12).ok_or(Error::Truncated)?;
+            let used = constraints
+                .iter()
+                .rposition(|byte| *byte != 0)
+                .map_or(0, |last| last + 1);
+            for byte in &constraints[..used] {
+                codec.push_str(&format!(".{byte:x}"));
+            }
+            Ok(Some(codec))
+        }
+        b"av01" => {
+            let Some(av1c) = config(children, b"av1C")? else {
+                return Ok(None);
+            };
+            let profile = read_u8(av1c, 1)?;
+            let flags = read_u8(av1c, 2)?;
+            let tier = if flags & 0x80 != 0 { 'H' } else { 'M' };
+            let bit_depth = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+                (true, true) => 12,
+                (true, false) => 10,
+                _ => 8,
+            };
+            Ok(Some(format!(
+                "av01.{}.{:02}{tier}.{bit_depth:02}",
+                profile >> 5,
+                profile & 0x1f
+            )))
+        }
+        b"vp09" => {
+            let Some(vpcc) = config(children, b"vpcC")? else {
+                return Ok(None);
+            };
+            Ok(Some(format!(
+                "vp09.{:02}.{:02}.{:02}",
+                read_u8(vpcc, 4)?,
+                read_u8(vpcc, 5)?,
+                read_u8(vpcc, 6)? >> 4
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn audio_codec(format: &[u8; 4], children: &[u8]) -> Result<Option<String>> {
+    match format {
+        b"mp4a" => {
+            let Some(esds) = config(children, b"esds")? else {
+                return Ok(None);
+            };
+            Ok(esds_codec(esds.get(4..).unwrap_or_default()))
+        }
+        b"Opus" => Ok(Some("opus".to_string())),
+        b"fLaC" => Ok(Some("flac".to_string())),
+        _ => Ok(None),
+    }
+}
+
+/// `mp4a.<object type>.<audio object type>` from an ES descriptor.
+fn esds_codec(descriptors: &[u8]) -> Option<String> {
+    let (tag, es) = descriptor(descriptors)?;
+    if tag != 0x03 {
+        return None;
+    }
+    let flags = *es.get(2)?;
+    let mut at = 3;
+    if flags & 0x80 != 0 {
+        at += 2;
+    }
+    if flags & 0x40 != 0 {
+        at += 1 + *es.get(at)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        at += 2;
+    }
+    let (tag, decoder_config) = descriptor(es.get(at..)?)?;
+    if tag != 0x04 {
+        return None;
+    }
+    let object_type = *decoder_config.first()?;
+    if object_type != 0x40 {
+        return Some(format!("mp4a.{object_type:02x}"));
+    }
+    let audio_object_type = match descriptor(decoder_config.get(13..)?) {
+        Some((0x05, specific)) => {
+            let aot = specific.first()? >> 3;
+            // 31 escapes to a 6 bit extension.
+            if aot == 31 {
+                32 + (((specific.first()? & 0x07) << 3) | (specific.get(1)? >> 5))
+            } else {
+                aot
+            }
+        }
+        _ => 2,
+    };
+    Some(format!("mp4a.40.{audio_object_type}"))
+}
+
+/// Tag and body of an MPEG-4 descriptor, whose length is stored in up to
+/// four bytes of seven bits each.
+fn descriptor(data: &[u8]) -> Option<(u8, &[u8])> {
+    let tag = *data.first()?;
+    let mut length = 0usize;
+    let mut at = 1;
+    loop {
+        let byte = *data.get(at)?;
+        length = (length << 7) | (byte & 0x7f) as usize;
+        at += 1;
+        if byte & 0x80 == 0 || at == 5 {
+            break;
+        }
+    }
+    let end = (at + length).min(data.len());
+    Some((tag, &data[at..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boxes::{write_box, write_full_box};
+
+    /// An `stsd` payload with one entry of `format`, whose fixed fields are
+    /// written by `fields` and followed by `children`.
+    fn stsd(format: &[u8; 4], fields: impl FnOnce(&mut Vec<u8>), children: &[u8]) -> Vec<u8> {
+        let mut stsd = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        write_box(&mut stsd, format, |out| {
+            fields(out);
+            out.extend_from_slice(children);
+        });
+        stsd
+    }
+
+    fn visual(format: &[u8; 4], children: &[u8]) -> Vec<u8> {
+        stsd(
+            format,
+            |out| {
+                let mut fields = [0; VISUAL_ENTRY_SIZE];
+                fields[24..26].copy_from_slice(&1920u16.to_be_bytes());
+                fields[26..28].copy_from_slice(&1080u16.to_be_bytes());
+                out.extend_from_slice(&fields);
+            },
+            children,
+        )
+    }
+
+    fn audio(format: &[u8; 4], children: &[u8]) -> Vec<u8> {
+        stsd(
+            format,
+            |out| {
+                let mut fields = [0; 28];
+                fields[16..18].copy_from_slice(&2u16.to_be_bytes());
+                fields[24..28].copy_from_slice(&(48_000u32 << 16).to_be_bytes());
+                out.extend_from_slice(&fields);
+            },
+            children,
+        )
+    }
+
+    fn config(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box(&mut out, kind, |out| out.extend_from_slice(body));
+        out
+    }
+
+    fn video_codec(format: &[u8; 4], children: &[u8]) -> String {
+        sample_entry(&visual(format, children), TrackKind::Video)
+            .unwrap()
+            .codec
+    }
+
+    /// An `esds` box with the decoder configuration of `object_type`, and the
+    /// decoder specific info if given.
+    fn esds(object_type: u8, specific: Option<&[u8]>) -> Vec<u8> {
+        let mut decoder_config = vec![object_type, 0x15, 0, 0, 0];
+        decoder_config.extend_from_slice(&[0; 8]);
+        if let Some(specific) = specific {
+            decoder_config.extend_from_slice(&[0x05, specific.len() as u8]);
+            decoder_config.extend_from_slice(specific);
+        }
+        // The ES id and flags, then the decoder configuration with its
+        // length in the four byte form some muxers write.
+        let mut es = vec![0, 1, 0, 0x04, 0x80, 0x80, 0x80, decoder_config.len() as u8];
+        es.extend_from_slice(&decoder_config);
+        let mut out = Vec::new();
+        write_full_box(&mut out, b"esds", 0, 0, |out| {
+            out.extend_from_slice(&[0x03, es.len() as u8]);
+            out.extend_from_slice(&es);
+        });
+        out
+    }
+
+    fn audio_codec(format: &[u8; 4], children: &[u8]) -> String {
+        sample_entry(&audio(format, children), TrackKind::Audio)
+            .unwrap()
+            .codec
+    }
+
+    #[test]
+    fn reads_the_frame_size_and_avc_profile() {
+        let avcc = config(b"avcC", &[1, 0x64, 0x00, 0x28, 0xff, 0xe1, 0]);
+        let entry = sample_entry(&visual(b"avc1", &avcc), TrackKind::Video).unwrap();
+        assert_eq!(&entry.format, b"avc1");
+        assert_eq!(entry.codec, "avc1.640028");
+        assert_eq!((entry.width, entry.height), (1920, 1080));
+        assert_eq!(video_codec(b"avc3", &avcc), "avc3.640028");
+    }
+
+    #[test]
+    fn writes_hevc_profile_compatibility_tier_level_and_constraints() {
+        // Main profile, main tier, level 4.1 with the progressive source flag.
+        let mut hvcc = vec![1, 0x01, 0x60, 0, 0, 0, 0x90, 0, 0, 0, 0, 0, 123];
+        hvcc.extend_from_slice(&[0; 10]);
+        assert_eq!(
+            video_codec(b"hvc1", &config(b"hvcC", &hvcc)),
+            "hvc1.1.6.L123.90"
+        );
+        // Main 10 in the high tier of profile space 1.
+        hvcc[1] = 0x62;
+        hvcc[2] = 0x20;
+        hvcc[6] = 0xb0;
+        hvcc[8] = 0x01;
+        assert_eq!(
+            video_codec(b"hev1", &config(b"hvcC", &hvcc)),
+            "hev1.A2.4.H123.b0.0.1"
+        );
+    }
+
+    #[test]
+    fn writes_av1_and_vp9_codec_strings() {
+        // Main profile, level 4.0 (8), main tier, 10 bit.
+        let av1c = config(b"av1C", &[0x81, 0x08, 0x40, 0]);
+        assert_eq!(video_codec(b"av01", &av1c), "av01.0.08M.10");
+        let av1c = config(b"av1C", &[0x81, 0x2d, 0xe0, 0]);
+        assert_eq!(video_codec(b"av01", &av1c), "av01.1.13H.12");
+
+        let mut vpcc = Vec::new();
+        write_full_box(&mut vpcc, b"vpcC", 1, 0, |out| {
+            out.extend_from_slice(&[2, 31, 0xa0, 1, 1, 1, 0, 0]);
+        });
+        assert_eq!(video_codec(b"vp09", &vpcc), "vp09.02.31.10");
+    }
+
+    #[test]
+    fn falls_back_to_the_fourcc_without_a_configuration() {
+        assert_eq!(video_codec(b"avc1", &[]), "avc1");
+        assert_eq!(video_codec(b"mp4v", &[]), "mp4v");
+        assert_eq!(audio_codec(b"ac-3", &[]), "ac-3");
+    }
+
+    #[test]
+    fn reads_channels_sample_rate_and_the_aac_object_type() {
+        let entry = sample_entry(
+            &audio(b"mp4a", &esds(0x40, Some(&[0x12, 0x10]))),
+            TrackKind::Audio,
+        )
+        .unwrap();
+        assert_eq!(entry.codec, "mp4a.40.2");
+        assert_eq!((entry.channels, entry.sample_rate), (2, 48_000));
+
+        // HE-AAC, and the escape to the six bit extension for USAC (42).
+        assert_eq!(
+            audio_codec(b"mp4a", &esds(0x40, Some(&[0x2b, 0x92]))),
+            "mp4a.40.5"
+        );
+        assert_eq!(
+            audio_codec(b"mp4a", &esds(0x40, Some(&[0xf9, 0x40]))),
+            "mp4a.40.42"
+        );
+        // AAC LC is assumed without the decoder specific info.
+        assert_eq!(audio_codec(b"mp4a", &esds(0x40, None)), "mp4a.40.2");
+        // MP3 has no audio object type.
+        assert_eq!(audio_codec(b"mp4a", &esds(0x6b, None)), "mp4a.6b");
+    }
+
+    #[test]
+    fn names_opus_and_flac() {
+        assert_eq!(audio_codec(b"Opus", &[]), "opus");
+        assert_eq!(audio_codec(b"fLaC", &[]), "flac");
+    }
+
+    #[test]
+    fn rejects_encrypted_and_unknown_entries() {
+        assert!(matches!(
+            sample_entry(&visual(b"encv", &[]), TrackKind::Video),
+            Err(Error::Unsupported(_))
+        ));
+        let mut entry = audio(b"mp4a", &[]);
+        // Sound description version 3 does not exist.
+        entry[8 + 8 + 9] = 3;
+        assert!(matches!(
+            sample_entry(&entry, TrackKind::Audio),
+            Err(Error::Unsupported(_))
+        ));
+        assert!(matches!(
+            sample_entry(&[0, 0, 0, 0, 0, 0, 0, 0], TrackKind::Video),
+            Err(Error::MissingBox(_))
+        ));
+    }
+}
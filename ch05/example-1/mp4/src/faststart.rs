@@ -0,0 +1,234 @@
+use crate::boxes::{BoxHeader, Boxes, payload, read_u32, read_u64, write_box, write_full_box};
+use crate::{Error, Result};
+
+/// Boxes on the way from `moov` to the chunk offset tables.
+const CONTAINERS: [&[u8; 4]; 5] = [b"moov", b"trak", b"mdia", b"minf", b"stbl"];
+
+/// Moves the `moov` box of `file` in front of the media data, so that players
+/// can start before the whole file has been downloaded. Chunk offsets are
+/// adjusted and widened to `co64` when they no longer fit 32 bits. Returns
+/// `None` when the file is faststart already or has no `mdat`.
+pub fn faststart(file: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut top_level = Vec::new();
+    let mut offset = 0u64;
+    for entry in Boxes::new(file) {
+        let (header, data) = entry?;
+        top_level.push((offset, header, data));
+        offset += data.len() as u64;
+    }
+    let moov = top_level
+        .iter()
+        .position(|(_, header, _)| header.is(b"moov"))
+        .ok_or(Error::MissingBox("moov"))?;
+    let Some(mdat) = top_level
+        .iter()
+        .position(|(_, header, _)| header.is(b"mdat"))
+    else {
+        return Ok(None);
+    };
+    if moov < mdat {
+        return Ok(None);
+    }
+    if top_level.iter().any(|(_, header, _)| header.is(b"moof")) {
+        return Err(Error::Unsupported(
+            "moving moov in fragmented files".to_string(),
+        ));
+    }
+
+    let (moov_offset, _, moov_data) = top_level[moov];
+    let new_moov = relocate(moov_data, top_level[mdat].0, moov_offset)?;
+
+    let mut out = Vec::with_capacity(file.len() + new_moov.len() - moov_data.len());
+    for (index, (_, _, data)) in top_level.iter().enumerate() {
+        if index == mdat {
+            out.extend_from_slice(&new_moov);
+        }
+        if index != moov {
+            out.extend_from_slice(data);
+        }
+    }
+    Ok(Some(out))
+}
+
+/// Rewrites `moov_data` for its move from `moov_offset` to `insert_at`.
+fn relocate(moov_data: &[u8], insert_at: u64, moov_offset: u64) -> Result<Vec<u8>> {
+    let mut relocation = Relocation {
+        insert_at,
+        moov_offset,
+        old_len: moov_data.len() as u64,
+        new_len: moov_data.len() as u64,
+        widen: false,
+    };
+    // The size of the new moov does not depend on the offsets in it, only on
+    // whether they are widened.
+    relocation.new_len = relocation.write(moov_data)?.0.len() as u64;
+    let (new_moov, overflow) = relocation.write(moov_data)?;
+    if !overflow {
+        return Ok(new_moov);
+    }
+    relocation.widen = true;
+    relocation.new_len = relocation.write(moov_data)?.0.len() as u64;
+    Ok(relocation.write(moov_data)?.0)
+}
+
+/// Where the `moov` box moves: from `moov_offset` to `insert_at`, in front
+/// of the first `mdat`.
+struct Relocation {
+    insert_at: u64,
+    moov_offset: u64,
+    old_len: u64,
+    new_len: u64,
+    /// Whether `stco` tables are rewritten as `co64`.
+    widen: bool,
+}
+
+impl Relocation {
+    /// The new position of a byte of the original file outside of `moov`.
+    fn map(&self, offset: u64) -> u64 {
+        if offset < self.insert_at {
+            offset
+        } else if offset < self.moov_offset {
+            offset + self.new_len
+        } else {
+            offset + self.new_len - self.old_len
+        }
+    }
+
+    /// Copies a box with its chunk offsets moved, and tells whether one of
+    /// them did not fit into an `stco` table.
+    fn write(&self, data: &[u8]) -> Result<(Vec<u8>, bool)> {
+        let mut out = Vec::with_capacity(data.len());
+        let mut overflow = false;
+        self.write_box(data, &mut out, &mut overflow)?;
+        Ok((out, overflow))
+    }
+
+    fn write_box(&self, data: &[u8], out: &mut Vec<u8>, overflow: &mut bool) -> Result<()> {
+        let header = BoxHeader::parse(data)?;
+        let body = payload(&header, data);
+        if CONTAINERS.iter().any(|kind| header.is(kind)) {
+            let mut result = Ok(());
+            write_box(out, &header.kind, |out| {
+                for child in Boxes::new(body) {
+                    result = child.and_then(|(_, child)| self.write_box(child, out, overflow));
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            });
+            return result;
+        }
+        if !(header.is(b"stco") || header.is(b"co64")) {
+            out.extend_from_slice(data);
+            return Ok(());
+        }
+
+        let count = read_u32(body, 4)? as usize;
+        let offsets = (0..count)
+            .map(|index| {
+                let offset = if header.is(b"co64") {
+                    read_u64(body, 8 + index * 8)?
+                } else {
+                    read_u32(body, 8 + index * 4)? as u64
+                };
+                Ok(self.map(offset))
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        if header.is(b"co64") || self.widen {
+            write_full_box(out, b"co64", 0, 0, |out| {
+                out.extend_from_slice(&(count as u32).to_be_bytes());
+                for offset in &offsets {
+                    out.extend_from_slice(&offset.to_be_bytes());
+                }
+            });
+        } else {
+            write_full_box(out, b"stco", 0, 0, |out| {
+                out.extend_from_slice(&(count as u32).to_be_bytes());
+                for offset in &offsets {
+                    let narrow = u32::try_from(*offset).unwrap_or_else(|_| {
+                        *overflow = true;
+                        0
+                    });
+                    out.extend_from_slice(&narrow.to_be_bytes());
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boxes::find_top_level;
+    use crate::movie::Movie;
+    use crate::movie::tests::progressive;
+
+    /// The bytes of each sample of the video track of `file`.
+    fn sample_data(file: &[u8]) -> Vec<Vec<u8>> {
+        let movie = Movie::parse(file).unwrap();
+        movie
+            .video_track()
+            .unwrap()
+            .samples
+            .iter()
+            .map(|sample| {
+                let start = sample.offset as usize;
+                file[start..start + sample.size as usize].to_vec()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn moves_moov_in_front_of_mdat_and_shifts_the_chunk_offsets() {
+        let file = progressive(12, 5);
+        let relocated = faststart(&file).unwrap().unwrap();
+        assert_eq!(relocated.len(), file.len());
+
+        let (moov, _) = find_top_level(&relocated, b"moov").unwrap().unwrap();
+        let (mdat, _) = find_top_level(&relocated, b"mdat").unwrap().unwrap();
+        assert!(moov < mdat);
+        assert_eq!(&relocated[..moov as usize], &file[..moov as usize]);
+        assert_eq!(sample_data(&relocated), sample_data(&file));
+    }
+
+    #[test]
+    fn leaves_files_that_are_faststart_already() {
+        let relocated = faststart(&progressive(12, 5)).unwrap().unwrap();
+        assert!(faststart(&relocated).unwrap().is_none());
+    }
+
+    #[test]
+    fn widens_chunk_offsets_that_no_longer_fit_32_bits() {
+        let file = progressive(3, 1);
+        let (moov_offset, header) = find_top_level(&file, b"moov").unwrap().unwrap();
+        let moov_end = (moov_offset + header.size) as usize;
+        let mut moov = file[moov_offset as usize..moov_end].to_vec();
+        // Pretend the only chunk starts just before 4 GiB.
+        let stco = moov.windows(4).position(|kind| kind == b"stco").unwrap();
+        let chunk = u32::MAX - 4;
+        moov[stco + 12..stco + 16].copy_from_slice(&chunk.to_be_bytes());
+
+        let relocated = relocate(&moov, 0, u64::from(u32::MAX) + 1).unwrap();
+        assert!(relocated.windows(4).any(|kind| kind == b"co64"));
+        assert!(!relocated.windows(4).any(|kind| kind == b"stco"));
+        // The one entry grows from four to eight bytes.
+        assert_eq!(relocated.len(), moov.len() + 4);
+
+        let movie = Movie::parse_moov(&relocated).unwrap();
+        let samples = &movie.video_track().unwrap().samples;
+        assert_eq!(samples[0].offset, u64::from(chunk) + relocated.len() as u64);
+        assert_eq!(samples[2].offset, samples[0].offset + 10 + 11);
+    }
+
+    #[test]
+    fn keeps_stco_when_the_shifted_offsets_fit() {
+        let file = progressive(3, 1);
+        let (moov_offset, header) = find_top_level(&file, b"moov").unwrap().unwrap();
+        let moov_end = (moov_offset + header.size) as usize;
+        let moov = &file[moov_offset as usize..moov_end];
+        let relocated = relocate(moov, 0, moov_offset).unwrap();
+        assert_eq!(relocated.len(), moov.len());
+        assert!(!relocated.windows(4).any(|kind| kind == b"co64"));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::boxes::{write_box, write_full_box};
+use crate::movie::{Movie, Sample, Track, TrackKind};
+use crate::{Error, Result};
+
+/// `trun` flags: data offset, and per sample duration, size, flags and
+/// composition time offset.
+const TRUN_FLAGS: u32 = 0x000001 | 0x000100 | 0x000200 | 0x000400 | 0x000800;
+/// `tfhd` flag: data offsets are relative to the start of the `moof` box.
+const DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+/// Sample flags of a sync sample: does not depend on other samples.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+/// Sample flags of other samples: depends on others, is not a sync sample.
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    /// Playback duration in seconds.
+    pub duration: f64,
+    pub range: ByteRange,
+}
+
+/// A fragmented MP4 file: an initialization section followed by one
+/// `moof`/`mdat` pair per segment.
+pub struct Fragmented {
+    pub data: Vec<u8>,
+    pub init: ByteRange,
+    pub segments: Vec<Segment>,
+}
+
+/// Remuxes the audio and video tracks of a progressive `movie`, whose samples
+/// are read from `file`, into fragmented MP4. Segments start at keyframes of
+/// the video track and are cut at the first keyframe at least
+/// `target_duration` seconds after the start of the segment.
+pub fn fragment(movie: &Movie, file: &[u8], target_duration: f64) -> Result<Fragmented> {
+    if movie.fragmented {
+        return Err(Error::Unsupported(
+            "the file is fragmented already".to_string(),
+        ));
+    }
+    let tracks: Vec<&Track> = movie
+        .tracks
+        .iter()
+        .filter(|track| matches!(track.kind, TrackKind::Video | TrackKind::Audio))
+        .filter(|track| !track.samples.is_empty())
+        .collect();
+    let lead = tracks
+        .iter()
+        .find(|track| track.kind == TrackKind::Video)
+        .or(tracks.first())
+        .copied()
+        .ok_or_else(|| Error::Unsupported("no audio or video samples".to_string()))?;
+    for sample in tracks.iter().flat_map(|track| &track.samples) {
+        let end = sample
+            .offset
+            .checked_add(sample.size as u64)
+            .ok_or_else(|| Error::Invalid(format!("sample at {} overflows", sample.offset)))?;
+        if end > file.len() as u64 {
+            return Err(Error::Invalid(format!(
+                "sample at {} lies beyond the end of the file",
+                sample.offset
+            )));
+        }
+    }
+
+    let boundaries = boundaries(lead, target_duration);
+    let mut data = Vec::new();
+    write_ftyp(&mut data);
+    write_init_moov(&mut data, movie, &tracks);
+    let init = ByteRange {
+        offset: 0,
+        length: data.len() as u64,
+    };
+
+    let mut segments = Vec::with_capacity(boundaries.len());
+    for (index, window) in boundaries.windows(2).enumerate() {
+        let (start, end) = (window[0], window[1]);
+        let first = index == 0;
+        let last = index + 2 == boundaries.len();
+        let parts: Vec<(&Track, &[Sample])> = tracks
+            .iter()
+            .map(|track| {
+                let samples = samples_between(
+                    track,
+                    lead.timescale,
+                    (!first).then_some(start),
+                    (!last).then_some(end),
+                );
+                (*track, samples)
+            })
+            .filter(|(_, samples)| !samples.is_empty())
+            .collect();
+        let offset = data.len() as u64;
+        write_fragment(&mut data, index as u32 + 1, &parts, file)?;
+        segments.push(Segment {
+            duration: (end - start) as f64 / lead.timescale as f64,
+            range: ByteRange {
+                offset,
+                length: data.len() as u64 - offset,
+            },
+        });
+    }
+    Ok(Fragmented {
+        data,
+        init,
+        segments,
+    })
+}
+
+/// Start times of the segments in units of the lead track's timescale,
+/// followed by the end of its last sample.
+fn boundaries(lead: &Track, target_duration: f64) -> Vec<u64> {
+    let target = (target_duration * lead.timescale as f64).round().max(1.0) as u64;
+    let mut boundaries = vec![lead.samples[0].decode_time];
+    for sample in &lead.samples[1..] {
+        if sample.sync && sample.decode_time - boundaries.last().unwrap() >= target {
+            boundaries.push(sample.decode_time);
+        }
+    }
+    let last = lead.samples.last().unwrap();
+    boundaries.push(last.decode_time + last.duration as u64);
+    boundaries
+}
+
+/// The samples of `track` whose decode time falls into `[start, end)`, given
+/// in `timescale` units. The first segment has no start and the last one no
+/// end, so that they take the samples of other tracks that begin earlier or
+/// end later than the lead track.
+fn samples_between(
+    track: &Track,
+    timescale: u32,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> &[Sample] {
+    // Compares `decode_time / track.timescale` with `time / timescale` exactly.
+    let before = |time: u64| {
+        move |sample: &Sample| {
+            (sample.decode_time as u128) * (timescale as u128)
+                < (time as u128) * (track.timescale as u128)
+        }
+    };
+    let first = start.map_or(0, |start| track.samples.partition_point(before(start)));
+    let after = end.map_or(track.samples.len(), |end| {
+        track.samples.partition_point(before(end))
+    });
+    &track.samples[first..after.max(first)]
+}
+
+fn write_ftyp(out: &mut Vec<u8>) {
+    write_box(out, b"ftyp", |out| {
+        out.extend_from_slice(b"iso6");
+        out.extend_from_slice(&0u32.to_be_bytes());
+        for brand in [b"iso6", b"mp41", b"dash"] {
+            out.extend_from_slice(brand);
+        }
+    });
+}
+
+/// The `moov` of the initialization section: the original track headers and
+/// sample descriptions, empty sample tables and an `mvex` box announcing the
+/// fragments.
+fn write_init_moov(out: &mut Vec<u8>, movie: &Movie, tracks: &[&Track]) {
+    write_box(out, b"moov", |out| {
+        out.extend_from_slice(&movie.mvhd);
+        for track in tracks {
+            let raw = &track.raw;
+            write_box(out, b"trak", |out| {
+                out.extend_from_slice(&raw.tkhd);
+                if let Some(edts) = &raw.edts {
+                    out.extend_from_slice(edts);
+                }
+                write_box(out, b"mdia", |out| {
+                    out.extend_from_slice(&raw.mdhd);
+                    out.extend_from_slice(&raw.hdlr);
+                    write_box(out, b"minf", |out| {
+                        out.extend_from_slice(&raw.media_header);
+                        out.extend_from_slice(&raw.dinf);
+                        write_box(out, b"stbl", |out| {
+                            out.extend_from_slice(&raw.stsd);
+                            for kind in [b"stts", b"stsc", b"stco"] {
+                                write_full_box(out, kind, 0, 0, |out| {
+                                    out.extend_from_slice(&0u32.to_be_bytes());
+                                });
+                            }
+                            write_full_box(out, b"stsz", 0, 0, |out| {
+                                out.extend_from_slice(&[0; 8]);
+                            });
+                        });
+                    });
+                });
+            });
+        }
+        write_box(out, b"mvex", |out| {
+            for track in tracks {
+                write_full_box(out, b"trex", 0, 0, |out| {
+                    out.extend_from_slice(&track.id.to_be_bytes());
+                    // Sample description index, then default duration, size and flags.
+                    out.extend_from_slice(&1u32.to_be_bytes());
+                    out.extend_from_slice(&[0; 12]);
+                });
+            }
+        });
+    });
+}
+
+/// Writes one `moof` with a `traf` per track and the `mdat` with their
+/// samples, track after track.
+fn write_fragment(
+    out: &mut Vec<u8>,
+    sequence: u32,
+    parts: &[(&Track, &[Sample])],
+    file: &[u8],
+) -> Result<()> {
+    let payload: u64 = parts
+        .iter()
+        .flat_map(|(_, samples)| samples.iter())
+        .map(|sample| sample.size as u64)
+        .sum();
+    let mdat_size = u32::try_from(payload + 8)
+        .map_err(|_| Error::Unsupported("segments larger than 4 GiB".to_string()))?;
+
+    // The data offsets depend on the size of the moof, which does not depend
+    // on their values, so write it once to measure and once for real.
+    let mut moof = Vec::new();
+    write_moof(&mut moof, sequence, parts, &vec![0; parts.len()]);
+    let mut data_offset = moof.len() as u64 + 8;
+    let mut offsets = Vec::with_capacity(parts.len());
+    for (_, samples) in parts {
+        let offset = i32::try_from(data_offset)
+            .map_err(|_| Error::Unsupported("segments larger than 2 GiB".to_string()))?;
+        offsets.push(offset);
+        data_offset += samples.iter().map(|sample| sample.size as u64).sum::<u64>();
+    }
+    write_moof(out, sequence, parts, &offsets);
+
+    out.extend_from_slice(&mdat_size.to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    for (_, samples) in parts {
+        for sample in *samples {
+            let start = sample.offset as usize;
+            out.extend_from_slice(&file[start..start + sample.size as usize]);
+        }
+    }
+    Ok(())
+}
+
+fn write_moof(out: &mut Vec<u8>, sequence: u32, parts: &[(&Track, &[Sample])], offsets: &[i32]) {
+    write_box(out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| {
+            out.extend_from_slice(&sequence.to_be_bytes());
+        });
+        for ((track, samples), data_offset) in parts.iter().zip(offsets) {
+            write_box(out, b"traf", |out| {
+                write_full_box(out, b"tfhd", 0, DEFAULT_BASE_IS_MOOF, |out| {
+                    out.extend_from_slice(&track.id.to_be_bytes());
+                });
+                write_full_box(out, b"tfdt", 1, 0, |out| {
+                    out.extend_from_slice(&samples[0].decode_time.to_be_bytes());
+                });
+                // Version 1 makes the composition offsets signed.
+                write_full_box(out, b"trun", 1, TRUN_FLAGS, |out| {
+                    out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                    out.extend_from_slice(&data_offset.to_be_bytes());
+                    for sample in *samples {
+                        let flags = if sample.sync || track.kind != TrackKind::Video {
+                            SYNC_SAMPLE_FLAGS
+                        } else {
+                            NON_SYNC_SAMPLE_FLAGS
+                        };
+                        out.extend_from_slice(&sample.duration.to_be_bytes());
+                        out.extend_from_slice(&sample.size.to_be_bytes());
+                        out.extend_from_slice(&flags.to_be_bytes());
+                        out.extend_from_slice(&sample.composition_offset.to_be_bytes());
+                    }
+                });
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boxes::{BoxHeader, Boxes, payload};
+    use crate::movie::tests::progressive;
+
+    #[test]
+    fn cuts_segments_at_the_keyframes_after_the_target_duration() {
+        // 100 ms samples with a keyframe every 0.4 s.
+        let file = progressive(25, 4);
+        let movie = Movie::parse(&file).unwrap();
+        let fragmented = fragment(&movie, &file, 1.0).unwrap();
+
+        let durations: Vec<f64> = fragmented
+            .segments
+            .iter()
+            .map(|segment| segment.duration)
+            .collect();
+        assert_eq!(durations, [1.2, 1.2, 0.1]);
+
+        assert_eq!(fragmented.init.offset, 0);
+        let mut offset = fragmented.init.length;
+        for segment in &fragmented.segments {
+            assert_eq!(segment.range.offset, offset);
+            offset += segment.range.length;
+        }
+        assert_eq!(offset, fragmented.data.len() as u64);
+    }
+
+    #[test]
+    fn the_init_section_describes_the_tracks_without_samples() {
+        let file = progressive(8, 4);
+        let movie = Movie::parse(&file).unwrap();
+        let fragmented = fragment(&movie, &file, 1.0).unwrap();
+
+        let init = &fragmented.data[..fragmented.init.length as usize];
+        let kinds: Vec<[u8; 4]> = Boxes::new(init)
+            .map(|entry| entry.unwrap().0.kind)
+            .collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov"]);
+        let parsed = Movie::parse(init).unwrap();
+        assert!(parsed.fragmented);
+        let track = parsed.video_track().unwrap();
+        assert_eq!(track.id, 1);
+        assert_eq!(track.entry.codec, "avc1.64001f");
+        assert!(track.samples.is_empty());
+    }
+
+    #[test]
+    fn segments_hold_the_samples_of_their_time_range() {
+        let file = progressive(8, 4);
+        let movie = Movie::parse(&file).unwrap();
+        let samples = &movie.tracks[0].samples;
+        let fragmented = fragment(&movie, &file, 0.4).unwrap();
+        assert_eq!(fragmented.segments.len(), 2);
+
+        for (segment, samples) in fragmented.segments.iter().zip(samples.chunks(4)) {
+            let range = segment.range;
+            let data = &fragmented.data[range.offset as usize..][..range.length as usize];
+            let boxes: Vec<(BoxHeader, &[u8])> =
+                Boxes::new(data).map(|entry| entry.unwrap()).collect();
+            assert_eq!(boxes.len(), 2);
+            assert!(boxes[0].0.is(b"moof") && boxes[1].0.is(b"mdat"));
+
+            let expected: Vec<u8> = samples
+                .iter()
+                .flat_map(|sample| &file[sample.offset as usize..][..sample.size as usize])
+                .copied()
+                .collect();
+            assert_eq!(payload(&boxes[1].0, boxes[1].1), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_samples_beyond_the_end_of_the_file() {
+        let file = progressive(8, 4);
+        let movie = Movie::parse(&file).unwrap();
+        let mdat_end = movie.tracks[0]
+            .samples
+            .last()
+            .map_or(0, |sample| (sample.offset + sample.size as u64) as usize);
+        let result = fragment(&movie, &file[..mdat_end - 1], 1.0);
+        assert!(matches!(result, Err(Error::Invalid(_))));
+
+        let mut movie = movie;
+        movie.tracks[0].samples[0].offset = u64::MAX - 1;
+        assert!(matches!(
+            fragment(&movie, &file, 1.0),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_data_offsets_beyond_the_signed_32_bits_of_trun() {
+        let file = progressive(2, 1);
+        let movie = Movie::parse(&file).unwrap();
+        let track = &movie.tracks[0];
+        // The samples are not read when the offsets do not fit.
+        let huge = [Sample {
+            size: 0x8000_0000,
+            ..track.samples[0]
+        }];
+        let parts = [(track, &huge[..]), (track, &track.samples[..1])];
+        let mut out = Vec::new();
+        assert!(matches!(
+            write_fragment(&mut out, 1, &parts, &file),
+            Err(Error::Unsupported(_))
+        ));
+    }
+}
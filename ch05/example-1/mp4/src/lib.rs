@@ -0,0 +1,51 @@
+//! Reading and writing of ISO base media files (MP4) without re-encoding.
+//!
+//! [`Movie::parse`] reads the `moov` box of a progressive MP4 into tracks and
+//! sample tables, [`fragment`] remuxes such a movie into fragmented MP4 for
+//! HLS and [`trim`] rewrites it to start at a keyframe. [`faststart`] moves
+//! the `moov` box of a file in front of its media data. Chapters are read from
+//! Nero `chpl` boxes and QuickTime chapter tracks.
+
+use std::fmt;
+
+mod boxes;
+mod chapters;
+mod codec;
+mod faststart;
+mod fragment;
+mod movie;
+mod trim;
+
+pub use boxes::{BoxHeader, Boxes, find_top_level};
+pub use chapters::{Chapter, chapter_title};
+pub use faststart::faststart;
+pub use fragment::{ByteRange, Fragmented, Segment, fragment};
+pub use movie::{Movie, Sample, SampleEntry, Track, TrackKind};
+pub use trim::{Trimmed, trim};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The data ends in the middle of a box or field.
+    Truncated,
+    /// A box the file cannot do without is missing, e.g. `moov`.
+    MissingBox(&'static str),
+    /// Valid, but not something this crate handles, e.g. encrypted tracks.
+    Unsupported(String),
+    /// The boxes contradict each other, e.g. sample tables of different length.
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "unexpected end of data"),
+            Error::MissingBox(name) => write!(f, "missing {name} box"),
+            Error::Unsupported(what) => write!(f, "unsupported: {what}"),
+            Error::Invalid(what) => write!(f, "invalid file: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,728 @@
+use crate::boxes::{
+    BoxHeader, Boxes, child, find_top_level, payload, read_u8, read_u16, read_u32, read_u64,
+    required,
+};
+use crate::chapters::chapter_references;
+use crate::codec;
+use crate::{Error, Result};
+
+/// The presentation described by a `moov` box.
+#[derive(Clone, Debug)]
+pub struct Movie {
+    /// Units per second of the movie header's duration.
+    pub timescale: u32,
+    pub duration: u64,
+    pub tracks: Vec<Track>,
+    /// The complete `mvhd` box.
+    pub mvhd: Vec<u8>,
+    /// The complete `udta` box, if any. Chapters and metadata live here.
+    pub udta: Option<Vec<u8>>,
+    /// Whether the movie has an `mvex` box, i.e. its samples are in fragments.
+    pub fragmented: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackKind {
+    Video,
+    Audio,
+    /// Subtitles, timecodes, hints and the like.
+    Other([u8; 4]),
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub id: u32,
+    pub kind: TrackKind,
+    /// Units per second of sample times and durations.
+    pub timescale: u32,
+    /// Duration in `timescale` units.
+    pub duration: u64,
+    /// ISO 639-2/T code from the media header, e.g. `und` or `eng`.
+    pub language: String,
+    pub entry: SampleEntry,
+    pub samples: Vec<Sample>,
+    /// Ids of the QuickTime chapter tracks this track refers to.
+    pub chapter_tracks: Vec<u32>,
+    /// The boxes fragmented output copies verbatim.
+    pub raw: RawTrackBoxes,
+}
+
+/// The description of a track's samples from the `stsd` box.
+#[derive(Clone, Debug)]
+pub struct SampleEntry {
+    /// Sample entry type, e.g. `avc1` or `mp4a`.
+    pub format: [u8; 4],
+    /// RFC 6381 codec string, e.g. `avc1.64001f`.
+    pub codec: String,
+    /// Frame size of video tracks.
+    pub width: u16,
+    pub height: u16,
+    /// Channel count and sample rate of audio tracks.
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct RawTrackBoxes {
+    pub tkhd: Vec<u8>,
+    pub edts: Option<Vec<u8>>,
+    pub mdhd: Vec<u8>,
+    pub hdlr: Vec<u8>,
+    /// `vmhd`, `smhd`, `sthd`, `nmhd`, or QuickTime's `gmhd` of text tracks.
+    pub media_header: Vec<u8>,
+    pub dinf: Vec<u8>,
+    pub stsd: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// Position of the sample in the file.
+    pub offset: u64,
+    pub size: u32,
+    /// Decode time in track timescale units.
+    pub decode_time: u64,
+    pub duration: u32,
+    /// Composition time minus decode time.
+    pub composition_offset: i32,
+    /// Whether decoding can start at this sample, i.e. it is a keyframe.
+    pub sync: bool,
+}
+
+impl Sample {
+    pub fn composition_time(&self) -> i64 {
+        self.decode_time as i64 + self.composition_offset as i64
+    }
+}
+
+impl Movie {
+    /// Parses the `moov` box of a complete file.
+    pub fn parse(file: &[u8]) -> Result<Self> {
+        let (offset, header) = find_top_level(file, b"moov")?.ok_or(Error::MissingBox("moov"))?;
+        let end = offset.saturating_add(header.size).min(file.len() as u64);
+        Self::parse_moov(&file[offset as usize..end as usize])
+    }
+
+    /// Parses a complete `moov` box, header included. Sample offsets refer to
+    /// the file the box was taken from.
+    pub fn parse_moov(moov: &[u8]) -> Result<Self> {
+        let header = BoxHeader::parse(moov)?;
+        if !header.is(b"moov") {
+            return Err(Error::MissingBox("moov"));
+        }
+        let moov = payload(&header, moov);
+        let mvhd_box = child(moov, b"mvhd")?.ok_or(Error::MissingBox("mvhd"))?;
+        let mvhd = payload(&BoxHeader::parse(mvhd_box)?, mvhd_box);
+        let (timescale, duration) = match read_u8(mvhd, 0)? {
+            1 => (read_u32(mvhd, 20)?, read_u64(mvhd, 24)?),
+            _ => (read_u32(mvhd, 12)?, read_u32(mvhd, 16)? as u64),
+        };
+        let mut tracks = Vec::new();
+        for entry in Boxes::new(moov) {
+            let (header, data) = entry?;
+            if header.is(b"trak") {
+                tracks.push(parse_track(data)?);
+            }
+        }
+        Ok(Self {
+            timescale,
+            duration,
+            tracks,
+            mvhd: mvhd_box.to_vec(),
+            udta: child(moov, b"udta")?.map(<[u8]>::to_vec),
+            fragmented: child(moov, b"mvex")?.is_some(),
+        })
+    }
+
+    /// Duration in seconds.
+    pub fn duration_secs(&self) -> f64 {
+        if self.timescale == 0 {
+            return 0.0;
+        }
+        self.duration as f64 / self.timescale as f64
+    }
+
+    pub fn video_track(&self) -> Option<&Track> {
+        self.tracks
+            .iter()
+            .find(|track| track.kind == TrackKind::Video)
+    }
+}
+
+impl Track {
+    /// Duration in seconds, taken from the samples when the header has none.
+    pub fn duration_secs(&self) -> f64 {
+        let duration = match self.samples.last() {
+            Some(last) if self.duration == 0 => last.decode_time + last.duration as u64,
+            _ => self.duration,
+        };
+        duration as f64 / self.timescale as f64
+    }
+
+    /// Average bitrate in bits per second.
+    pub fn bitrate(&self) -> u64 {
+        let seconds = self.duration_secs();
+        if seconds <= 0.0 {
+            return 0;
+        }
+        let bytes: u64 = self.samples.iter().map(|sample| sample.size as u64).sum();
+        (bytes as f64 * 8.0 / seconds).round() as u64
+    }
+}
+
+fn parse_track(trak_box: &[u8]) -> Result<Track> {
+    let trak = payload(&BoxHeader::parse(trak_box)?, trak_box);
+    let tkhd_box = child(trak, b"tkhd")?.ok_or(Error::MissingBox("tkhd"))?;
+    let tkhd = payload(&BoxHeader::parse(tkhd_box)?, tkhd_box);
+    let id = match read_u8(tkhd, 0)? {
+        1 => read_u32(tkhd, 20)?,
+        _ => read_u32(tkhd, 12)?,
+    };
+
+    let mdia_box = child(trak, b"mdia")?.ok_or(Error::MissingBox("mdia"))?;
+    let mdia = payload(&BoxHeader::parse(mdia_box)?, mdia_box);
+    let mdhd_box = child(mdia, b"mdhd")?.ok_or(Error::MissingBox("mdhd"))?;
+    let mdhd = payload(&BoxHeader::parse(mdhd_box)?, mdhd_box);
+    let (timescale, duration, language_at) = match read_u8(mdhd, 0)? {
+        1 => (read_u32(mdhd, 20)?, read_u64(mdhd, 24)?, 32),
+        _ => (read_u32(mdhd, 12)?, read_u32(mdhd, 16)? as u64, 20),
+    };
+    if timescale == 0 {
+        return Err(Error::Invalid(format!("track {id} has a timescale of 0")));
+    }
+    let language = language(read_u16(mdhd, language_at)?);
+    let hdlr_box = child(mdia, b"hdlr")?.ok_or(Error::MissingBox("hdlr"))?;
+    let hdlr = payload(&BoxHeader::parse(hdlr_box)?, hdlr_box);
+    let handler: [u8; 4] = hdlr.get(8..12).ok_or(Error::Truncated)?.try_into().unwrap();
+    let kind = match &handler {
+        b"vide" => TrackKind::Video,
+        b"soun" => TrackKind::Audio,
+        _ => TrackKind::Other(handler),
+    };
+
+    let minf_box = child(mdia, b"minf")?.ok_or(Error::MissingBox("minf"))?;
+    let minf = payload(&BoxHeader::parse(minf_box)?, minf_box);
+    let media_header = [b"vmhd", b"smhd", b"sthd", b"nmhd", b"gmhd"]
+        .into_iter()
+        .find_map(|kind| child(minf, kind).transpose())
+        .transpose()?
+        .ok_or(Error::MissingBox("media header"))?;
+    let dinf = child(minf, b"dinf")?.ok_or(Error::MissingBox("dinf"))?;
+    let stbl = required(minf, b"stbl", "stbl")?;
+    let stsd = child(stbl, b"stsd")?.ok_or(Error::MissingBox("stsd"))?;
+    let entry = codec::sample_entry(payload(&BoxHeader::parse(stsd)?, stsd), kind)?;
+    let samples = samples(stbl)?;
+
+    Ok(Track {
+        id,
+        kind,
+        timescale,
+        duration,
+        language,
+        entry,
+        samples,
+        chapter_tracks: chapter_references(trak)?,
+        raw: RawTrackBoxes {
+            tkhd: tkhd_box.to_vec(),
+            edts: child(trak, b"edts")?.map(<[u8]>::to_vec),
+            mdhd: mdhd_box.to_vec(),
+            hdlr: hdlr_box.to_vec(),
+            media_header: media_header.to_vec(),
+            dinf: dinf.to_vec(),
+            stsd: stsd.to_vec(),
+        },
+    })
+}
+
+/// Packed ISO 639-2/T code: three 5 bit letters offset by 0x60.
+fn language(packed: u16) -> String {
+    [10, 5, 0]
+        .iter()
+        .map(|shift| (((packed >> shift) & 0x1f) as u8 + 0x60) as char)
+        .collect()
+}
+
+/// The most samples a track may have: a day of 60 fps video, or of audio
+/// frames of 1024 samples at 48 kHz, in well under a GiB of sample entries.
+const MAX_SAMPLES: u64 = 1 << 23;
+
+/// Expands the sample tables of an `stbl` payload into one entry per sample.
+fn samples(stbl: &[u8]) -> Result<Vec<Sample>> {
+    let stts = required(stbl, b"stts", "stts")?;
+    let time_runs: Vec<(u32, u32)> = (0..entries(stts, 4, 8, "stts")?)
+        .map(|entry| {
+            Ok((
+                read_u32(stts, 8 + entry * 8)?,
+                read_u32(stts, 12 + entry * 8)?,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let chunk_offsets = chunk_offsets(stbl)?;
+    let stsc = required(stbl, b"stsc", "stsc")?;
+    let runs: Vec<(u32, u32)> = (0..entries(stsc, 4, 12, "stsc")?)
+        .map(|entry| {
+            Ok((
+                read_u32(stsc, 8 + entry * 12)?,
+                read_u32(stsc, 12 + entry * 12)?,
+            ))
+        })
+        .collect::<Result<_>>()?;
+    let per_chunk = samples_per_chunk(&runs, chunk_offsets.len());
+
+    // Every sample needs a duration and a place in a chunk, and the counts of
+    // both are stated rather than held, so a fixed maximum bounds them too.
+    let timed: u64 = time_runs.iter().map(|(run, _)| *run as u64).sum();
+    let chunked: u64 = per_chunk.iter().map(|count| *count as u64).sum();
+    let limit = timed.min(chunked).min(MAX_SAMPLES);
+    let sizes = sample_sizes(stbl, limit)?;
+    let count = sizes.len();
+
+    let mut samples = Vec::with_capacity(count);
+    let mut decode_time = 0u64;
+    for (run, delta) in time_runs {
+        for _ in 0..run {
+            if samples.len() == count {
+                break;
+            }
+            samples.push(Sample {
+                offset: 0,
+                size: sizes[samples.len()],
+                decode_time,
+                duration: delta,
+                composition_offset: 0,
+                sync: true,
+            });
+            decode_time += delta as u64;
+        }
+    }
+    if samples.len() != count {
+        return Err(Error::Invalid(format!(
+            "stts describes {} of {count} samples",
+            samples.len()
+        )));
+    }
+
+    if let Some(ctts) = child(stbl, b"ctts")? {
+        let ctts = payload(&BoxHeader::parse(ctts)?, ctts);
+        let mut sample = 0usize;
+        for entry in 0..entries(ctts, 4, 8, "ctts")? {
+            let run = read_u32(ctts, 8 + entry * 8)? as usize;
+            // Version 0 offsets are unsigned, but writers use them signed too.
+            let offset = read_u32(ctts, 12 + entry * 8)? as i32;
+            // Runs beyond the last sample are ignored rather than counted out.
+            let end = sample.saturating_add(run).min(count);
+            for sample in &mut samples[sample.min(count)..end] {
+                sample.composition_offset = offset;
+            }
+            sample = end;
+        }
+    }
+
+    // Without an stss box every sample is a sync sample.
+    if let Some(stss) = child(stbl, b"stss")? {
+        let stss = payload(&BoxHeader::parse(stss)?, stss);
+        samples.iter_mut().for_each(|sample| sample.sync = false);
+        for entry in 0..entries(stss, 4, 4, "stss")? {
+            let number = read_u32(stss, 8 + entry * 4)? as usize;
+            if let Some(sample) = number.checked_sub(1).and_then(|i| samples.get_mut(i)) {
+                sample.sync = true;
+            }
+        }
+    }
+
+    let mut sample = 0;
+    for (chunk_offset, per_chunk) in chunk_offsets.iter().zip(per_chunk) {
+        let mut offset = *chunk_offset;
+        for sample in samples.iter_mut().skip(sample).take(per_chunk as usize) {
+            sample.offset = offset;
+            offset = offset
+                .checked_add(sample.size as u64)
+                .ok_or_else(|| Error::Invalid("sample offset overflows".to_string()))?;
+        }
+        sample = sample.saturating_add(per_chunk as usize);
+        if sample >= count {
+            break;
+        }
+    }
+    Ok(samples)
+}
+
+/// The number of samples in each of `chunks` chunks, from the runs of the
+/// `stsc` box, which are ordered by their first chunk.
+fn samples_per_chunk(runs: &[(u32, u32)], chunks: usize) -> Vec<u32> {
+    let mut run = 0;
+    (1..=chunks as u32)
+        .map(|chunk| {
+            while runs
+                .get(run + 1)
+                .is_some_and(|(first_chunk, _)| *first_chunk <= chunk)
+            {
+                run += 1;
+            }
+            match runs.get(run) {
+                Some((first_chunk, per_chunk)) if *first_chunk <= chunk => *per_chunk,
+                _ => 0,
+            }
+        })
+        .collect()
+}
+
+/// The size of every sample. There can not be more than `limit` samples,
+/// which bounds tables that state a count without holding an entry per
+/// sample.
+fn sample_sizes(stbl: &[u8], limit: u64) -> Result<Vec<u32>> {
+    let too_many = |count: usize| {
+        Error::Invalid(format!(
+            "{count} sample sizes for at most {limit} samples with a duration and chunk"
+        ))
+    };
+    if let Some(stsz) = child(stbl, b"stsz")? {
+        let stsz = payload(&BoxHeader::parse(stsz)?, stsz);
+        let uniform = read_u32(stsz, 4)?;
+        if uniform != 0 {
+            let count = read_u32(stsz, 8)? as usize;
+            if count as u64 > limit {
+                return Err(too_many(count));
+            }
+            return Ok(vec![uniform; count]);
+        }
+        let count = entries(stsz, 8, 4, "stsz")?;
+        if count as u64 > limit {
+            return Err(too_many(count));
+        }
+        return (0..count).map(|i| read_u32(stsz, 12 + i * 4)).collect();
+    }
+    let stz2 = required(stbl, b"stz2", "stsz")?;
+    let field_size = read_u8(stz2, 7)?;
+    let count = read_u32(stz2, 8)? as usize;
+    let held = stz2.len().saturating_sub(12) * 8 / (field_size.max(1) as usize);
+    if count > held {
+        return Err(Error::Invalid(format!(
+            "stz2 declares {count} entries but holds {held}"
+        )));
+    }
+    if count as u64 > limit {
+        return Err(too_many(count));
+    }
+    (0..count)
+        .map(|i| match field_size {
+            4 => {
+                let byte = read_u8(stz2, 12 + i / 2)?;
+                Ok(if i % 2 == 0 { byte >> 4 } else { byte & 0x0f } as u32)
+            }
+            8 => Ok(read_u8(stz2, 12 + i)? as u32),
+            16 => Ok(read_u16(stz2, 12 + i * 2)? as u32),
+            size => Err(Error::Invalid(format!("stz2 field size {size}"))),
+        })
+        .collect()
+}
+
+fn chunk_offsets(stbl: &[u8]) -> Result<Vec<u64>> {
+    if let Some(stco) = child(stbl, b"stco")? {
+        let stco = payload(&BoxHeader::parse(stco)?, stco);
+        return (0..entries(stco, 4, 4, "stco")?)
+            .map(|i| Ok(read_u32(stco, 8 + i * 4)? as u64))
+            .collect();
+    }
+    let co64 = required(stbl, b"co64", "stco")?;
+    (0..entries(co64, 4, 8, "co64")?)
+        .map(|i| read_u64(co64, 8 + i * 8))
+        .collect()
+}
+
+/// Reads the entry count of a table at `count_at`, checking that the entries
+/// of `entry_size` bytes that follow it are all there, so that a corrupt
+/// count can not make the caller allocate or loop far beyond the data.
+fn entries(table: &[u8], count_at: usize, entry_size: usize, name: &str) -> Result<usize> {
+    let count = read_u32(table, count_at)? as usize;
+    let held = table.len().saturating_sub(count_at + 4) / entry_size;
+    if count > held {
+        return Err(Error::Invalid(format!(
+            "{name} declares {count} entries but holds {held}"
+        )));
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::boxes::{write_box, write_full_box};
+
+    /// Milliseconds per sample of [`progressive`] files.
+    pub(crate) const SAMPLE_DURATION: u32 = 100;
+
+    fn u32s(out: &mut Vec<u8>, values: &[u32]) {
+        for value in values {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    /// A table box: an entry count followed by the entries.
+    fn table(out: &mut Vec<u8>, kind: &[u8; 4], entries: &[&[u32]]) {
+        write_full_box(out, kind, 0, 0, |out| {
+            u32s(out, &[entries.len() as u32]);
+            for entry in entries {
+                u32s(out, entry);
+            }
+        });
+    }
+
+    /// The sample tables of `count` samples of `size` bytes, every one of
+    /// them a keyframe, in a single chunk at offset 0.
+    fn uniform_stbl(count: u32, size: u32) -> Vec<u8> {
+        let mut stbl = Vec::new();
+        table(&mut stbl, b"stts", &[&[count, SAMPLE_DURATION]]);
+        table(&mut stbl, b"stsc", &[&[1, count, 1]]);
+        write_full_box(&mut stbl, b"stsz", 0, 0, |out| u32s(out, &[size, count]));
+        table(&mut stbl, b"stco", &[&[0]]);
+        stbl
+    }
+
+    /// A progressive file of `count` video samples of 100 ms with a keyframe
+    /// every `keyframe_every` samples. Sample `i` is `10 + i` bytes of the
+    /// value `i`, and the `moov` box follows the media data.
+    pub(crate) fn progressive(count: u32, keyframe_every: u32) -> Vec<u8> {
+        let mut file = Vec::new();
+        write_box(&mut file, b"ftyp", |out| {
+            out.extend_from_slice(b"isom");
+            u32s(out, &[0x200]);
+            out.extend_from_slice(b"isomavc1");
+        });
+        let sizes: Vec<u32> = (0..count).map(|i| 10 + i).collect();
+        let mdat_payload = file.len() as u32 + 8;
+        write_box(&mut file, b"mdat", |out| {
+            for (i, size) in sizes.iter().enumerate() {
+                out.extend(std::iter::repeat_n(i as u8, *size as usize));
+            }
+        });
+
+        let duration = count * SAMPLE_DURATION;
+        write_box(&mut file, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                u32s(out, &[0, 0, 1000, duration, 0x0001_0000]);
+                out.extend_from_slice(&[1, 0]);
+                out.extend_from_slice(&[0; 10]);
+                u32s(
+                    out,
+                    &[0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000],
+                );
+                out.extend_from_slice(&[0; 24]);
+                u32s(out, &[2]);
+            });
+            write_box(out, b"trak", |out| {
+                write_full_box(out, b"tkhd", 0, 3, |out| {
+                    u32s(out, &[0, 0, 1, 0, duration, 0, 0, 0, 0]);
+                    u32s(
+                        out,
+                        &[0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000],
+                    );
+                    u32s(out, &[640 << 16, 360 << 16]);
+                });
+                write_box(out, b"mdia", |out| {
+                    write_full_box(out, b"mdhd", 0, 0, |out| {
+                        u32s(out, &[0, 0, 1000, duration]);
+                        // "und"
+                        out.extend_from_slice(&[0x55, 0xc4, 0, 0]);
+                    });
+                    write_full_box(out, b"hdlr", 0, 0, |out| {
+                        u32s(out, &[0]);
+                        out.extend_from_slice(b"vide");
+                        out.extend_from_slice(&[0; 13]);
+                    });
+                    write_box(out, b"minf", |out| {
+                        write_full_box(out, b"vmhd", 0, 1, |out| out.extend_from_slice(&[0; 8]));
+                        write_box(out, b"dinf", |out| {
+                            write_full_box(out, b"dref", 0, 0, |out| {
+                                u32s(out, &[1]);
+                                write_full_box(out, b"url ", 0, 1, |_| {});
+                            });
+                        });
+                        write_box(out, b"stbl", |out| {
+                            write_full_box(out, b"stsd", 0, 0, |out| {
+                                u32s(out, &[1]);
+                                write_box(out, b"avc1", |out| {
+                                    out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+                                    out.extend_from_slice(&[0; 16]);
+                                    out.extend_from_slice(&[2, 0x80, 1, 0x68]);
+                                    u32s(out, &[0x0048_0000, 0x0048_0000, 0]);
+                                    out.extend_from_slice(&[0, 1]);
+                                    out.extend_from_slice(&[0; 32]);
+                                    out.extend_from_slice(&[0, 0x18, 0xff, 0xff]);
+                                    write_box(out, b"avcC", |out| {
+                                        out.extend_from_slice(&[
+                                            1, 0x64, 0x00, 0x1f, 0xff, 0xe0, 0,
+                                        ]);
+                                    });
+                                });
+                            });
+                            table(out, b"stts", &[&[count, SAMPLE_DURATION]]);
+                            let keyframes: Vec<[u32; 1]> = (0..count)
+                                .step_by(keyframe_every as usize)
+                                .map(|i| [i + 1])
+                                .collect();
+                            let keyframes: Vec<&[u32]> =
+                                keyframes.iter().map(|entry| &entry[..]).collect();
+                            table(out, b"stss", &keyframes);
+                            table(out, b"stsc", &[&[1, count, 1]]);
+                            write_full_box(out, b"stsz", 0, 0, |out| {
+                                u32s(out, &[0, count]);
+                                u32s(out, &sizes);
+                            });
+                            table(out, b"stco", &[&[mdat_payload]]);
+                        });
+                    });
+                });
+            });
+        });
+        file
+    }
+
+    #[test]
+    fn parses_the_tracks_and_sample_tables_of_a_movie() {
+        let file = progressive(12, 5);
+        let movie = Movie::parse(&file).unwrap();
+        assert_eq!((movie.timescale, movie.duration), (1000, 1200));
+        assert_eq!(movie.duration_secs(), 1.2);
+        assert!(!movie.fragmented);
+
+        let track = movie.video_track().unwrap();
+        assert_eq!((track.id, track.timescale), (1, 1000));
+        assert_eq!(track.language, "und");
+        assert_eq!(&track.entry.format, b"avc1");
+        assert_eq!(track.entry.codec, "avc1.64001f");
+        assert_eq!((track.entry.width, track.entry.height), (640, 360));
+
+        assert_eq!(track.samples.len(), 12);
+        let (mdat, _) = find_top_level(&file, b"mdat").unwrap().unwrap();
+        let mut offset = mdat + 8;
+        for (i, sample) in track.samples.iter().enumerate() {
+            assert_eq!(sample.offset, offset);
+            assert_eq!(sample.size, 10 + i as u32);
+            assert_eq!(sample.decode_time, i as u64 * 100);
+            assert_eq!(sample.duration, SAMPLE_DURATION);
+            assert_eq!(sample.sync, i % 5 == 0, "sample {i}");
+            assert!(
+                file[offset as usize..][..sample.size as usize]
+                    .iter()
+                    .all(|byte| *byte == i as u8)
+            );
+            offset += sample.size as u64;
+        }
+    }
+
+    #[test]
+    fn expands_sample_tables() {
+        let mut stbl = Vec::new();
+        table(&mut stbl, b"stts", &[&[2, 100], &[2, 50]]);
+        table(&mut stbl, b"ctts", &[&[1, 200], &[3, 0]]);
+        table(&mut stbl, b"stss", &[&[3]]);
+        table(&mut stbl, b"stsc", &[&[1, 1, 1], &[2, 3, 1]]);
+        write_full_box(&mut stbl, b"stsz", 0, 0, |out| u32s(out, &[7, 4]));
+        table(&mut stbl, b"stco", &[&[1000], &[2000]]);
+
+        let samples = samples(&stbl).unwrap();
+        let fields: Vec<_> = samples
+            .iter()
+            .map(|sample| {
+                (
+                    sample.offset,
+                    sample.decode_time,
+                    sample.duration,
+                    sample.composition_offset,
+                    sample.sync,
+                )
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                (1000, 0, 100, 200, false),
+                (2000, 100, 100, 0, false),
+                (2007, 200, 50, 0, true),
+                (2014, 250, 50, 0, false),
+            ]
+        );
+    }
+
+    fn is_invalid<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::Invalid(_)))
+    }
+
+    #[test]
+    fn rejects_entry_counts_beyond_the_data() {
+        for kind in [b"stts", b"stss", b"stsc", b"stco"] {
+            let mut stbl = Vec::new();
+            for entry in Boxes::new(&uniform_stbl(4, 10)) {
+                let (header, data) = entry.unwrap();
+                if header.is(kind) {
+                    // A count of a billion entries in a box that holds none.
+                    write_full_box(&mut stbl, kind, 0, 0, |out| u32s(out, &[1 << 30]));
+                } else {
+                    stbl.extend_from_slice(data);
+                }
+            }
+            if kind == b"stss" {
+                write_full_box(&mut stbl, kind, 0, 0, |out| u32s(out, &[1 << 30]));
+            }
+            assert!(
+                is_invalid(samples(&stbl)),
+                "{}",
+                String::from_utf8_lossy(kind)
+            );
+        }
+    }
+
+    /// Tables whose counts agree but are only stated, not held.
+    fn stated_stbl(timed: u32, per_chunk: u32, sizes: u32) -> Vec<u8> {
+        let mut stbl = Vec::new();
+        table(&mut stbl, b"stts", &[&[timed, SAMPLE_DURATION]]);
+        table(&mut stbl, b"stsc", &[&[1, per_chunk, 1]]);
+        // A uniform size states the count without an entry per sample.
+        write_full_box(&mut stbl, b"stsz", 0, 0, |out| u32s(out, &[10, sizes]));
+        table(&mut stbl, b"stco", &[&[0]]);
+        stbl
+    }
+
+    #[test]
+    fn rejects_more_samples_than_have_durations_or_chunks() {
+        assert!(is_invalid(samples(&stated_stbl(2, u32::MAX, u32::MAX))));
+        assert!(is_invalid(samples(&stated_stbl(u32::MAX, 2, u32::MAX))));
+        assert_eq!(samples(&stated_stbl(2, 2, 2)).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_more_samples_than_a_track_may_have() {
+        let stbl = stated_stbl(u32::MAX, u32::MAX, u32::MAX);
+        assert!(is_invalid(samples(&stbl)));
+    }
+
+    #[test]
+    fn assigns_samples_to_chunks_by_the_run_they_fall_into() {
+        let runs = [(1, 3), (3, 1), (6, 2)];
+        assert_eq!(samples_per_chunk(&runs, 7), [3, 3, 1, 1, 1, 2, 2]);
+        assert_eq!(samples_per_chunk(&[(2, 4)], 3), [0, 4, 4]);
+        assert!(samples_per_chunk(&runs, 0).is_empty());
+    }
+
+    #[test]
+    fn rejects_samples_missing_from_the_chunks() {
+        let mut stbl = uniform_stbl(4, 10);
+        // One chunk of four samples becomes one of three.
+        let stsc = stbl.windows(4).position(|kind| kind == b"stsc").unwrap();
+        stbl[stsc + 16..stsc + 20].copy_from_slice(&3u32.to_be_bytes());
+        assert!(is_invalid(samples(&stbl)));
+    }
+
+    #[test]
+    fn rejects_chunk_offsets_that_overflow() {
+        let mut stbl = Vec::new();
+        table(&mut stbl, b"stts", &[&[2, SAMPLE_DURATION]]);
+        table(&mut stbl, b"stsc", &[&[1, 2, 1]]);
+        write_full_box(&mut stbl, b"stsz", 0, 0, |out| u32s(out, &[10, 2]));
+        write_full_box(&mut stbl, b"co64", 0, 0, |out| {
+            u32s(out, &[1]);
+            out.extend_from_slice(&(u64::MAX - 5).to_be_bytes());
+        });
+        assert!(is_invalid(samples(&stbl)));
+    }
+}
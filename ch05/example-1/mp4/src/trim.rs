@@ -0,0 +1,473 @@
+use crate::boxes::{BoxHeader, read_u8, write_box, write_full_box};
+use crate::fragment::ByteRange;
+use crate::movie::{Movie, Sample, Track, TrackKind};
+use crate::{Error, Result};
+
+/// A progressive MP4 that starts at a keyframe of another one. The new file is
+/// `header` followed by the bytes of `source` in the original file.
+pub struct Trimmed {
+    /// `ftyp`, `moov` and the header of the `mdat` box of the new file.
+    pub header: Vec<u8>,
+    /// The part of the original file that holds all samples of the new one.
+    /// It may contain samples that are dropped, which are never referenced.
+    pub source: ByteRange,
+    /// Where in the original the new file starts, in seconds.
+    pub start: f64,
+}
+
+impl Trimmed {
+    pub fn len(&self) -> u64 {
+        self.header.len() as u64 + self.source.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Cuts the audio and video tracks of a progressive `movie` at the last
+/// keyframe at or before `start` seconds and rewrites the sample tables to
+/// match. Other tracks, chapters and metadata are dropped. `ftyp` is the
+/// original file type box, if there is one.
+pub fn trim(movie: &Movie, ftyp: Option<&[u8]>, start: f64) -> Result<Trimmed> {
+    if movie.fragmented {
+        return Err(Error::Unsupported(
+            "seeking in fragmented files".to_string(),
+        ));
+    }
+    let tracks: Vec<&Track> = movie
+        .tracks
+        .iter()
+        .filter(|track| matches!(track.kind, TrackKind::Video | TrackKind::Audio))
+        .filter(|track| !track.samples.is_empty())
+        .collect();
+    let lead = tracks
+        .iter()
+        .find(|track| track.kind == TrackKind::Video)
+        .or(tracks.first())
+        .copied()
+        .ok_or_else(|| Error::Unsupported("no audio or video samples".to_string()))?;
+    if start >= lead.duration_secs() {
+        return Err(Error::Invalid(format!(
+            "{start} s is beyond the end of the video"
+        )));
+    }
+
+    let target = (start * lead.timescale as f64) as i64;
+    let cut = lead
+        .samples
+        .iter()
+        .rposition(|sample| sample.sync && sample.composition_time() <= target)
+        .unwrap_or(0);
+    let cut_time = lead.samples[cut].decode_time;
+    let kept: Vec<(&Track, &[Sample])> = tracks
+        .iter()
+        .map(|track| {
+            // The sample playing at the cut, or the keyframe before it.
+            let at_or_before = track.samples.partition_point(|sample| {
+                (sample.decode_time as u128) * (lead.timescale as u128)
+                    <= (cut_time as u128) * (track.timescale as u128)
+            });
+            let first = track.samples[..at_or_before]
+                .iter()
+                .rposition(|sample| sample.sync || track.kind != TrackKind::Video)
+                .unwrap_or(0);
+            (*track, &track.samples[first..])
+        })
+        .collect();
+
+    let span_start = kept
+        .iter()
+        .flat_map(|(_, samples)| samples.iter())
+        .map(|sample| sample.offset)
+        .min()
+        .unwrap();
+    let span_end = kept
+        .iter()
+        .flat_map(|(_, samples)| samples.iter())
+        .map(|sample| sample.offset + sample.size as u64)
+        .max()
+        .unwrap();
+    let source = ByteRange {
+        offset: span_start,
+        length: span_end - span_start,
+    };
+
+    let ftyp = ftyp.map(<[u8]>::to_vec).unwrap_or_else(default_ftyp);
+    let mdat_header_size = if source.length + 8 > u32::MAX as u64 {
+        16
+    } else {
+        8
+    };
+    // The size of the moov does not depend on the chunk offsets, only on
+    // whether they need 64 bits.
+    let measure = |co64| {
+        let mut moov = Vec::new();
+        write_moov(&mut moov, movie, &kept, 0, co64);
+        moov.len() as u64
+    };
+    let base = |co64| ftyp.len() as u64 + measure(co64) + mdat_header_size;
+    let co64 = base(false) + source.length > u32::MAX as u64;
+    let data_start = base(co64);
+
+    let mut header = ftyp;
+    let shift = data_start as i64 - span_start as i64;
+    write_moov(&mut header, movie, &kept, shift, co64);
+    if mdat_header_size == 16 {
+        header.extend_from_slice(&1u32.to_be_bytes());
+        header.extend_from_slice(b"mdat");
+        header.extend_from_slice(&(source.length + 16).to_be_bytes());
+    } else {
+        header.extend_from_slice(&((source.length + 8) as u32).to_be_bytes());
+        header.extend_from_slice(b"mdat");
+    }
+    Ok(Trimmed {
+        header,
+        source,
+        start: cut_time as f64 / lead.timescale as f64,
+    })
+}
+
+fn default_ftyp() -> Vec<u8> {
+    let mut ftyp = Vec::new();
+    write_box(&mut ftyp, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(&0x200u32.to_be_bytes());
+        for brand in [b"isom", b"iso2", b"mp41"] {
+            out.extend_from_slice(brand);
+        }
+    });
+    ftyp
+}
+
+/// Writes a `moov` for the kept samples, whose offsets move by `shift`.
+fn write_moov(
+    out: &mut Vec<u8>,
+    movie: &Movie,
+    kept: &[(&Track, &[Sample])],
+    shift: i64,
+    co64: bool,
+) {
+    let durations: Vec<u64> = kept
+        .iter()
+        .map(|(_, samples)| samples.iter().map(|sample| sample.duration as u64).sum())
+        .collect();
+    let in_movie_timescale = |track: &Track, duration: u64| {
+        (duration as u128 * movie.timescale as u128 / track.timescale as u128) as u64
+    };
+    let movie_duration = kept
+        .iter()
+        .zip(&durations)
+        .map(|((track, _), duration)| in_movie_timescale(track, *duration))
+        .max()
+        .unwrap_or(0);
+
+    write_box(out, b"moov", |out| {
+        out.extend_from_slice(&with_duration(&movie.mvhd, movie_duration));
+        for ((track, samples), duration) in kept.iter().zip(&durations) {
+            let raw = &track.raw;
+            let presentation = in_movie_timescale(track, *duration);
+            write_box(out, b"trak", |out| {
+                out.extend_from_slice(&with_duration(&raw.tkhd, presentation));
+                if let Some(edts) = raw
+                    .edts
+                    .as_deref()
+                    .and_then(|edts| single_edit(edts, presentation))
+                {
+                    out.extend_from_slice(&edts);
+                }
+                write_box(out, b"mdia", |out| {
+                    out.extend_from_slice(&with_duration(&raw.mdhd, *duration));
+                    out.extend_from_slice(&raw.hdlr);
+                    write_box(out, b"minf", |out| {
+                        out.extend_from_slice(&raw.media_header);
+                        out.extend_from_slice(&raw.dinf);
+                        write_box(out, b"stbl", |out| {
+                            out.extend_from_slice(&raw.stsd);
+                            write_sample_tables(out, samples, shift, co64);
+                        });
+                    });
+                });
+            });
+        }
+    });
+}
+
+/// Sample tables with one sample per chunk, which keeps them simple at the
+/// cost of a chunk offset per sample.
+fn write_sample_tables(out: &mut Vec<u8>, samples: &[Sample], shift: i64, co64: bool) {
+    let mut stts: Vec<(u32, u32)> = Vec::new();
+    for sample in samples {
+        match stts.last_mut() {
+            Some((count, delta)) if *delta == sample.duration => *count += 1,
+            _ => stts.push((1, sample.duration)),
+        }
+    }
+    write_full_box(out, b"stts", 0, 0, |out| {
+        out.extend_from_slice(&(stts.len() as u32).to_be_bytes());
+        for (count, delta) in &stts {
+            out.extend_from_slice(&count.to_be_bytes());
+            out.extend_from_slice(&delta.to_be_bytes());
+        }
+    });
+
+    if samples.iter().any(|sample| sample.composition_offset != 0) {
+        let mut ctts: Vec<(u32, i32)> = Vec::new();
+        for sample in samples {
+            match ctts.last_mut() {
+                Some((count, offset)) if *offset == sample.composition_offset => *count += 1,
+                _ => ctts.push((1, sample.composition_offset)),
+            }
+        }
+        let version = u8::from(ctts.iter().any(|(_, offset)| *offset < 0));
+        write_full_box(out, b"ctts", version, 0, |out| {
+            out.extend_from_slice(&(ctts.len() as u32).to_be_bytes());
+            for (count, offset) in &ctts {
+                out.extend_from_slice(&count.to_be_bytes());
+                out.extend_from_slice(&offset.to_be_bytes());
+            }
+        });
+    }
+
+    if !samples.iter().all(|sample| sample.sync) {
+        let sync: Vec<u32> = (1..)
+            .zip(samples)
+            .filter(|(_, sample)| sample.sync)
+            .map(|(number, _)| number)
+            .collect();
+        write_full_box(out, b"stss", 0, 0, |out| {
+            out.extend_from_slice(&(sync.len() as u32).to_be_bytes());
+            for number in &sync {
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+        });
+    }
+
+    write_full_box(out, b"stsc", 0, 0, |out| {
+        for value in [1u32, 1, 1, 1] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    });
+    write_full_box(out, b"stsz", 0, 0, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes());
+        out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        for sample in samples {
+            out.extend_from_slice(&sample.size.to_be_bytes());
+        }
+    });
+    let kind = if co64 { b"co64" } else { b"stco" };
+    write_full_box(out, kind, 0, 0, |out| {
+        out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        for sample in samples {
+            let offset = (sample.offset as i64 + shift) as u64;
+            if co64 {
+                out.extend_from_slice(&offset.to_be_bytes());
+            } else {
+                out.extend_from_slice(&(offset as u32).to_be_bytes());
+            }
+        }
+    });
+}
+
+/// A copy of an `mvhd`, `tkhd` or `mdhd` box with another duration. The
+/// duration follows the same fields in all three.
+fn with_duration(full_box: &[u8], duration: u64) -> Vec<u8> {
+    let mut copy = full_box.to_vec();
+    let Ok(header) = BoxHeader::parse(full_box) else {
+        return copy;
+    };
+    let payload = header.header_size as usize;
+    let at = match (&header.kind, read_u8(full_box, payload)) {
+        (b"tkhd", Ok(1)) => payload + 28,
+        (b"tkhd", _) => payload + 20,
+        (_, Ok(1)) => payload + 24,
+        _ => payload + 16,
+    };
+    match read_u8(full_box, payload) {
+        Ok(1) if copy.len() >= at + 8 => {
+            copy[at..at + 8].copy_from_slice(&duration.to_be_bytes());
+        }
+        Ok(0) if copy.len() >= at + 4 => {
+            let duration = u32::try_from(duration).unwrap_or(u32::MAX);
+            copy[at..at + 4].copy_from_slice(&duration.to_be_bytes());
+        }
+        _ => {}
+    }
+    copy
+}
+
+/// An `edts` with a single edit, which usually skips the composition delay of
+/// B-frames, adjusted to the new duration. Other edit lists are dropped, as
+/// they refer to times that are gone.
+fn single_edit(edts: &[u8], duration: u64) -> Option<Vec<u8>> {
+    let header = BoxHeader::parse(edts).ok()?;
+    let elst_at = header.header_size as usize;
+    let elst = BoxHeader::parse(edts.get(elst_at..)?).ok()?;
+    if !elst.is(b"elst") {
+        return None;
+    }
+    let payload = elst_at + elst.header_size as usize;
+    let version = read_u8(edts, payload).ok()?;
+    if edts.get(payload + 4..payload + 8)? != 1u32.to_be_bytes() {
+        return None;
+    }
+    let mut copy = edts.to_vec();
+    let at = payload + 8;
+    if version == 1 {
+        copy.get_mut(at..at + 8)?
+            .copy_from_slice(&duration.to_be_bytes());
+    } else {
+        let duration = u32::try_from(duration).unwrap_or(u32::MAX);
+        copy.get_mut(at..at + 4)?
+            .copy_from_slice(&duration.to_be_bytes());
+    }
+    Some(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boxes::find_top_level;
+    use crate::movie::tests::{SAMPLE_DURATION, progressive};
+
+    /// The file `trimmed` describes, cut from `file`.
+    fn cut(file: &[u8], trimmed: &Trimmed) -> Vec<u8> {
+        let source = trimmed.source;
+        let mut cut = trimmed.header.clone();
+        cut.extend_from_slice(&file[source.offset as usize..][..source.length as usize]);
+        assert_eq!(cut.len() as u64, trimmed.len());
+        cut
+    }
+
+    /// Checks that the samples of the trimmed file are samples `first..` of
+    /// the original, from the new offsets.
+    fn assert_samples_from(file: &[u8], trimmed: &[u8], first: usize) {
+        let original = Movie::parse(file).unwrap();
+        let original = &original.tracks[0].samples[first..];
+        let movie = Movie::parse(trimmed).unwrap();
+        let samples = &movie.tracks[0].samples;
+        assert_eq!(samples.len(), original.len());
+        for (i, (sample, original)) in samples.iter().zip(original).enumerate() {
+            assert_eq!(sample.size, original.size);
+            assert_eq!(sample.decode_time, i as u64 * SAMPLE_DURATION as u64);
+            assert_eq!(sample.duration, original.duration);
+            assert_eq!(sample.sync, original.sync);
+            assert_eq!(
+                &trimmed[sample.offset as usize..][..sample.size as usize],
+                &file[original.offset as usize..][..original.size as usize],
+            );
+        }
+    }
+
+    #[test]
+    fn cuts_at_a_keyframe() {
+        let file = progressive(12, 4);
+        let movie = Movie::parse(&file).unwrap();
+        let trimmed = trim(&movie, None, 0.8).unwrap();
+        assert_eq!(trimmed.start, 0.8);
+        let cut = cut(&file, &trimmed);
+        assert_samples_from(&file, &cut, 8);
+
+        let parsed = Movie::parse(&cut).unwrap();
+        assert_eq!(parsed.duration, 400);
+        assert_eq!(parsed.tracks[0].duration, 400);
+        // The mdat holds just the samples.
+        let (offset, mdat) = find_top_level(&cut, b"mdat").unwrap().unwrap();
+        assert_eq!(offset + mdat.size, cut.len() as u64);
+        assert_eq!(parsed.tracks[0].samples[0].offset, offset + 8);
+    }
+
+    #[test]
+    fn cuts_at_the_keyframe_before_a_time_between_keyframes() {
+        let file = progressive(12, 4);
+        let movie = Movie::parse(&file).unwrap();
+        let trimmed = trim(&movie, None, 1.1).unwrap();
+        assert_eq!(trimmed.start, 0.8);
+        assert_samples_from(&file, &cut(&file, &trimmed), 8);
+
+        let trimmed = trim(&movie, None, 0.3).unwrap();
+        assert_eq!(trimmed.start, 0.0);
+        assert_samples_from(&file, &cut(&file, &trimmed), 0);
+    }
+
+    #[test]
+    fn keeps_the_file_type_and_composition_offsets() {
+        let file = progressive(8, 4);
+        let mut movie = Movie::parse(&file).unwrap();
+        for (sample, offset) in movie.tracks[0]
+            .samples
+            .iter_mut()
+            .zip([0, 200, -100, 0].into_iter().cycle())
+        {
+            sample.composition_offset = offset;
+        }
+        let (_, ftyp) = find_top_level(&file, b"ftyp").unwrap().unwrap();
+        let ftyp = &file[..ftyp.size as usize];
+        let trimmed = trim(&movie, Some(ftyp), 0.4).unwrap();
+        assert!(trimmed.header.starts_with(ftyp));
+
+        let parsed = Movie::parse(&cut(&file, &trimmed)).unwrap();
+        let offsets: Vec<i32> = parsed.tracks[0]
+            .samples
+            .iter()
+            .map(|sample| sample.composition_offset)
+            .collect();
+        assert_eq!(offsets, [0, 200, -100, 0]);
+    }
+
+    #[test]
+    fn rejects_starts_beyond_the_end() {
+        let file = progressive(8, 4);
+        let movie = Movie::parse(&file).unwrap();
+        assert!(matches!(trim(&movie, None, 0.8), Err(Error::Invalid(_))));
+        assert!(trim(&movie, None, 0.79).is_ok());
+    }
+
+    /// An `edts` with an `elst` of the given version and entries of a
+    /// duration, media time and rate.
+    fn edts(version: u8, entries: &[u64]) -> Vec<u8> {
+        let mut edts = Vec::new();
+        write_box(&mut edts, b"edts", |out| {
+            write_full_box(out, b"elst", version, 0, |out| {
+                out.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+                for duration in entries {
+                    if version == 1 {
+                        out.extend_from_slice(&duration.to_be_bytes());
+                        out.extend_from_slice(&512u64.to_be_bytes());
+                    } else {
+                        out.extend_from_slice(&(*duration as u32).to_be_bytes());
+                        out.extend_from_slice(&512u32.to_be_bytes());
+                    }
+                    out.extend_from_slice(&0x0001_0000u32.to_be_bytes());
+                }
+            });
+        });
+        edts
+    }
+
+    #[test]
+    fn shortens_single_edits_and_drops_edit_lists() {
+        assert_eq!(single_edit(&edts(0, &[5000]), 400), Some(edts(0, &[400])));
+        assert_eq!(single_edit(&edts(1, &[5000]), 400), Some(edts(1, &[400])));
+        assert_eq!(single_edit(&edts(0, &[100, 4900]), 400), None);
+    }
+
+    #[test]
+    fn rewrites_durations_of_headers() {
+        let mut mdhd = Vec::new();
+        write_full_box(&mut mdhd, b"mdhd", 0, 0, |out| {
+            for value in [0u32, 0, 1000, 5000, 0] {
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+        });
+        let rewritten = with_duration(&mdhd, 400);
+        assert_eq!(rewritten[24..28], 400u32.to_be_bytes());
+        assert_eq!(rewritten[..24], mdhd[..24]);
+        assert_eq!(rewritten[28..], mdhd[28..]);
+        // Durations that do not fit 32 bits are capped.
+        assert_eq!(
+            with_duration(&mdhd, 1 << 40)[24..28],
+            u32::MAX.to_be_bytes()
+        );
+    }
+}
@@ -0,0 +1,333 @@
+use futures::TryStreamExt;
+use mongodb::bson::{self, DateTime, Document, doc, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use std::{env, error::Error, path::PathBuf, str::FromStr, time::Duration};
+use tokio::io::AsyncWriteExt;
+
+/// Videos that failed to package are retried after this long, doubling with
+/// every further failure up to the maximum.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The part of a video record the packager reads.
+#[derive(Deserialize)]
+struct Video {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    #[serde(rename = "videoPath")]
+    video_path: String,
+    #[serde(default)]
+    renditions: Vec<Document>,
+    /// Set while packaging the video fails.
+    packaging: Option<PackagingFailure>,
+}
+
+/// The failed attempts to package a video, kept on its record until one
+/// succeeds.
+#[derive(Deserialize)]
+struct PackagingFailure {
+    failures: u32,
+    #[serde(rename = "failedAt")]
+    failed_at: DateTime,
+}
+
+impl PackagingFailure {
+    fn retry_at(&self) -> DateTime {
+        let doublings = self.failures.saturating_sub(1).min(16);
+        let delay = (MIN_RETRY_DELAY * 2u32.pow(doublings)).min(MAX_RETRY_DELAY);
+        self.failed_at.saturating_add_duration(delay)
+    }
+}
+
+/// A packaged rendition, in the format video-streaming reads them.
+#[derive(Serialize)]
+struct Rendition {
+    width: u32,
+    height: u32,
+    bitrate: u64,
+    codec: String,
+    path: String,
+    init: ByteRange,
+    segments: Vec<Segment>,
+}
+
+#[derive(Serialize)]
+struct ByteRange {
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Serialize)]
+struct Segment {
+    duration: f64,
+    offset: u64,
+    length: u64,
+}
+
+struct Packager {
+    videos: mongodb::Collection<Video>,
+    http: reqwest::Client,
+    video_storage_host: String,
+    video_storage_port: String,
+    /// Bearer token for video-storage uploads.
+    storage_token: Option<String>,
+    /// Target segment duration in seconds.
+    segment_duration: f64,
+}
+
+/// Remuxes uploaded MP4 files into fragmented MP4 for HLS. Videos given by id
+/// on the command line are packaged once; without arguments the packager
+/// keeps polling for videos that have unsegmented files.
+#[tokio::main]
+async fn main() {
+    let db_host = env::var("DBHOST").expect("DBHOST environment variable not set");
+    let db_name = env::var("DBNAME").expect("DBNAME environment variable not set");
+    let video_storage_host =
+        env::var("VIDEO_STORAGE_HOST").expect("VIDEO_STORAGE_HOST environment variable not set");
+    let video_storage_port =
+        env::var("VIDEO_STORAGE_PORT").expect("VIDEO_STORAGE_PORT environment variable not set");
+    let segment_duration = env::var("SEGMENT_DURATION_SECS")
+        .map(|secs| secs.parse().expect("SEGMENT_DURATION_SECS is not a number"))
+        .unwrap_or(6.0);
+    let poll_interval = env::var("PACKAGER_POLL_SECS")
+        .map(|secs| secs.parse().expect("PACKAGER_POLL_SECS is not a number"))
+        .unwrap_or(60);
+
+    let mut client_options = mongodb::options::ClientOptions::parse(db_host)
+        .await
+        .expect("Can not create connection options");
+    let server_api = mongodb::options::ServerApi::builder()
+        .version(mongodb::options::ServerApiVersion::V1)
+        .build();
+    client_options.server_api = Some(server_api);
+    let client = mongodb::Client::with_options(client_options).expect("Can not create clients");
+    let packager = Packager {
+        videos: client.database(&db_name).collection("videos"),
+        http: reqwest::Client::new(),
+        video_storage_host,
+        video_storage_port,
+        storage_token: env::var("STORAGE_TOKEN").ok(),
+        segment_duration,
+    };
+
+    let ids: Vec<ObjectId> = env::args()
+        .skip(1)
+        .map(|id| ObjectId::from_str(&id).expect("Arguments must be video ids"))
+        .collect();
+    if !ids.is_empty() {
+        for id in ids {
+            if !packager.package_or_record_failure(&id).await {
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    loop {
+        match packager.pending().await {
+            Ok(ids) => {
+                for id in ids {
+                    packager.package_or_record_failure(&id).await;
+                }
+            }
+            Err(e) => eprintln!("Error looking for videos to package: {e}"),
+        }
+        tokio::time::sleep(Duration::from_secs(poll_interval)).await;
+    }
+}
+
+impl Packager {
+    /// Videos without renditions or with a rendition that has no segments,
+    /// leaving out the ones that failed until it is time to retry them.
+    async fn pending(&self) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+        let filter = doc! {"$or": [
+            {"renditions": {"$exists": false}},
+            {"renditions": {"$size": 0}},
+            {"renditions": {"$elemMatch": {"segments": {"$exists": false}}}},
+        ]};
+        let videos: Vec<Video> = self.videos.find(filter).await?.try_collect().await?;
+        let now = DateTime::now();
+        Ok(videos
+            .into_iter()
+            .filter(|video| {
+                video
+                    .packaging
+                    .as_ref()
+                    .is_none_or(|failure| failure.retry_at() <= now)
+            })
+            .map(|video| video.id)
+            .collect())
+    }
+
+    /// Packages a video, clearing the failures of earlier attempts, or notes
+    /// on the video record that this attempt failed. Returns whether it
+    /// succeeded.
+    async fn package_or_record_failure(&self, id: &ObjectId) -> bool {
+        let (update, succeeded) = match self.package(id).await {
+            Ok(()) => (doc! {"$unset": {"packaging": ""}}, true),
+            Err(e) => {
+                eprintln!("Error packaging video {id}: {e}");
+                let update = doc! {
+                    "$inc": {"packaging.failures": 1},
+                    "$set": {
+                        "packaging.failedAt": DateTime::now(),
+                        "packaging.lastError": e.to_string(),
+                    },
+                };
+                (update, false)
+            }
+        };
+        if let Err(e) = self.videos.update_one(doc! {"_id": id}, update).await {
+            eprintln!("Error storing the packaging state of video {id}: {e}");
+        }
+        succeeded
+    }
+
+    /// Packages the file of a video without renditions into a new rendition,
+    /// or replaces the files of renditions without segments by packaged ones.
+    async fn package(&self, id: &ObjectId) -> Result<(), Box<dyn Error>> {
+        let video = self
+            .videos
+            .find_one(doc! {"_id": id})
+            .await?
+            .ok_or("video not found")?;
+        if video.renditions.is_empty() {
+            let rendition = self.package_file(&video.video_path).await?;
+            self.videos
+                .update_one(
+                    doc! {"_id": id, "renditions.0": {"$exists": false}},
+                    doc! {"$push": {"renditions": bson::to_bson(&rendition)?}},
+                )
+                .await?;
+            println!("Packaged video {id} into {}", rendition.path);
+            return Ok(());
+        }
+        for (index, existing) in video.renditions.iter().enumerate() {
+            if existing.contains_key("segments") {
+                continue;
+            }
+            let source = existing.get_str("path")?;
+            let rendition = self.package_file(source).await?;
+            // Only if the rendition has not been changed in the meantime.
+            let at = |field: &str| format!("renditions.{index}.{field}");
+            self.videos
+                .update_one(
+                    doc! {"_id": id, at("path"): source},
+                    doc! {"$set": {
+                        at("path"): &rendition.path,
+                        at("init"): bson::to_bson(&rendition.init)?,
+                        at("segments"): bson::to_bson(&rendition.segments)?,
+                    }},
+                )
+                .await?;
+            println!("Packaged {source} of video {id} into {}", rendition.path);
+        }
+        Ok(())
+    }
+
+    async fn package_file(&self, source: &str) -> Result<Rendition, Box<dyn Error>> {
+        let download = self.download(source).await?;
+        let file = tokio::fs::read(&download.0).await?;
+        drop(download);
+        let movie = mp4::Movie::parse(&file)?;
+        let video_track = movie.video_track().ok_or("the file has no video track")?;
+        let codec = movie
+            .tracks
+            .iter()
+            .filter(|track| matches!(track.kind, mp4::TrackKind::Video | mp4::TrackKind::Audio))
+            .map(|track| track.entry.codec.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        let bitrate = movie
+            .tracks
+            .iter()
+            .filter(|track| matches!(track.kind, mp4::TrackKind::Video | mp4::TrackKind::Audio))
+            .map(mp4::Track::bitrate)
+            .sum();
+        let width = video_track.entry.width as u32;
+        let height = video_track.entry.height as u32;
+        let fragmented = mp4::fragment(&movie, &file, self.segment_duration)?;
+
+        let path = packaged_path(source);
+        let mut upload = self
+            .http
+            .put(self.storage_url(&path))
+            .header("Content-Type", "video/mp4")
+            .body(fragmented.data);
+        if let Some(token) = &self.storage_token {
+            upload = upload.bearer_auth(token);
+        }
+        upload.send().await?.error_for_status()?;
+
+        Ok(Rendition {
+            width,
+            height,
+            bitrate,
+            codec,
+            path,
+            init: ByteRange {
+                offset: fragmented.init.offset,
+                length: fragmented.init.length,
+            },
+            segments: fragmented
+                .segments
+                .iter()
+                .map(|segment| Segment {
+                    duration: segment.duration,
+                    offset: segment.range.offset,
+                    length: segment.range.length,
+                })
+                .collect(),
+        })
+    }
+
+    /// Streams a file from video-storage to a temporary file. Parsing and
+    /// fragmenting need the whole file in memory, so it is read back in one
+    /// allocation of its final size instead of a buffer that grows with the
+    /// response.
+    async fn download(&self, source: &str) -> Result<TempFile, Box<dyn Error>> {
+        let mut response = self
+            .http
+            .get(self.storage_url(source))
+            .send()
+            .await?
+            .error_for_status()?;
+        let name = format!(
+            "packager-{}-{}",
+            std::process::id(),
+            source.replace('/', "_")
+        );
+        let path = env::temp_dir().join(name);
+        let mut file = tokio::fs::File::create(&path).await?;
+        let temp = TempFile(path);
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(temp)
+    }
+
+    fn storage_url(&self, path: &str) -> String {
+        format!(
+            "http://{}:{}/video?path={path}",
+            self.video_storage_host, self.video_storage_port
+        )
+    }
+}
+
+/// A file that is removed when it is dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            eprintln!("Error removing {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// `videos/sample.mp4` is packaged into `videos/sample.fmp4.mp4`.
+fn packaged_path(source: &str) -> String {
+    let stem = source.strip_suffix(".mp4").unwrap_or(source);
+    format!("{stem}.fmp4.mp4")
+}
@@ -0,0 +1,155 @@
+use axum::body::Bytes;
+use azure_core::http::headers::HeaderName;
+use azure_storage_blob::{
+    BlobClient, BlobContainerClient,
+    models::{
+        BlobClientDownloadOptions, BlobContainerClientListBlobFlatSegmentOptions,
+        BlockBlobClientUploadOptions,
+    },
+};
+use futures::TryStreamExt;
+use std::error::Error;
+
+/// Give up looking for `moov` or `mdat` after this many top-level boxes.
+const MAX_TOP_LEVEL_BOXES: usize = 32;
+
+/// Whether an upload is an MP4 file, by its content type or name.
+pub fn is_mp4(path: &str, content_type: &str) -> bool {
+    content_type == "video/mp4" || path.to_ascii_lowercase().ends_with(".mp4")
+}
+
+/// Moves the `moov` box of an uploaded MP4 to the front. Files that are
+/// faststart already or can not be rewritten are stored as they are.
+pub async fn prepare_upload(path: &str, body: Bytes) -> Bytes {
+    let file = body.clone();
+    match tokio::task::spawn_blocking(move || mp4::faststart(&file)).await {
+        Ok(Ok(Some(relocated))) => {
+            println!("Moved the moov box of {path} to the front");
+            Bytes::from(relocated)
+        }
+        Ok(Ok(None)) => body,
+        Ok(Err(e)) => {
+            println!("Storing {path} as is, can not move its moov box: {e}");
+            body
+        }
+        Err(e) => {
+            eprintln!("Error moving the moov box of {path}: {e}");
+            body
+        }
+    }
+}
+
+/// Rewrites all MP4 blobs in the container, or those whose name starts with
+/// `prefix`, that have their `moov` box after the media data. Blobs larger
+/// than `limit` bytes are not downloaded, as with uploads. Returns the number
+/// of blobs that could not be processed.
+pub async fn fix_container(
+    container: &BlobContainerClient,
+    prefix: Option<String>,
+    limit: usize,
+) -> Result<usize, Box<dyn Error>> {
+    let mut pages = container.list_blobs(Some(BlobContainerClientListBlobFlatSegmentOptions {
+        prefix,
+        ..Default::default()
+    }))?;
+    let (mut fixed, mut skipped, mut failed) = (0, 0, 0);
+    while let Some(page) = pages.try_next().await? {
+        let page = page.into_body().await?;
+        for item in page.segment.blob_items {
+            let Some(name) = item.name.and_then(|name| name.content) else {
+                continue;
+            };
+            if !is_mp4(&name, "") {
+                continue;
+            }
+            match fix_blob(&container.blob_client(name.clone()), limit).await {
+                Ok(true) => {
+                    println!("Moved the moov box of {name} to the front");
+                    fixed += 1;
+                }
+                Ok(false) => skipped += 1,
+                Err(e) => {
+                    eprintln!("Error processing {name}: {e}");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    println!("{fixed} blobs rewritten, {skipped} already faststart, {failed} failed");
+    Ok(failed)
+}
+
+/// Rewrites a blob unless it is faststart already. The blob is only replaced
+/// if it has not changed since it was downloaded, and only read if it has at
+/// most `limit` bytes.
+async fn fix_blob(blob_client: &BlobClient, limit: usize) -> Result<bool, Box<dyn Error>> {
+    if !moov_after_mdat(blob_client).await? {
+        return Ok(false);
+    }
+    let blob = blob_client.download(None).await?;
+    let etag = blob
+        .headers()
+        .get_optional_str(&HeaderName::from_static("etag"))
+        .map(str::to_string);
+    let content_type = blob
+        .headers()
+        .get_optional_str(&HeaderName::from_static("content-type"))
+        .unwrap_or("video/mp4")
+        .to_string();
+    let size = blob
+        .headers()
+        .get_optional_str(&HeaderName::from_static("content-length"))
+        .and_then(|size| size.parse::<u64>().ok())
+        .ok_or("the download has no Content-Length")?;
+    if size > limit as u64 {
+        return Err(format!("{size} bytes is more than the limit of {limit}").into());
+    }
+    let file = blob.into_raw_body().collect().await?;
+    let Some(relocated) = tokio::task::spawn_blocking(move || mp4::faststart(&file)).await?? else {
+        return Ok(false);
+    };
+    let content_length = relocated.len() as u64;
+    let options = BlockBlobClientUploadOptions {
+        blob_content_type: Some(content_type),
+        if_match: etag,
+        ..Default::default()
+    };
+    blob_client
+        .upload(
+            azure_core::http::Body::from(relocated).into(),
+            true,
+            content_length,
+            Some(options),
+        )
+        .await?;
+    Ok(true)
+}
+
+/// Walks the top-level box headers with range requests, so that files that
+/// are faststart already are not downloaded.
+async fn moov_after_mdat(blob_client: &BlobClient) -> Result<bool, Box<dyn Error>> {
+    let mut offset = 0;
+    for _ in 0..MAX_TOP_LEVEL_BOXES {
+        let header = blob_client
+            .download(Some(BlobClientDownloadOptions {
+                range: Some(format!("bytes={offset}-{}", offset + 15)),
+                ..Default::default()
+            }))
+            .await?
+            .into_raw_body()
+            .collect()
+            .await?;
+        let header = mp4::BoxHeader::parse(&header)?;
+        if header.is(b"moov") {
+            return Ok(false);
+        }
+        if header.is(b"mdat") {
+            return Ok(true);
+        }
+        if header.size == u64::MAX {
+            break;
+        }
+        offset += header.size;
+    }
+    Ok(false)
+}
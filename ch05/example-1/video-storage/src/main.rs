@@ -1,20 +1,31 @@
+use auth::{AuthLayer, require_roles};
 use axum::{
     Router,
-    extract::{Query, State},
+    body::Bytes,
+    extract::{DefaultBodyLimit, Query, State},
+    http::HeaderMap,
     response::IntoResponse,
-    routing::get,
+    routing::{get, put},
 };
 use azure_core::http::StatusCode;
 use azure_core::http::headers::HeaderName;
 use azure_identity::ClientSecretCredential;
 use azure_storage_blob::{
     BlobContainerClient, BlobContainerClientOptions,
-    models::{BlobClientDownloadOptions, BlobClientGetPropertiesOptions},
+    models::{
+        BlobClientDownloadOptions, BlobClientGetPropertiesOptions, BlockBlobClientUploadOptions,
+    },
 };
 use serde::Deserialize;
 use std::{env, error::Error};
 use std::{result::Result, sync::Arc};
 
+mod faststart;
+
+/// Uploads are buffered in memory, twice while their moov box is moved, so
+/// the limit is what bounds the memory an upload takes.
+const DEFAULT_UPLOAD_LIMIT_BYTES: usize = 512 * 1024 * 1024;
+
 #[derive(Deserialize)]
 struct VideoName {
     path: String,
@@ -23,20 +34,24 @@ struct VideoName {
 #[derive(Clone)]
 struct AppState {
     blob_server: Arc<BlobContainerClient>,
+    /// Largest accepted upload in bytes.
+    upload_limit: usize,
 }
 
 impl AppState {
-    fn new(azure: BlobContainerClient) -> Self {
+    fn new(azure: BlobContainerClient, upload_limit: usize) -> Self {
         Self {
             blob_server: Arc::new(azure),
+            upload_limit,
         }
     }
 }
 
+/// Serves the video container. `video-storage faststart [prefix]` instead
+/// moves the `moov` box of existing MP4 blobs to the front and exits.
 #[tokio::main]
 async fn main() {
     // Retrieve environment variables
-    let port = env::var("PORT").expect("PORT environment variable not set");
     let storage_account_name =
         env::var("STORAGE_ACCOUNT_NAME").expect("STORAGE_ACCOUNT_NAME variable not set");
     // Collect the necessary data from the environment to authorize access to blob storage.
@@ -54,7 +69,27 @@ async fn main() {
         create_blob_service(storage_account_name, tenant_id, client_id, client_secret)
             .expect("Can not create BLOB service");
 
-    let app_state = AppState::new(azure_blob_service);
+    let upload_limit = env::var("UPLOAD_LIMIT_BYTES")
+        .map(|limit| limit.parse().expect("UPLOAD_LIMIT_BYTES is not a number"))
+        .unwrap_or(DEFAULT_UPLOAD_LIMIT_BYTES);
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("faststart") {
+        match faststart::fix_container(&azure_blob_service, args.get(1).cloned(), upload_limit)
+            .await
+        {
+            Ok(0) => return,
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Error listing blobs: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let port = env::var("PORT").expect("PORT environment variable not set");
+
+    let app_state = AppState::new(azure_blob_service, upload_limit);
 
     let app = app(app_state);
 
@@ -69,8 +104,17 @@ async fn main() {
 }
 
 fn app(state: AppState) -> Router {
+    let upload_limit = state.upload_limit;
     Router::new()
-        .route("/video", get(get_video))
+        .route(
+            "/video",
+            get(get_video).merge(
+                put(put_video)
+                    .route_layer(require_roles(["uploader", "admin"]))
+                    .layer(DefaultBodyLimit::max(upload_limit)),
+            ),
+        )
+        .layer(AuthLayer::from_env())
         .with_state(state)
 }
 fn create_blob_service(
@@ -94,6 +138,7 @@ fn create_blob_service(
 async fn get_video(
     State(state): State<AppState>,
     Query(vid_name): Query<VideoName>,
+    headers: axum::http::HeaderMap,
 ) -> impl IntoResponse {
     let video_path = vid_name.path;
     let container_server = state.blob_server.clone();
@@ -112,29 +157,110 @@ async fn get_video(
         }
         _ => panic!("Request for properties failed!"),
     };
+    // Headers are lower-cased
+    let content_type = blob_properties
+        .get_str(&HeaderName::from_static("content-type"))
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let blob_length = blob_properties
+        .get_as::<u64, _>(&HeaderName::from_static("content-length"))
+        .unwrap_or(0);
 
+    // Byte ranges are passed on to blob storage, which answers them with 206.
+    let range = headers
+        .get(axum::http::header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
     println!("Retrieving blob");
-    let blob = blob_client
-        .download(Some(BlobClientDownloadOptions::default()))
+    let blob = match blob_client
+        .download(Some(BlobClientDownloadOptions {
+            range,
+            ..Default::default()
+        }))
         .await
-        .unwrap();
-    let stream = match blob.status() {
-        StatusCode::Ok => blob.into_raw_body(),
+    {
+        Ok(blob) => blob,
+        Err(e) if e.http_status() == Some(StatusCode::RequestedRangeNotSatisfiable) => {
+            return axum::response::Response::builder()
+                .status(axum::http::StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("Content-Range", format!("bytes */{blob_length}"))
+                .body(axum::body::Body::empty())
+                .unwrap();
+        }
+        Err(e) => panic!("Request for blob failed: {e}"),
+    };
+    let status = match blob.status() {
+        StatusCode::Ok => axum::http::StatusCode::OK,
+        StatusCode::PartialContent => axum::http::StatusCode::PARTIAL_CONTENT,
         _ => panic!("Request for blob failed!"),
     };
 
     println!("Extracting headers");
-    // Headers are lower-cased
-    let content_type = blob_properties
-        .get_str(&HeaderName::from_static("content-type"))
-        .unwrap_or("application/octet-stream");
-    let content_length = blob_properties
-        .get_as::<u32, _>(&HeaderName::from_static("content-length"))
-        .unwrap_or(0);
-    axum::response::Response::builder()
-        .status(axum::http::StatusCode::OK)
+    let content_length = blob
+        .headers()
+        .get_as::<u64, _>(&HeaderName::from_static("content-length"))
+        .unwrap_or(blob_length);
+    let content_range = blob
+        .headers()
+        .get_optional_str(&HeaderName::from_static("content-range"))
+        .map(str::to_string);
+    let stream = blob.into_raw_body();
+    let mut response = axum::response::Response::builder()
+        .status(status)
         .header("Content-Type", content_type)
         .header("Content-Length", content_length)
+        .header("Accept-Ranges", "bytes");
+    if let Some(content_range) = content_range {
+        response = response.header("Content-Range", content_range);
+    }
+    response
         .body(axum::body::Body::from_stream(stream))
         .unwrap()
 }
+
+/// Stores the request body under `path`, replacing an existing blob. MP4
+/// files are stored with their `moov` box first. The body is buffered in
+/// memory, up to the upload limit.
+async fn put_video(
+    State(state): State<AppState>,
+    Query(vid_name): Query<VideoName>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let blob_client = state.blob_server.blob_client(vid_name.path.clone());
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let body = if faststart::is_mp4(&vid_name.path, &content_type) {
+        faststart::prepare_upload(&vid_name.path, body).await
+    } else {
+        body
+    };
+    let content_length = body.len() as u64;
+    let options = BlockBlobClientUploadOptions {
+        blob_content_type: Some(content_type),
+        ..Default::default()
+    };
+    println!("Uploading {} ({content_length} bytes)", vid_name.path);
+    match blob_client
+        .upload(
+            azure_core::http::Body::from(body).into(),
+            true,
+            content_length,
+            Some(options),
+        )
+        .await
+    {
+        Ok(_) => axum::http::StatusCode::CREATED.into_response(),
+        Err(e) => {
+            eprintln!("Error uploading {}: {e}", vid_name.path);
+            (
+                axum::http::StatusCode::BAD_GATEWAY,
+                "Upload to blob storage failed",
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use auth::Principal;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use events::{VideoDeleted, VideoUploaded};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::chapters::Chapter;
+use crate::probe::{self, MediaInfo};
+use crate::rendition::Rendition;
+use crate::repository::RepositoryError;
+use crate::slug::{self, VideoRef};
+use crate::tracks::TextTrack;
+use crate::video::{Video, VideoInput, Visibility};
+use crate::{AppState, publish_event};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    offset: Option<u64>,
+    limit: Option<i64>,
+}
+
+/// JSON representation of a video record in the catalog API.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CatalogVideo {
+    id: String,
+    video_path: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    renditions: Vec<Rendition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slug: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    previous_slugs: Vec<String>,
+    title: String,
+    description: String,
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
+    visibility: Visibility,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    grants: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media: Option<MediaInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    text_tracks: Vec<TextTrack>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    chapters: Vec<Chapter>,
+}
+
+impl From<Video> for CatalogVideo {
+    fn from(video: Video) -> Self {
+        Self {
+            id: video.id.to_hex(),
+            video_path: video.video_path,
+            renditions: video.renditions,
+            slug: video.slug,
+            previous_slugs: video.previous_slugs,
+            title: video.title,
+            description: video.description,
+            tags: video.tags,
+            duration: video.duration,
+            visibility: video.visibility,
+            owner: video.owner,
+            grants: video.grants,
+            media: video.media,
+            text_tracks: video.text_tracks,
+            chapters: video.chapters,
+        }
+    }
+}
+
+/// Lists public videos, or all of them for admins.
+pub async fn list_videos(
+    State(app_state): State<AppState>,
+    caller: Option<Principal>,
+    Query(params): Query<ListParams>,
+) -> Response {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let public_only = !caller.is_some_and(|caller| caller.has_role("admin"));
+    match app_state
+        .videos
+        .list(params.offset.unwrap_or(0), limit, public_only)
+        .await
+    {
+        Ok(videos) => Json(
+            videos
+                .into_iter()
+                .map(CatalogVideo::from)
+                .collect::<Vec<_>>(),
+        )
+        .into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn create_video(
+    State(app_state): State<AppState>,
+    caller: Option<Principal>,
+    Json(mut input): Json<VideoInput>,
+) -> Response {
+    if let Some(response) = validate(&input) {
+        return response;
+    }
+    if input.owner.is_none() {
+        input.owner = caller.map(|caller| caller.subject);
+    }
+    match app_state.videos.create(input).await {
+        Ok(video) => {
+            probe::spawn(app_state.clone(), video.id, video.video_path.clone());
+            publish_event(
+                &app_state,
+                VideoUploaded {
+                    video_id: video.id.to_hex(),
+                    video_path: video.video_path.clone(),
+                    title: video.title.clone(),
+                    owner: video.owner.clone(),
+                },
+            );
+            (StatusCode::CREATED, Json(CatalogVideo::from(video))).into_response()
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn get_catalog_video(
+    State(app_state): State<AppState>,
+    caller: Option<Principal>,
+    Path(id): Path<String>,
+) -> Response {
+    let video_record = match VideoRef::parse(&id) {
+        Some(VideoRef::Id(id)) => app_state.videos.find_by_id(&id).await,
+        Some(VideoRef::Slug(slug)) => app_state.videos.find_by_slug(&slug).await,
+        None => return invalid_id(),
+    };
+    let viewer = caller.as_ref().map(|caller| caller.subject.as_str());
+    match video_record {
+        Ok(Some(video)) if video.is_accessible_by(viewer) => {
+            Json(CatalogVideo::from(video)).into_response()
+        }
+        Ok(_) => (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn update_video(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    Json(input): Json<VideoInput>,
+) -> Response {
+    let Some(id) = parse_id(&id) else {
+        return invalid_id();
+    };
+    if let Some(response) = validate(&input) {
+        return response;
+    }
+    match app_state.videos.update(&id, input).await {
+        Ok(Some(video)) => {
+            if video.media.is_none() {
+                probe::spawn(app_state.clone(), video.id, video.video_path.clone());
+            }
+            Json(CatalogVideo::from(video)).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn delete_video(State(app_state): State<AppState>, Path(id): Path<String>) -> Response {
+    let Some(id) = parse_id(&id) else {
+        return invalid_id();
+    };
+    match app_state.videos.delete(&id).await {
+        Ok(true) => {
+            publish_event(
+                &app_state,
+                VideoDeleted {
+                    video_id: id.to_hex(),
+                },
+            );
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+fn parse_id(id: &str) -> Option<ObjectId> {
+    ObjectId::from_str(id).ok()
+}
+
+fn validate(input: &VideoInput) -> Option<Response> {
+    if let Some(requested) = &input.slug
+        && !slug::is_valid(requested)
+    {
+        return Some(
+            (
+                StatusCode::BAD_REQUEST,
+                "slug may only contain lower case letters, digits and single dashes",
+            )
+                .into_response(),
+        );
+    }
+    if input.renditions.iter().flatten().any(|rendition| {
+        rendition.width == 0
+            || rendition.height == 0
+            || rendition.bitrate == 0
+            || rendition.path.is_empty()
+            || rendition
+                .segments
+                .iter()
+                .any(|segment| segment.duration <= 0.0 || segment.length == 0)
+    }) {
+        return Some(
+            (
+                StatusCode::BAD_REQUEST,
+                "renditions need a width, height, bitrate and path, and segments a duration and length",
+            )
+                .into_response(),
+        );
+    }
+    None
+}
+
+fn invalid_id() -> Response {
+    (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response()
+}
+
+fn internal_error(e: RepositoryError) -> Response {
+    if let RepositoryError::Conflict(message) = e {
+        return (StatusCode::CONFLICT, message).into_response();
+    }
+    eprintln!("Error accessing the video catalog: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
@@ -0,0 +1,386 @@
+use auth::Principal;
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, net::SocketAddr, str::FromStr};
+
+use crate::slug::VideoRef;
+use crate::video::Video;
+use crate::{AppState, PlaybackRequest, find_playable_video};
+
+const MAX_CHAPTERS: usize = 500;
+const MAX_TITLE_LENGTH: usize = 200;
+/// End of the last cue of the WebVTT track when the duration is unknown.
+const OPEN_END: f64 = 359_999.999;
+
+/// A navigable section of a video, running until the next chapter starts.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    /// In seconds from the start of the video.
+    pub start: f64,
+    pub title: String,
+    /// Position of the frame shown as the chapter's thumbnail, within the
+    /// chapter. Players use the start when there is none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_time: Option<f64>,
+}
+
+#[derive(Deserialize)]
+pub struct ChapterTrackParams {
+    /// Playback token, required when signed playback is enabled.
+    token: Option<String>,
+}
+
+/// `GET /videos/{id}/chapters`: the chapters of a video in order.
+pub async fn get_chapters(
+    State(app_state): State<AppState>,
+    caller: Option<Principal>,
+    Path(id): Path<String>,
+) -> Response {
+    let video_record = match VideoRef::parse(&id) {
+        Some(VideoRef::Id(id)) => app_state.videos.find_by_id(&id).await,
+        Some(VideoRef::Slug(slug)) => app_state.videos.find_by_slug(&slug).await,
+        None => return (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response(),
+    };
+    let viewer = caller.as_ref().map(|caller| caller.subject.as_str());
+    match video_record {
+        Ok(Some(video)) if video.is_accessible_by(viewer) => Json(video.chapters).into_response(),
+        Ok(_) => (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// `PUT /videos/{id}/chapters`: replaces the chapters of a video. An empty
+/// list removes them.
+pub async fn put_chapters(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    Json(chapters): Json<Vec<Chapter>>,
+) -> Response {
+    let Ok(id) = ObjectId::from_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response();
+    };
+    let video = match app_state.videos.find_by_id(&id).await {
+        Ok(Some(video)) => video,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => return internal_error(e),
+    };
+    if let Err(message) = validate(&chapters, duration(&video)) {
+        return (StatusCode::BAD_REQUEST, message).into_response();
+    }
+    match app_state.videos.set_chapters(&id, chapters.clone()).await {
+        Ok(true) => Json(chapters).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// `GET /video/{id}/chapters.vtt`: the chapters as a WebVTT track of kind
+/// `chapters`, with the same access checks as the video.
+pub async fn get_chapters_track(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<ChapterTrackParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
+) -> Response {
+    let request = PlaybackRequest {
+        id: &id,
+        token: params.token.as_deref(),
+        peer,
+        headers: &headers,
+        caller: caller.as_ref(),
+    };
+    let video = match find_playable_video(&app_state, request).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    if video.chapters.is_empty() {
+        return (StatusCode::NOT_FOUND, "Video has no chapters").into_response();
+    }
+    let end = duration(&video).unwrap_or(OPEN_END);
+    (
+        [(header::CONTENT_TYPE, "text/vtt; charset=utf-8")],
+        webvtt(&video.chapters, end),
+    )
+        .into_response()
+}
+
+/// The probed duration of a video, or the one it was created with.
+fn duration(video: &Video) -> Option<f64> {
+    video
+        .media
+        .as_ref()
+        .and_then(|media| media.duration)
+        .or(video.duration)
+}
+
+/// Chapters must be in order of their start, within the video, and have a
+/// title that fits into a single WebVTT cue.
+fn validate(chapters: &[Chapter], duration: Option<f64>) -> Result<(), String> {
+    if chapters.len() > MAX_CHAPTERS {
+        return Err(format!("at most {MAX_CHAPTERS} chapters are allowed"));
+    }
+    for (index, chapter) in chapters.iter().enumerate() {
+        let number = index + 1;
+        if !chapter.start.is_finite() || chapter.start < 0.0 {
+            return Err(format!(
+                "chapter {number}: start must be a number of seconds"
+            ));
+        }
+        if let Some(duration) = duration
+            && chapter.start >= duration
+        {
+            return Err(format!(
+                "chapter {number}: starts after the end of the video at {duration:.3}s"
+            ));
+        }
+        let end = chapters
+            .get(index + 1)
+            .map(|next| next.start)
+            .or(duration)
+            .unwrap_or(f64::INFINITY);
+        if end <= chapter.start {
+            return Err(format!(
+                "chapter {number}: chapters must be ordered by their start"
+            ));
+        }
+        if chapter
+            .thumbnail_time
+            .is_some_and(|time| !(chapter.start..end).contains(&time))
+        {
+            return Err(format!(
+                "chapter {number}: thumbnailTime must lie within the chapter"
+            ));
+        }
+        let title = chapter.title.trim();
+        if title.is_empty() || title.chars().count() > MAX_TITLE_LENGTH {
+            return Err(format!(
+                "chapter {number}: title must have 1 to {MAX_TITLE_LENGTH} characters"
+            ));
+        }
+        if title.contains(['\n', '\r']) || title.contains("-->") {
+            return Err(format!(
+                "chapter {number}: title must be a single line without -->"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Tidies chapters read from a file so that they pass [`validate`]: titles
+/// on one line, in order of their start and within the video.
+pub fn from_file(chapters: Vec<mp4::Chapter>, duration: Option<f64>) -> Vec<Chapter> {
+    let mut imported: Vec<Chapter> = Vec::new();
+    for chapter in chapters {
+        let start = chapter.start.max(0.0);
+        let title: String = chapter
+            .title
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace("-->", "->")
+            .chars()
+            .take(MAX_TITLE_LENGTH)
+            .collect();
+        let in_order = imported
+            .last()
+            .is_none_or(|previous| previous.start < start);
+        let in_video = duration.is_none_or(|duration| start < duration);
+        if !title.is_empty() && start.is_finite() && in_order && in_video {
+            imported.push(Chapter {
+                start,
+                title,
+                thumbnail_time: None,
+            });
+        }
+    }
+    imported.truncate(MAX_CHAPTERS);
+    imported
+}
+
+fn webvtt(chapters: &[Chapter], end: f64) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for (index, chapter) in chapters.iter().enumerate() {
+        let until = chapters.get(index + 1).map_or(end, |next| next.start);
+        write!(
+            vtt,
+            "\n{}\n{} --> {}\n{}\n",
+            index + 1,
+            timestamp(chapter.start),
+            timestamp(until.max(chapter.start)),
+            escape(chapter.title.trim())
+        )
+        .unwrap();
+    }
+    vtt
+}
+
+/// `hh:mm:ss.ttt`
+fn timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn internal_error(e: impl std::fmt::Display) -> Response {
+    eprintln!("Error accessing the video catalog: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chapter(start: f64, title: &str) -> Chapter {
+        Chapter {
+            start,
+            title: title.to_string(),
+            thumbnail_time: None,
+        }
+    }
+
+    fn embedded(start: f64, title: &str) -> mp4::Chapter {
+        mp4::Chapter {
+            start,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn accepts_ordered_chapters_within_the_video() {
+        let mut chapters = vec![chapter(0.0, "Intro"), chapter(30.0, "Main part")];
+        chapters[1].thumbnail_time = Some(45.0);
+        assert_eq!(validate(&chapters, Some(60.0)), Ok(()));
+        assert_eq!(validate(&chapters, None), Ok(()));
+        assert_eq!(validate(&[], Some(60.0)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_chapters_out_of_order_or_outside_the_video() {
+        let message = |chapters: &[Chapter], duration| validate(chapters, duration).unwrap_err();
+        assert_eq!(
+            message(&[chapter(-1.0, "Intro")], None),
+            "chapter 1: start must be a number of seconds"
+        );
+        assert_eq!(
+            message(&[chapter(f64::NAN, "Intro")], None),
+            "chapter 1: start must be a number of seconds"
+        );
+        assert_eq!(
+            message(
+                &[chapter(0.0, "Intro"), chapter(60.0, "Credits")],
+                Some(60.0)
+            ),
+            "chapter 2: starts after the end of the video at 60.000s"
+        );
+        assert_eq!(
+            message(&[chapter(10.0, "Intro"), chapter(10.0, "Again")], None),
+            "chapter 1: chapters must be ordered by their start"
+        );
+        let mut thumbnail = chapter(0.0, "Intro");
+        thumbnail.thumbnail_time = Some(30.0);
+        assert_eq!(
+            message(&[thumbnail, chapter(30.0, "Main part")], None),
+            "chapter 1: thumbnailTime must lie within the chapter"
+        );
+        let too_many = vec![chapter(0.0, "Intro"); MAX_CHAPTERS + 1];
+        assert_eq!(
+            message(&too_many, None),
+            format!("at most {MAX_CHAPTERS} chapters are allowed")
+        );
+    }
+
+    #[test]
+    fn rejects_titles_that_do_not_fit_a_cue() {
+        let message = |title: &str| validate(&[chapter(0.0, title)], None).unwrap_err();
+        assert_eq!(
+            message("  "),
+            format!("chapter 1: title must have 1 to {MAX_TITLE_LENGTH} characters")
+        );
+        assert_eq!(
+            message(&"é".repeat(MAX_TITLE_LENGTH + 1)),
+            format!("chapter 1: title must have 1 to {MAX_TITLE_LENGTH} characters")
+        );
+        assert!(validate(&[chapter(0.0, &"é".repeat(MAX_TITLE_LENGTH))], None).is_ok());
+        for title in ["Two\nlines", "Two\rlines", "A --> B"] {
+            assert_eq!(
+                message(title),
+                "chapter 1: title must be a single line without -->"
+            );
+        }
+    }
+
+    #[test]
+    fn tidies_chapters_read_from_a_file() {
+        let chapters = from_file(
+            vec![
+                embedded(-0.5, "  Intro\r\n  part "),
+                embedded(10.0, "A --> B"),
+                // Out of order, without a title, and after the end.
+                embedded(5.0, "Back"),
+                embedded(20.0, " "),
+                embedded(f64::INFINITY, "Never"),
+                embedded(60.0, "Credits"),
+            ],
+            Some(60.0),
+        );
+        assert_eq!(
+            chapters,
+            [chapter(0.0, "Intro part"), chapter(10.0, "A -> B")]
+        );
+        assert_eq!(validate(&chapters, Some(60.0)), Ok(()));
+    }
+
+    #[test]
+    fn shortens_long_titles_and_many_chapters_from_a_file() {
+        let long = "x".repeat(MAX_TITLE_LENGTH + 10);
+        let chapters = from_file(vec![embedded(0.0, &long)], None);
+        assert_eq!(chapters[0].title.len(), MAX_TITLE_LENGTH);
+
+        let many = (0..MAX_CHAPTERS + 5)
+            .map(|index| embedded(index as f64, "Part"))
+            .collect();
+        assert_eq!(from_file(many, None).len(), MAX_CHAPTERS);
+    }
+
+    #[test]
+    fn writes_chapters_as_webvtt_cues() {
+        let chapters = [chapter(0.0, " Tom & Jerry <3 "), chapter(61.5, "Credits")];
+        assert_eq!(
+            webvtt(&chapters, 3725.25),
+            "WEBVTT\n\n\
+             1\n00:00:00.000 --> 00:01:01.500\nTom &amp; Jerry &lt;3\n\n\
+             2\n00:01:01.500 --> 01:02:05.250\nCredits\n"
+        );
+        // An end before the last start makes an empty cue, not a broken one.
+        assert!(webvtt(&chapters, 10.0).ends_with("00:01:01.500 --> 00:01:01.500\nCredits\n"));
+        assert!(webvtt(&chapters[..1], OPEN_END).contains("00:00:00.000 --> 99:59:59.999\n"));
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(0.0), "00:00:00.000");
+        assert_eq!(timestamp(0.0005), "00:00:00.001");
+        assert_eq!(timestamp(59.9999), "00:01:00.000");
+        assert_eq!(timestamp(3600.0 * 100.0 + 1.25), "100:00:01.250");
+    }
+}
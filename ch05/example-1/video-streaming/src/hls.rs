@@ -0,0 +1,450 @@
+use auth::Principal;
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+use crate::rendition::{ByteRange, Rendition};
+use crate::tracks::{TextTrack, TextTrackKind};
+use crate::video::Video;
+use crate::{AppState, PlaybackRequest, find_playable_video, forward_to_storage, record_view};
+
+const CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+/// Version 7 covers fragmented MP4 segments with `EXT-X-MAP` byte ranges.
+const VERSION: u8 = 7;
+const SUBTITLES_GROUP: &str = "subs";
+const CAPTIONS_CHARACTERISTICS: &str =
+    "public.accessibility.transcribes-spoken-dialog,public.accessibility.describes-music-and-sound";
+
+#[derive(Deserialize)]
+pub struct HlsParams {
+    /// Playback token, passed on to every URI of the playlists.
+    token: Option<String>,
+}
+
+/// `GET /hls/{id}/master.m3u8`: one variant stream per segmented rendition,
+/// with the subtitles and captions of the video as alternative renditions.
+pub async fn master_playlist(
+    State(app_state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<HlsParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
+) -> Response {
+    let request = PlaybackRequest {
+        id: &id,
+        token: params.token.as_deref(),
+        peer,
+        headers: &headers,
+        caller: caller.as_ref(),
+    };
+    let video = match find_playable_video(&app_state, request).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    let Some(playlist) = master(&video, params.token.as_deref()) else {
+        return (StatusCode::NOT_FOUND, "Video has no HLS renditions").into_response();
+    };
+    // Players fetch the master playlist once per view, segments many times.
+    record_view(&app_state, &video, request).await;
+    playlist_response(playlist)
+}
+
+/// `GET /hls/{id}/{rendition}/index.m3u8`: the segments of a rendition as byte
+/// ranges of its file.
+pub async fn media_playlist(
+    State(app_state): State<AppState>,
+    Path((id, index)): Path<(String, usize)>,
+    Query(params): Query<HlsParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
+) -> Response {
+    let video = match find_video(&app_state, &id, &params, peer, &headers, caller).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    let Some(rendition) = segmented_rendition(&video, index) else {
+        return (StatusCode::NOT_FOUND, "Rendition not found").into_response();
+    };
+    let Some(init) = rendition.init else {
+        return (StatusCode::NOT_FOUND, "Rendition not found").into_response();
+    };
+
+    playlist_response(media_segments(rendition, init, params.token.as_deref()))
+}
+
+/// `GET /hls/{id}/tracks/{track}/index.m3u8`: a text track as a single WebVTT
+/// segment spanning the whole video.
+pub async fn subtitles_playlist(
+    State(app_state): State<AppState>,
+    Path((id, index)): Path<(String, usize)>,
+    Query(params): Query<HlsParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
+) -> Response {
+    let video = match find_video(&app_state, &id, &params, peer, &headers, caller).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    let Some((_, track)) = hls_text_tracks(&video).find(|(track, _)| *track == index) else {
+        return (StatusCode::NOT_FOUND, "Text track not found").into_response();
+    };
+    playlist_response(text_track(&video, track, params.token.as_deref()))
+}
+
+/// `GET /hls/{id}/{rendition}/media.mp4`: the file of a rendition, fetched by
+/// players with the byte ranges of the media playlist.
+pub async fn media(
+    State(app_state): State<AppState>,
+    Path((id, index)): Path<(String, usize)>,
+    Query(params): Query<HlsParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
+) -> Response {
+    let video = match find_video(&app_state, &id, &params, peer, &headers, caller).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    match segmented_rendition(&video, index) {
+        Some(rendition) => forward_to_storage(&app_state, &rendition.path, &headers).await,
+        None => (StatusCode::NOT_FOUND, "Rendition not found").into_response(),
+    }
+}
+
+async fn find_video(
+    app_state: &AppState,
+    id: &str,
+    params: &HlsParams,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    caller: Option<Principal>,
+) -> Result<Video, Response> {
+    let request = PlaybackRequest {
+        id,
+        token: params.token.as_deref(),
+        peer,
+        headers,
+        caller: caller.as_ref(),
+    };
+    find_playable_video(app_state, request).await
+}
+
+/// The master playlist of `video`, or `None` if it has no segmented
+/// renditions. `token` is passed on to the playlists it refers to.
+fn master(video: &Video, token: Option<&str>) -> Option<String> {
+    let mut variants: Vec<(usize, &Rendition)> = video
+        .renditions
+        .iter()
+        .enumerate()
+        .filter(|(_, rendition)| rendition.is_segmented())
+        .collect();
+    if variants.is_empty() {
+        return None;
+    }
+    variants.sort_by_key(|(_, rendition)| rendition.bitrate);
+
+    let mut playlist = format!("#EXTM3U\n#EXT-X-VERSION:{VERSION}\n#EXT-X-INDEPENDENT-SEGMENTS\n");
+    let mut subtitles = String::new();
+    for (index, track) in hls_text_tracks(video) {
+        write!(
+            playlist,
+            "#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"{SUBTITLES_GROUP}\",NAME=\"{}\",\
+             LANGUAGE=\"{}\",DEFAULT=NO,AUTOSELECT=YES,FORCED=NO",
+            // Quoted strings of playlists can not escape quotes.
+            track.label.replace('"', "'"),
+            track.language
+        )
+        .unwrap();
+        if track.kind == TextTrackKind::Captions {
+            write!(playlist, ",CHARACTERISTICS=\"{CAPTIONS_CHARACTERISTICS}\"").unwrap();
+        }
+        writeln!(
+            playlist,
+            ",URI=\"tracks/{index}/index.m3u8{}\"",
+            token_query(token)
+        )
+        .unwrap();
+        subtitles = format!(",SUBTITLES=\"{SUBTITLES_GROUP}\"");
+    }
+    for (index, rendition) in variants {
+        writeln!(
+            playlist,
+            "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={},RESOLUTION={}x{},CODECS=\"{}\"{subtitles}",
+            rendition.peak_bitrate(),
+            rendition.bitrate,
+            rendition.width,
+            rendition.height,
+            rendition.codec
+        )
+        .unwrap();
+        writeln!(playlist, "{index}/index.m3u8{}", token_query(token)).unwrap();
+    }
+    Some(playlist)
+}
+
+/// The media playlist of a rendition with its `init` section.
+fn media_segments(rendition: &Rendition, init: ByteRange, token: Option<&str>) -> String {
+    let uri = format!("media.mp4{}", token_query(token));
+    let target_duration = rendition
+        .segments
+        .iter()
+        .map(|segment| segment.duration.round() as u64)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut playlist = format!(
+        "#EXTM3U\n#EXT-X-VERSION:{VERSION}\n#EXT-X-TARGETDURATION:{target_duration}\n\
+         #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+         #EXT-X-MAP:URI=\"{uri}\",BYTERANGE=\"{}@{}\"\n",
+        init.length, init.offset
+    );
+    for segment in &rendition.segments {
+        writeln!(
+            playlist,
+            "#EXTINF:{:.3},\n#EXT-X-BYTERANGE:{}@{}\n{uri}",
+            segment.duration, segment.length, segment.offset
+        )
+        .unwrap();
+    }
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// The playlist of a text track of `video`.
+fn text_track(video: &Video, track: &TextTrack, token: Option<&str>) -> String {
+    // The duration of the video, or else of its first segmented rendition.
+    let duration = video.duration.unwrap_or_else(|| {
+        video
+            .renditions
+            .iter()
+            .find(|rendition| rendition.is_segmented())
+            .map_or(0.0, |rendition| {
+                rendition
+                    .segments
+                    .iter()
+                    .map(|segment| segment.duration)
+                    .sum()
+            })
+    });
+    let target_duration = (duration.ceil() as u64).max(1);
+    let token = token.map_or(String::new(), |token| format!("&token={token}"));
+    format!(
+        "#EXTM3U\n#EXT-X-VERSION:{VERSION}\n#EXT-X-TARGETDURATION:{target_duration}\n\
+         #EXT-X-MEDIA-SEQUENCE:0\n#EXT-X-PLAYLIST-TYPE:VOD\n\
+         #EXTINF:{duration:.3},\n/video/{}/tracks/{}.vtt?kind={}{token}\n#EXT-X-ENDLIST\n",
+        video.id.to_hex(),
+        track.language,
+        track.kind.as_str()
+    )
+}
+
+fn segmented_rendition(video: &Video, index: usize) -> Option<&Rendition> {
+    video
+        .renditions
+        .get(index)
+        .filter(|rendition| rendition.is_segmented())
+}
+
+/// The text tracks players can show, by their index in the video record.
+/// Descriptions are meant for screen readers and left out.
+fn hls_text_tracks(video: &Video) -> impl Iterator<Item = (usize, &TextTrack)> {
+    video
+        .text_tracks
+        .iter()
+        .enumerate()
+        .filter(|(_, track)| track.kind != TextTrackKind::Descriptions)
+}
+
+fn token_query(token: Option<&str>) -> String {
+    match token {
+        Some(token) => format!("?token={token}"),
+        None => String::new(),
+    }
+}
+
+fn playlist_response(playlist: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, CONTENT_TYPE),
+            // Playlists of a VOD rendition only change when the video is re-encoded.
+            (header::CACHE_CONTROL, "private, max-age=60"),
+        ],
+        playlist,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendition::Segment;
+    use crate::video::{VideoInput, Visibility};
+    use mongodb::bson::oid::ObjectId;
+
+    fn rendition(height: u32, bitrate: u64, segmented: bool) -> Rendition {
+        Rendition {
+            width: height * 16 / 9,
+            height,
+            bitrate,
+            codec: "avc1.64001f,mp4a.40.2".to_string(),
+            path: format!("videos/sample-{height}p.fmp4.mp4"),
+            init: segmented.then_some(ByteRange {
+                offset: 0,
+                length: 812,
+            }),
+            segments: if segmented {
+                vec![
+                    Segment {
+                        duration: 4.0,
+                        offset: 812,
+                        length: bitrate / 2,
+                    },
+                    Segment {
+                        duration: 1.5,
+                        offset: 812 + bitrate / 2,
+                        length: bitrate / 8,
+                    },
+                ]
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    fn track(language: &str, label: &str, kind: TextTrackKind) -> TextTrack {
+        TextTrack {
+            language: language.to_string(),
+            label: label.to_string(),
+            kind,
+            path: format!("videos/sample.{language}.vtt"),
+        }
+    }
+
+    fn video(text_tracks: Vec<TextTrack>) -> Video {
+        let input = VideoInput {
+            video_path: "videos/sample.mp4".to_string(),
+            // The progressive original, then 720p and 360p out of order.
+            renditions: Some(vec![
+                rendition(1080, 5_000_000, false),
+                rendition(720, 2_500_000, true),
+                rendition(360, 800_000, true),
+            ]),
+            slug: None,
+            title: "Sample".to_string(),
+            description: String::new(),
+            tags: Vec::new(),
+            duration: Some(5.5),
+            visibility: Visibility::Public,
+            owner: None,
+            grants: None,
+        };
+        let id = ObjectId::parse_str("65a1b2c3d4e5f6a7b8c9d0e1").unwrap();
+        let mut video = Video::new(id, input, "sample".to_string(), Vec::new());
+        video.text_tracks = text_tracks;
+        video
+    }
+
+    #[test]
+    fn lists_segmented_renditions_by_bitrate() {
+        assert_eq!(
+            master(&video(Vec::new()), None).unwrap(),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=800000,AVERAGE-BANDWIDTH=800000,\
+             RESOLUTION=640x360,CODECS=\"avc1.64001f,mp4a.40.2\"\n\
+             2/index.m3u8\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=2500000,AVERAGE-BANDWIDTH=2500000,\
+             RESOLUTION=1280x720,CODECS=\"avc1.64001f,mp4a.40.2\"\n\
+             1/index.m3u8\n"
+        );
+    }
+
+    #[test]
+    fn groups_the_text_tracks_and_passes_the_token_on() {
+        let video = video(vec![
+            track("en", "English", TextTrackKind::Subtitles),
+            track("fr", "Audio description", TextTrackKind::Descriptions),
+            track("de", "Deutsch \"CC\"", TextTrackKind::Captions),
+        ]);
+        assert_eq!(
+            master(&video, Some("abc")).unwrap(),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"English\",LANGUAGE=\"en\",\
+             DEFAULT=NO,AUTOSELECT=YES,FORCED=NO,URI=\"tracks/0/index.m3u8?token=abc\"\n\
+             #EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"Deutsch 'CC'\",LANGUAGE=\"de\",\
+             DEFAULT=NO,AUTOSELECT=YES,FORCED=NO,\
+             CHARACTERISTICS=\"public.accessibility.transcribes-spoken-dialog,\
+             public.accessibility.describes-music-and-sound\",\
+             URI=\"tracks/2/index.m3u8?token=abc\"\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=800000,AVERAGE-BANDWIDTH=800000,\
+             RESOLUTION=640x360,CODECS=\"avc1.64001f,mp4a.40.2\",SUBTITLES=\"subs\"\n\
+             2/index.m3u8?token=abc\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=2500000,AVERAGE-BANDWIDTH=2500000,\
+             RESOLUTION=1280x720,CODECS=\"avc1.64001f,mp4a.40.2\",SUBTITLES=\"subs\"\n\
+             1/index.m3u8?token=abc\n"
+        );
+    }
+
+    #[test]
+    fn has_no_master_playlist_without_segmented_renditions() {
+        let mut video = video(Vec::new());
+        video.renditions.truncate(1);
+        assert_eq!(master(&video, None), None);
+    }
+
+    #[test]
+    fn addresses_segments_as_byte_ranges_of_the_rendition() {
+        let video = video(Vec::new());
+        let rendition = &video.renditions[2];
+        assert_eq!(
+            media_segments(rendition, rendition.init.unwrap(), Some("abc")),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:4\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXT-X-MAP:URI=\"media.mp4?token=abc\",BYTERANGE=\"812@0\"\n\
+             #EXTINF:4.000,\n\
+             #EXT-X-BYTERANGE:400000@812\n\
+             media.mp4?token=abc\n\
+             #EXTINF:1.500,\n\
+             #EXT-X-BYTERANGE:100000@400812\n\
+             media.mp4?token=abc\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn serves_a_text_track_as_one_segment() {
+        let mut video = video(Vec::new());
+        let captions = track("de", "Deutsch", TextTrackKind::Captions);
+        assert_eq!(
+            text_track(&video, &captions, Some("abc")),
+            "#EXTM3U\n\
+             #EXT-X-VERSION:7\n\
+             #EXT-X-TARGETDURATION:6\n\
+             #EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n\
+             #EXTINF:5.500,\n\
+             /video/65a1b2c3d4e5f6a7b8c9d0e1/tracks/de.vtt?kind=captions&token=abc\n\
+             #EXT-X-ENDLIST\n"
+        );
+        // Without a duration, that of the first segmented rendition.
+        video.duration = None;
+        assert!(text_track(&video, &captions, None).contains(
+            "#EXTINF:5.500,\n/video/65a1b2c3d4e5f6a7b8c9d0e1/tracks/de.vtt?kind=captions\n"
+        ));
+    }
+}
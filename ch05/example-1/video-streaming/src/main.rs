@@ -1,31 +1,72 @@
+use auth::{AuthLayer, Principal, require_roles};
 use axum::{
     Router,
     body::Body,
-    extract::{Query, State},
-    response::IntoResponse,
-    routing::get,
+    extract::{ConnectInfo, Query, RawQuery, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Redirect},
+    routing::{get, post, put},
+};
+use event_bus::{EventBus, EventBusExt};
+use events::{CloudEvent, EventData};
+use playback::PlaybackSigner;
+use rendition::{ClientHints, Quality};
+use repository::{
+    CacheConfig, CachedVideoRepository, InMemoryVideoRepository, MongoVideoRepository, VideoCache,
+    VideoRepository,
 };
-use mongodb::bson::doc;
 use serde::Deserialize;
-use serde_json::json;
-use std::{env, str::FromStr};
+use slug::VideoRef;
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
+use video::Video;
+use viewed::{ViewedSender, Viewer};
+
+mod catalog;
+mod chapters;
+mod hls;
+mod playback;
+mod probe;
+mod rendition;
+mod repository;
+mod search;
+mod seek;
+mod slug;
+mod storage;
+mod tracks;
+mod video;
+mod viewed;
+
+/// The CloudEvents `source` of the events video-streaming publishes.
+const EVENT_SOURCE: &str = "/video-streaming";
 
 #[derive(Deserialize)]
 struct VideoId {
     id: String,
-}
-
-#[derive(Deserialize)]
-struct Video {
-    #[serde(rename = "videoPath")]
-    video_path: String,
+    /// Playback token, required when signed playback is enabled.
+    token: Option<String>,
+    /// `auto` (the default) or a frame height such as `720p`.
+    quality: Option<String>,
+    /// Start position in seconds. The video starts at the last keyframe
+    /// before it, see [`seek`].
+    t: Option<f64>,
 }
 
 #[derive(Clone)]
 struct AppState {
     video_storage_host: String,
     video_storage_port: String,
-    videos: mongodb::Collection<Video>,
+    /// Bearer token for uploads to video-storage, such as text tracks.
+    storage_token: Option<String>,
+    videos: Arc<dyn VideoRepository>,
+    video_cache: Arc<VideoCache>,
+    /// Set when `PLAYBACK_KEYS` is configured, which makes playback tokens
+    /// mandatory for `GET /video`.
+    playback: Option<Arc<PlaybackSigner>>,
+    /// Where views are sent to, see [`viewed`].
+    viewed: ViewedSender,
+    /// Set when an event bus is configured, which catalog changes are
+    /// published to.
+    events: Option<Arc<dyn EventBus>>,
 }
 
 #[tokio::main]
@@ -35,6 +76,83 @@ async fn main() {
         env::var("VIDEO_STORAGE_HOST").expect("VIDEO_STORAGE_HOST environment variable not set");
     let video_storage_port =
         env::var("VIDEO_STORAGE_PORT").expect("VIDEO_STORAGE_PORT environment variable not set");
+    let video_cache = Arc::new(VideoCache::new(cache_config()));
+    let (videos, db) = match env::var("VIDEO_REPOSITORY").as_deref() {
+        Ok("memory") => (create_in_memory_repository(), None),
+        Ok("mongo") | Err(_) => {
+            let db = connect_mongo().await;
+            (create_mongo_repository(&db, &video_cache).await, Some(db))
+        }
+        Ok(other) => panic!("Unknown VIDEO_REPOSITORY {other}, expected mongo or memory"),
+    };
+    let playback = match env::var("PLAYBACK_KEYS") {
+        Ok(keys) => {
+            let ttl = env::var("PLAYBACK_TOKEN_TTL_SECS")
+                .map(|ttl| {
+                    ttl.parse()
+                        .expect("PLAYBACK_TOKEN_TTL_SECS is not a number")
+                })
+                .unwrap_or(3600);
+            let trust_forwarded_for = env::var("TRUST_FORWARDED_FOR").is_ok_and(|v| v == "true");
+            Some(Arc::new(PlaybackSigner::from_config(
+                &keys,
+                Duration::from_secs(ttl),
+                trust_forwarded_for,
+            )))
+        }
+        Err(_) => {
+            println!("PLAYBACK_KEYS not set, videos can be streamed without a playback token");
+            None
+        }
+    };
+    let events = event_bus::from_env("video-streaming");
+    if events.is_none() {
+        println!("RABBIT not set, catalog changes are not published");
+    }
+    let app_state = AppState {
+        video_storage_host,
+        video_storage_port,
+        storage_token: env::var("STORAGE_TOKEN").ok(),
+        videos: Arc::new(CachedVideoRepository::new(videos, video_cache.clone())),
+        video_cache,
+        playback,
+        viewed: ViewedSender::from_env(db.as_ref(), events.clone()).await,
+        events,
+    };
+    let app = app(app_state);
+
+    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
+        .await
+        .unwrap();
+
+    println!("Server running at {}", listener.local_addr().unwrap());
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+/// Reads the sizing of the video metadata cache. A capacity of 0 disables it.
+fn cache_config() -> CacheConfig {
+    let number = |name: &str, default: u64| {
+        env::var(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("{name} is not a number"))
+            })
+            .unwrap_or(default)
+    };
+    CacheConfig {
+        capacity: number("VIDEO_CACHE_CAPACITY", 10_000) as usize,
+        ttl: Duration::from_secs(number("VIDEO_CACHE_TTL_SECS", 300)),
+        negative_ttl: Duration::from_secs(number("VIDEO_CACHE_NEGATIVE_TTL_SECS", 30)),
+    }
+}
+
+async fn connect_mongo() -> mongodb::Database {
     let db_host = env::var("DBHOST").expect("DBHOST environment variable not set");
     let db_name = env::var("DBNAME").expect("DBNAME environment variable not set");
 
@@ -46,89 +164,615 @@ async fn main() {
         .build();
     client_options.server_api = Some(server_api);
     let client = mongodb::Client::with_options(client_options).expect("Can not create clients");
-    let db = client.database(&db_name);
-    let videos = db.collection::<Video>("videos");
-    let app_state = AppState {
-        video_storage_host,
-        video_storage_port,
-        videos,
-    };
-    let app = app(app_state);
+    client.database(&db_name)
+}
 
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
-        .await
-        .unwrap();
+async fn create_mongo_repository(
+    db: &mongodb::Database,
+    video_cache: &Arc<VideoCache>,
+) -> Arc<dyn VideoRepository> {
+    let repository = MongoVideoRepository::new(db.collection::<Video>("videos"));
+    if let Err(e) = repository.ensure_indexes().await {
+        eprintln!("Error creating the search index: {e}");
+    }
+    repository.spawn_change_listener(video_cache.clone());
+    Arc::new(repository)
+}
 
-    println!("Server running at {}", listener.local_addr().unwrap());
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+/// Offline demo mode: the catalog lives in memory and is optionally seeded from
+/// `SEED_VIDEOS`, a JSON array in the format written by `mongoexport --jsonArray`.
+fn create_in_memory_repository() -> Arc<dyn VideoRepository> {
+    let videos: Vec<Video> = match env::var("SEED_VIDEOS") {
+        Ok(path) => {
+            let seed = std::fs::read_to_string(&path).expect("Can not read SEED_VIDEOS file");
+            serde_json::from_str(&seed).expect("Can not parse SEED_VIDEOS file")
+        }
+        Err(_) => Vec::new(),
+    };
+    println!("Serving {} videos from the in-memory catalog", videos.len());
+    Arc::new(InMemoryVideoRepository::new(videos))
 }
 
 fn app(state: AppState) -> Router {
     Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/video", get(get_video))
+        .route("/video/{id}/tracks/{file}", get(tracks::get_track))
+        .route(
+            "/video/{id}/chapters.vtt",
+            get(chapters::get_chapters_track),
+        )
+        .route("/hls/{id}/master.m3u8", get(hls::master_playlist))
+        .route("/hls/{id}/{rendition}/index.m3u8", get(hls::media_playlist))
+        .route("/hls/{id}/{rendition}/media.mp4", get(hls::media))
+        .route(
+            "/hls/{id}/tracks/{track}/index.m3u8",
+            get(hls::subtitles_playlist),
+        )
+        .route("/search", get(search::search))
+        .route(
+            "/videos",
+            get(catalog::list_videos)
+                .merge(post(catalog::create_video).route_layer(require_roles(["editor", "admin"]))),
+        )
+        .route(
+            "/videos/{id}",
+            get(catalog::get_catalog_video).merge(
+                put(catalog::update_video)
+                    .delete(catalog::delete_video)
+                    .route_layer(require_roles(["editor", "admin"])),
+            ),
+        )
+        .route(
+            "/videos/{id}/probe",
+            post(probe::probe_video).route_layer(require_roles(["editor", "admin"])),
+        )
+        .route(
+            "/videos/{id}/chapters",
+            get(chapters::get_chapters)
+                .merge(put(chapters::put_chapters).route_layer(require_roles(["editor", "admin"]))),
+        )
+        .route(
+            "/videos/{id}/tracks/{language}",
+            put(tracks::put_track)
+                .delete(tracks::delete_track)
+                .route_layer(require_roles(["editor", "admin"])),
+        )
+        .route(
+            "/metrics/cache",
+            get(get_cache_metrics).route_layer(require_roles(["admin"])),
+        )
+        .route("/playback-tokens", post(playback::issue_token))
+        .layer(AuthLayer::from_env())
         .with_state(state)
 }
 
+async fn get_cache_metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    axum::Json(app_state.video_cache.metrics())
+}
+
 async fn get_video(
     State(app_state): State<AppState>,
     Query(video_id): Query<VideoId>,
+    RawQuery(raw_query): RawQuery,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
 ) -> impl IntoResponse {
-    let video_id =
-        mongodb::bson::oid::ObjectId::from_str(&video_id.id).expect("Invalid video ID format");
-    println!("Successfully created video id: {video_id}");
-    println!("Successfully connected to the videos collection");
-    let video_record = app_state.videos.find_one(doc! { "_id": &video_id });
-    match video_record.await {
-        Ok(Some(video)) => {
-            let video_path = video.video_path;
-            let video_storage_host = &app_state.video_storage_host;
-            let video_storage_port = &app_state.video_storage_port;
-            let forward_response = reqwest::Client::new()
-                .get(format!(
-                    "http://{video_storage_host}:{video_storage_port}/video?path={video_path}"
-                ))
-                .send()
-                .await
-                .expect("Failed to forward request");
-            let status_code = forward_response.status();
-            if status_code == axum::http::StatusCode::OK {
-                tokio::spawn(async move {
-                    if let Err(e) = send_viewed_message(video_path).await {
-                        eprintln!("Error sending the viewed message: {e}");
-                    }
-                });
+    let quality = match video_id.quality.as_deref().map(Quality::parse) {
+        None => Quality::Auto,
+        Some(Some(quality)) => quality,
+        Some(None) => {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                "quality must be auto or a frame height such as 720p",
+            )
+                .into_response();
+        }
+    };
+    if video_id.t.is_some_and(|t| !t.is_finite() || t < 0.0) {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            "t must be a number of seconds",
+        )
+            .into_response();
+    }
+    let request = PlaybackRequest {
+        id: &video_id.id,
+        token: video_id.token.as_deref(),
+        peer,
+        headers: &headers,
+        caller: caller.as_ref(),
+    };
+    let video = match find_playable_video(&app_state, request).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    // An outdated slug, send the client to the current one.
+    if VideoRef::parse(&video_id.id).is_some_and(|requested| match requested {
+        VideoRef::Slug(slug) => video.slug.as_ref() != Some(&slug),
+        VideoRef::Id(_) => false,
+    }) {
+        let current = video.slug.unwrap_or_else(|| video.id.to_hex());
+        let query = replace_id(raw_query.as_deref().unwrap_or_default(), &current);
+        return Redirect::permanent(&format!("/video?{query}")).into_response();
+    }
+
+    // Seeking rewrites the sample tables of progressive files, which packaged
+    // renditions do not have.
+    let renditions: Vec<_> = match video_id.t {
+        Some(_) => video
+            .renditions
+            .iter()
+            .filter(|rendition| !rendition.is_segmented())
+            .cloned()
+            .collect(),
+        None => video.renditions.clone(),
+    };
+    let rendition = rendition::select(&renditions, quality, &ClientHints::from_headers(&headers));
+    let file_path = rendition.map_or(&video.video_path, |rendition| &rendition.path);
+    let mut response = match video_id.t {
+        Some(t) if t > 0.0 => {
+            seek::seek(&app_state, file_path, t, headers.get(header::RANGE)).await
+        }
+        _ => forward_to_storage(&app_state, file_path, &headers).await,
+    };
+    if let Some(rendition) = rendition {
+        let headers = response.headers_mut();
+        headers.insert(
+            "x-video-rendition",
+            HeaderValue::from_str(&rendition.label()).unwrap(),
+        );
+        if quality == Quality::Auto {
+            headers.insert(
+                "accept-ch",
+                HeaderValue::from_static(rendition::CLIENT_HINTS),
+            );
+            headers.insert("vary", HeaderValue::from_static(rendition::CLIENT_HINTS));
+        }
+    }
+    // Range requests past the start of the file continue a view that has
+    // been counted already.
+    if response.status().is_success() && !continues_view(&headers) {
+        record_view(&app_state, &video, request).await;
+    }
+    response
+}
+
+/// A request to play a video, by `GET /video` or one of the HLS endpoints.
+#[derive(Clone, Copy)]
+struct PlaybackRequest<'a> {
+    /// ObjectId or slug of the video.
+    id: &'a str,
+    token: Option<&'a str>,
+    peer: SocketAddr,
+    headers: &'a HeaderMap,
+    caller: Option<&'a Principal>,
+}
+
+/// Looks up a video and checks that the client may play it, either with a
+/// playback token or, when signed playback is disabled, by its visibility.
+/// Tokens bound to a viewer only play for that viewer signed in.
+async fn find_playable_video(
+    app_state: &AppState,
+    request: PlaybackRequest<'_>,
+) -> Result<Video, axum::response::Response> {
+    let claims = match &app_state.playback {
+        Some(signer) => {
+            let Some(token) = request.token else {
+                return Err(
+                    (axum::http::StatusCode::FORBIDDEN, "Missing playback token").into_response(),
+                );
+            };
+            match signer.verify(token, signer.client_address(request.headers, request.peer)) {
+                Ok(claims) => Some(claims),
+                Err(e) => {
+                    println!("Rejected playback token: {e:?}");
+                    return Err(
+                        (axum::http::StatusCode::FORBIDDEN, "Invalid playback token")
+                            .into_response(),
+                    );
+                }
             }
-            let headers = forward_response.headers().clone();
-            let video_data = forward_response.bytes_stream();
-            (
-                status_code,
-                (headers, Body::from_stream(video_data)).into_response(),
+        }
+        None => None,
+    };
+    let viewer = request.caller.map(|caller| caller.subject.as_str());
+    if let Some(sub) = claims.as_ref().and_then(|claims| claims.sub.as_deref())
+        && viewer != Some(sub)
+    {
+        println!("Rejected playback token of {sub} from {viewer:?}");
+        return Err((
+            axum::http::StatusCode::FORBIDDEN,
+            "Playback token was issued to another viewer",
+        )
+            .into_response());
+    }
+    let video_record = match VideoRef::parse(request.id) {
+        Some(VideoRef::Id(id)) => app_state.videos.find_by_id(&id).await,
+        Some(VideoRef::Slug(slug)) => app_state.videos.find_by_slug(&slug).await,
+        None => {
+            return Err((
+                axum::http::StatusCode::BAD_REQUEST,
+                "Invalid video ID format",
             )
-                .into_response()
+                .into_response());
         }
-        Ok(None) => (axum::http::StatusCode::NOT_FOUND, "Video not found").into_response(),
+    };
+    match video_record {
+        Ok(Some(video))
+            if claims
+                .as_ref()
+                .is_some_and(|claims| claims.vid != video.id.to_hex()) =>
+        {
+            Err((axum::http::StatusCode::FORBIDDEN, "Invalid playback token").into_response())
+        }
+        // Tokens are only issued to clients that may access the video.
+        Ok(Some(video)) if claims.is_none() && !video.is_accessible_by(viewer) => {
+            Err((axum::http::StatusCode::NOT_FOUND, "Video not found").into_response())
+        }
+        Ok(Some(video)) => Ok(video),
+        Ok(None) => Err((axum::http::StatusCode::NOT_FOUND, "Video not found").into_response()),
         Err(e) => {
             eprintln!("Error fetching video: {}", e);
-            (
+            Err((
                 axum::http::StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal server error",
             )
-                .into_response()
+                .into_response())
         }
     }
 }
 
-async fn send_viewed_message(video_path: String) -> Result<reqwest::Response, reqwest::Error> {
-    let json_body = json!({
-        "video_path": video_path
-    });
-    reqwest::Client::new()
-        .post("http://history/viewed")
-        .body(json_body.to_string())
-        .header("Content-Type", "application/json")
+/// Streams a file from video-storage, passing on the `Range` header of the
+/// client so that players can seek and fetch HLS segments.
+async fn forward_to_storage(
+    app_state: &AppState,
+    file_path: &str,
+    headers: &HeaderMap,
+) -> axum::response::Response {
+    let video_storage_host = &app_state.video_storage_host;
+    let video_storage_port = &app_state.video_storage_port;
+    let mut forward_request = reqwest::Client::new().get(format!(
+        "http://{video_storage_host}:{video_storage_port}/video?path={file_path}"
+    ));
+    if let Some(range) = headers.get(header::RANGE) {
+        forward_request = forward_request.header(header::RANGE, range);
+    }
+    let forward_response = forward_request
         .send()
         .await
+        .expect("Failed to forward request");
+    let status_code = forward_response.status();
+    let headers = forward_response.headers().clone();
+    let video_data = forward_response.bytes_stream();
+    (
+        status_code,
+        (headers, Body::from_stream(video_data)).into_response(),
+    )
+        .into_response()
+}
+
+/// Whether a request asks for a range that does not start at the beginning
+/// of the file, as players do to continue playing or to seek.
+fn continues_view(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.trim().strip_prefix("bytes="))
+        .is_some_and(|spec| !spec.trim_start().starts_with("0-"))
+}
+
+/// Tells the history service about a view. The response only waits for the
+/// view to be written to the outbox, if there is one.
+async fn record_view(app_state: &AppState, video: &Video, request: PlaybackRequest<'_>) {
+    let client = match &app_state.playback {
+        Some(signer) => signer.client_address(request.headers, request.peer),
+        None => request.peer.ip(),
+    };
+    let viewer = Viewer::new(request.headers, client, request.caller);
+    app_state.viewed.send(video, viewer).await;
+}
+
+/// Publishes an event to the event bus, if there is one, without waiting for
+/// the bus to take it.
+fn publish_event<T: EventData + Sync>(app_state: &AppState, data: T) {
+    let Some(bus) = app_state.events.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        let event = CloudEvent::new(EVENT_SOURCE, data);
+        if let Err(e) = bus.publish(&event).await {
+            eprintln!("Error publishing {} event {}: {e}", T::TYPE, event.id);
+        }
+    });
+}
+
+/// Replaces the value of the `id` parameter in a raw query string, keeping all
+/// other parameters as they are.
+fn replace_id(raw_query: &str, id: &str) -> String {
+    raw_query
+        .split('&')
+        .map(|pair| {
+            if pair.split('=').next() == Some("id") {
+                format!("id={id}")
+            } else {
+                pair.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::{Request, StatusCode},
+        response::Response,
+    };
+    use http_body_util::BodyExt;
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    /// The routes on an in-memory catalog, called by `caller` or, without
+    /// one, anonymously.
+    fn app_as(videos: Arc<InMemoryVideoRepository>, caller: Option<(&str, &str)>) -> Router {
+        let state = AppState {
+            video_storage_host: "localhost".to_string(),
+            video_storage_port: "0".to_string(),
+            storage_token: None,
+            videos,
+            video_cache: Arc::new(VideoCache::new(CacheConfig {
+                capacity: 0,
+                ttl: Duration::from_secs(60),
+                negative_ttl: Duration::from_secs(60),
+            })),
+            playback: None,
+            viewed: ViewedSender::Direct(viewed::Transport::http()),
+            events: None,
+        };
+        match caller {
+            // Requests without an Authorization header pass the auth layer
+            // as they are, so the principal set outside of it reaches the
+            // handlers.
+            Some((subject, role)) => app(state).layer(axum::Extension(Principal {
+                subject: subject.to_string(),
+                roles: vec![role.to_string()],
+            })),
+            None => app(state),
+        }
+    }
+
+    /// The routes on an empty in-memory catalog, called by an editor.
+    fn editor_app() -> Router {
+        app_as(
+            Arc::new(InMemoryVideoRepository::new(vec![])),
+            Some(("editor", "editor")),
+        )
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str, body: Option<Value>) -> Response {
+        let builder = Request::builder().method(method).uri(uri);
+        let mut request = match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        }
+        .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn json_body(response: Response) -> Value {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    async fn create(app: &Router, title: &str) -> Value {
+        let input = json!({ "videoPath": format!("{title}.mp4"), "title": title });
+        let response = send(app, "POST", "/videos", Some(input)).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        json_body(response).await
+    }
+
+    fn titles(videos: &Value) -> Vec<&str> {
+        videos
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|video| video["title"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn lists_videos_in_pages() {
+        let app = editor_app();
+        for title in ["First", "Second", "Third"] {
+            create(&app, title).await;
+        }
+
+        let first = json_body(send(&app, "GET", "/videos?limit=2", None).await).await;
+        assert_eq!(titles(&first), ["First", "Second"]);
+        let second = json_body(send(&app, "GET", "/videos?offset=2&limit=2", None).await).await;
+        assert_eq!(titles(&second), ["Third"]);
+        let past_end = json_body(send(&app, "GET", "/videos?offset=3", None).await).await;
+        assert!(titles(&past_end).is_empty());
+    }
+
+    #[tokio::test]
+    async fn creates_reads_updates_and_deletes_a_video() {
+        let app = editor_app();
+        let created = create(&app, "Intro").await;
+        let id = created["id"].as_str().unwrap();
+        assert_eq!(created["owner"], "editor");
+
+        let uri = format!("/videos/{id}");
+        let response = send(&app, "GET", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["title"], "Intro");
+
+        let input = json!({ "videoPath": "Intro.mp4", "title": "Introduction" });
+        let response = send(&app, "PUT", &uri, Some(input)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let updated = json_body(response).await;
+        assert_eq!(updated["title"], "Introduction");
+        assert_eq!(updated["owner"], "editor");
+
+        let response = send(&app, "DELETE", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = send(&app, "GET", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = send(&app, "DELETE", &uri, None).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_ids_and_does_not_find_unknown_ones() {
+        let app = editor_app();
+        let unknown = ObjectId::new().to_hex();
+        let input = json!({ "videoPath": "intro.mp4" });
+
+        for (method, uri, body) in [
+            ("GET", "/videos/Not_A_Slug".to_string(), None),
+            ("PUT", "/videos/not-an-id".to_string(), Some(input.clone())),
+            ("DELETE", "/videos/not-an-id".to_string(), None),
+            ("GET", "/video?id=Not_A_Slug".to_string(), None),
+        ] {
+            let response = send(&app, method, &uri, body).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{method} {uri}");
+        }
+        for (method, uri, body) in [
+            ("GET", format!("/videos/{unknown}"), None),
+            ("GET", "/videos/unknown-slug".to_string(), None),
+            ("PUT", format!("/videos/{unknown}"), Some(input.clone())),
+            ("DELETE", format!("/videos/{unknown}"), None),
+            ("GET", format!("/video?id={unknown}"), None),
+        ] {
+            let response = send(&app, method, &uri, body).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{method} {uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_videos_by_current_and_previous_slugs() {
+        let app = editor_app();
+        let created = create(&app, "My First Video").await;
+        let id = created["id"].as_str().unwrap();
+        assert_eq!(created["slug"], "my-first-video");
+
+        let response = send(&app, "GET", "/videos/my-first-video", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(response).await["id"], id);
+
+        let input = json!({ "videoPath": "My First Video.mp4", "slug": "renamed" });
+        let response = send(&app, "PUT", &format!("/videos/{id}"), Some(input)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(response).await["previousSlugs"],
+            json!(["my-first-video"])
+        );
+
+        let response = send(&app, "GET", "/videos/my-first-video", None).await;
+        assert_eq!(json_body(response).await["id"], id);
+        let response = send(&app, "GET", "/video?id=my-first-video&quality=720p", None).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "/video?id=renamed&quality=720p"
+        );
+    }
+
+    /// A catalog with a public, an unlisted and a private video of `owner`,
+    /// the last granted to `friend`, with the ids of the videos by title.
+    async fn visibility_catalog() -> (Arc<InMemoryVideoRepository>, Vec<(String, String)>) {
+        let videos = Arc::new(InMemoryVideoRepository::new(vec![]));
+        let owner = app_as(videos.clone(), Some(("owner", "editor")));
+        let mut ids = Vec::new();
+        for (title, visibility) in [
+            ("Public", "public"),
+            ("Unlisted", "unlisted"),
+            ("Private", "private"),
+        ] {
+            let input = json!({
+                "videoPath": format!("{title}.mp4"),
+                "title": title,
+                "visibility": visibility,
+                "grants": ["friend"],
+            });
+            let response = send(&owner, "POST", "/videos", Some(input)).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let id = json_body(response).await["id"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            ids.push((title.to_string(), id));
+        }
+        (videos, ids)
+    }
+
+    #[tokio::test]
+    async fn lists_only_public_videos_except_to_admins() {
+        let (videos, _) = visibility_catalog().await;
+        for (caller, expected) in [
+            (None, vec!["Public"]),
+            (Some(("owner", "editor")), vec!["Public"]),
+            (Some(("friend", "viewer")), vec!["Public"]),
+            (
+                Some(("admin", "admin")),
+                vec!["Public", "Unlisted", "Private"],
+            ),
+        ] {
+            let app = app_as(videos.clone(), caller);
+            let listed = json_body(send(&app, "GET", "/videos", None).await).await;
+            let mut listed = titles(&listed);
+            listed.sort_unstable();
+            let mut expected = expected;
+            expected.sort_unstable();
+            assert_eq!(listed, expected, "{caller:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_404_for_videos_the_caller_may_not_access() {
+        let (videos, ids) = visibility_catalog().await;
+        for (caller, accessible) in [
+            (None, ["Public", "Unlisted"].as_slice()),
+            (Some(("stranger", "viewer")), &["Public", "Unlisted"]),
+            (
+                Some(("owner", "editor")),
+                &["Public", "Unlisted", "Private"],
+            ),
+            (
+                Some(("friend", "viewer")),
+                &["Public", "Unlisted", "Private"],
+            ),
+        ] {
+            let app = app_as(videos.clone(), caller);
+            for (title, id) in &ids {
+                let response = send(&app, "GET", &format!("/videos/{id}"), None).await;
+                if accessible.contains(&title.as_str()) {
+                    assert_eq!(response.status(), StatusCode::OK, "{title} {caller:?}");
+                    assert_eq!(json_body(response).await["title"], title.as_str());
+                } else {
+                    assert_eq!(
+                        response.status(),
+                        StatusCode::NOT_FOUND,
+                        "{title} {caller:?}"
+                    );
+                    // Streaming is refused before video-storage is asked.
+                    let response = send(&app, "GET", &format!("/video?id={id}"), None).await;
+                    assert_eq!(
+                        response.status(),
+                        StatusCode::NOT_FOUND,
+                        "{title} {caller:?}"
+                    );
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,426 @@
+use auth::Principal;
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::AppState;
+use crate::slug::VideoRef;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const TOKEN_VERSION: &str = "v1";
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Claims of a playback token.
+#[derive(Serialize, Deserialize)]
+pub struct PlaybackClaims {
+    /// Hex ObjectId of the video the token grants access to.
+    pub vid: String,
+    /// Expiry as seconds since the Unix epoch.
+    pub exp: u64,
+    /// Subject of the only signed in viewer who may play with the token.
+    /// Tokens without one play for anyone who holds them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// Network prefix the client address has to be in, e.g. `203.0.113.0/24`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Malformed,
+    UnknownKey,
+    BadSignature,
+    Expired,
+    WrongAddress,
+}
+
+/// Signs and verifies playback tokens with HMAC-SHA256. All configured keys
+/// are accepted for verification, but only the first one is used to sign, so a
+/// key is rotated by prepending its successor and dropping it once the tokens
+/// it signed have expired.
+pub struct PlaybackSigner {
+    keys: Vec<(String, Vec<u8>)>,
+    ttl: Duration,
+    /// Whether the client address may be taken from `X-Forwarded-For`.
+    trust_forwarded_for: bool,
+}
+
+impl PlaybackSigner {
+    /// Parses `PLAYBACK_KEYS`, a comma separated list of `key-id:secret` pairs.
+    pub fn from_config(keys: &str, ttl: Duration, trust_forwarded_for: bool) -> Self {
+        let keys: Vec<(String, Vec<u8>)> = keys
+            .split(',')
+            .filter_map(|pair| pair.trim().split_once(':'))
+            .map(|(kid, secret)| (kid.to_string(), secret.as_bytes().to_vec()))
+            .collect();
+        assert!(
+            !keys.is_empty(),
+            "PLAYBACK_KEYS must contain at least one key-id:secret pair"
+        );
+        assert!(
+            keys.iter()
+                .all(|(kid, _)| !kid.is_empty() && !kid.contains('.')),
+            "PLAYBACK_KEYS key ids must not be empty or contain dots"
+        );
+        Self {
+            keys,
+            ttl,
+            trust_forwarded_for,
+        }
+    }
+
+    pub fn sign(&self, claims: &PlaybackClaims) -> String {
+        let (kid, secret) = &self.keys[0];
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let signed = format!("{TOKEN_VERSION}.{kid}.{payload}");
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+        mac.update(signed.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{signed}.{signature}")
+    }
+
+    /// Checks signature, expiry and client address. Whether the token belongs
+    /// to the requested video is up to the caller, which knows the video id.
+    pub fn verify(&self, token: &str, client: IpAddr) -> Result<PlaybackClaims, TokenError> {
+        let (signed, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let mut parts = signed.splitn(3, '.');
+        let (Some(TOKEN_VERSION), Some(kid), Some(payload)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+        let secret = self
+            .keys
+            .iter()
+            .find(|(key_id, _)| key_id == kid)
+            .map(|(_, secret)| secret)
+            .ok_or(TokenError::UnknownKey)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        let payload = URL_SAFE_NO_PAD
+            .decode(payload)
+            .map_err(|_| TokenError::Malformed)?;
+        let claims: PlaybackClaims =
+            serde_json::from_slice(&payload).map_err(|_| TokenError::Malformed)?;
+        if claims.exp <= now() {
+            return Err(TokenError::Expired);
+        }
+        if let Some(prefix) = &claims.ip
+            && ip_prefix(client) != *prefix
+        {
+            return Err(TokenError::WrongAddress);
+        }
+        Ok(claims)
+    }
+
+    /// The address of the client, taken from the first `X-Forwarded-For`
+    /// entry when the service runs behind a trusted proxy.
+    pub fn client_address(&self, headers: &HeaderMap, peer: SocketAddr) -> IpAddr {
+        if self.trust_forwarded_for
+            && let Some(forwarded) = headers
+                .get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .and_then(|value| value.trim().parse().ok())
+        {
+            return forwarded;
+        }
+        peer.ip()
+    }
+}
+
+/// The network a client address belongs to: /24 for IPv4 and /64 for IPv6,
+/// so that tokens survive address changes within a provider's network.
+fn ip_prefix(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let [a, b, c, _] = address.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        IpAddr::V6(address) => {
+            let segments = address.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before 1970")
+        .as_secs()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TokenRequest {
+    /// ObjectId or slug of the video.
+    video_id: String,
+    /// Binds the token to a viewer, see [`PlaybackClaims::sub`].
+    viewer_id: Option<String>,
+    /// Binds the token to the network of the requesting client.
+    #[serde(default)]
+    bind_ip: bool,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenResponse {
+    token: String,
+    expires_at: u64,
+    url: String,
+}
+
+/// Issues a playback token to an authenticated client.
+pub async fn issue_token(
+    State(app_state): State<AppState>,
+    caller: Principal,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<TokenRequest>,
+) -> Response {
+    let Some(signer) = &app_state.playback else {
+        return (
+            StatusCode::NOT_FOUND,
+            "Signed playback is not enabled on this server",
+        )
+            .into_response();
+    };
+    if let Some(response) = validate(&request, &caller) {
+        return response;
+    }
+    let video_record = match VideoRef::parse(&request.video_id) {
+        Some(VideoRef::Id(id)) => app_state.videos.find_by_id(&id).await,
+        Some(VideoRef::Slug(slug)) => app_state.videos.find_by_slug(&slug).await,
+        None => return (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response(),
+    };
+    let video = match video_record {
+        Ok(Some(video)) if video.is_accessible_by(Some(&caller.subject)) => video,
+        Ok(_) => return (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => {
+            eprintln!("Error fetching video: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response();
+        }
+    };
+
+    let ttl = request
+        .ttl_secs
+        .map(Duration::from_secs)
+        .unwrap_or(signer.ttl)
+        .min(MAX_TTL);
+    let claims = PlaybackClaims {
+        vid: video.id.to_hex(),
+        exp: now() + ttl.as_secs(),
+        sub: request.viewer_id,
+        ip: request
+            .bind_ip
+            .then(|| ip_prefix(signer.client_address(&headers, peer))),
+    };
+    let token = signer.sign(&claims);
+    println!(
+        "Issued playback token for video {} to {}",
+        claims.vid, caller.subject
+    );
+    Json(TokenResponse {
+        url: format!("/video?id={}&token={token}", claims.vid),
+        expires_at: claims.exp,
+        token,
+    })
+    .into_response()
+}
+
+/// Rejects tokens that never play and tokens bound to another viewer than
+/// the caller, which only admins may issue.
+fn validate(request: &TokenRequest, caller: &Principal) -> Option<Response> {
+    if request.ttl_secs == Some(0) {
+        return Some((StatusCode::BAD_REQUEST, "ttlSecs must be at least 1").into_response());
+    }
+    if let Some(viewer) = &request.viewer_id
+        && *viewer != caller.subject
+        && !caller.has_role("admin")
+    {
+        return Some(
+            (
+                StatusCode::FORBIDDEN,
+                "Only admins issue tokens for other viewers",
+            )
+                .into_response(),
+        );
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+    fn signer(keys: &str) -> PlaybackSigner {
+        PlaybackSigner::from_config(keys, Duration::from_secs(60), false)
+    }
+
+    fn claims(exp: u64, ip: Option<&str>) -> PlaybackClaims {
+        PlaybackClaims {
+            vid: "64b7f0c2a1e4d3b2c1a09f8e".to_string(),
+            exp,
+            sub: Some("viewer".to_string()),
+            ip: ip.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn verifies_the_tokens_it_signs() {
+        let signer = signer("k1:secret");
+        let token = signer.sign(&claims(now() + 60, Some("203.0.113.0/24")));
+        assert!(token.starts_with("v1.k1."));
+        let verified = signer.verify(&token, CLIENT).unwrap();
+        assert_eq!(verified.vid, "64b7f0c2a1e4d3b2c1a09f8e");
+        assert_eq!(verified.sub.as_deref(), Some("viewer"));
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let signer = signer("k1:secret");
+        let token = signer.sign(&claims(now() - 1, None));
+        assert!(matches!(
+            signer.verify(&token, CLIENT),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn rejects_tokens_from_other_networks() {
+        let signer = signer("k1:secret");
+        let token = signer.sign(&claims(now() + 60, Some("203.0.113.0/24")));
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 114, 7));
+        assert!(matches!(
+            signer.verify(&token, other),
+            Err(TokenError::WrongAddress)
+        ));
+        let v6 = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(ip_prefix(v6), "2001:db8:1:2::/64");
+    }
+
+    #[test]
+    fn rejects_tampered_and_foreign_tokens() {
+        let signer = signer("k1:secret");
+        let token = signer.sign(&claims(now() + 60, None));
+
+        // The claims of a longer lived token under the original signature.
+        let forged =
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims(now() + 3600, None)).unwrap());
+        let mut parts: Vec<&str> = token.split('.').collect();
+        parts[2] = &forged;
+        let tampered = parts.join(".");
+        assert!(matches!(
+            signer.verify(&tampered, CLIENT),
+            Err(TokenError::BadSignature)
+        ));
+
+        let other_secret = PlaybackSigner::from_config("k1:other", Duration::from_secs(60), false)
+            .sign(&claims(now() + 60, None));
+        assert!(matches!(
+            signer.verify(&other_secret, CLIENT),
+            Err(TokenError::BadSignature)
+        ));
+        let other_key = PlaybackSigner::from_config("k2:secret", Duration::from_secs(60), false)
+            .sign(&claims(now() + 60, None));
+        assert!(matches!(
+            signer.verify(&other_key, CLIENT),
+            Err(TokenError::UnknownKey)
+        ));
+        for malformed in ["", "v1.k1", "v2.k1.e30.sig", "v1.k1.e30.!!!"] {
+            assert!(
+                matches!(signer.verify(malformed, CLIENT), Err(TokenError::Malformed)),
+                "{malformed}"
+            );
+        }
+    }
+
+    #[test]
+    fn signs_with_the_first_key_and_accepts_all_of_them() {
+        let old = signer("old:before");
+        let rotated = signer("new:after,old:before");
+        let token = old.sign(&claims(now() + 60, None));
+        assert!(rotated.verify(&token, CLIENT).is_ok());
+        assert!(
+            rotated
+                .sign(&claims(now() + 60, None))
+                .starts_with("v1.new.")
+        );
+    }
+
+    #[test]
+    fn takes_the_client_address_from_a_trusted_proxy_only() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.1, 10.0.0.1".parse().unwrap());
+        let peer = SocketAddr::from(([10, 0, 0, 1], 443));
+        let trusting = PlaybackSigner::from_config("k1:secret", Duration::from_secs(60), true);
+        assert_eq!(
+            trusting.client_address(&headers, peer).to_string(),
+            "198.51.100.1"
+        );
+        assert_eq!(
+            signer("k1:secret").client_address(&headers, peer),
+            peer.ip()
+        );
+    }
+
+    fn request(viewer_id: Option<&str>, ttl_secs: Option<u64>) -> TokenRequest {
+        TokenRequest {
+            video_id: "intro".to_string(),
+            viewer_id: viewer_id.map(str::to_string),
+            bind_ip: false,
+            ttl_secs,
+        }
+    }
+
+    fn caller(roles: &[&str]) -> Principal {
+        Principal {
+            subject: "alice".to_string(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn binds_tokens_to_other_viewers_for_admins_only() {
+        let viewer = caller(&["viewer"]);
+        assert!(validate(&request(None, None), &viewer).is_none());
+        assert!(validate(&request(Some("alice"), None), &viewer).is_none());
+        let rejected = validate(&request(Some("bob"), None), &viewer).unwrap();
+        assert_eq!(rejected.status(), StatusCode::FORBIDDEN);
+
+        let admin = caller(&["admin"]);
+        assert!(validate(&request(Some("bob"), None), &admin).is_none());
+    }
+
+    #[test]
+    fn rejects_tokens_that_expire_at_once() {
+        let viewer = caller(&["viewer"]);
+        let rejected = validate(&request(None, Some(0)), &viewer).unwrap();
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+        assert!(validate(&request(None, Some(1)), &viewer).is_none());
+    }
+}
@@ -0,0 +1,454 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::AppState;
+use crate::chapters::{self, Chapter};
+use crate::storage::{StorageError, StorageFile};
+
+mod webm;
+
+/// How much of a WebM file is read at first, and at most, to find its tracks.
+const WEBM_FIRST_READ: u64 = 64 * 1024;
+const WEBM_MAX_READ: u64 = 4 * 1024 * 1024;
+/// Titles read from a QuickTime chapter track, one range request each.
+const MAX_CHAPTER_SAMPLES: usize = 500;
+
+/// What the container headers of a video file say about it.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaInfo {
+    /// `mp4`, `webm` or `matroska`.
+    pub container: String,
+    /// In seconds, unknown for some live recordings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// File size in bytes.
+    pub size: u64,
+    /// Average bitrate of the whole file in bits/s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoStream>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub audio: Vec<AudioStream>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VideoStream {
+    /// RFC 6381 codec string for MP4, the Matroska codec id otherwise.
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame_rate: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioStream {
+    pub codec: String,
+    pub channels: u16,
+    pub sample_rate: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    Storage(StorageError),
+    /// Neither MP4 nor WebM, or headers that can not be read.
+    Unsupported(String),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeError::Storage(e) => write!(f, "video storage: {e}"),
+            ProbeError::Unsupported(what) => write!(f, "{what}"),
+        }
+    }
+}
+
+impl From<StorageError> for ProbeError {
+    fn from(e: StorageError) -> Self {
+        ProbeError::Storage(e)
+    }
+}
+
+impl From<mp4::Error> for ProbeError {
+    fn from(e: mp4::Error) -> Self {
+        ProbeError::Unsupported(e.to_string())
+    }
+}
+
+/// Reads the container headers of a stored file with range requests, and the
+/// chapters embedded in MP4 files.
+pub async fn probe(
+    app_state: &AppState,
+    file_path: &str,
+) -> Result<(MediaInfo, Vec<Chapter>), ProbeError> {
+    let file = StorageFile::new(app_state, file_path);
+    let size = file
+        .size()
+        .await?
+        .ok_or_else(|| ProbeError::Unsupported("unknown file size".to_string()))?;
+    let start = file.read(0, 16).await?.unwrap_or_default();
+    let (mut info, chapters) = if webm::is_webm(&start) {
+        (probe_webm(&file, size).await?, Vec::new())
+    } else {
+        probe_mp4(&file).await?
+    };
+    info.size = size;
+    info.bitrate = info
+        .duration
+        .filter(|duration| *duration > 0.0)
+        .map(|duration| (size as f64 * 8.0 / duration).round() as u64);
+    let chapters = chapters::from_file(chapters, info.duration);
+    Ok((info, chapters))
+}
+
+async fn probe_mp4(file: &StorageFile) -> Result<(MediaInfo, Vec<mp4::Chapter>), ProbeError> {
+    let (_, moov) = file.mp4_boxes::<ProbeError>().await?;
+    let moov = moov.ok_or(mp4::Error::MissingBox("moov"))?;
+    let movie = mp4::Movie::parse_moov(&moov)?;
+    Ok((mp4_info(&movie), mp4_chapters(file, &movie).await?))
+}
+
+/// The streams of a movie. Size and bitrate are filled in by [`probe`].
+fn mp4_info(movie: &mp4::Movie) -> MediaInfo {
+    let video = movie.video_track().map(|track| VideoStream {
+        codec: track.entry.codec.clone(),
+        width: track.entry.width as u32,
+        height: track.entry.height as u32,
+        // Fragmented files have their samples in the fragments.
+        frame_rate: (!track.samples.is_empty() && track.duration_secs() > 0.0)
+            .then(|| round_frame_rate(track.samples.len() as f64 / track.duration_secs())),
+    });
+    let audio = movie
+        .tracks
+        .iter()
+        .filter(|track| track.kind == mp4::TrackKind::Audio)
+        .map(|track| AudioStream {
+            codec: track.entry.codec.clone(),
+            channels: track.entry.channels,
+            sample_rate: track.entry.sample_rate,
+            language: (track.language != "und").then(|| track.language.clone()),
+        })
+        .collect();
+    MediaInfo {
+        container: "mp4".to_string(),
+        duration: Some(movie.duration_secs()),
+        size: 0,
+        bitrate: None,
+        video,
+        audio,
+    }
+}
+
+/// Chapters from the Nero `chpl` box, or else from a QuickTime chapter track.
+/// Chapters that can not be read are left out rather than failing the probe.
+async fn mp4_chapters(
+    file: &StorageFile,
+    movie: &mp4::Movie,
+) -> Result<Vec<mp4::Chapter>, StorageError> {
+    let chapters = movie.nero_chapters().unwrap_or_default();
+    if !chapters.is_empty() {
+        return Ok(chapters);
+    }
+    let Some(track) = movie.chapter_track() else {
+        return Ok(Vec::new());
+    };
+    let mut chapters = Vec::new();
+    for sample in track.samples.iter().take(MAX_CHAPTER_SAMPLES) {
+        let Some(data) = file.read(sample.offset, sample.size as u64).await? else {
+            break;
+        };
+        if let Ok(title) = mp4::chapter_title(&data) {
+            chapters.push(mp4::Chapter {
+                start: sample.composition_time() as f64 / track.timescale as f64,
+                title,
+            });
+        }
+    }
+    Ok(chapters)
+}
+
+async fn probe_webm(file: &StorageFile, size: u64) -> Result<MediaInfo, ProbeError> {
+    let mut length = WEBM_FIRST_READ;
+    let parsed = loop {
+        let data = file.read(0, length.min(size)).await?.unwrap_or_default();
+        match webm::parse(&data) {
+            Ok(parsed) => break parsed,
+            Err(webm::Error::Truncated) if length < WEBM_MAX_READ && length < size => length *= 4,
+            Err(webm::Error::Truncated) => {
+                return Err(ProbeError::Unsupported(
+                    "no tracks at the start of the file".to_string(),
+                ));
+            }
+            Err(webm::Error::Invalid(what)) => return Err(ProbeError::Unsupported(what.into())),
+        }
+    };
+    Ok(webm_info(parsed))
+}
+
+/// The streams of a WebM or Matroska file. Size and bitrate are filled in
+/// by [`probe`].
+fn webm_info(parsed: webm::Webm) -> MediaInfo {
+    let video = parsed
+        .tracks
+        .iter()
+        .find(|track| track.is_video())
+        .map(|track| VideoStream {
+            codec: track.codec_id.clone(),
+            width: track.width,
+            height: track.height,
+            frame_rate: track
+                .default_duration
+                .filter(|nanos| *nanos > 0)
+                .map(|nanos| round_frame_rate(1e9 / nanos as f64)),
+        });
+    let audio = parsed
+        .tracks
+        .iter()
+        .filter(|track| track.is_audio())
+        .map(|track| AudioStream {
+            codec: track.codec_id.clone(),
+            channels: track.channels,
+            sample_rate: track.sampling_frequency.round() as u32,
+            language: track.language.clone().filter(|language| language != "und"),
+        })
+        .collect();
+    MediaInfo {
+        container: parsed.doc_type,
+        duration: parsed.duration,
+        size: 0,
+        bitrate: None,
+        video,
+        audio,
+    }
+}
+
+/// 29.97002997 becomes 29.97.
+fn round_frame_rate(rate: f64) -> f64 {
+    (rate * 1000.0).round() / 1000.0
+}
+
+/// Probes a video in the background and stores the result on its record.
+pub fn spawn(app_state: AppState, id: ObjectId, video_path: String) {
+    tokio::spawn(async move {
+        let stored = match probe(&app_state, &video_path).await {
+            Ok((media, chapters)) => app_state
+                .videos
+                .set_media(&id, &video_path, media, chapters)
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match stored {
+            Ok(true) => println!("Probed {video_path} of video {id}"),
+            // The video has been changed or deleted in the meantime.
+            Ok(false) => {}
+            Err(e) => println!("Can not probe {video_path} of video {id}: {e}"),
+        }
+    });
+}
+
+/// `POST /videos/{id}/probe` probes the file of a video again, e.g. one
+/// created before probing existed, and answers with the result. Embedded
+/// chapters are imported if the video has none.
+pub async fn probe_video(State(app_state): State<AppState>, Path(id): Path<String>) -> Response {
+    let Ok(id) = ObjectId::from_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response();
+    };
+    let video = match app_state.videos.find_by_id(&id).await {
+        Ok(Some(video)) => video,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => {
+            eprintln!("Error accessing the video catalog: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response();
+        }
+    };
+    let (media, chapters) = match probe(&app_state, &video.video_path).await {
+        Ok(probed) => probed,
+        Err(ProbeError::Unsupported(what)) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, what).into_response();
+        }
+        Err(ProbeError::Storage(StorageError::Status(StatusCode::NOT_FOUND))) => {
+            return (StatusCode::NOT_FOUND, "Video file not found").into_response();
+        }
+        Err(e) => {
+            eprintln!("Error probing {}: {e}", video.video_path);
+            return (StatusCode::BAD_GATEWAY, "Video storage unavailable").into_response();
+        }
+    };
+    match app_state
+        .videos
+        .set_media(&id, &video.video_path, media.clone(), chapters)
+        .await
+    {
+        Ok(true) => Json(media).into_response(),
+        Ok(false) => (StatusCode::CONFLICT, "The video changed while probing").into_response(),
+        Err(e) => {
+            eprintln!("Error accessing the video catalog: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// A box with version 0 and no flags.
+    fn full_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        mp4_box(kind, &[&[0; 4], body].concat())
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    /// A track of `count` samples of `delta` units each, all in one chunk.
+    fn trak(
+        id: u32,
+        handler: &[u8; 4],
+        timescale: u32,
+        (count, delta): (u32, u32),
+        language: u16,
+        entry: Vec<u8>,
+    ) -> Vec<u8> {
+        let mut mdhd = u32s(&[0, 0, timescale, count * delta]);
+        mdhd.extend_from_slice(&language.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 2]);
+        let media_header = if handler == b"vide" {
+            full_box(b"vmhd", &[0; 8])
+        } else {
+            full_box(b"smhd", &[0; 4])
+        };
+        let stbl = [
+            full_box(b"stsd", &[u32s(&[1]), entry].concat()),
+            full_box(b"stts", &u32s(&[1, count, delta])),
+            full_box(b"stsc", &u32s(&[1, 1, count, 1])),
+            full_box(b"stsz", &u32s(&[100, count])),
+            full_box(b"stco", &u32s(&[1, 48])),
+        ]
+        .concat();
+        let minf = [media_header, mp4_box(b"dinf", &[]), mp4_box(b"stbl", &stbl)].concat();
+        let mdia = [
+            full_box(b"mdhd", &mdhd),
+            full_box(b"hdlr", &[&[0; 4], &handler[..], &[0; 13]].concat()),
+            mp4_box(b"minf", &minf),
+        ]
+        .concat();
+        let tkhd = full_box(b"tkhd", &u32s(&[0, 0, id, 0, 0]));
+        mp4_box(b"trak", &[tkhd, mp4_box(b"mdia", &mdia)].concat())
+    }
+
+    fn avc1() -> Vec<u8> {
+        let mut fields = vec![0; 78];
+        fields[24..26].copy_from_slice(&640u16.to_be_bytes());
+        fields[26..28].copy_from_slice(&360u16.to_be_bytes());
+        fields.extend(mp4_box(b"avcC", &[1, 0x64, 0x00, 0x1e, 0xff, 0xe1, 0]));
+        mp4_box(b"avc1", &fields)
+    }
+
+    fn opus() -> Vec<u8> {
+        let mut fields = vec![0; 28];
+        fields[16..18].copy_from_slice(&2u16.to_be_bytes());
+        fields[24..28].copy_from_slice(&(48_000u32 << 16).to_be_bytes());
+        mp4_box(b"Opus", &fields)
+    }
+
+    /// Packed ISO 639-2/T codes of the media header.
+    const ENG: u16 = (5 << 10) | (14 << 5) | 7;
+    const UND: u16 = (21 << 10) | (14 << 5) | 4;
+
+    /// Two seconds of 25 fps video, with English and undetermined audio.
+    fn moov() -> Vec<u8> {
+        [
+            full_box(b"mvhd", &u32s(&[0, 0, 1000, 2000])),
+            trak(1, b"vide", 12_800, (50, 512), UND, avc1()),
+            trak(2, b"soun", 48_000, (100, 960), ENG, opus()),
+            trak(3, b"soun", 48_000, (100, 960), UND, opus()),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn describes_the_streams_of_an_mp4_file() {
+        let movie = mp4::Movie::parse_moov(&mp4_box(b"moov", &moov())).unwrap();
+        let opus = |language: Option<&str>| AudioStream {
+            codec: "opus".to_string(),
+            channels: 2,
+            sample_rate: 48_000,
+            language: language.map(str::to_string),
+        };
+        assert_eq!(
+            mp4_info(&movie),
+            MediaInfo {
+                container: "mp4".to_string(),
+                duration: Some(2.0),
+                size: 0,
+                bitrate: None,
+                video: Some(VideoStream {
+                    codec: "avc1.64001e".to_string(),
+                    width: 640,
+                    height: 360,
+                    frame_rate: Some(25.0),
+                }),
+                audio: vec![opus(Some("eng")), opus(None)],
+            }
+        );
+    }
+
+    #[test]
+    fn describes_the_streams_of_a_webm_file() {
+        let parsed = webm::parse(&webm::tests::sample(Some(5000.0))).unwrap();
+        assert_eq!(
+            webm_info(parsed),
+            MediaInfo {
+                container: "webm".to_string(),
+                duration: Some(5.0),
+                size: 0,
+                bitrate: None,
+                video: Some(VideoStream {
+                    codec: "V_VP9".to_string(),
+                    width: 1280,
+                    height: 720,
+                    frame_rate: Some(29.97),
+                }),
+                audio: vec![AudioStream {
+                    codec: "A_OPUS".to_string(),
+                    channels: 2,
+                    sample_rate: 48_000,
+                    language: Some("de".to_string()),
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn rounds_frame_rates_to_three_decimals() {
+        assert_eq!(round_frame_rate(30_000.0 / 1001.0), 29.97);
+        assert_eq!(round_frame_rate(24_000.0 / 1001.0), 23.976);
+        assert_eq!(round_frame_rate(25.0), 25.0);
+    }
+}
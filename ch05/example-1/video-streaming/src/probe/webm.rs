@@ -0,0 +1,485 @@
+//! Just enough of EBML to read the segment information and track entries at
+//! the start of WebM and Matroska files.
+
+const EBML: u32 = 0x1A45_DFA3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const DURATION: u32 = 0x4489;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const LANGUAGE: u32 = 0x22_B59C;
+const LANGUAGE_BCP47: u32 = 0x22_B59D;
+const DEFAULT_DURATION: u32 = 0x23_E383;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CLUSTER: u32 = 0x1F43_B675;
+
+const TRACK_TYPE_VIDEO: u64 = 1;
+const TRACK_TYPE_AUDIO: u64 = 2;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The headers continue past the data read so far.
+    Truncated,
+    Invalid(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Webm {
+    /// `webm` or `matroska`.
+    pub doc_type: String,
+    /// Missing in live recordings.
+    pub duration: Option<f64>,
+    pub tracks: Vec<WebmTrack>,
+}
+
+#[derive(Debug, Default)]
+pub struct WebmTrack {
+    pub track_type: u64,
+    pub codec_id: String,
+    pub language: Option<String>,
+    /// Duration of a frame in nanoseconds.
+    pub default_duration: Option<u64>,
+    pub width: u32,
+    pub height: u32,
+    pub sampling_frequency: f64,
+    pub channels: u16,
+}
+
+impl WebmTrack {
+    pub fn is_video(&self) -> bool {
+        self.track_type == TRACK_TYPE_VIDEO
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.track_type == TRACK_TYPE_AUDIO
+    }
+}
+
+/// Whether `data` starts like an EBML document.
+pub fn is_webm(data: &[u8]) -> bool {
+    data.starts_with(&EBML.to_be_bytes())
+}
+
+/// Parses the start of a file up to its first cluster. Fails with
+/// [`Error::Truncated`] if `data` ends before the tracks have been read.
+pub fn parse(data: &[u8]) -> Result<Webm, Error> {
+    let mut reader = Reader { data, pos: 0 };
+    let (id, size) = reader.element()?;
+    if id != EBML {
+        return Err(Error::Invalid("not an EBML document"));
+    }
+    let header = reader.body(size)?;
+    let doc_type = children(header)
+        .find_map(|child| match child {
+            Ok((DOC_TYPE, body)) => Some(Ok(string(body))),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .transpose()?
+        .unwrap_or_else(|| "matroska".to_string());
+    if doc_type != "webm" && doc_type != "matroska" {
+        return Err(Error::Invalid("not a WebM or Matroska file"));
+    }
+
+    let (id, size) = reader.element()?;
+    if id != SEGMENT {
+        return Err(Error::Invalid("missing segment"));
+    }
+    // The segment usually has an unknown size; its children are read up to
+    // the first cluster, which comes after the headers.
+    let end = size.map_or(data.len(), |size| {
+        (reader.pos + size as usize).min(data.len())
+    });
+    let mut segment = Reader {
+        data: &data[..end],
+        pos: reader.pos,
+    };
+    let mut duration = None;
+    let mut tracks = None;
+    while tracks.is_none() || duration.is_none() {
+        if segment.pos == segment.data.len() {
+            break;
+        }
+        let (id, size) = match segment.element() {
+            // Elements after the tracks, such as cues, are not needed when
+            // the file has no duration.
+            Err(Error::Truncated) if tracks.is_some() => break,
+            element => element?,
+        };
+        if id == CLUSTER {
+            break;
+        }
+        let body = match segment.body(size) {
+            Err(Error::Truncated) if tracks.is_some() => break,
+            body => body?,
+        };
+        match id {
+            INFO => duration = info(body)?,
+            TRACKS => tracks = Some(track_entries(body)?),
+            _ => {}
+        }
+    }
+    Ok(Webm {
+        doc_type,
+        duration,
+        tracks: tracks.ok_or(Error::Truncated)?,
+    })
+}
+
+/// Duration in seconds, if the segment information has one.
+fn info(body: &[u8]) -> Result<Option<f64>, Error> {
+    let mut scale = 1_000_000;
+    let mut duration = None;
+    for child in children(body) {
+        match child? {
+            (TIMESTAMP_SCALE, body) => scale = uint(body),
+            (DURATION, body) => duration = Some(float(body)?),
+            _ => {}
+        }
+    }
+    Ok(duration.map(|duration| duration * scale as f64 / 1e9))
+}
+
+fn track_entries(body: &[u8]) -> Result<Vec<WebmTrack>, Error> {
+    let mut tracks = Vec::new();
+    for child in children(body) {
+        let (id, body) = child?;
+        if id != TRACK_ENTRY {
+            continue;
+        }
+        let mut track = WebmTrack {
+            channels: 1,
+            sampling_frequency: 8000.0,
+            ..Default::default()
+        };
+        let mut language = None;
+        for field in children(body) {
+            match field? {
+                (TRACK_TYPE, body) => track.track_type = uint(body),
+                (CODEC_ID, body) => track.codec_id = string(body),
+                (LANGUAGE, body) if language.is_none() => language = Some(string(body)),
+                // The BCP 47 tag takes precedence over the legacy one.
+                (LANGUAGE_BCP47, body) => track.language = Some(string(body)),
+                (DEFAULT_DURATION, body) => track.default_duration = Some(uint(body)),
+                (VIDEO, body) => {
+                    for field in children(body) {
+                        match field? {
+                            (PIXEL_WIDTH, body) => track.width = uint(body) as u32,
+                            (PIXEL_HEIGHT, body) => track.height = uint(body) as u32,
+                            _ => {}
+                        }
+                    }
+                }
+                (AUDIO, body) => {
+                    for field in children(body) {
+                        match field? {
+                            (SAMPLING_FREQUENCY, body) => track.sampling_frequency = float(body)?,
+                            (CHANNELS, body) => track.channels = uint(body) as u16,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Matroska's default language is English.
+        track.language = track
+            .language
+            .or(language)
+            .or_else(|| Some("eng".to_string()));
+        tracks.push(track);
+    }
+    Ok(tracks)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Reads an element header: the id with its length marker and the size,
+    /// which is `None` if unknown.
+    fn element(&mut self) -> Result<(u32, Option<u64>), Error> {
+        let start = self.pos;
+        let (_, id_length) = self.vint()?;
+        if id_length > 4 {
+            return Err(Error::Invalid("element id longer than 4 bytes"));
+        }
+        // Ids are compared including their length marker.
+        let id = uint(&self.data[start..self.pos]) as u32;
+        let (size, size_length) = self.vint()?;
+        let unknown = size == (1u64 << (7 * size_length)) - 1;
+        Ok((id, (!unknown).then_some(size)))
+    }
+
+    fn body(&mut self, size: Option<u64>) -> Result<&'a [u8], Error> {
+        let size = size.ok_or(Error::Invalid("element of unknown size"))? as usize;
+        let body = self
+            .data
+            .get(self.pos..self.pos.saturating_add(size))
+            .ok_or(Error::Truncated)?;
+        self.pos += size;
+        Ok(body)
+    }
+
+    /// Reads a variable length integer without its length marker.
+    fn vint(&mut self) -> Result<(u64, usize), Error> {
+        let first = *self.data.get(self.pos).ok_or(Error::Truncated)?;
+        if first == 0 {
+            return Err(Error::Invalid(
+                "variable length integer longer than 8 bytes",
+            ));
+        }
+        let length = first.leading_zeros() as usize + 1;
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + length)
+            .ok_or(Error::Truncated)?;
+        let value = bytes[1..]
+            .iter()
+            .fold((first as u64) & (0xFF >> length), |value, byte| {
+                value << 8 | *byte as u64
+            });
+        self.pos += length;
+        Ok((value, length))
+    }
+}
+
+/// The child elements of a master element that has been read completely.
+fn children(body: &[u8]) -> impl Iterator<Item = Result<(u32, &[u8]), Error>> {
+    let mut reader = Reader { data: body, pos: 0 };
+    std::iter::from_fn(move || {
+        if reader.pos >= reader.data.len() {
+            return None;
+        }
+        let child = reader
+            .element()
+            .and_then(|(id, size)| Ok((id, reader.body(size)?)))
+            // Within a complete parent, a child running past it is broken.
+            .map_err(|e| match e {
+                Error::Truncated => Error::Invalid("child element larger than its parent"),
+                e => e,
+            });
+        if child.is_err() {
+            reader.pos = reader.data.len();
+        }
+        Some(child)
+    })
+}
+
+fn uint(body: &[u8]) -> u64 {
+    body.iter().fold(0, |value, byte| value << 8 | *byte as u64)
+}
+
+fn float(body: &[u8]) -> Result<f64, Error> {
+    match body.len() {
+        0 => Ok(0.0),
+        4 => Ok(f32::from_be_bytes(body.try_into().unwrap()) as f64),
+        8 => Ok(f64::from_be_bytes(body.try_into().unwrap())),
+        _ => Err(Error::Invalid("float of invalid size")),
+    }
+}
+
+fn string(body: &[u8]) -> String {
+    let end = body
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(body.len());
+    String::from_utf8_lossy(&body[..end]).into_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// An element with a one byte size, or an eight byte one for larger bodies.
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut out: Vec<u8> = id
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        if body.len() < 0x7f {
+            out.push(0x80 | body.len() as u8);
+        } else {
+            out.push(0x01);
+            out.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        }
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn uint_element(id: u32, value: u64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(7);
+        element(id, &bytes[start..])
+    }
+
+    fn header(doc_type: &[u8]) -> Vec<u8> {
+        element(EBML, &element(DOC_TYPE, doc_type))
+    }
+
+    fn info(duration: Option<f64>) -> Vec<u8> {
+        let mut body = uint_element(TIMESTAMP_SCALE, 1_000_000);
+        if let Some(duration) = duration {
+            body.extend(element(DURATION, &duration.to_be_bytes()));
+        }
+        element(INFO, &body)
+    }
+
+    /// VP9 at 1280x720 and 29.97 fps, and stereo Opus in German.
+    fn tracks() -> Vec<u8> {
+        let mut video = uint_element(TRACK_TYPE, TRACK_TYPE_VIDEO);
+        video.extend(element(CODEC_ID, b"V_VP9"));
+        video.extend(uint_element(DEFAULT_DURATION, 33_366_667));
+        let mut picture = uint_element(PIXEL_WIDTH, 1280);
+        picture.extend(uint_element(PIXEL_HEIGHT, 720));
+        video.extend(element(VIDEO, &picture));
+
+        let mut audio = uint_element(TRACK_TYPE, TRACK_TYPE_AUDIO);
+        audio.extend(element(CODEC_ID, b"A_OPUS"));
+        audio.extend(element(LANGUAGE, b"ger"));
+        audio.extend(element(LANGUAGE_BCP47, b"de"));
+        let mut sound = element(SAMPLING_FREQUENCY, &48_000f32.to_be_bytes());
+        sound.extend(uint_element(CHANNELS, 2));
+        audio.extend(element(AUDIO, &sound));
+
+        let mut entries = element(TRACK_ENTRY, &video);
+        entries.extend(element(TRACK_ENTRY, &audio));
+        element(TRACKS, &entries)
+    }
+
+    /// A WebM file up to its first cluster, with a segment of unknown size
+    /// as live recorders and most muxers write it.
+    pub(crate) fn sample(duration: Option<f64>) -> Vec<u8> {
+        let mut file = header(b"webm");
+        file.extend_from_slice(&SEGMENT.to_be_bytes());
+        file.push(0xff);
+        file.extend(info(duration));
+        file.extend(tracks());
+        file.extend(element(CLUSTER, &[0xe7, 0x81, 0]));
+        file
+    }
+
+    #[test]
+    fn reads_the_duration_and_tracks() {
+        let webm = parse(&sample(Some(5000.0))).unwrap();
+        assert_eq!(webm.doc_type, "webm");
+        assert_eq!(webm.duration, Some(5.0));
+        assert_eq!(webm.tracks.len(), 2);
+
+        let video = &webm.tracks[0];
+        assert!(video.is_video());
+        assert_eq!(video.codec_id, "V_VP9");
+        assert_eq!((video.width, video.height), (1280, 720));
+        assert_eq!(video.default_duration, Some(33_366_667));
+        // English unless stated otherwise.
+        assert_eq!(video.language.as_deref(), Some("eng"));
+
+        let audio = &webm.tracks[1];
+        assert!(audio.is_audio());
+        assert_eq!(audio.codec_id, "A_OPUS");
+        assert_eq!((audio.channels, audio.sampling_frequency), (2, 48_000.0));
+        // The BCP 47 tag wins over the legacy one.
+        assert_eq!(audio.language.as_deref(), Some("de"));
+    }
+
+    #[test]
+    fn tracks_without_audio_fields_get_the_matroska_defaults() {
+        let entry = element(TRACK_ENTRY, &uint_element(TRACK_TYPE, TRACK_TYPE_AUDIO));
+        let tracks = track_entries(&entry).unwrap();
+        assert_eq!(
+            (tracks[0].channels, tracks[0].sampling_frequency),
+            (1, 8000.0)
+        );
+    }
+
+    #[test]
+    fn a_missing_duration_is_not_an_error() {
+        let webm = parse(&sample(None)).unwrap();
+        assert_eq!(webm.duration, None);
+        assert_eq!(webm.tracks.len(), 2);
+    }
+
+    #[test]
+    fn asks_for_more_data_until_the_tracks_are_complete() {
+        let file = sample(Some(5000.0));
+        let cluster = element(CLUSTER, &[0xe7, 0x81, 0]);
+        let tracks_end = file.len() - cluster.len();
+        for end in [0, 3, 20, tracks_end - 1] {
+            assert!(
+                matches!(parse(&file[..end]), Err(Error::Truncated)),
+                "{end}"
+            );
+        }
+        // What follows the tracks is not needed.
+        let webm = parse(&file[..tracks_end + 2]).unwrap();
+        assert_eq!(webm.tracks.len(), 2);
+    }
+
+    #[test]
+    fn rejects_other_documents() {
+        assert!(!is_webm(b"\0\0\0\x18ftypisom"));
+        assert!(is_webm(&sample(None)));
+        assert!(matches!(
+            parse(b"\x80\x80"),
+            Err(Error::Invalid("not an EBML document"))
+        ));
+        let mut file = header(b"mkv3d");
+        file.extend(element(SEGMENT, &[]));
+        assert!(matches!(
+            parse(&file),
+            Err(Error::Invalid("not a WebM or Matroska file"))
+        ));
+        let mut file = header(b"matroska");
+        file.extend(element(INFO, &[]));
+        assert!(matches!(
+            parse(&file),
+            Err(Error::Invalid("missing segment"))
+        ));
+    }
+
+    #[test]
+    fn rejects_broken_elements() {
+        // A variable length integer can not be longer than eight bytes.
+        assert!(matches!(parse(&[0, 0x42, 0x86]), Err(Error::Invalid(_))));
+        // A child claiming more bytes than its parent has.
+        let broken = element(TRACK_ENTRY, &[0x83, 0x84, 1]);
+        assert!(matches!(
+            track_entries(&broken),
+            Err(Error::Invalid("child element larger than its parent"))
+        ));
+        assert!(matches!(float(&[0; 3]), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn reads_numbers_and_strings() {
+        assert_eq!(uint(&[]), 0);
+        assert_eq!(uint(&[0x01, 0x00]), 256);
+        assert_eq!(float(&[]).unwrap(), 0.0);
+        assert_eq!(float(&1.5f32.to_be_bytes()).unwrap(), 1.5);
+        assert_eq!(string(b"eng\0\0"), "eng");
+
+        let mut reader = Reader {
+            data: &[0x1a, 0x45, 0xdf, 0xa3, 0x42, 0x86],
+            pos: 0,
+        };
+        assert_eq!(reader.element().unwrap(), (EBML, Some(0x286)));
+        let mut reader = Reader {
+            data: &[0xec, 0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            pos: 0,
+        };
+        // All ones is the unknown size, whatever its length.
+        assert_eq!(reader.element().unwrap(), (0xec, None));
+    }
+}
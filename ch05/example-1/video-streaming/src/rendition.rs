@@ -0,0 +1,335 @@
+use axum::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+/// Client hints `GET /video` asks browsers for with `Accept-CH`.
+pub const CLIENT_HINTS: &str = "Sec-CH-Viewport-Width, Sec-CH-DPR, Downlink, ECT, Save-Data";
+
+/// Share of the reported downlink a rendition may use, leaving headroom for
+/// throughput fluctuations.
+const BANDWIDTH_HEADROOM: f64 = 0.8;
+
+/// One encoding of a video.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Rendition {
+    pub width: u32,
+    pub height: u32,
+    /// Average bitrate in bits per second.
+    pub bitrate: u64,
+    /// RFC 6381 codec string, e.g. `avc1.64001f,mp4a.40.2`.
+    pub codec: String,
+    pub path: String,
+    /// Initialization section of a fragmented MP4 rendition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init: Option<ByteRange>,
+    /// Media segments of a fragmented MP4 rendition, in playback order.
+    /// Renditions with segments can be streamed with HLS.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<Segment>,
+}
+
+/// A contiguous part of a file.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Segment {
+    /// Playback duration in seconds.
+    pub duration: f64,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl Rendition {
+    /// Short name of the rendition, e.g. `720p`.
+    pub fn label(&self) -> String {
+        format!("{}p", self.height)
+    }
+
+    /// Whether the rendition has the segment index HLS playlists are made of.
+    pub fn is_segmented(&self) -> bool {
+        self.init.is_some() && !self.segments.is_empty()
+    }
+
+    /// Highest bitrate of any segment in bits per second, which is what HLS
+    /// expects as `BANDWIDTH`. Falls back to the average bitrate.
+    pub fn peak_bitrate(&self) -> u64 {
+        self.segments
+            .iter()
+            .filter(|segment| segment.duration > 0.0)
+            .map(|segment| (segment.length as f64 * 8.0 / segment.duration).ceil() as u64)
+            .max()
+            .unwrap_or(self.bitrate)
+            .max(self.bitrate)
+    }
+}
+
+/// The `quality` parameter of `GET /video`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quality {
+    /// Chosen from the client hints of the request.
+    Auto,
+    /// A frame height such as `720p`, served by the closest rendition.
+    Height(u32),
+}
+
+impl Quality {
+    pub fn parse(value: &str) -> Option<Self> {
+        if value == "auto" {
+            return Some(Quality::Auto);
+        }
+        match value.strip_suffix('p').unwrap_or(value).parse() {
+            Ok(height) if height > 0 => Some(Quality::Height(height)),
+            _ => None,
+        }
+    }
+}
+
+/// What a client tells about its display and network, see
+/// https://developer.mozilla.org/en-US/docs/Web/HTTP/Client_hints.
+#[derive(Debug, Default)]
+pub struct ClientHints {
+    /// Width of the viewport in CSS pixels.
+    pub viewport_width: Option<f64>,
+    /// Device pixels per CSS pixel.
+    pub dpr: Option<f64>,
+    /// Estimated bandwidth in megabits per second.
+    pub downlink: Option<f64>,
+    pub save_data: bool,
+}
+
+impl ClientHints {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name))
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+        };
+        let number = |names: &[&str]| {
+            header(names)
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|value| value.is_finite() && *value > 0.0)
+        };
+        // ECT is coarser than Downlink, so it only fills in when Downlink is missing.
+        let effective_type = header(&["ect"]).and_then(|ect| match ect {
+            "slow-2g" => Some(0.05),
+            "2g" => Some(0.07),
+            "3g" => Some(0.7),
+            _ => None,
+        });
+        Self {
+            viewport_width: number(&["sec-ch-viewport-width", "viewport-width"]),
+            dpr: number(&["sec-ch-dpr", "dpr"]),
+            downlink: number(&["downlink"]).or(effective_type),
+            save_data: header(&["save-data"]).is_some_and(|value| value == "on"),
+        }
+    }
+}
+
+/// Picks the rendition to serve. Returns `None` for videos without
+/// renditions, which are served from their `videoPath`.
+pub fn select<'a>(
+    renditions: &'a [Rendition],
+    quality: Quality,
+    hints: &ClientHints,
+) -> Option<&'a Rendition> {
+    match quality {
+        Quality::Height(height) => closest(renditions, height),
+        Quality::Auto => automatic(renditions, hints),
+    }
+}
+
+/// The rendition whose height is closest to `height`, the smaller one on ties.
+fn closest(renditions: &[Rendition], height: u32) -> Option<&Rendition> {
+    renditions
+        .iter()
+        .min_by_key(|rendition| (rendition.height.abs_diff(height), rendition.height))
+}
+
+/// Without hints this is the best rendition. With them it is the smallest one
+/// that covers the viewport in device pixels among those the downlink can
+/// sustain, and the cheapest one if the client asks to save data.
+fn automatic<'a>(renditions: &'a [Rendition], hints: &ClientHints) -> Option<&'a Rendition> {
+    let cheapest = renditions.iter().min_by_key(|rendition| rendition.bitrate);
+    if hints.save_data {
+        return cheapest;
+    }
+    let affordable: Vec<&Rendition> = match hints.downlink {
+        Some(megabits) => renditions
+            .iter()
+            .filter(|rendition| {
+                rendition.bitrate as f64 <= megabits * 1_000_000.0 * BANDWIDTH_HEADROOM
+            })
+            .collect(),
+        None => renditions.iter().collect(),
+    };
+    if affordable.is_empty() {
+        return cheapest;
+    }
+    let largest = affordable
+        .iter()
+        .copied()
+        .max_by_key(|rendition| (rendition.width, rendition.bitrate));
+    let Some(viewport_width) = hints.viewport_width else {
+        return largest;
+    };
+    let target = (viewport_width * hints.dpr.unwrap_or(1.0)).ceil() as u32;
+    affordable
+        .iter()
+        .copied()
+        .filter(|rendition| rendition.width >= target)
+        .min_by_key(|rendition| (rendition.width, rendition.bitrate))
+        .or(largest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn rendition(width: u32, height: u32, bitrate: u64) -> Rendition {
+        Rendition {
+            width,
+            height,
+            bitrate,
+            codec: "avc1.64001f,mp4a.40.2".to_string(),
+            path: format!("videos/sample-{height}p.mp4"),
+            init: None,
+            segments: Vec::new(),
+        }
+    }
+
+    /// 360p at 0.8, 720p at 2.5 and 1080p at 5 Mbit/s, out of order.
+    fn ladder() -> Vec<Rendition> {
+        vec![
+            rendition(1280, 720, 2_500_000),
+            rendition(640, 360, 800_000),
+            rendition(1920, 1080, 5_000_000),
+        ]
+    }
+
+    fn selected(renditions: &[Rendition], quality: Quality, hints: &ClientHints) -> Option<u32> {
+        select(renditions, quality, hints).map(|rendition| rendition.height)
+    }
+
+    fn hints(headers: &[(&'static str, &'static str)]) -> ClientHints {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.insert(*name, HeaderValue::from_static(value));
+        }
+        ClientHints::from_headers(&map)
+    }
+
+    #[test]
+    fn parses_quality() {
+        assert_eq!(Quality::parse("auto"), Some(Quality::Auto));
+        assert_eq!(Quality::parse("720p"), Some(Quality::Height(720)));
+        assert_eq!(Quality::parse("480"), Some(Quality::Height(480)));
+        assert_eq!(Quality::parse("0p"), None);
+        assert_eq!(Quality::parse("hd"), None);
+    }
+
+    #[test]
+    fn serves_an_explicit_quality_from_the_closest_height() {
+        let none = ClientHints::default();
+        assert_eq!(selected(&ladder(), Quality::Height(720), &none), Some(720));
+        assert_eq!(
+            selected(&ladder(), Quality::Height(1000), &none),
+            Some(1080)
+        );
+        assert_eq!(selected(&ladder(), Quality::Height(144), &none), Some(360));
+        // 540 is as close to 360 as to 720, and the smaller one wins.
+        assert_eq!(selected(&ladder(), Quality::Height(540), &none), Some(360));
+        // An explicit quality overrides the hints.
+        let save_data = hints(&[("save-data", "on")]);
+        assert_eq!(
+            selected(&ladder(), Quality::Height(1080), &save_data),
+            Some(1080)
+        );
+    }
+
+    #[test]
+    fn serves_the_best_rendition_without_hints() {
+        assert_eq!(
+            selected(&ladder(), Quality::Auto, &ClientHints::default()),
+            Some(1080)
+        );
+        assert_eq!(selected(&[], Quality::Auto, &ClientHints::default()), None);
+        assert_eq!(
+            selected(&[], Quality::Height(720), &ClientHints::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn serves_the_cheapest_rendition_to_save_data() {
+        let client = hints(&[("save-data", "on"), ("downlink", "100")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(360));
+    }
+
+    #[test]
+    fn stays_within_the_downlink() {
+        // 3.5 Mbit/s leaves 2.8 for the video.
+        let client = hints(&[("downlink", "3.5")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(720));
+        // Nothing fits, so the cheapest is all that can be done.
+        let client = hints(&[("downlink", "0.5")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(360));
+        // ECT only applies without Downlink.
+        let client = hints(&[("ect", "3g")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(360));
+        let client = hints(&[("ect", "3g"), ("downlink", "10")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(1080));
+    }
+
+    #[test]
+    fn covers_the_viewport_in_device_pixels() {
+        let client = hints(&[("sec-ch-viewport-width", "600")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(360));
+        let client = hints(&[("sec-ch-viewport-width", "600"), ("sec-ch-dpr", "2")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(720));
+        // Wider than every rendition, so the largest.
+        let client = hints(&[("viewport-width", "2560")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(1080));
+        // The viewport asks for 1080p, but the downlink only allows 720p.
+        let client = hints(&[("sec-ch-viewport-width", "1800"), ("downlink", "4")]);
+        assert_eq!(selected(&ladder(), Quality::Auto, &client), Some(720));
+    }
+
+    #[test]
+    fn ignores_invalid_hints() {
+        let client = hints(&[
+            ("sec-ch-viewport-width", "-1"),
+            ("sec-ch-dpr", "wide"),
+            ("downlink", "NaN"),
+            ("save-data", "off"),
+        ]);
+        assert_eq!(client.viewport_width, None);
+        assert_eq!(client.dpr, None);
+        assert_eq!(client.downlink, None);
+        assert!(!client.save_data);
+    }
+
+    #[test]
+    fn peak_bitrate_is_that_of_the_largest_segment() {
+        let mut rendition = rendition(1280, 720, 2_500_000);
+        assert_eq!(rendition.peak_bitrate(), 2_500_000);
+        rendition.segments = vec![
+            Segment {
+                duration: 2.0,
+                offset: 0,
+                length: 500_000,
+            },
+            Segment {
+                duration: 2.0,
+                offset: 500_000,
+                length: 1_000_000,
+            },
+        ];
+        assert_eq!(rendition.peak_bitrate(), 4_000_000);
+    }
+}
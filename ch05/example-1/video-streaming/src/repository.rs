@@ -0,0 +1,170 @@
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use std::fmt;
+
+use crate::chapters::Chapter;
+use crate::probe::MediaInfo;
+use crate::search::{SearchQuery, SearchResults};
+use crate::slug;
+use crate::tracks::{TextTrack, TextTrackKind};
+use crate::video::{Video, VideoInput};
+
+mod cached;
+mod memory;
+mod mongo;
+
+pub use cached::{CacheConfig, CachedVideoRepository, VideoCache};
+pub use memory::InMemoryVideoRepository;
+pub use mongo::MongoVideoRepository;
+
+/// Storage of the video catalog. The service only talks to the catalog through
+/// this trait so that the router can run against MongoDB as well as against
+/// the in-memory implementation used for tests and the offline demo mode.
+#[async_trait]
+pub trait VideoRepository: Send + Sync {
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<Video>, RepositoryError>;
+
+    /// Finds the video that currently has or used to have the given slug.
+    async fn find_by_slug(&self, slug: &str) -> Result<Option<Video>, RepositoryError>;
+
+    /// Lists the catalog ordered by id, leaving out unlisted and private
+    /// videos if `public_only` is set.
+    async fn list(
+        &self,
+        offset: u64,
+        limit: i64,
+        public_only: bool,
+    ) -> Result<Vec<Video>, RepositoryError>;
+
+    /// Fails with [`RepositoryError::Conflict`] if the requested slug is taken.
+    async fn create(&self, input: VideoInput) -> Result<Video, RepositoryError>;
+
+    /// Replaces the record, returning `None` if no video has the given id. A
+    /// changed slug is kept in the record's previous slugs for redirects, and
+    /// what the input omits is kept as [`VideoInput::keep_stored`] describes.
+    async fn update(
+        &self,
+        id: &ObjectId,
+        input: VideoInput,
+    ) -> Result<Option<Video>, RepositoryError>;
+
+    /// Stores the probe results of `video_path` and the duration found in
+    /// them, and the chapters embedded in the file unless the video has
+    /// chapters already. Returns false if the video is gone or has another
+    /// file by now.
+    async fn set_media(
+        &self,
+        id: &ObjectId,
+        video_path: &str,
+        media: MediaInfo,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Replaces the chapters of a video. Returns false if no video has the
+    /// given id.
+    async fn set_chapters(
+        &self,
+        id: &ObjectId,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Adds a text track to a video, replacing the one of the same language
+    /// and kind. Returns false if no video has the given id.
+    async fn set_text_track(
+        &self,
+        id: &ObjectId,
+        track: TextTrack,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Returns whether the video had a text track of the language and kind.
+    async fn remove_text_track(
+        &self,
+        id: &ObjectId,
+        language: &str,
+        kind: TextTrackKind,
+    ) -> Result<bool, RepositoryError>;
+
+    /// Returns whether a record was deleted.
+    async fn delete(&self, id: &ObjectId) -> Result<bool, RepositoryError>;
+
+    /// Searches the public part of the catalog.
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, RepositoryError>;
+}
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    Database(mongodb::error::Error),
+    Conflict(String),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Database(e) => write!(f, "database error: {e}"),
+            RepositoryError::Conflict(message) => write!(f, "conflict: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+impl From<mongodb::error::Error> for RepositoryError {
+    fn from(e: mongodb::error::Error) -> Self {
+        RepositoryError::Database(e)
+    }
+}
+
+/// Works out the slug of a video that is created (`existing` is `None`) or
+/// updated, together with the previous slugs to keep for redirects.
+async fn assign_slug<R: VideoRepository + ?Sized>(
+    repository: &R,
+    input: &VideoInput,
+    existing: Option<&Video>,
+) -> Result<(String, Vec<String>), RepositoryError> {
+    let id = existing.map(|video| video.id);
+    let current = existing.and_then(|video| video.slug.clone());
+    let slug = match (&input.slug, current) {
+        (Some(requested), _) => match repository.find_by_slug(requested).await? {
+            Some(owner) if Some(owner.id) != id => {
+                return Err(RepositoryError::Conflict(format!(
+                    "slug {requested} is already in use"
+                )));
+            }
+            _ => requested.clone(),
+        },
+        (None, Some(current)) => current,
+        (None, None) => {
+            let title = if input.title.trim().is_empty() {
+                file_stem(&input.video_path)
+            } else {
+                &input.title
+            };
+            let base = slug::slugify(title);
+            let mut attempt = 1;
+            loop {
+                let candidate = slug::candidate(&base, attempt);
+                match repository.find_by_slug(&candidate).await? {
+                    Some(owner) if Some(owner.id) != id => attempt += 1,
+                    _ => break candidate,
+                }
+            }
+        }
+    };
+
+    let mut previous_slugs = existing
+        .map(|video| video.previous_slugs.clone())
+        .unwrap_or_default();
+    if let Some(old) = existing.and_then(|video| video.slug.as_ref())
+        && *old != slug
+        && !previous_slugs.contains(old)
+    {
+        previous_slugs.push(old.clone());
+    }
+    previous_slugs.retain(|previous| *previous != slug);
+    Ok((slug, previous_slugs))
+}
+
+fn file_stem(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
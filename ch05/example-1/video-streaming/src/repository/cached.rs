@@ -0,0 +1,459 @@
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::{RepositoryError, VideoRepository};
+use crate::chapters::Chapter;
+use crate::probe::MediaInfo;
+use crate::search::{SearchQuery, SearchResults};
+use crate::tracks::{TextTrack, TextTrackKind};
+use crate::video::{Video, VideoInput};
+
+pub struct CacheConfig {
+    /// Maximum number of ids kept, including negative entries.
+    pub capacity: usize,
+    pub ttl: Duration,
+    /// How long an unknown id is remembered as missing.
+    pub negative_ttl: Duration,
+}
+
+struct Entry {
+    video: Option<Video>,
+    expires_at: Instant,
+    /// Position of the entry in the eviction queue.
+    sequence: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<ObjectId, Entry>,
+    /// Ids in insertion order. Invalidated or replaced entries leave stale
+    /// items behind, which are recognised by their sequence number.
+    queue: VecDeque<(ObjectId, u64)>,
+    next_sequence: u64,
+    /// Bumped on every invalidation so that lookups which raced with an
+    /// invalidation do not put the outdated record back into the cache.
+    generation: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheMetrics {
+    hits: u64,
+    negative_hits: u64,
+    misses: u64,
+    hit_rate: f64,
+    evictions: u64,
+    invalidations: u64,
+    size: usize,
+    capacity: usize,
+}
+
+/// Bounded id→video cache with expiry. Invalidations come from the write
+/// methods of [`CachedVideoRepository`] and, for MongoDB, from a change stream.
+pub struct VideoCache {
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    invalidations: AtomicU64,
+}
+
+impl VideoCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            negative_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached lookup result, `Ok(None)` being a cached miss, or
+    /// the generation to pass to [`VideoCache::insert`] after a lookup.
+    fn get(&self, id: &ObjectId) -> Result<Option<Video>, u64> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.map.get(id) {
+            Some(entry) if entry.expires_at > Instant::now() => {
+                if entry.video.is_some() {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    self.negative_hits.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(entry.video.clone())
+            }
+            Some(_) => {
+                entries.map.remove(id);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Err(entries.generation)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Err(entries.generation)
+            }
+        }
+    }
+
+    fn insert(&self, id: ObjectId, video: Option<Video>, generation: u64) {
+        if self.config.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation != generation {
+            return;
+        }
+        let ttl = if video.is_some() {
+            self.config.ttl
+        } else {
+            self.config.negative_ttl
+        };
+        let sequence = entries.next_sequence;
+        entries.next_sequence += 1;
+        entries.queue.push_back((id, sequence));
+        entries.map.insert(
+            id,
+            Entry {
+                video,
+                expires_at: Instant::now() + ttl,
+                sequence,
+            },
+        );
+
+        while entries.map.len() > self.config.capacity {
+            let Some((oldest, sequence)) = entries.queue.pop_front() else {
+                break;
+            };
+            if entries.map.get(&oldest).map(|entry| entry.sequence) == Some(sequence) {
+                entries.map.remove(&oldest);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if entries.queue.len() > 2 * self.config.capacity {
+            let Entries { map, queue, .. } = &mut *entries;
+            queue.retain(|(id, sequence)| {
+                map.get(id).map(|entry| entry.sequence) == Some(*sequence)
+            });
+        }
+    }
+
+    pub fn invalidate(&self, id: &ObjectId) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.map.remove(id);
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn invalidate_all(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.map.clear();
+        entries.queue.clear();
+        self.invalidations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> CacheMetrics {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let negative_hits = self.negative_hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = hits + negative_hits + misses;
+        CacheMetrics {
+            hits,
+            negative_hits,
+            misses,
+            hit_rate: if lookups == 0 {
+                0.0
+            } else {
+                (hits + negative_hits) as f64 / lookups as f64
+            },
+            evictions: self.evictions.load(Ordering::Relaxed),
+            invalidations: self.invalidations.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().map.len(),
+            capacity: self.config.capacity,
+        }
+    }
+}
+
+/// Serves `find_by_id` from a [`VideoCache`] and forwards everything else to
+/// the wrapped repository, invalidating the ids it writes.
+pub struct CachedVideoRepository {
+    inner: Arc<dyn VideoRepository>,
+    cache: Arc<VideoCache>,
+}
+
+impl CachedVideoRepository {
+    pub fn new(inner: Arc<dyn VideoRepository>, cache: Arc<VideoCache>) -> Self {
+        Self { inner, cache }
+    }
+}
+
+#[async_trait]
+impl VideoRepository for CachedVideoRepository {
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<Video>, RepositoryError> {
+        let generation = match self.cache.get(id) {
+            Ok(video) => return Ok(video),
+            Err(generation) => generation,
+        };
+        let video = self.inner.find_by_id(id).await?;
+        self.cache.insert(*id, video.clone(), generation);
+        Ok(video)
+    }
+
+    async fn find_by_slug(&self, slug: &str) -> Result<Option<Video>, RepositoryError> {
+        self.inner.find_by_slug(slug).await
+    }
+
+    async fn list(
+        &self,
+        offset: u64,
+        limit: i64,
+        public_only: bool,
+    ) -> Result<Vec<Video>, RepositoryError> {
+        self.inner.list(offset, limit, public_only).await
+    }
+
+    async fn create(&self, input: VideoInput) -> Result<Video, RepositoryError> {
+        let video = self.inner.create(input).await?;
+        self.cache.invalidate(&video.id);
+        Ok(video)
+    }
+
+    async fn update(
+        &self,
+        id: &ObjectId,
+        input: VideoInput,
+    ) -> Result<Option<Video>, RepositoryError> {
+        let video = self.inner.update(id, input).await;
+        self.cache.invalidate(id);
+        video
+    }
+
+    async fn set_media(
+        &self,
+        id: &ObjectId,
+        video_path: &str,
+        media: MediaInfo,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError> {
+        let stored = self.inner.set_media(id, video_path, media, chapters).await;
+        self.cache.invalidate(id);
+        stored
+    }
+
+    async fn set_chapters(
+        &self,
+        id: &ObjectId,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError> {
+        let stored = self.inner.set_chapters(id, chapters).await;
+        self.cache.invalidate(id);
+        stored
+    }
+
+    async fn set_text_track(
+        &self,
+        id: &ObjectId,
+        track: TextTrack,
+    ) -> Result<bool, RepositoryError> {
+        let stored = self.inner.set_text_track(id, track).await;
+        self.cache.invalidate(id);
+        stored
+    }
+
+    async fn remove_text_track(
+        &self,
+        id: &ObjectId,
+        language: &str,
+        kind: TextTrackKind,
+    ) -> Result<bool, RepositoryError> {
+        let removed = self.inner.remove_text_track(id, language, kind).await;
+        self.cache.invalidate(id);
+        removed
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, RepositoryError> {
+        let deleted = self.inner.delete(id).await;
+        self.cache.invalidate(id);
+        deleted
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, RepositoryError> {
+        self.inner.search(query).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::InMemoryVideoRepository;
+    use crate::video::Visibility;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn cache(capacity: usize) -> VideoCache {
+        VideoCache::new(CacheConfig {
+            capacity,
+            ttl: TTL,
+            negative_ttl: TTL,
+        })
+    }
+
+    fn input(title: &str) -> VideoInput {
+        VideoInput {
+            video_path: format!("videos/{title}.mp4"),
+            renditions: None,
+            slug: None,
+            title: title.to_string(),
+            description: String::new(),
+            tags: Vec::new(),
+            duration: None,
+            visibility: Visibility::Public,
+            owner: None,
+            grants: None,
+        }
+    }
+
+    fn video(title: &str) -> Video {
+        Video::new(ObjectId::new(), input(title), title.to_string(), Vec::new())
+    }
+
+    /// Looks `video` up and stores it on a miss, like `find_by_id` does.
+    fn load(cache: &VideoCache, video: &Video) -> bool {
+        match cache.get(&video.id) {
+            Ok(_) => true,
+            Err(generation) => {
+                cache.insert(video.id, Some(video.clone()), generation);
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn serves_stored_videos_and_remembers_missing_ids() {
+        let cache = cache(10);
+        let video = video("cats");
+        assert!(!load(&cache, &video));
+        assert_eq!(cache.get(&video.id).unwrap().unwrap().title, "cats");
+
+        let missing = ObjectId::new();
+        let generation = cache.get(&missing).unwrap_err();
+        cache.insert(missing, None, generation);
+        assert!(cache.get(&missing).unwrap().is_none());
+
+        let metrics = cache.metrics();
+        assert_eq!(
+            (metrics.hits, metrics.negative_hits, metrics.misses),
+            (1, 1, 2)
+        );
+        assert_eq!(metrics.hit_rate, 0.5);
+        assert_eq!((metrics.size, metrics.capacity), (2, 10));
+    }
+
+    #[test]
+    fn evicts_the_oldest_entries_beyond_the_capacity() {
+        let cache = cache(2);
+        let videos: Vec<Video> = ["a", "b", "c"].into_iter().map(video).collect();
+        for video in &videos {
+            load(&cache, video);
+        }
+        assert!(cache.get(&videos[0].id).is_err());
+        assert!(cache.get(&videos[1].id).is_ok());
+        assert!(cache.get(&videos[2].id).is_ok());
+        let metrics = cache.metrics();
+        assert_eq!((metrics.evictions, metrics.size), (1, 2));
+    }
+
+    #[test]
+    fn replaced_entries_are_not_evicted_by_their_old_position() {
+        let cache = cache(2);
+        let (a, b, c) = (video("a"), video("b"), video("c"));
+        load(&cache, &a);
+        load(&cache, &b);
+        cache.invalidate(&a.id);
+        load(&cache, &a);
+        load(&cache, &c);
+        // b is the oldest entry now, not the first a.
+        assert!(cache.get(&a.id).is_ok());
+        assert!(cache.get(&b.id).is_err());
+        assert!(cache.get(&c.id).is_ok());
+    }
+
+    #[test]
+    fn a_disabled_cache_stores_nothing() {
+        let cache = cache(0);
+        let video = video("cats");
+        assert!(!load(&cache, &video));
+        assert!(!load(&cache, &video));
+        assert_eq!(cache.metrics().size, 0);
+    }
+
+    #[test]
+    fn expired_entries_are_looked_up_again() {
+        let cache = VideoCache::new(CacheConfig {
+            capacity: 10,
+            ttl: Duration::ZERO,
+            negative_ttl: TTL,
+        });
+        let video = video("cats");
+        load(&cache, &video);
+        assert!(cache.get(&video.id).is_err());
+        assert_eq!(cache.metrics().size, 0);
+    }
+
+    #[test]
+    fn a_load_that_raced_an_invalidation_is_not_stored() {
+        let cache = cache(10);
+        let video = video("cats");
+        let generation = cache.get(&video.id).unwrap_err();
+        // The record changes while the outdated one is being read.
+        cache.invalidate(&video.id);
+        cache.insert(video.id, Some(video.clone()), generation);
+        assert!(cache.get(&video.id).is_err());
+
+        let generation = cache.get(&video.id).unwrap_err();
+        cache.invalidate_all();
+        cache.insert(video.id, Some(video.clone()), generation);
+        assert!(cache.get(&video.id).is_err());
+    }
+
+    #[test]
+    fn invalidate_all_empties_the_cache() {
+        let cache = cache(10);
+        let (a, b) = (video("a"), video("b"));
+        load(&cache, &a);
+        load(&cache, &b);
+        cache.invalidate(&a.id);
+        cache.invalidate_all();
+        assert!(cache.get(&a.id).is_err());
+        assert!(cache.get(&b.id).is_err());
+        let metrics = cache.metrics();
+        assert_eq!((metrics.invalidations, metrics.size), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn writes_through_the_repository_invalidate_the_cache() {
+        let cache = Arc::new(cache(10));
+        let inner = Arc::new(InMemoryVideoRepository::new([]));
+        let repository = CachedVideoRepository::new(inner, cache.clone());
+        let created = repository.create(input("cats")).await.unwrap();
+
+        repository.find_by_id(&created.id).await.unwrap();
+        repository.find_by_id(&created.id).await.unwrap();
+        assert_eq!(cache.metrics().hits, 1);
+
+        repository.update(&created.id, input("dogs")).await.unwrap();
+        let found = repository.find_by_id(&created.id).await.unwrap().unwrap();
+        assert_eq!(found.title, "dogs");
+
+        repository.delete(&created.id).await.unwrap();
+        assert!(repository.find_by_id(&created.id).await.unwrap().is_none());
+    }
+}
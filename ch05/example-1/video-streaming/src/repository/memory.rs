@@ -0,0 +1,404 @@
+use async_trait::async_trait;
+use mongodb::bson::oid::ObjectId;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use super::{RepositoryError, VideoRepository, assign_slug};
+use crate::chapters::Chapter;
+use crate::probe::MediaInfo;
+use crate::search::{self, DurationBucket, ScoredVideo, SearchQuery, SearchResults, SearchSort};
+use crate::tracks::{TextTrack, TextTrackKind};
+use crate::video::{Video, VideoInput, Visibility};
+
+/// Same field weights as the MongoDB text index.
+const TITLE_WEIGHT: f64 = 10.0;
+const TAGS_WEIGHT: f64 = 5.0;
+const DESCRIPTION_WEIGHT: f64 = 1.0;
+
+/// Keeps the catalog in a map ordered by id. Used by tests and by the offline
+/// demo mode, where it is seeded from a JSON file instead of MongoDB.
+#[derive(Default)]
+pub struct InMemoryVideoRepository {
+    videos: RwLock<BTreeMap<ObjectId, Video>>,
+}
+
+impl InMemoryVideoRepository {
+    pub fn new(videos: impl IntoIterator<Item = Video>) -> Self {
+        Self {
+            videos: RwLock::new(videos.into_iter().map(|video| (video.id, video)).collect()),
+        }
+    }
+}
+
+#[async_trait]
+impl VideoRepository for InMemoryVideoRepository {
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<Video>, RepositoryError> {
+        Ok(self.videos.read().unwrap().get(id).cloned())
+    }
+
+    async fn find_by_slug(&self, slug: &str) -> Result<Option<Video>, RepositoryError> {
+        let videos = self.videos.read().unwrap();
+        Ok(videos
+            .values()
+            .find(|video| {
+                video.slug.as_deref() == Some(slug)
+                    || video.previous_slugs.iter().any(|previous| previous == slug)
+            })
+            .cloned())
+    }
+
+    async fn list(
+        &self,
+        offset: u64,
+        limit: i64,
+        public_only: bool,
+    ) -> Result<Vec<Video>, RepositoryError> {
+        let videos = self.videos.read().unwrap();
+        Ok(videos
+            .values()
+            .filter(|video| !public_only || video.visibility == Visibility::Public)
+            .skip(usize::try_from(offset).unwrap_or(usize::MAX))
+            .take(usize::try_from(limit).unwrap_or(0))
+            .cloned()
+            .collect())
+    }
+
+    async fn create(&self, input: VideoInput) -> Result<Video, RepositoryError> {
+        let (slug, previous_slugs) = assign_slug(self, &input, None).await?;
+        let video = Video::new(ObjectId::new(), input, slug, previous_slugs);
+        self.videos.write().unwrap().insert(video.id, video.clone());
+        Ok(video)
+    }
+
+    async fn update(
+        &self,
+        id: &ObjectId,
+        mut input: VideoInput,
+    ) -> Result<Option<Video>, RepositoryError> {
+        let Some(existing) = self.find_by_id(id).await? else {
+            return Ok(None);
+        };
+        input.keep_stored(&existing);
+        let (slug, previous_slugs) = assign_slug(self, &input, Some(&existing)).await?;
+        let mut video = Video::new(*id, input, slug, previous_slugs);
+        video.keep_attached(&existing);
+        let mut videos = self.videos.write().unwrap();
+        Ok(videos.get_mut(id).map(|existing| {
+            *existing = video;
+            existing.clone()
+        }))
+    }
+
+    async fn set_media(
+        &self,
+        id: &ObjectId,
+        video_path: &str,
+        media: MediaInfo,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError> {
+        let mut videos = self.videos.write().unwrap();
+        match videos.get_mut(id) {
+            Some(video) if video.video_path == video_path => {
+                video.duration = media.duration.or(video.duration);
+                video.media = Some(media);
+                if video.chapters.is_empty() {
+                    video.chapters = chapters;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn set_chapters(
+        &self,
+        id: &ObjectId,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError> {
+        let mut videos = self.videos.write().unwrap();
+        let Some(video) = videos.get_mut(id) else {
+            return Ok(false);
+        };
+        video.chapters = chapters;
+        Ok(true)
+    }
+
+    async fn set_text_track(
+        &self,
+        id: &ObjectId,
+        track: TextTrack,
+    ) -> Result<bool, RepositoryError> {
+        let mut videos = self.videos.write().unwrap();
+        let Some(video) = videos.get_mut(id) else {
+            return Ok(false);
+        };
+        match video
+            .text_tracks
+            .iter_mut()
+            .find(|existing| existing.language == track.language && existing.kind == track.kind)
+        {
+            Some(existing) => *existing = track,
+            None => video.text_tracks.push(track),
+        }
+        Ok(true)
+    }
+
+    async fn remove_text_track(
+        &self,
+        id: &ObjectId,
+        language: &str,
+        kind: TextTrackKind,
+    ) -> Result<bool, RepositoryError> {
+        let mut videos = self.videos.write().unwrap();
+        let Some(video) = videos.get_mut(id) else {
+            return Ok(false);
+        };
+        let count = video.text_tracks.len();
+        video
+            .text_tracks
+            .retain(|track| !(track.language == language && track.kind == kind));
+        Ok(video.text_tracks.len() < count)
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, RepositoryError> {
+        Ok(self.videos.write().unwrap().remove(id).is_some())
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, RepositoryError> {
+        let terms = query
+            .text
+            .as_deref()
+            .map(search::query_terms)
+            .unwrap_or_default();
+        let videos = self.videos.read().unwrap();
+        let mut matches: Vec<ScoredVideo> = videos
+            .values()
+            .filter(|video| video.visibility == Visibility::Public)
+            .filter(|video| query.tags.iter().all(|tag| video.tags.contains(tag)))
+            .filter(|video| {
+                query.duration.is_none_or(|bucket| {
+                    let known = video.duration.filter(|duration| *duration >= 0.0);
+                    known.map(DurationBucket::of) == Some(bucket)
+                })
+            })
+            .filter_map(|video| {
+                if query.text.is_none() {
+                    return Some(ScoredVideo {
+                        video: video.clone(),
+                        score: None,
+                    });
+                }
+                let score = score(video, &terms);
+                (score > 0.0).then(|| ScoredVideo {
+                    video: video.clone(),
+                    score: Some(score),
+                })
+            })
+            .collect();
+
+        let mut tag_counts: HashMap<&str, u64> = HashMap::new();
+        let mut duration_counts: BTreeMap<Option<DurationBucket>, u64> = BTreeMap::new();
+        for hit in &matches {
+            for tag in &hit.video.tags {
+                *tag_counts.entry(tag).or_default() += 1;
+            }
+            let bucket = hit
+                .video
+                .duration
+                .filter(|duration| *duration >= 0.0)
+                .map(DurationBucket::of);
+            *duration_counts.entry(bucket).or_default() += 1;
+        }
+        let mut tag_facets: Vec<(String, u64)> = tag_counts
+            .into_iter()
+            .map(|(tag, count)| (tag.to_string(), count))
+            .collect();
+        tag_facets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        match query.sort {
+            SearchSort::Relevance => matches.sort_by(|a, b| {
+                b.score
+                    .partial_cmp(&a.score)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then_with(|| a.video.title.cmp(&b.video.title))
+            }),
+            SearchSort::Title => matches.sort_by(|a, b| a.video.title.cmp(&b.video.title)),
+            SearchSort::Duration => matches.sort_by(|a, b| {
+                a.video
+                    .duration
+                    .partial_cmp(&b.video.duration)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+        }
+        let total = matches.len() as u64;
+        let hits = matches
+            .into_iter()
+            .skip(query.offset as usize)
+            .take(query.limit as usize)
+            .collect();
+
+        Ok(SearchResults {
+            total,
+            hits,
+            tag_facets,
+            // Known durations first, like the buckets of MongoDB.
+            duration_facets: duration_counts
+                .iter()
+                .filter(|(bucket, _)| bucket.is_some())
+                .chain(
+                    duration_counts
+                        .iter()
+                        .filter(|(bucket, _)| bucket.is_none()),
+                )
+                .map(|(bucket, count)| (*bucket, *count))
+                .collect(),
+        })
+    }
+}
+
+fn score(video: &Video, terms: &[String]) -> f64 {
+    let tags = video.tags.join(" ");
+    [
+        (video.title.as_str(), TITLE_WEIGHT),
+        (tags.as_str(), TAGS_WEIGHT),
+        (video.description.as_str(), DESCRIPTION_WEIGHT),
+    ]
+    .into_iter()
+    .map(|(field, weight)| {
+        let matches = search::query_terms(field)
+            .iter()
+            .filter(|word| terms.iter().any(|term| word.starts_with(term.as_str())))
+            .count();
+        matches as f64 * weight
+    })
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(title: &str, tags: &[&str], duration: Option<f64>, visibility: Visibility) -> Video {
+        let input = VideoInput {
+            video_path: format!("videos/{title}.mp4"),
+            renditions: None,
+            slug: None,
+            title: title.to_string(),
+            description: format!("All about {title}"),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            duration,
+            visibility,
+            owner: Some("alice".to_string()),
+            grants: None,
+        };
+        Video::new(ObjectId::new(), input, title.to_lowercase(), Vec::new())
+    }
+
+    fn query(text: Option<&str>) -> SearchQuery {
+        SearchQuery {
+            text: text.map(str::to_string),
+            tags: Vec::new(),
+            duration: None,
+            sort: if text.is_some() {
+                SearchSort::Relevance
+            } else {
+                SearchSort::Title
+            },
+            limit: 20,
+            offset: 0,
+        }
+    }
+
+    fn titles(results: &SearchResults) -> Vec<&str> {
+        results
+            .hits
+            .iter()
+            .map(|hit| hit.video.title.as_str())
+            .collect()
+    }
+
+    fn repository() -> InMemoryVideoRepository {
+        InMemoryVideoRepository::new([
+            video(
+                "Cats",
+                &["animals", "pets"],
+                Some(120.0),
+                Visibility::Public,
+            ),
+            video("Dogs", &["animals"], Some(600.0), Visibility::Public),
+            video("Cathedrals", &["travel"], Some(3600.0), Visibility::Public),
+            video("Broken", &["animals"], Some(-1.0), Visibility::Public),
+            video("Untimed", &[], None, Visibility::Public),
+            video("Secret cats", &["animals"], Some(60.0), Visibility::Private),
+            video(
+                "Unlisted cats",
+                &["animals"],
+                Some(60.0),
+                Visibility::Unlisted,
+            ),
+        ])
+    }
+
+    #[tokio::test]
+    async fn searches_public_videos_by_weighted_prefix() {
+        let results = repository().search(&query(Some("cat"))).await.unwrap();
+        assert_eq!(titles(&results), ["Cathedrals", "Cats"]);
+        assert_eq!(results.total, 2);
+        // A title match outweighs the description.
+        assert_eq!(
+            results.hits[0].score,
+            Some(TITLE_WEIGHT + DESCRIPTION_WEIGHT)
+        );
+
+        let results = repository().search(&query(Some("pets"))).await.unwrap();
+        assert_eq!(titles(&results), ["Cats"]);
+        assert_eq!(results.hits[0].score, Some(TAGS_WEIGHT));
+
+        let results = repository().search(&query(Some("zebras"))).await.unwrap();
+        assert!(results.hits.is_empty());
+    }
+
+    #[tokio::test]
+    async fn filters_by_tags_and_duration_and_counts_facets() {
+        let mut animals = query(None);
+        animals.tags = vec!["animals".to_string()];
+        let results = repository().search(&animals).await.unwrap();
+        assert_eq!(titles(&results), ["Broken", "Cats", "Dogs"]);
+        assert_eq!(results.tag_facets[0], ("animals".to_string(), 3));
+        assert_eq!(
+            results.duration_facets,
+            [
+                (Some(DurationBucket::Short), 1),
+                (Some(DurationBucket::Medium), 1),
+                (None, 1),
+            ]
+        );
+
+        let mut short = query(None);
+        short.duration = Some(DurationBucket::Short);
+        let results = repository().search(&short).await.unwrap();
+        // Negative durations are unknown, not short.
+        assert_eq!(titles(&results), ["Cats"]);
+
+        let mut long = query(None);
+        long.duration = Some(DurationBucket::Long);
+        let results = repository().search(&long).await.unwrap();
+        assert_eq!(titles(&results), ["Cathedrals"]);
+    }
+
+    #[tokio::test]
+    async fn sorts_and_pages_the_hits() {
+        let mut by_duration = query(None);
+        by_duration.sort = SearchSort::Duration;
+        by_duration.tags = vec!["animals".to_string()];
+        let results = repository().search(&by_duration).await.unwrap();
+        assert_eq!(titles(&results), ["Broken", "Cats", "Dogs"]);
+
+        let mut page = query(None);
+        page.offset = 1;
+        page.limit = 2;
+        let results = repository().search(&page).await.unwrap();
+        assert_eq!(results.total, 5);
+        assert_eq!(titles(&results), ["Cathedrals", "Cats"]);
+    }
+}
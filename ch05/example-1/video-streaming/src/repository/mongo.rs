@@ -0,0 +1,515 @@
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{
+    Collection, IndexModel,
+    bson::{Bson, Document, doc, oid::ObjectId},
+    change_stream::event::{ChangeStreamEvent, OperationType},
+    error::{ErrorKind, WriteFailure},
+    options::IndexOptions,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{RepositoryError, VideoCache, VideoRepository, assign_slug};
+use crate::chapters::Chapter;
+use crate::probe::MediaInfo;
+use crate::search::{self, DurationBucket, ScoredVideo, SearchQuery, SearchResults, SearchSort};
+use crate::tracks::{TextTrack, TextTrackKind};
+use crate::video::{Video, VideoInput};
+
+pub struct MongoVideoRepository {
+    videos: Collection<Video>,
+}
+
+impl MongoVideoRepository {
+    pub fn new(videos: Collection<Video>) -> Self {
+        Self { videos }
+    }
+
+    /// Creates the weighted text index the search relies on and the unique
+    /// index on `slugs`, after adding `slugs` to records written before it
+    /// was stored. Creating an index that already exists with the same
+    /// definition is a no-op in MongoDB.
+    pub async fn ensure_indexes(&self) -> Result<(), RepositoryError> {
+        self.videos
+            .update_many(
+                doc! { "slugs": { "$exists": false }, "slug": { "$type": "string" } },
+                vec![doc! {
+                    "$set": {
+                        "slugs": {
+                            "$concatArrays": [["$slug"], { "$ifNull": ["$previousSlugs", []] }]
+                        }
+                    }
+                }],
+            )
+            .await?;
+        let text_index = IndexModel::builder()
+            .keys(doc! { "title": "text", "description": "text", "tags": "text" })
+            .options(
+                IndexOptions::builder()
+                    .name("catalog_text".to_string())
+                    .weights(doc! { "title": 10, "tags": 5, "description": 1 })
+                    .build(),
+            )
+            .build();
+        // Records created before slugs were introduced have no `slugs`.
+        let slugs_index = IndexModel::builder()
+            .keys(doc! { "slugs": 1 })
+            .options(IndexOptions::builder().unique(true).sparse(true).build())
+            .build();
+        self.videos
+            .create_indexes([text_index, slugs_index])
+            .await?;
+        Ok(())
+    }
+
+    /// Invalidates cached records whenever the `videos` collection changes.
+    /// Change streams require a replica set; until one can be opened, and
+    /// while it is reopened after an error, cached records only expire by
+    /// TTL. Reopened streams resume after the last change that was seen, and
+    /// the whole cache is invalidated on every reconnect, as changes may have
+    /// been missed all the same.
+    pub fn spawn_change_listener(&self, cache: Arc<VideoCache>) {
+        let videos = self.videos.clone_with_type::<Document>();
+        tokio::spawn(async move {
+            let mut backoff = CHANGE_STREAM_MIN_BACKOFF;
+            let mut resume_token = None;
+            loop {
+                match videos.watch().resume_after(resume_token.clone()).await {
+                    Ok(mut changes) => {
+                        println!("Watching the videos collection for changes");
+                        backoff = CHANGE_STREAM_MIN_BACKOFF;
+                        loop {
+                            match changes.try_next().await {
+                                Ok(Some(event)) => invalidate(&cache, event),
+                                Ok(None) => break,
+                                Err(e) => {
+                                    eprintln!("Error reading the videos change stream: {e}");
+                                    break;
+                                }
+                            }
+                            resume_token = changes.resume_token();
+                        }
+                    }
+                    Err(e) if resume_token.is_some() && is_lost_history(&e) => {
+                        eprintln!("Can not resume the videos change stream, restarting it: {e}");
+                        resume_token = None;
+                    }
+                    Err(e) => eprintln!(
+                        "Error opening the videos change stream, retrying in {backoff:?}: {e}"
+                    ),
+                }
+                cache.invalidate_all();
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(CHANGE_STREAM_MAX_BACKOFF);
+            }
+        });
+    }
+}
+
+const CHANGE_STREAM_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const CHANGE_STREAM_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Whether a change stream can not be resumed because the oplog no longer
+/// holds the change of its resume token.
+fn is_lost_history(e: &mongodb::error::Error) -> bool {
+    const INVALID_RESUME_TOKEN: i32 = 260;
+    const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Command(error)
+            if error.code == INVALID_RESUME_TOKEN || error.code == CHANGE_STREAM_HISTORY_LOST
+    )
+}
+
+fn invalidate(cache: &VideoCache, event: ChangeStreamEvent<Document>) {
+    let id = event
+        .document_key
+        .as_ref()
+        .and_then(|key| key.get_object_id("_id").ok());
+    match (event.operation_type, id) {
+        (
+            OperationType::Insert
+            | OperationType::Update
+            | OperationType::Replace
+            | OperationType::Delete,
+            Some(id),
+        ) => cache.invalidate(&id),
+        _ => cache.invalidate_all(),
+    }
+}
+
+#[async_trait]
+impl VideoRepository for MongoVideoRepository {
+    async fn find_by_id(&self, id: &ObjectId) -> Result<Option<Video>, RepositoryError> {
+        Ok(self.videos.find_one(doc! { "_id": id }).await?)
+    }
+
+    async fn find_by_slug(&self, slug: &str) -> Result<Option<Video>, RepositoryError> {
+        Ok(self.videos.find_one(doc! { "slugs": slug }).await?)
+    }
+
+    async fn list(
+        &self,
+        offset: u64,
+        limit: i64,
+        public_only: bool,
+    ) -> Result<Vec<Video>, RepositoryError> {
+        let filter = if public_only {
+            public_filter()
+        } else {
+            doc! {}
+        };
+        let cursor = self
+            .videos
+            .find(filter)
+            .sort(doc! { "_id": 1 })
+            .skip(offset)
+            .limit(limit)
+            .await?;
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn create(&self, input: VideoInput) -> Result<Video, RepositoryError> {
+        let (slug, previous_slugs) = assign_slug(self, &input, None).await?;
+        let video = Video::new(ObjectId::new(), input, slug, previous_slugs);
+        self.videos
+            .clone_with_type::<Document>()
+            .insert_one(record(&video)?)
+            .await
+            .map_err(slug_conflict)?;
+        Ok(video)
+    }
+
+    async fn update(
+        &self,
+        id: &ObjectId,
+        mut input: VideoInput,
+    ) -> Result<Option<Video>, RepositoryError> {
+        let Some(existing) = self.find_by_id(id).await? else {
+            return Ok(None);
+        };
+        input.keep_stored(&existing);
+        let (slug, previous_slugs) = assign_slug(self, &input, Some(&existing)).await?;
+        let mut video = Video::new(*id, input, slug, previous_slugs);
+        video.keep_attached(&existing);
+        let result = self
+            .videos
+            .clone_with_type::<Document>()
+            .replace_one(doc! { "_id": id }, record(&video)?)
+            .await
+            .map_err(slug_conflict)?;
+        Ok((result.matched_count > 0).then_some(video))
+    }
+
+    async fn set_media(
+        &self,
+        id: &ObjectId,
+        video_path: &str,
+        media: MediaInfo,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError> {
+        // A pipeline update, so that chapters are only imported into videos
+        // without chapters. `$literal` keeps strings starting with `$` from
+        // being read as paths.
+        let mut update = doc! {
+            "media": { "$literal": mongodb::bson::to_bson(&media).map_err(mongodb::error::Error::from)? }
+        };
+        if let Some(duration) = media.duration {
+            update.insert("duration", duration);
+        }
+        if !chapters.is_empty() {
+            let chapters =
+                mongodb::bson::to_bson(&chapters).map_err(mongodb::error::Error::from)?;
+            update.insert(
+                "chapters",
+                doc! {
+                    "$cond": [
+                        { "$gt": [{ "$size": { "$ifNull": ["$chapters", []] } }, 0] },
+                        "$chapters",
+                        { "$literal": chapters },
+                    ]
+                },
+            );
+        }
+        let result = self
+            .videos
+            .update_one(
+                doc! { "_id": id, "videoPath": video_path },
+                vec![doc! { "$set": update }],
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn set_chapters(
+        &self,
+        id: &ObjectId,
+        chapters: Vec<Chapter>,
+    ) -> Result<bool, RepositoryError> {
+        let chapters = mongodb::bson::to_bson(&chapters).map_err(mongodb::error::Error::from)?;
+        let result = self
+            .videos
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "chapters": chapters } },
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn set_text_track(
+        &self,
+        id: &ObjectId,
+        track: TextTrack,
+    ) -> Result<bool, RepositoryError> {
+        let (language, kind) = (track.language.clone(), track.kind.as_str());
+        let track = mongodb::bson::to_bson(&track).map_err(mongodb::error::Error::from)?;
+        // A pipeline update, so that the old track is replaced atomically.
+        // `$literal` keeps labels starting with `$` from being read as paths.
+        let update = vec![doc! {
+            "$set": {
+                "textTracks": {
+                    "$concatArrays": [
+                        {
+                            "$filter": {
+                                "input": { "$ifNull": ["$textTracks", []] },
+                                "cond": {
+                                    "$not": [{
+                                        "$and": [
+                                            { "$eq": ["$$this.language", language] },
+                                            { "$eq": ["$$this.kind", kind] },
+                                        ]
+                                    }]
+                                },
+                            }
+                        },
+                        { "$literal": [track] },
+                    ]
+                }
+            }
+        }];
+        let result = self.videos.update_one(doc! { "_id": id }, update).await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn remove_text_track(
+        &self,
+        id: &ObjectId,
+        language: &str,
+        kind: TextTrackKind,
+    ) -> Result<bool, RepositoryError> {
+        let result = self
+            .videos
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$pull": { "textTracks": { "language": language, "kind": kind.as_str() } } },
+            )
+            .await?;
+        Ok(result.modified_count > 0)
+    }
+
+    async fn delete(&self, id: &ObjectId) -> Result<bool, RepositoryError> {
+        let result = self.videos.delete_one(doc! { "_id": id }).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<SearchResults, RepositoryError> {
+        let mut filter = public_filter();
+        if let Some(text) = &query.text {
+            filter.insert("$text", doc! { "$search": text });
+        }
+        if !query.tags.is_empty() {
+            filter.insert("tags", doc! { "$all": &query.tags });
+        }
+        if let Some(bucket) = query.duration {
+            let (min, max) = bucket.range();
+            let mut range = doc! { "$gte": min };
+            if let Some(max) = max {
+                range.insert("$lt", max);
+            }
+            filter.insert("duration", range);
+        }
+        let sort = match query.sort {
+            SearchSort::Relevance => doc! { "score": -1, "title": 1 },
+            SearchSort::Title => doc! { "title": 1, "_id": 1 },
+            SearchSort::Duration => doc! { "duration": 1, "_id": 1 },
+        };
+
+        let mut pipeline = vec![doc! { "$match": filter }];
+        if query.text.is_some() {
+            pipeline.push(doc! { "$addFields": { "score": { "$meta": "textScore" } } });
+        }
+        pipeline.push(doc! {
+            "$facet": {
+                "results": [
+                    { "$sort": sort },
+                    { "$skip": i64::from(query.offset) },
+                    { "$limit": i64::from(query.limit) },
+                ],
+                "total": [{ "$count": "count" }],
+                "tags": [
+                    { "$unwind": "$tags" },
+                    { "$sortByCount": "$tags" },
+                ],
+                "duration": [{
+                    "$bucket": {
+                        "groupBy": "$duration",
+                        "boundaries": duration_boundaries(),
+                        "default": search::UNKNOWN_DURATION,
+                        "output": { "count": { "$sum": 1 } },
+                    }
+                }],
+            }
+        });
+
+        let mut cursor = self
+            .videos
+            .clone_with_type::<Document>()
+            .aggregate(pipeline)
+            .await?;
+        let facets = cursor.try_next().await?.unwrap_or_default();
+
+        let hits = documents(&facets, "results")
+            .filter_map(|document| {
+                let score = document.get_f64("score").ok();
+                let video = mongodb::bson::from_document::<Video>(document).ok()?;
+                Some(ScoredVideo { video, score })
+            })
+            .collect();
+        let total = documents(&facets, "total")
+            .next()
+            .and_then(|document| count(&document))
+            .unwrap_or(0);
+        let tag_facets = documents(&facets, "tags")
+            .filter_map(|document| {
+                Some((document.get_str("_id").ok()?.to_string(), count(&document)?))
+            })
+            .collect();
+        let duration_facets = documents(&facets, "duration")
+            .filter_map(|document| Some((duration_bucket(document.get("_id")?), count(&document)?)))
+            .collect();
+
+        Ok(SearchResults {
+            total,
+            hits,
+            tag_facets,
+            duration_facets,
+        })
+    }
+}
+
+/// Matches public videos, including the ones stored before videos had a
+/// visibility.
+fn public_filter() -> Document {
+    doc! { "visibility": { "$nin": ["unlisted", "private"] } }
+}
+
+/// The document stored for a video: its fields and `slugs`, its current and
+/// previous slugs together. A unique index on `slugs` keeps a slug from
+/// being used twice, also as the current slug of one video and a previous
+/// one of another, which separate indexes on `slug` and `previousSlugs`
+/// would let concurrent writes do.
+fn record(video: &Video) -> Result<Document, RepositoryError> {
+    let mut record = mongodb::bson::to_document(video).map_err(mongodb::error::Error::from)?;
+    let slugs = video
+        .slug
+        .iter()
+        .chain(&video.previous_slugs)
+        .collect::<Vec<_>>();
+    if !slugs.is_empty() {
+        record.insert("slugs", slugs);
+    }
+    Ok(record)
+}
+
+/// Maps a violation of the unique slug index, which means a concurrent
+/// write took the slug after [`assign_slug`] checked it, to a conflict.
+fn slug_conflict(e: mongodb::error::Error) -> RepositoryError {
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(error)) if error.code == 11000 => {
+            RepositoryError::Conflict("slug is already in use".to_string())
+        }
+        _ => RepositoryError::Database(e),
+    }
+}
+
+/// The boundaries of the duration buckets for `$bucket`, closed with the
+/// largest double so that the `long` bucket has an upper boundary and the
+/// `default` bucket only gets the videos without a valid duration.
+fn duration_boundaries() -> Vec<f64> {
+    let mut boundaries = DurationBucket::BOUNDARIES.to_vec();
+    boundaries.push(f64::MAX);
+    boundaries
+}
+
+/// `$bucket` reports buckets by their lower boundary, and the `default`
+/// bucket, `None`, by its name.
+fn duration_bucket(id: &Bson) -> Option<DurationBucket> {
+    match id {
+        Bson::Double(lower) => Some(DurationBucket::of(*lower)),
+        _ => None,
+    }
+}
+
+fn documents<'a>(facets: &'a Document, key: &str) -> impl Iterator<Item = Document> + 'a {
+    facets
+        .get_array(key)
+        .map(|values| values.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|value| value.as_document().cloned())
+}
+
+fn count(document: &Document) -> Option<u64> {
+    match document.get("count")? {
+        Bson::Int32(count) => u64::try_from(*count).ok(),
+        Bson::Int64(count) => u64::try_from(*count).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(slug: Option<&str>, previous_slugs: &[&str]) -> Video {
+        let input =
+            serde_json::from_value(serde_json::json!({ "videoPath": "intro.mp4" })).unwrap();
+        let mut video = Video::new(ObjectId::new(), input, String::new(), Vec::new());
+        video.slug = slug.map(str::to_string);
+        video.previous_slugs = previous_slugs.iter().map(|slug| slug.to_string()).collect();
+        video
+    }
+
+    #[test]
+    fn stores_current_and_previous_slugs_together() {
+        let stored = record(&video(Some("intro"), &["first", "second"])).unwrap();
+        assert_eq!(stored.get_str("slug").unwrap(), "intro");
+        let slugs = stored.get_array("slugs").unwrap();
+        assert_eq!(
+            slugs,
+            &vec![
+                Bson::from("intro"),
+                Bson::from("first"),
+                Bson::from("second")
+            ]
+        );
+
+        let stored = record(&video(Some("intro"), &[])).unwrap();
+        assert_eq!(
+            stored.get_array("slugs").unwrap(),
+            &vec![Bson::from("intro")]
+        );
+    }
+
+    #[test]
+    fn stores_no_slugs_for_videos_without_slug() {
+        let stored = record(&video(None, &[])).unwrap();
+        assert!(!stored.contains_key("slugs"));
+        assert_eq!(
+            mongodb::bson::from_document::<Video>(stored).unwrap().slug,
+            None
+        );
+    }
+}
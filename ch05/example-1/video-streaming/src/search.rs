@@ -0,0 +1,398 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+use crate::video::Video;
+
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 100;
+/// The duration facet of videos without a known duration.
+pub const UNKNOWN_DURATION: &str = "unknown";
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: Option<String>,
+    /// Comma separated list of tags, all of which have to be present on a hit.
+    tags: Option<String>,
+    /// One of `short`, `medium` or `long`.
+    duration: Option<String>,
+    sort: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+pub struct SearchQuery {
+    pub text: Option<String>,
+    pub tags: Vec<String>,
+    pub duration: Option<DurationBucket>,
+    pub sort: SearchSort,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+#[derive(Clone, Copy)]
+pub enum SearchSort {
+    Relevance,
+    Title,
+    Duration,
+}
+
+/// Duration facet of a video. Videos without a known duration are in none of
+/// them and counted as [`UNKNOWN_DURATION`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DurationBucket {
+    Short,
+    Medium,
+    Long,
+}
+
+impl DurationBucket {
+    /// Lower bounds in seconds of the `short`, `medium` and `long` buckets.
+    pub const BOUNDARIES: [f64; 3] = [0.0, 240.0, 1200.0];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "short" => Some(DurationBucket::Short),
+            "medium" => Some(DurationBucket::Medium),
+            "long" => Some(DurationBucket::Long),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DurationBucket::Short => "short",
+            DurationBucket::Medium => "medium",
+            DurationBucket::Long => "long",
+        }
+    }
+
+    pub fn of(seconds: f64) -> Self {
+        if seconds < Self::BOUNDARIES[1] {
+            DurationBucket::Short
+        } else if seconds < Self::BOUNDARIES[2] {
+            DurationBucket::Medium
+        } else {
+            DurationBucket::Long
+        }
+    }
+
+    /// The `[min, max)` range in seconds covered by the bucket.
+    pub fn range(self) -> (f64, Option<f64>) {
+        match self {
+            DurationBucket::Short => (Self::BOUNDARIES[0], Some(Self::BOUNDARIES[1])),
+            DurationBucket::Medium => (Self::BOUNDARIES[1], Some(Self::BOUNDARIES[2])),
+            DurationBucket::Long => (Self::BOUNDARIES[2], None),
+        }
+    }
+}
+
+pub struct ScoredVideo {
+    pub video: Video,
+    pub score: Option<f64>,
+}
+
+pub struct SearchResults {
+    pub total: u64,
+    pub hits: Vec<ScoredVideo>,
+    pub tag_facets: Vec<(String, u64)>,
+    /// `None` counts the videos without a known duration.
+    pub duration_facets: Vec<(Option<DurationBucket>, u64)>,
+}
+
+#[derive(Serialize)]
+struct SearchHit {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    slug: Option<String>,
+    title: String,
+    description: String,
+    tags: Vec<String>,
+    duration: Option<f64>,
+    score: Option<f64>,
+    highlights: Highlights,
+}
+
+#[derive(Serialize)]
+struct Highlights {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FacetCount {
+    value: String,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct Facets {
+    tags: Vec<FacetCount>,
+    duration: Vec<FacetCount>,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    total: u64,
+    results: Vec<SearchHit>,
+    facets: Facets,
+}
+
+pub async fn search(
+    State(app_state): State<AppState>,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    let text = params
+        .q
+        .as_deref()
+        .map(str::trim)
+        .filter(|q| !q.is_empty())
+        .map(str::to_string);
+    let duration = match params.duration.as_deref() {
+        None => None,
+        Some(name) => match DurationBucket::parse(name) {
+            Some(bucket) => Some(bucket),
+            None => {
+                return (
+                    axum::http::StatusCode::BAD_REQUEST,
+                    "duration must be one of short, medium or long",
+                )
+                    .into_response();
+            }
+        },
+    };
+    let sort = match (params.sort.as_deref(), &text) {
+        (None | Some("relevance"), Some(_)) => SearchSort::Relevance,
+        (None | Some("title"), _) => SearchSort::Title,
+        (Some("duration"), _) => SearchSort::Duration,
+        (Some("relevance"), None) => {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                "Sorting by relevance requires a query",
+            )
+                .into_response();
+        }
+        (Some(_), _) => {
+            return (
+                axum::http::StatusCode::BAD_REQUEST,
+                "sort must be one of relevance, title or duration",
+            )
+                .into_response();
+        }
+    };
+    let query = SearchQuery {
+        text,
+        tags: split_tags(params.tags.as_deref()),
+        duration,
+        sort,
+        limit: params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        offset: params.offset.unwrap_or(0),
+    };
+
+    let results = match app_state.videos.search(&query).await {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Error searching videos: {e}");
+            return (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error",
+            )
+                .into_response();
+        }
+    };
+
+    let terms = query.text.as_deref().map(query_terms).unwrap_or_default();
+    let hits = results
+        .hits
+        .into_iter()
+        .map(|hit| SearchHit {
+            id: hit.video.id.to_hex(),
+            slug: hit.video.slug,
+            highlights: Highlights {
+                title: highlight(&hit.video.title, &terms),
+                description: highlight(&hit.video.description, &terms),
+            },
+            title: hit.video.title,
+            description: hit.video.description,
+            tags: hit.video.tags,
+            duration: hit.video.duration,
+            score: hit.score,
+        })
+        .collect();
+
+    Json(SearchResponse {
+        total: results.total,
+        results: hits,
+        facets: Facets {
+            tags: results
+                .tag_facets
+                .into_iter()
+                .map(|(value, count)| FacetCount { value, count })
+                .collect(),
+            duration: results
+                .duration_facets
+                .into_iter()
+                .map(|(bucket, count)| FacetCount {
+                    value: bucket
+                        .map_or(UNKNOWN_DURATION, DurationBucket::name)
+                        .to_string(),
+                    count,
+                })
+                .collect(),
+        },
+    })
+    .into_response()
+}
+
+fn split_tags(tags: Option<&str>) -> Vec<String> {
+    tags.map(|tags| {
+        tags.split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+pub fn query_terms(query: &str) -> Vec<String> {
+    query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Wraps every word of `text` that starts with one of the query terms in
+/// `<em>` tags. MongoDB stems the query, so prefix matching catches the plural
+/// and inflected forms the text index matched on. Returns `None` when nothing
+/// matched so that clients can fall back to the plain field. The rest of the
+/// text is HTML-escaped, so a highlight can be inserted as markup as it is.
+fn highlight(text: &str, terms: &[String]) -> Option<String> {
+    if terms.is_empty() {
+        return None;
+    }
+    let mut highlighted = String::with_capacity(text.len());
+    let mut matched = false;
+    let mut word_start = None;
+    for (index, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if c.is_alphanumeric() {
+            word_start.get_or_insert(index);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            let word = &text[start..index];
+            let lower = word.to_lowercase();
+            if terms.iter().any(|term| lower.starts_with(term.as_str())) {
+                matched = true;
+                highlighted.push_str("<em>");
+                push_escaped(&mut highlighted, word);
+                highlighted.push_str("</em>");
+            } else {
+                push_escaped(&mut highlighted, word);
+            }
+        }
+        if index < text.len() {
+            push_escaped(&mut highlighted, &text[index..index + c.len_utf8()]);
+        }
+    }
+    matched.then_some(highlighted)
+}
+
+/// Appends `text` with the characters that are markup in HTML escaped, so
+/// that the `<em>` tags are the only markup of a highlight.
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(query: &str) -> Vec<String> {
+        query_terms(query)
+    }
+
+    #[test]
+    fn splits_queries_into_lowercase_words() {
+        assert_eq!(terms("Big  Buck-Bunny!"), ["big", "buck", "bunny"]);
+        assert_eq!(terms("Élan 4K"), ["élan", "4k"]);
+        assert!(terms(" ,.- ").is_empty());
+    }
+
+    #[test]
+    fn highlights_words_starting_with_a_term() {
+        assert_eq!(
+            highlight("Cats and a cathedral, not a bobcat", &terms("cat")).as_deref(),
+            Some("<em>Cats</em> and a <em>cathedral</em>, not a bobcat")
+        );
+        assert_eq!(
+            highlight("Big Buck Bunny", &terms("bunny big")).as_deref(),
+            Some("<em>Big</em> Buck <em>Bunny</em>")
+        );
+    }
+
+    #[test]
+    fn does_not_highlight_without_a_match_or_terms() {
+        assert_eq!(highlight("Big Buck Bunny", &terms("sintel")), None);
+        assert_eq!(highlight("Big Buck Bunny", &[]), None);
+    }
+
+    #[test]
+    fn escapes_markup_around_and_inside_highlights() {
+        assert_eq!(
+            highlight("<b>Tom & Jerry's</b> \"cartoon\"", &terms("tom cartoon")).as_deref(),
+            Some("&lt;b&gt;<em>Tom</em> &amp; Jerry&#39;s&lt;/b&gt; &quot;<em>cartoon</em>&quot;")
+        );
+    }
+
+    #[test]
+    fn escapes_the_markup_characters_of_html() {
+        let mut out = String::from("kept ");
+        push_escaped(&mut out, "<a href=\"x\">'&'</a>");
+        assert_eq!(
+            out,
+            "kept &lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn splits_tag_lists() {
+        assert_eq!(
+            split_tags(Some(" animals, ,pets,")),
+            ["animals".to_string(), "pets".to_string()]
+        );
+        assert!(split_tags(None).is_empty());
+    }
+
+    #[test]
+    fn buckets_durations() {
+        assert_eq!(DurationBucket::of(0.0), DurationBucket::Short);
+        assert_eq!(DurationBucket::of(239.9), DurationBucket::Short);
+        assert_eq!(DurationBucket::of(240.0), DurationBucket::Medium);
+        assert_eq!(DurationBucket::of(1200.0), DurationBucket::Long);
+        assert_eq!(
+            DurationBucket::parse("medium"),
+            Some(DurationBucket::Medium)
+        );
+        assert_eq!(DurationBucket::parse("huge"), None);
+    }
+}
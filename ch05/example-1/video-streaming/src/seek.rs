@@ -0,0 +1,219 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures::{StreamExt, future::Either, stream};
+use std::fmt;
+
+use crate::AppState;
+use crate::storage::{StorageError, StorageFile};
+
+enum SeekError {
+    Storage(StorageError),
+    Mp4(mp4::Error),
+    BeyondEnd,
+}
+
+impl fmt::Display for SeekError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeekError::Storage(e) => write!(f, "{e}"),
+            SeekError::Mp4(e) => write!(f, "{e}"),
+            SeekError::BeyondEnd => write!(f, "start is beyond the end"),
+        }
+    }
+}
+
+impl From<StorageError> for SeekError {
+    fn from(e: StorageError) -> Self {
+        SeekError::Storage(e)
+    }
+}
+
+impl From<mp4::Error> for SeekError {
+    fn from(e: mp4::Error) -> Self {
+        SeekError::Mp4(e)
+    }
+}
+
+/// Streams an MP4 that starts at the last keyframe at or before `start`
+/// seconds. Only the box headers and the `moov` of the file are read up
+/// front; the samples follow straight from video-storage. A `range` of the
+/// new file is answered with its part, like video-storage does for files.
+///
+/// Every request reads and parses the `moov` again, which takes a few range
+/// requests and up to the size of the `moov`. It is not cached, because
+/// players seek far less often than they fetch, and a cache would have to
+/// notice files that are replaced, as faststart does.
+pub async fn seek(
+    app_state: &AppState,
+    file_path: &str,
+    start: f64,
+    range: Option<&HeaderValue>,
+) -> Response {
+    let storage = StorageFile::new(app_state, file_path);
+    let trimmed = match trimmed(&storage, start).await {
+        Ok(trimmed) => trimmed,
+        Err(e) => return error_response(file_path, e),
+    };
+    let length = trimmed.len();
+    let requested = range.and_then(|range| requested_range(range, length));
+    let (first, last) = match requested {
+        Some(Ok(range)) => range,
+        Some(Err(())) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{length}"))],
+            )
+                .into_response();
+        }
+        None => (0, length - 1),
+    };
+
+    // The part of the rewritten header in the range, then the samples.
+    let header_length = trimmed.header.len() as u64;
+    let header = Bytes::from(trimmed.header)
+        .slice(first.min(header_length) as usize..(last + 1).min(header_length) as usize);
+    let samples = if last >= header_length {
+        let from = first.max(header_length) - header_length;
+        let request = storage.request(
+            trimmed.source.offset + from,
+            last + 1 - header_length - from,
+        );
+        match request.await {
+            Ok(samples) => Either::Left(samples.bytes_stream()),
+            Err(e) => return error_response(file_path, e.into()),
+        }
+    } else {
+        Either::Right(stream::empty())
+    };
+    let body = stream::once(async { Ok(header) }).chain(samples);
+    let mut response = Body::from_stream(body).into_response();
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("video/mp4"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(last + 1 - first));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        "x-video-start",
+        HeaderValue::from_str(&format!("{:.3}", trimmed.start)).unwrap(),
+    );
+    if requested.is_some() {
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+        response.headers_mut().insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {first}-{last}/{length}")).unwrap(),
+        );
+    }
+    response
+}
+
+fn error_response(file_path: &str, e: SeekError) -> Response {
+    match e {
+        SeekError::BeyondEnd => {
+            (StatusCode::BAD_REQUEST, "t is beyond the end of the video").into_response()
+        }
+        SeekError::Mp4(e) => {
+            println!("Can not seek in {file_path}: {e}");
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Seeking is not supported for this video",
+            )
+                .into_response()
+        }
+        SeekError::Storage(StorageError::Status(StatusCode::NOT_FOUND)) => {
+            (StatusCode::NOT_FOUND, "Video not found").into_response()
+        }
+        e => {
+            eprintln!("Error reading {file_path} from video storage: {e}");
+            (StatusCode::BAD_GATEWAY, "Video storage unavailable").into_response()
+        }
+    }
+}
+
+/// The first and last byte of the range a `Range` header asks for. `None`
+/// for headers other than a single byte range, which are answered with the
+/// whole file, and `Some(Err(()))` for a range that starts past the end.
+fn requested_range(range: &HeaderValue, length: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.to_str().ok()?.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = match (first.trim(), last.trim()) {
+        ("", suffix) => match suffix.parse::<u64>().ok()? {
+            0 => return Some(Err(())),
+            suffix => (length.saturating_sub(suffix), length.saturating_sub(1)),
+        },
+        (first, "") => (first.parse().ok()?, length.saturating_sub(1)),
+        (first, last) => {
+            let (first, last): (u64, u64) = (first.parse().ok()?, last.parse().ok()?);
+            if last < first {
+                return None;
+            }
+            (first, last.min(length.saturating_sub(1)))
+        }
+    };
+    if first >= length {
+        return Some(Err(()));
+    }
+    Some(Ok((first, last)))
+}
+
+/// The rewritten header and the part of the stored file with the samples it
+/// refers to.
+async fn trimmed(storage: &StorageFile, start: f64) -> Result<mp4::Trimmed, SeekError> {
+    let (ftyp, moov) = storage.mp4_boxes::<SeekError>().await?;
+    let moov = moov.ok_or(mp4::Error::MissingBox("moov"))?;
+    let movie = mp4::Movie::parse_moov(&moov)?;
+    if start >= movie.duration_secs() {
+        return Err(SeekError::BeyondEnd);
+    }
+    Ok(mp4::trim(&movie, ftyp.as_deref(), start)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(header: &str, length: u64) -> Option<Result<(u64, u64), ()>> {
+        requested_range(&HeaderValue::from_str(header).unwrap(), length)
+    }
+
+    #[test]
+    fn reads_single_byte_ranges() {
+        assert_eq!(range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(range("bytes=500-", 1000), Some(Ok((500, 999))));
+        assert_eq!(range(" bytes=999-999 ", 1000), Some(Ok((999, 999))));
+        // A range past the end ends with the file.
+        assert_eq!(range("bytes=990-2000", 1000), Some(Ok((990, 999))));
+    }
+
+    #[test]
+    fn reads_suffix_ranges() {
+        assert_eq!(range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(range("bytes=-1000", 1000), Some(Ok((0, 999))));
+        assert_eq!(range("bytes=-5000", 1000), Some(Ok((0, 999))));
+        assert_eq!(range("bytes=-0", 1000), Some(Err(())));
+    }
+
+    #[test]
+    fn rejects_ranges_that_start_beyond_the_end() {
+        assert_eq!(range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(range("bytes=5000-6000", 1000), Some(Err(())));
+    }
+
+    #[test]
+    fn answers_other_ranges_with_the_whole_file() {
+        for header in [
+            "bytes=0-1,5-6",
+            "bytes=-1,-2",
+            "bytes=5-2",
+            "bytes=x-",
+            "bytes=0",
+            "items=0-1",
+        ] {
+            assert_eq!(range(header, 1000), None, "{header}");
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use mongodb::bson::oid::ObjectId;
+use std::str::FromStr;
+
+const MAX_SLUG_LENGTH: usize = 80;
+
+/// How a client refers to a video: by its ObjectId or by one of its slugs.
+pub enum VideoRef {
+    Id(ObjectId),
+    Slug(String),
+}
+
+impl VideoRef {
+    /// Returns `None` for anything that is neither a 24 digit hex ObjectId nor
+    /// a well-formed slug.
+    pub fn parse(value: &str) -> Option<Self> {
+        if let Ok(id) = ObjectId::from_str(value) {
+            Some(VideoRef::Id(id))
+        } else if is_valid(value) {
+            Some(VideoRef::Slug(value.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Slugs consist of lower case ASCII letters, digits and single dashes. They
+/// must not look like an ObjectId, as ids take precedence when resolving.
+pub fn is_valid(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LENGTH
+        && !slug.starts_with('-')
+        && !slug.ends_with('-')
+        && !slug.contains("--")
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && ObjectId::from_str(slug).is_err()
+}
+
+/// Derives a slug from a title, e.g. "Rust & Docker: Part 2" → "rust-docker-part-2".
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::with_capacity(title.len());
+    for c in title.chars().flat_map(char::to_lowercase) {
+        let c = fold_accent(c);
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= MAX_SLUG_LENGTH {
+            break;
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        "video".to_string()
+    } else if ObjectId::from_str(slug).is_ok() {
+        format!("{slug}-video")
+    } else {
+        slug.to_string()
+    }
+}
+
+/// Returns the `attempt`th candidate for a slug that is already taken,
+/// e.g. `intro`, `intro-2`, `intro-3`.
+pub fn candidate(base: &str, attempt: u32) -> String {
+    if attempt <= 1 {
+        return base.to_string();
+    }
+    let suffix = format!("-{attempt}");
+    let base = &base[..base.len().min(MAX_SLUG_LENGTH - suffix.len())];
+    format!("{}{suffix}", base.trim_end_matches('-'))
+}
+
+fn fold_accent(c: char) -> char {
+    match c {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'ç' => 'c',
+        'è' | 'é' | 'ê' | 'ë' => 'e',
+        'ì' | 'í' | 'î' | 'ï' => 'i',
+        'ñ' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' => 'u',
+        'ý' | 'ÿ' => 'y',
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_well_formed_slugs() {
+        for slug in ["intro", "rust-docker-part-2", "2024", "a"] {
+            assert!(is_valid(slug), "{slug}");
+        }
+        let too_long = "a".repeat(MAX_SLUG_LENGTH + 1);
+        let not_slugs = [
+            "",
+            "Intro",
+            "-intro",
+            "intro-",
+            "double--dash",
+            "under_score",
+            "space d",
+            "café",
+            "64b7f0c2a1e4d3b2c1a09f8e",
+            &too_long,
+        ];
+        for slug in not_slugs {
+            assert!(!is_valid(slug), "{slug}");
+        }
+    }
+
+    #[test]
+    fn parses_ids_before_slugs() {
+        let id = "64b7f0c2a1e4d3b2c1a09f8e";
+        assert!(matches!(VideoRef::parse(id), Some(VideoRef::Id(parsed)) if parsed.to_hex() == id));
+        assert!(matches!(VideoRef::parse("intro"), Some(VideoRef::Slug(slug)) if slug == "intro"));
+        assert!(VideoRef::parse("Intro!").is_none());
+    }
+
+    #[test]
+    fn slugifies_titles() {
+        assert_eq!(slugify("Rust & Docker: Part 2"), "rust-docker-part-2");
+        assert_eq!(
+            slugify("  Crème Brûlée à la Carte  "),
+            "creme-brulee-a-la-carte"
+        );
+        assert_eq!(slugify("!!!"), "video");
+        assert_eq!(slugify(""), "video");
+        assert_eq!(
+            slugify("64B7F0C2A1E4D3B2C1A09F8E"),
+            "64b7f0c2a1e4d3b2c1a09f8e-video"
+        );
+
+        let long = slugify(&"word ".repeat(40));
+        assert!(long.len() <= MAX_SLUG_LENGTH);
+        assert!(is_valid(&long), "{long}");
+    }
+
+    #[test]
+    fn numbers_candidates_for_taken_slugs() {
+        assert_eq!(candidate("intro", 0), "intro");
+        assert_eq!(candidate("intro", 1), "intro");
+        assert_eq!(candidate("intro", 2), "intro-2");
+        assert_eq!(candidate("intro", 13), "intro-13");
+
+        let long = "a".repeat(MAX_SLUG_LENGTH);
+        let numbered = candidate(&long, 10);
+        assert_eq!(numbered.len(), MAX_SLUG_LENGTH);
+        assert!(numbered.ends_with("a-10"));
+        // A cut right before a dash does not leave two of them.
+        let dashed = format!("{}-b", "a".repeat(MAX_SLUG_LENGTH - 3));
+        assert_eq!(
+            candidate(&dashed, 2),
+            format!("{}-2", "a".repeat(MAX_SLUG_LENGTH - 3))
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use axum::{
+    body::Bytes,
+    http::{StatusCode, header},
+};
+use std::fmt;
+
+use crate::AppState;
+
+/// Most files have `ftyp`, `moov` and `mdat` at the top level, sometimes with
+/// `free` or `uuid` boxes in between. Give up on files with more than this.
+const MAX_TOP_LEVEL_BOXES: usize = 32;
+/// The largest `ftyp` or `moov` read, which are held in memory as a whole.
+/// The `moov` of a day of 60 fps video takes about 60 MiB.
+const MAX_HEADER_BOX_SIZE: u64 = 96 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Request(reqwest::Error),
+    /// video-storage answered with something other than the requested bytes.
+    Status(StatusCode),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Request(e) => write!(f, "{e}"),
+            StorageError::Status(status) => write!(f, "unexpected status {status}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(e: reqwest::Error) -> Self {
+        StorageError::Request(e)
+    }
+}
+
+/// Stores `body` under `file_path` in video-storage, replacing an existing
+/// file. Uploads require the `uploader` role, sent as STORAGE_TOKEN.
+pub async fn upload(
+    app_state: &AppState,
+    file_path: &str,
+    content_type: &str,
+    body: String,
+) -> Result<(), StorageError> {
+    let mut request = reqwest::Client::new()
+        .put(format!(
+            "http://{}:{}/video?path={file_path}",
+            app_state.video_storage_host, app_state.video_storage_port
+        ))
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body);
+    if let Some(token) = &app_state.storage_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        status => Err(StorageError::Status(status)),
+    }
+}
+
+/// A file in video-storage, read piece by piece with range requests.
+pub struct StorageFile {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl StorageFile {
+    pub fn new(app_state: &AppState, file_path: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: format!(
+                "http://{}:{}/video?path={file_path}",
+                app_state.video_storage_host, app_state.video_storage_port
+            ),
+        }
+    }
+
+    /// The size of the file, from the `Content-Range` of a one byte request.
+    pub async fn size(&self) -> Result<Option<u64>, StorageError> {
+        let response = self.request(0, 1).await?;
+        Ok(response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse().ok()))
+    }
+
+    /// Reads up to `length` bytes at `offset`, or nothing past the end.
+    pub async fn read(&self, offset: u64, length: u64) -> Result<Option<Bytes>, StorageError> {
+        match self.request(offset, length).await {
+            Ok(response) => Ok(Some(response.bytes().await?)),
+            Err(StorageError::Status(StatusCode::RANGE_NOT_SATISFIABLE)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Requests `length` bytes at `offset`, leaving the body to the caller.
+    pub async fn request(
+        &self,
+        offset: u64,
+        length: u64,
+    ) -> Result<reqwest::Response, StorageError> {
+        let response = self
+            .client
+            .get(&self.url)
+            .header(
+                header::RANGE,
+                format!("bytes={offset}-{}", offset + length - 1),
+            )
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => Ok(response),
+            status => Err(StorageError::Status(status)),
+        }
+    }
+
+    /// Walks the top-level boxes of an MP4 file by their headers and reads
+    /// `ftyp` and `moov`, wherever they are in the file. Fails on boxes
+    /// larger than [`MAX_HEADER_BOX_SIZE`].
+    pub async fn mp4_boxes<E>(&self) -> Result<(Option<Bytes>, Option<Bytes>), E>
+    where
+        E: From<StorageError> + From<mp4::Error>,
+    {
+        let mut ftyp = None;
+        let mut offset = 0;
+        for _ in 0..MAX_TOP_LEVEL_BOXES {
+            let Some(data) = self.read(offset, 16).await? else {
+                break;
+            };
+            let header = mp4::BoxHeader::parse(&data)?;
+            if header.size == u64::MAX {
+                // The last box, which extends to the end of the file.
+                break;
+            }
+            if header.is(b"ftyp") || header.is(b"moov") {
+                if header.size > MAX_HEADER_BOX_SIZE {
+                    return Err(mp4::Error::Unsupported(format!(
+                        "{} box of {} bytes",
+                        String::from_utf8_lossy(&header.kind),
+                        header.size
+                    ))
+                    .into());
+                }
+                let data = self
+                    .read(offset, header.size)
+                    .await?
+                    .filter(|data| data.len() as u64 == header.size)
+                    .ok_or(mp4::Error::Truncated)?;
+                if header.is(b"moov") {
+                    return Ok((ftyp, Some(data)));
+                }
+                ftyp = Some(data);
+            }
+            offset += header.size;
+        }
+        Ok((ftyp, None))
+    }
+}
@@ -0,0 +1,217 @@
+use auth::Principal;
+use axum::{
+    Json,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, str::FromStr};
+
+use crate::storage;
+use crate::{AppState, PlaybackRequest, find_playable_video, forward_to_storage};
+
+mod webvtt;
+
+pub const CONTENT_TYPE: &str = "text/vtt";
+
+/// What a text track is for, as in the `kind` of an HTML `<track>`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TextTrackKind {
+    /// Translation of the dialogue.
+    #[default]
+    Subtitles,
+    /// Transcription of the dialogue and other sounds, for viewers who can
+    /// not hear them.
+    Captions,
+    /// Textual description of what is seen, for screen readers.
+    Descriptions,
+}
+
+impl TextTrackKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TextTrackKind::Subtitles => "subtitles",
+            TextTrackKind::Captions => "captions",
+            TextTrackKind::Descriptions => "descriptions",
+        }
+    }
+}
+
+/// A WebVTT file that belongs to a video. A video has at most one track of
+/// each kind per language.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TextTrack {
+    /// BCP 47 language tag, such as `en` or `pt-BR`.
+    pub language: String,
+    /// Shown in the track menu of players.
+    pub label: String,
+    #[serde(default)]
+    pub kind: TextTrackKind,
+    /// The WebVTT file in video-storage.
+    pub path: String,
+}
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    #[serde(default)]
+    kind: TextTrackKind,
+    /// Defaults to the language tag.
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteParams {
+    #[serde(default)]
+    kind: TextTrackKind,
+}
+
+#[derive(Deserialize)]
+pub struct TrackFileParams {
+    /// The first track of the language is served when omitted.
+    kind: Option<TextTrackKind>,
+    /// Playback token, required when signed playback is enabled.
+    token: Option<String>,
+}
+
+/// `PUT /videos/{id}/tracks/{language}?kind=&label=`: stores a WebVTT file as
+/// a text track of a video, replacing the track of the same language and
+/// kind. SubRip files are converted to WebVTT.
+pub async fn put_track(
+    State(app_state): State<AppState>,
+    Path((id, language)): Path<(String, String)>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let Ok(id) = ObjectId::from_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response();
+    };
+    if !is_language_tag(&language) {
+        return (
+            StatusCode::BAD_REQUEST,
+            "language must be a BCP 47 tag such as en or pt-BR",
+        )
+            .into_response();
+    }
+    let label = params.label.unwrap_or_else(|| language.clone());
+    if label.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "label must not be empty").into_response();
+    }
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let vtt = if content_type.starts_with(CONTENT_TYPE) || webvtt::is_webvtt(&body) {
+        webvtt::validate(&body).map_err(|e| format!("Invalid WebVTT file, {e}"))
+    } else {
+        webvtt::validate(&webvtt::from_srt(&body))
+            .map_err(|e| format!("Invalid SubRip file, {e} after conversion to WebVTT"))
+    };
+    let vtt = match vtt {
+        Ok(vtt) => vtt,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+
+    match app_state.videos.find_by_id(&id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => return internal_error(e),
+    }
+    let track = TextTrack {
+        path: format!("tracks/{id}/{language}.{}.vtt", params.kind.as_str()),
+        language,
+        label,
+        kind: params.kind,
+    };
+    if let Err(e) = storage::upload(&app_state, &track.path, CONTENT_TYPE, vtt).await {
+        eprintln!("Error uploading {}: {e}", track.path);
+        return (StatusCode::BAD_GATEWAY, "Video storage unavailable").into_response();
+    }
+    match app_state.videos.set_text_track(&id, track.clone()).await {
+        Ok(true) => (StatusCode::CREATED, Json(track)).into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Video not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// `DELETE /videos/{id}/tracks/{language}?kind=`: removes a text track from a
+/// video. Its file stays in video-storage.
+pub async fn delete_track(
+    State(app_state): State<AppState>,
+    Path((id, language)): Path<(String, String)>,
+    Query(params): Query<DeleteParams>,
+) -> Response {
+    let Ok(id) = ObjectId::from_str(&id) else {
+        return (StatusCode::BAD_REQUEST, "Invalid video ID format").into_response();
+    };
+    match app_state
+        .videos
+        .remove_text_track(&id, &language, params.kind)
+        .await
+    {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "Text track not found").into_response(),
+        Err(e) => internal_error(e),
+    }
+}
+
+/// `GET /video/{id}/tracks/{language}.vtt?kind=&token=`: streams the WebVTT
+/// file of a text track, with the same access checks as the video.
+pub async fn get_track(
+    State(app_state): State<AppState>,
+    Path((id, file)): Path<(String, String)>,
+    Query(params): Query<TrackFileParams>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    caller: Option<Principal>,
+) -> Response {
+    // The router can not match a parameter followed by a suffix.
+    let Some(language) = file.strip_suffix(".vtt") else {
+        return (StatusCode::NOT_FOUND, "Text track not found").into_response();
+    };
+    let request = PlaybackRequest {
+        id: &id,
+        token: params.token.as_deref(),
+        peer,
+        headers: &headers,
+        caller: caller.as_ref(),
+    };
+    let video = match find_playable_video(&app_state, request).await {
+        Ok(video) => video,
+        Err(response) => return response,
+    };
+    let Some(track) = video.text_tracks.iter().find(|track| {
+        track.language == language && params.kind.is_none_or(|kind| track.kind == kind)
+    }) else {
+        return (StatusCode::NOT_FOUND, "Text track not found").into_response();
+    };
+    let mut response = forward_to_storage(&app_state, &track.path, &headers).await;
+    if response.status().is_success() {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/vtt; charset=utf-8"),
+        );
+    }
+    response
+}
+
+/// A simplified check of BCP 47 tags: a primary language subtag of letters,
+/// followed by subtags of up to 8 letters or digits.
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    (2..=8).contains(&primary.len())
+        && primary.bytes().all(|byte| byte.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len())
+                && subtag.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        })
+}
+
+fn internal_error(e: impl std::fmt::Display) -> Response {
+    eprintln!("Error accessing the video catalog: {e}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response()
+}
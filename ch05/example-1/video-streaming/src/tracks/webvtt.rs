@@ -0,0 +1,340 @@
+//! Validation of WebVTT files and conversion from SubRip (SRT), which most
+//! subtitle editors still write.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub struct Error {
+    /// 1-based line of the input.
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Whether `text` starts with the `WEBVTT` signature.
+pub fn is_webvtt(text: &str) -> bool {
+    let text = text.strip_prefix('\u{FEFF}').unwrap_or(text);
+    text.strip_prefix("WEBVTT")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t', '\r', '\n']))
+}
+
+/// Checks the structure of a WebVTT file: the signature, and timings with an
+/// end after the start for every cue. Returns the file with `\n` line endings
+/// and without a byte order mark.
+pub fn validate(text: &str) -> Result<String, Error> {
+    let text = normalize(text);
+    if !is_webvtt(&text) {
+        return Err(Error {
+            line: 1,
+            message: "missing WEBVTT signature",
+        });
+    }
+    let lines: Vec<&str> = text.lines().collect();
+    let mut cues = 0;
+    for (start, block) in blocks(&lines).skip(1) {
+        let first = block[0];
+        // NOTE, STYLE and REGION blocks carry no cue. Whether STYLE and REGION
+        // come before the first cue is left to the players.
+        if ["NOTE", "STYLE", "REGION"]
+            .iter()
+            .any(|keyword| first == *keyword || first.starts_with(&format!("{keyword} ")))
+            && !first.contains("-->")
+        {
+            continue;
+        }
+        // An optional identifier precedes the timings.
+        let (line, timings) = if first.contains("-->") {
+            (start, first)
+        } else {
+            match block.get(1) {
+                Some(timings) => (start + 1, *timings),
+                None => {
+                    return Err(Error {
+                        line: start + 1,
+                        message: "cue without timings",
+                    });
+                }
+            }
+        };
+        let error = |message| Error {
+            line: line + 1,
+            message,
+        };
+        let (begin, rest) = timings
+            .split_once("-->")
+            .ok_or(error("cue without timings"))?;
+        let end = rest.split_whitespace().next().unwrap_or_default();
+        let begin = timestamp(begin.trim()).ok_or(error("invalid cue start time"))?;
+        let end = timestamp(end).ok_or(error("invalid cue end time"))?;
+        if end <= begin {
+            return Err(error("cue ends before it starts"));
+        }
+        cues += 1;
+    }
+    if cues == 0 {
+        return Err(Error {
+            line: lines.len().max(1),
+            message: "no cues",
+        });
+    }
+    let mut text = text.strip_prefix('\u{FEFF}').unwrap_or(&text).to_string();
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+/// Converts a SubRip file to WebVTT: cue numbers are dropped, the decimal
+/// commas of the timings become points, `<font>` tags, which WebVTT does
+/// not know, are removed and bare ampersands escaped. The result still has
+/// to be validated.
+pub fn from_srt(text: &str) -> String {
+    let text = normalize(text);
+    let text = text.strip_prefix('\u{FEFF}').unwrap_or(&text);
+    let lines: Vec<&str> = text.lines().collect();
+    let mut out = String::from("WEBVTT\n");
+    for (_, block) in blocks(&lines) {
+        let mut block = block;
+        if block.len() > 1 && block[0].trim().chars().all(|c| c.is_ascii_digit()) {
+            block = &block[1..];
+        }
+        out.push('\n');
+        for (index, line) in block.iter().enumerate() {
+            if index == 0 && line.contains("-->") {
+                // SubRip's X1:… Y2:… coordinates have no WebVTT equivalent.
+                let (begin, rest) = line.split_once("-->").unwrap();
+                let end = rest.split_whitespace().next().unwrap_or_default();
+                out.push_str(&format!(
+                    "{} --> {}\n",
+                    begin.trim().replace(',', "."),
+                    end.replace(',', ".")
+                ));
+            } else {
+                out.push_str(&escape_ampersands(&strip_font_tags(line)));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+fn normalize(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Blocks of consecutive non-empty lines, with the index of their first line.
+fn blocks<'a>(lines: &'a [&'a str]) -> impl Iterator<Item = (usize, &'a [&'a str])> {
+    let mut index = 0;
+    std::iter::from_fn(move || {
+        while index < lines.len() && lines[index].trim().is_empty() {
+            index += 1;
+        }
+        if index == lines.len() {
+            return None;
+        }
+        let start = index;
+        while index < lines.len() && !lines[index].trim().is_empty() {
+            index += 1;
+        }
+        Some((start, &lines[start..index]))
+    })
+}
+
+/// Parses `hh:mm:ss.ttt` or `mm:ss.ttt` into milliseconds.
+fn timestamp(value: &str) -> Option<u64> {
+    let (clock, millis) = value.split_once('.')?;
+    let parts: Vec<&str> = clock.split(':').collect();
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes, seconds] if hours.len() >= 2 => (*hours, *minutes, *seconds),
+        [minutes, seconds] => ("0", *minutes, *seconds),
+        _ => return None,
+    };
+    let number = |digits: &str, length: Option<usize>| {
+        (length.is_none_or(|length| digits.len() == length)
+            && !digits.is_empty()
+            && digits.bytes().all(|byte| byte.is_ascii_digit()))
+        .then(|| digits.parse::<u64>().ok())
+        .flatten()
+    };
+    let hours = number(hours, None)?;
+    let minutes = number(minutes, Some(2)).filter(|minutes| *minutes < 60)?;
+    let seconds = number(seconds, Some(2)).filter(|seconds| *seconds < 60)?;
+    let millis = number(millis, Some(3))?;
+    Some(((hours * 60 + minutes) * 60 + seconds) * 1000 + millis)
+}
+
+fn strip_font_tags(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find('<') {
+        let tag = &rest[start..];
+        let lower = tag.to_ascii_lowercase();
+        out.push_str(&rest[..start]);
+        if (lower.starts_with("<font") || lower.starts_with("</font"))
+            && let Some(end) = tag.find('>')
+        {
+            rest = &tag[end + 1..];
+        } else {
+            out.push('<');
+            rest = &tag[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escapes `&` unless it starts a character reference such as `&amp;`.
+fn escape_ampersands(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    for (index, part) in line.split('&').enumerate() {
+        if index > 0 {
+            let reference = part.split_once(';').is_some_and(|(name, _)| {
+                !name.is_empty()
+                    && name
+                        .bytes()
+                        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'#')
+            });
+            out.push_str(if reference { "&" } else { "&amp;" });
+        }
+        out.push_str(part);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> (usize, &'static str) {
+        let e = validate(text).unwrap_err();
+        (e.line, e.message)
+    }
+
+    #[test]
+    fn recognises_the_signature() {
+        assert!(is_webvtt("WEBVTT"));
+        assert!(is_webvtt("\u{FEFF}WEBVTT - Subtitles\n"));
+        assert!(is_webvtt("WEBVTT\r\n"));
+        assert!(!is_webvtt("WEBVTTX"));
+        assert!(!is_webvtt("webvtt"));
+        assert!(!is_webvtt(" WEBVTT"));
+    }
+
+    #[test]
+    fn accepts_cues_with_identifiers_settings_and_comments() {
+        let text = "\u{FEFF}WEBVTT\r\n\r\n\
+                    NOTE written by hand\r\n\r\n\
+                    STYLE\r\n::cue { color: yellow }\r\n\r\n\
+                    intro\r\n00:01.000 --> 00:04.000 line:0 align:start\r\nHello\r\n\r\n\
+                    01:00:00.000 --> 01:00:02.500\r\nGoodbye";
+        assert_eq!(
+            validate(text).unwrap(),
+            "WEBVTT\n\nNOTE written by hand\n\nSTYLE\n::cue { color: yellow }\n\n\
+             intro\n00:01.000 --> 00:04.000 line:0 align:start\nHello\n\n\
+             01:00:00.000 --> 01:00:02.500\nGoodbye\n"
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_the_first_problem() {
+        assert_eq!(
+            error("1\n00:01.000 --> 00:02.000\n"),
+            (1, "missing WEBVTT signature")
+        );
+        assert_eq!(error("WEBVTT\n\nNOTE only a comment\n"), (3, "no cues"));
+        assert_eq!(error("WEBVTT\n"), (1, "no cues"));
+        assert_eq!(error("WEBVTT\n\nintro\n"), (3, "cue without timings"));
+        assert_eq!(
+            error("WEBVTT\n\nintro\nHello\n"),
+            (4, "cue without timings")
+        );
+        assert_eq!(
+            error("WEBVTT\n\n00:01,000 --> 00:02.000\nHello\n"),
+            (3, "invalid cue start time")
+        );
+        assert_eq!(
+            error("WEBVTT\n\n00:01.000 --> 00:02\nHello\n"),
+            (3, "invalid cue end time")
+        );
+        assert_eq!(
+            error("WEBVTT\n\nintro\n00:02.000 --> 00:02.000\nHello\n"),
+            (4, "cue ends before it starts")
+        );
+    }
+
+    #[test]
+    fn parses_timestamps_into_milliseconds() {
+        assert_eq!(timestamp("00:00.000"), Some(0));
+        assert_eq!(timestamp("01:02.345"), Some(62_345));
+        assert_eq!(timestamp("01:00:00.001"), Some(3_600_001));
+        // Hours may have more than two digits.
+        assert_eq!(timestamp("100:00:00.000"), Some(360_000_000));
+        for invalid in [
+            "1:00:00.000",
+            "00:60.000",
+            "00:00.00",
+            "00:00,000",
+            "0:00.000",
+            "00:00",
+            "aa:00.000",
+            "00:+1.000",
+            "00:00:00:00.000",
+        ] {
+            assert_eq!(timestamp(invalid), None, "{invalid}");
+        }
+        // The minutes of hh:mm:ss are bounded too.
+        assert_eq!(timestamp("00:60:00.000"), None);
+    }
+
+    #[test]
+    fn converts_subrip_to_webvtt() {
+        let srt = "\u{FEFF}1\r\n\
+                   00:00:01,000 --> 00:00:04,000 X1:100 X2:200 Y1:10 Y2:20\r\n\
+                   <font color=\"#ffff00\">Tom & Jerry</font>\r\n\
+                   <i>&amp; friends</i>\r\n\r\n\
+                   2\r\n\
+                   00:00:05,500 --> 00:00:06,000\r\n\
+                   3 < 4\r\n";
+        let vtt = from_srt(srt);
+        assert_eq!(
+            vtt,
+            "WEBVTT\n\n\
+             00:00:01.000 --> 00:00:04.000\n\
+             Tom &amp; Jerry\n\
+             <i>&amp; friends</i>\n\n\
+             00:00:05.500 --> 00:00:06.000\n\
+             3 < 4\n"
+        );
+        assert!(validate(&vtt).is_ok());
+    }
+
+    #[test]
+    fn keeps_a_cue_that_is_only_a_number() {
+        // A one-line block is text, not a cue number.
+        assert_eq!(from_srt("42\n"), "WEBVTT\n\n42\n");
+    }
+
+    #[test]
+    fn strips_only_font_tags() {
+        assert_eq!(
+            strip_font_tags("<FONT face=\"Arial\">a</Font> <b>b</b>"),
+            "a <b>b</b>"
+        );
+        assert_eq!(strip_font_tags("a < b"), "a < b");
+        // An unclosed tag is kept as text.
+        assert_eq!(strip_font_tags("<font a"), "<font a");
+    }
+
+    #[test]
+    fn escapes_ampersands_that_start_no_reference() {
+        assert_eq!(escape_ampersands("Tom & Jerry"), "Tom &amp; Jerry");
+        assert_eq!(escape_ampersands("&amp; &lt; &#38;"), "&amp; &lt; &#38;");
+        assert_eq!(escape_ampersands("R&D and Q&A"), "R&amp;D and Q&amp;A");
+        assert_eq!(escape_ampersands("&; &"), "&amp;; &amp;");
+    }
+}
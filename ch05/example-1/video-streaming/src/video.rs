@@ -0,0 +1,209 @@
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::chapters::Chapter;
+use crate::probe::MediaInfo;
+use crate::rendition::Rendition;
+use crate::tracks::TextTrack;
+
+/// Who can find and stream a video. Videos that cannot be accessed are
+/// answered with 404, so that clients cannot probe for hidden content.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed in the catalog and streamable by anyone.
+    #[default]
+    Public,
+    /// Streamable by anyone who knows its id or slug, but never listed.
+    Unlisted,
+    /// Only accessible to its owner and the viewers it has been granted to.
+    Private,
+}
+
+/// A record of the `videos` collection.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Video {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// The file served to clients that do not get one of the `renditions`.
+    #[serde(rename = "videoPath")]
+    pub video_path: String,
+    /// Encodings of the video in other resolutions and bitrates.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renditions: Vec<Rendition>,
+    /// Human readable identifier, unique across the `slug` and `previousSlugs`
+    /// of all videos. Records created before slugs were introduced have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    /// Former slugs, which are redirected to the current one.
+    #[serde(
+        rename = "previousSlugs",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub previous_slugs: Vec<String>,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Length of the video in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Records without a visibility predate it and are public.
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Subject of the user who uploaded the video.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// Subjects that may access the video even if it is private.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grants: Vec<String>,
+    /// Container headers of `video_path`, filled in by probing the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<MediaInfo>,
+    /// Subtitles and captions, uploaded separately from the record.
+    #[serde(rename = "textTracks", default, skip_serializing_if = "Vec::is_empty")]
+    pub text_tracks: Vec<TextTrack>,
+    /// In order of their start.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chapters: Vec<Chapter>,
+}
+
+/// The writable part of a video record, as accepted by the catalog API.
+#[derive(Clone, Debug, Deserialize)]
+pub struct VideoInput {
+    #[serde(rename = "videoPath")]
+    pub video_path: String,
+    /// Updates that omit it keep the renditions of the file, among them the
+    /// ones the packager added.
+    pub renditions: Option<Vec<Rendition>>,
+    /// Generated from the title when omitted.
+    pub slug: Option<String>,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// Defaults to the authenticated caller when a video is created, and to
+    /// the current owner when it is updated.
+    pub owner: Option<String>,
+    /// Updates that omit it keep the current grants.
+    pub grants: Option<Vec<String>>,
+}
+
+impl VideoInput {
+    /// Fills in what an update omits from the record it replaces: the owner
+    /// and grants, and the renditions unless the file has changed.
+    pub fn keep_stored(&mut self, existing: &Video) {
+        if self.owner.is_none() {
+            self.owner = existing.owner.clone();
+        }
+        if self.grants.is_none() {
+            self.grants = Some(existing.grants.clone());
+        }
+        if self.renditions.is_none() && self.video_path == existing.video_path {
+            self.renditions = Some(existing.renditions.clone());
+        }
+    }
+}
+
+impl Video {
+    pub fn new(id: ObjectId, input: VideoInput, slug: String, previous_slugs: Vec<String>) -> Self {
+        Self {
+            id,
+            video_path: input.video_path,
+            renditions: input.renditions.unwrap_or_default(),
+            slug: Some(slug),
+            previous_slugs,
+            title: input.title,
+            description: input.description,
+            tags: input.tags,
+            duration: input.duration,
+            visibility: input.visibility,
+            owner: input.owner,
+            grants: input.grants.unwrap_or_default(),
+            media: None,
+            text_tracks: Vec::new(),
+            chapters: Vec::new(),
+        }
+    }
+
+    /// Keeps what the catalog API does not write from the record this one
+    /// replaces: text tracks and chapters, and the probe results unless the
+    /// file has changed, with the probed duration if none is given.
+    pub fn keep_attached(&mut self, existing: &Video) {
+        self.text_tracks = existing.text_tracks.clone();
+        self.chapters = existing.chapters.clone();
+        if self.video_path == existing.video_path {
+            self.media = existing.media.clone();
+            if self.duration.is_none() {
+                self.duration = self.media.as_ref().and_then(|media| media.duration);
+            }
+        }
+    }
+
+    /// Whether `viewer`, the subject of an authenticated client, may stream
+    /// the video when addressing it directly by id or slug.
+    pub fn is_accessible_by(&self, viewer: Option<&str>) -> bool {
+        match self.visibility {
+            Visibility::Public | Visibility::Unlisted => true,
+            Visibility::Private => viewer.is_some_and(|viewer| {
+                self.owner.as_deref() == Some(viewer)
+                    || self.grants.iter().any(|grant| grant == viewer)
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(visibility: Visibility) -> Video {
+        let input = VideoInput {
+            video_path: "intro.mp4".to_string(),
+            renditions: None,
+            slug: None,
+            title: "Intro".to_string(),
+            description: String::new(),
+            tags: Vec::new(),
+            duration: None,
+            visibility,
+            owner: Some("owner".to_string()),
+            grants: Some(vec!["friend".to_string()]),
+        };
+        Video::new(ObjectId::new(), input, "intro".to_string(), Vec::new())
+    }
+
+    #[test]
+    fn public_and_unlisted_videos_are_accessible_by_anyone() {
+        for visibility in [Visibility::Public, Visibility::Unlisted] {
+            let video = video(visibility);
+            for viewer in [None, Some("stranger"), Some("owner")] {
+                assert!(video.is_accessible_by(viewer), "{visibility:?} {viewer:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn private_videos_are_accessible_by_their_owner_and_grantees() {
+        let video = video(Visibility::Private);
+        assert!(video.is_accessible_by(Some("owner")));
+        assert!(video.is_accessible_by(Some("friend")));
+        assert!(!video.is_accessible_by(Some("stranger")));
+        assert!(!video.is_accessible_by(None));
+
+        let unowned = Video {
+            owner: None,
+            grants: Vec::new(),
+            ..video
+        };
+        assert!(!unowned.is_accessible_by(Some("owner")));
+    }
+}
@@ -0,0 +1,202 @@
+use auth::Principal;
+use axum::http::{HeaderMap, header};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use event_bus::{Event, EventBus, EventBusExt};
+use events::{CloudEvent, VideoViewed};
+use outbox::Outbox;
+use sha2::{Digest, Sha256};
+use std::{env, net::IpAddr, sync::Arc, time::Duration};
+
+use crate::{EVENT_SOURCE, video::Video};
+
+mod outbox;
+
+const HISTORY_URL: &str = "http://history/viewed";
+/// How long [`Transport::deliver`] waits for the bus to take a message, or
+/// for history to answer. Shorter than the claim on outbox entries, so that
+/// an entry is not delivered twice while it is still being posted.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The message history records views from.
+pub type Viewed = CloudEvent<VideoViewed>;
+
+/// Who views a video, and with which client.
+pub struct Viewer {
+    /// Subject of the signed in viewer.
+    id: Option<String>,
+    /// For viewers who are not signed in, a hash of the client address and
+    /// user agent, so that their views can be told apart without storing
+    /// the address.
+    anonymous_id: Option<String>,
+    user_agent: Option<String>,
+    referrer: Option<String>,
+}
+
+impl Viewer {
+    pub fn new(headers: &HeaderMap, client: IpAddr, caller: Option<&Principal>) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let user_agent = header(header::USER_AGENT);
+        let anonymous_id = match caller {
+            Some(_) => None,
+            None => {
+                let mut hasher = Sha256::new();
+                hasher.update(client.to_string());
+                hasher.update([0]);
+                hasher.update(user_agent.as_deref().unwrap_or_default());
+                Some(URL_SAFE_NO_PAD.encode(&hasher.finalize()[..16]))
+            }
+        };
+        Self {
+            id: caller.map(|caller| caller.subject.clone()),
+            anonymous_id,
+            user_agent,
+            referrer: header(header::REFERER),
+        }
+    }
+}
+
+/// How viewed messages reach the history service.
+#[derive(Clone)]
+pub enum ViewedSender {
+    /// Sent right away. Views are lost if video-streaming stops before they
+    /// are delivered.
+    Direct(Transport),
+    /// Written to the outbox collection, from which a relay task delivers
+    /// them, see [`outbox`].
+    Outbox(Arc<Outbox>),
+}
+
+impl ViewedSender {
+    /// Reads VIEWED_OUTBOX and the transport, see [`Transport::from_env`].
+    /// The outbox lives in the database of the catalog, so it requires the
+    /// mongo repository.
+    pub async fn from_env(db: Option<&mongodb::Database>, bus: Option<Arc<dyn EventBus>>) -> Self {
+        let transport = Transport::from_env(bus);
+        if !env::var("VIEWED_OUTBOX").is_ok_and(|v| v == "true") {
+            return ViewedSender::Direct(transport);
+        }
+        let db = db.expect("VIEWED_OUTBOX requires VIDEO_REPOSITORY=mongo");
+        let retention = env::var("OUTBOX_RETENTION_SECS")
+            .map(|secs| secs.parse().expect("OUTBOX_RETENTION_SECS is not a number"))
+            .unwrap_or(outbox::DEFAULT_RETENTION_SECS);
+        let outbox = Arc::new(Outbox::new(db, Duration::from_secs(retention)));
+        if let Err(e) = outbox.ensure_indexes().await {
+            eprintln!("Error creating the outbox indexes: {e}");
+        }
+        outbox::spawn_relay(outbox.clone(), transport);
+        ViewedSender::Outbox(outbox)
+    }
+
+    /// Tells the history service about a view. Only waits for the outbox
+    /// entry to be written, not for the delivery.
+    pub async fn send(&self, video: &Video, viewer: Viewer) {
+        let viewed = Viewed::new(
+            EVENT_SOURCE,
+            VideoViewed {
+                video_id: Some(video.id.to_hex()),
+                video_path: video.video_path.clone(),
+                viewer_id: viewer.id,
+                anonymous_id: viewer.anonymous_id,
+                user_agent: viewer.user_agent,
+                referrer: viewer.referrer,
+            },
+        );
+        match self {
+            ViewedSender::Direct(transport) => transport.send(viewed),
+            ViewedSender::Outbox(outbox) => {
+                if let Err(e) = outbox.add(&viewed).await {
+                    eprintln!(
+                        "Error writing the view of {} to the outbox: {e}",
+                        viewed.data.video_path
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// How messages are delivered to history, selected by VIEWED_TRANSPORT.
+#[derive(Clone)]
+pub enum Transport {
+    /// `POST /viewed` to history. Views are lost while history is down,
+    /// unless they go through the outbox.
+    Http(reqwest::Client),
+    /// Published to the `viewed` topic of the event bus.
+    Bus(Arc<dyn EventBus>),
+}
+
+impl Transport {
+    /// Reads VIEWED_TRANSPORT, `http` (the default) or `bus`. The latter
+    /// requires the event bus of the service, see [`event_bus::from_env`].
+    fn from_env(bus: Option<Arc<dyn EventBus>>) -> Self {
+        match env::var("VIEWED_TRANSPORT").as_deref() {
+            Ok("http") | Err(_) => Transport::http(),
+            Ok("bus") => {
+                Transport::Bus(bus.expect("VIEWED_TRANSPORT=bus requires RABBIT or EVENT_BUS"))
+            }
+            Ok(other) => panic!("Unknown VIEWED_TRANSPORT {other}, expected http or bus"),
+        }
+    }
+
+    /// Posts to history with a client that gives up after
+    /// [`DELIVERY_TIMEOUT`].
+    pub fn http() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .expect("Can not create the HTTP client");
+        Transport::Http(client)
+    }
+
+    /// Sends a message without waiting for it to be delivered.
+    fn send(&self, viewed: Viewed) {
+        let transport = self.clone();
+        tokio::spawn(async move {
+            let sent = match &transport {
+                Transport::Http(client) => match serde_json::to_vec(&viewed) {
+                    Ok(body) => post_viewed_message(client, body).await,
+                    Err(e) => Err(e.to_string()),
+                },
+                Transport::Bus(bus) => bus.publish(&viewed).await.map_err(|e| e.to_string()),
+            };
+            if let Err(e) = sent {
+                eprintln!("Error sending the viewed message: {e}");
+            }
+        });
+    }
+
+    /// Delivers an encoded message, waiting for history to accept it or the
+    /// bus to take it.
+    async fn deliver(&self, payload: Vec<u8>) -> Result<(), String> {
+        match self {
+            Transport::Http(client) => post_viewed_message(client, payload).await,
+            Transport::Bus(bus) => {
+                let published = bus.publish_raw(Viewed::TOPIC, payload);
+                match tokio::time::timeout(DELIVERY_TIMEOUT, published).await {
+                    Ok(result) => result.map_err(|e| e.to_string()),
+                    Err(_) => Err("not taken by the event bus in time".to_string()),
+                }
+            }
+        }
+    }
+}
+
+async fn post_viewed_message(client: &reqwest::Client, body: Vec<u8>) -> Result<(), String> {
+    let response = client
+        .post(HISTORY_URL)
+        .body(body)
+        .header("Content-Type", events::CONTENT_TYPE)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("history answered {}", response.status()))
+    }
+}
@@ -0,0 +1,202 @@
+//! The outbox keeps viewed messages in Mongo until they are delivered, so
+//! that views survive restarts of video-streaming and outages of history
+//! and the broker. Delivery is at least once: a message whose delivery is
+//! not recorded, because the relay stopped or lost the database, is
+//! delivered again.
+
+use mongodb::{
+    Collection, IndexModel,
+    bson::{DateTime, doc, oid::ObjectId},
+    options::{IndexOptions, ReturnDocument},
+};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Notify;
+
+use super::{Transport, Viewed};
+
+/// Delivered entries are kept for a day by default.
+pub const DEFAULT_RETENTION_SECS: u64 = 86_400;
+/// Entries claimed and delivered at a time.
+const BATCH_SIZE: usize = 100;
+/// How often the relay looks for due entries when no view wakes it up.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a claimed entry is left to the relay that claimed it, before
+/// another relay may deliver it. Longer than any delivery attempt takes.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboxEntry {
+    #[serde(rename = "_id")]
+    id: ObjectId,
+    /// The message as sent to history.
+    message: serde_json::Value,
+    created_at: DateTime,
+    /// Delivery attempts so far, including the one in progress.
+    attempts: u32,
+    /// When the entry is due for delivery. Removed once it is delivered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_attempt_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    delivered_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+pub struct Outbox {
+    entries: Collection<OutboxEntry>,
+    /// Wakes the relay up when an entry is added.
+    added: Notify,
+    /// How long delivered entries are kept.
+    retention: Duration,
+}
+
+impl Outbox {
+    /// The outbox in the `outbox` collection of `db`.
+    pub fn new(db: &mongodb::Database, retention: Duration) -> Self {
+        Self {
+            entries: db.collection("outbox"),
+            added: Notify::new(),
+            retention,
+        }
+    }
+
+    /// The index due entries are claimed by, and a TTL index that removes
+    /// delivered entries once the retention has passed.
+    pub async fn ensure_indexes(&self) -> Result<(), mongodb::error::Error> {
+        let due_index = IndexModel::builder()
+            .keys(doc! { "nextAttemptAt": 1 })
+            .options(IndexOptions::builder().sparse(true).build())
+            .build();
+        let delivered_index = IndexModel::builder()
+            .keys(doc! { "deliveredAt": 1 })
+            .options(IndexOptions::builder().expire_after(self.retention).build())
+            .build();
+        self.entries
+            .create_indexes([due_index, delivered_index])
+            .await?;
+        Ok(())
+    }
+
+    /// Adds a message, due for delivery right away.
+    pub async fn add(&self, viewed: &Viewed) -> Result<(), mongodb::error::Error> {
+        let now = DateTime::now();
+        let entry = OutboxEntry {
+            id: ObjectId::new(),
+            message: serde_json::to_value(viewed).expect("viewed messages are JSON"),
+            created_at: now,
+            attempts: 0,
+            next_attempt_at: Some(now),
+            delivered_at: None,
+            last_error: None,
+        };
+        self.entries.insert_one(entry).await?;
+        self.added.notify_one();
+        Ok(())
+    }
+
+    /// Takes the entry that has been due the longest, postponing it by the
+    /// claim timeout so that no other relay takes it meanwhile.
+    async fn claim(&self) -> Result<Option<OutboxEntry>, mongodb::error::Error> {
+        let now = DateTime::now();
+        self.entries
+            .find_one_and_update(
+                doc! { "nextAttemptAt": { "$lte": now } },
+                doc! {
+                    "$set": { "nextAttemptAt": now.saturating_add_duration(CLAIM_TIMEOUT) },
+                    "$inc": { "attempts": 1 },
+                },
+            )
+            .sort(doc! { "nextAttemptAt": 1 })
+            .return_document(ReturnDocument::After)
+            .await
+    }
+
+    async fn delivered(&self, id: ObjectId) -> Result<(), mongodb::error::Error> {
+        self.entries
+            .update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": { "deliveredAt": DateTime::now() },
+                    "$unset": { "nextAttemptAt": "", "lastError": "" },
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Schedules the next attempt, with exponential backoff.
+    async fn failed(&self, entry: &OutboxEntry, error: &str) -> Result<(), mongodb::error::Error> {
+        let delay =
+            Duration::from_secs(1 << entry.attempts.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY);
+        self.entries
+            .update_one(
+                doc! { "_id": entry.id },
+                doc! {
+                    "$set": {
+                        "nextAttemptAt": DateTime::now().saturating_add_duration(delay),
+                        "lastError": error,
+                    },
+                },
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Delivers due outbox entries in the background, as soon as they are added
+/// by this instance and otherwise every [`POLL_INTERVAL`].
+pub fn spawn_relay(outbox: Arc<Outbox>, transport: Transport) {
+    tokio::spawn(async move {
+        loop {
+            match relay(&outbox, &transport).await {
+                Ok(0) => {
+                    tokio::select! {
+                        _ = outbox.added.notified() => {}
+                        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Error relaying viewed messages from the outbox: {e}");
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+    });
+}
+
+/// Claims a batch of due entries and delivers them concurrently. Returns how
+/// many entries were claimed.
+async fn relay(outbox: &Outbox, transport: &Transport) -> Result<usize, mongodb::error::Error> {
+    let mut claimed = Vec::new();
+    while claimed.len() < BATCH_SIZE
+        && let Some(entry) = outbox.claim().await?
+    {
+        claimed.push(entry);
+    }
+    let count = claimed.len();
+    let deliveries = claimed.into_iter().map(|entry| async move {
+        let delivered = transport
+            .deliver(entry.message.to_string().into_bytes())
+            .await;
+        match delivered {
+            Ok(()) => outbox.delivered(entry.id).await.map(|_| None),
+            Err(e) => outbox.failed(&entry, &e).await.map(|_| Some(e)),
+        }
+    });
+    let mut failures = 0;
+    let mut last_error = None;
+    for result in futures::future::join_all(deliveries).await {
+        if let Some(e) = result? {
+            failures += 1;
+            last_error = Some(e);
+        }
+    }
+    if let Some(e) = last_error {
+        eprintln!("Can not deliver {failures} of {count} viewed messages, retrying later: {e}");
+    }
+    Ok(count)
+}